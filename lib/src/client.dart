@@ -75,6 +75,9 @@ class Whixp extends WhixpBase {
     super.disableStartTLS,
     super.useWebSocket,
     super.wsPath,
+    super.useRfc7395Framing,
+    super.interactiveTrust,
+    super.pushEvents,
     super.pingKeepAlive,
     super.pingKeepAliveInterval,
     super.logger,
@@ -99,10 +102,10 @@ class Whixp extends WhixpBase {
     registerFeature(
       'starttls',
       (_) async {
-        await transport.connection.startTLS();
-        final result = StartTLS.handleStartTLS(transport);
-        return result;
+        if (transport.isConnectionSecured) return false;
+        return StartTLS.handleStartTLS(transport);
       },
+      restart: true,
       order: 10,
     );
     registerFeature(
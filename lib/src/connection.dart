@@ -99,7 +99,14 @@ class Connection {
             'createNativeTransport returned null');
       }
 
-      final result = _native!.connect();
+      final native = _native!;
+      // A trust prompt arrives on this isolate's event port, which a blocking
+      // connect would hold up until the prompt times out.
+      final result = configuration.interactiveTrust
+          ? await native.connectAsync(push: configuration.pushEvents)
+          : native.connect();
+      // Aborted while connecting on the helper isolate.
+      if (!identical(_native, native)) return;
       if (result != 0) {
         final message = _native!.lastError;
         throw WhixpInternalException(
@@ -111,7 +118,7 @@ class Connection {
       serviceName = _native!.resolvedHost.isNotEmpty
           ? _native!.resolvedHost
           : configuration.host;
-      _native!.startPolling();
+      _native!.startPolling(push: configuration.pushEvents);
 
       await _onStart();
     } catch (exception) {
@@ -202,18 +209,46 @@ class Connection {
     }
   }
 
-  /// Performs a handshake for TLS. Not used with native transport (TLS is
-  /// handled inside Rust for DirectTls / TcpStartTls).
+  /// Upgrades the live stream to TLS in place. Call after the server's
+  /// `<proceed/>`; the caller then restarts the stream. A failed handshake is
+  /// passed to [handleError] and aborts the connection.
   Future<bool> startTLS() async {
     if (configuration.disableStartTLS) {
       Log.instance
           .info('Disable StartTLS is enabled, can not negotiate handshake');
       return false;
     }
-    // Native path: TLS is already negotiated by Rust; no separate StartTLS step.
-    return false;
+    final native = _native;
+    if (native == null) return false;
+    final result = configuration.interactiveTrust
+        ? await native.startTlsAsync()
+        : native.startTls();
+    if (!identical(_native, native)) return false;
+    if (result != 0) {
+      final message = native.lastError;
+      handleError(
+        WhixpInternalException(
+          message.isNotEmpty ? message : 'StartTLS failed with code $result',
+        ),
+      );
+      abort(state: TransportState.connectionFailure);
+      return false;
+    }
+    return true;
   }
 
+  /// Answers a pending 'badCertificate' event: [accept] lets the paused
+  /// handshake continue with the presented certificate, otherwise it fails.
+  /// Returns `false` when no handshake is waiting for a decision.
+  bool trustDecision(bool accept) => _native?.trustDecision(accept) == 0;
+
+  /// Whether the live native transport delivers events in push mode.
+  bool get pushesEvents => _native?.pushMode ?? false;
+
+  /// Tells the native framer a stream restart follows (after SASL success), so
+  /// the server's new stream header is framed as a header again.
+  void restartStream() => _native?.restartStream();
+
   /// Reschedules current connection attempt when an error occurs.
   void _rescheduleConnectionAttempt() =>
       currentConnectionAttempt = async.Completer()..complete(start());
@@ -291,9 +326,9 @@ class Connection {
   Future<void>? setShouldReconnect(bool value) =>
       _reconnectionPolicy?.setShouldReconnect(value);
 
-  /// Indicates whether the connection is secured (TLS). Native transport uses
-  /// TLS when kind is DirectTls or TcpStartTls after handshake.
-  bool get isConnectionSecure => _native != null;
+  /// Indicates whether the connection is secured (TLS): DirectTls, or
+  /// TcpStartTls after the StartTLS upgrade.
+  bool get isConnectionSecure => _native?.isSecure ?? false;
 }
 
 /// Factory that creates a native transport. Rust performs DNS (SRV + A/AAAA) and connect.
@@ -322,6 +357,8 @@ class ConnectionConfiguration {
     this.service,
     this.useWebSocket = false,
     this.wsPath,
+    this.interactiveTrust = false,
+    this.pushEvents = true,
   });
 
   /// The host to connect to.
@@ -365,6 +402,15 @@ class ConnectionConfiguration {
   /// WebSocket path (e.g. "/ws" or "/xmpp-websocket"). Only used when [useWebSocket] is true. Defaults to "/ws".
   final String? wsPath;
 
+  /// If `true`, the native transport pauses a TLS handshake on a certificate
+  /// that fails verification until [Connection.trustDecision] is called, so
+  /// connect and StartTLS run on a helper isolate.
+  final bool interactiveTrust;
+
+  /// If `true`, the native transport wakes this isolate when its event queue
+  /// becomes non-empty; otherwise events are polled every 2 ms.
+  final bool pushEvents;
+
   @override
   int get hashCode => Object.hashAll([
         host,
@@ -377,6 +423,8 @@ class ConnectionConfiguration {
         service,
         useWebSocket,
         wsPath,
+        interactiveTrust,
+        pushEvents,
       ]);
 
   @override
@@ -392,6 +440,8 @@ class ConnectionConfiguration {
         other.useIPv6WhenResolvingDNS &&
         other.service == service &&
         other.useWebSocket == useWebSocket &&
-        other.wsPath == wsPath;
+        other.wsPath == wsPath &&
+        other.interactiveTrust == interactiveTrust &&
+        other.pushEvents == pushEvents;
   }
 }
@@ -38,7 +38,8 @@ bool get isNativeTransportAvailable {
 
 DynamicLibrary? _lib;
 
-// No callbacks from Rust threads; we poll from main isolate instead.
+// Events are drained from the main isolate, on a timer or when a NativeCallable
+// listener (push mode) says the queue became non-empty.
 
 /// Load the native library. Called once; falls back to null if not found.
 /// Tries the simple name first (app bundle / LD_LIBRARY_PATH), then the
@@ -151,8 +152,48 @@ const int kKindDirectTls = 2;
 const int kKindWebSocket = 3;
 const int kKindWebSocketTls = 4;
 
+/// How XMPP is carried over WebSocket (Rust: WsFraming), passed as
+/// `ws_framing`. RFC 7395 requires the `xmpp` subprotocol and reports
+/// 'open', 'close' and 'seeOtherHost' events.
+const int kWsFramingStream = 0;
+const int kWsFramingRfc7395 = 1;
+
+/// Address family policy (Rust: IpPreference), passed as `use_ipv6`. Both
+/// "prefer" values race IPv6 and IPv4 (Happy Eyeballs); "only" values restrict.
+const int kIpPreferIpv4 = 0;
+const int kIpPreferIpv6 = 1;
+const int kIpv4Only = 2;
+const int kIpv6Only = 3;
+
+/// DoH message format (Rust: DohFormat), passed as `doh_format`.
+const int kDohFormatJson = 0;
+const int kDohFormatWire = 1;
+
+/// Root certificates to verify servers against (Rust: TrustStore).
+const int kTrustStoreBundled = 0;
+const int kTrustStoreSystem = 1;
+const int kTrustStoreBundledAndSystem = 2;
+
+/// How SPKI pins combine with chain validation (Rust: PinMode).
+const int kPinAndChain = 0;
+const int kPinOnly = 1;
+
+/// Lowest TLS version offered (Rust: TlsVersion), passed as `tls_min_version`.
+const int kTlsVersion12 = 0;
+const int kTlsVersion13 = 1;
+
+/// Where TLS sessions are kept for resumption (Rust: SessionCache).
+const int kSessionCachePerHandle = 0;
+const int kSessionCacheShared = 1;
+const int kSessionCacheDisabled = 2;
+
+/// Channel binding type bits (Rust: ChannelBinding) for SCRAM-PLUS / XEP-0440.
+const int kChannelBindingTlsExporter = 1;
+const int kChannelBindingTlsServerEndPoint = 2;
+
 /// C config struct (Rust: CTransportConfig). host = domain to resolve; service = SRV name (e.g. xmpp-client) or null.
 /// ws_path = WebSocket path (e.g. "/ws") or null for default "/ws".
+/// auto_reconnect = 1 to reconnect natively with the retry_* policy (0 = default per field).
 final class CTransportConfig extends Struct {
   external Pointer<Utf8> host_ptr;
   @Uint32()
@@ -174,6 +215,75 @@ final class CTransportConfig extends Struct {
   external Pointer<Utf8> ws_path_ptr;
   @Uint32()
   external int ws_path_len;
+  @Int32()
+  external int auto_reconnect;
+  @Uint32()
+  external int retry_max_attempts;
+  @Uint32()
+  external int retry_initial_delay_ms;
+  @Uint32()
+  external int retry_max_delay_ms;
+  @Double()
+  external double retry_multiplier;
+  @Double()
+  external double retry_jitter;
+  @Uint32()
+  external int attempt_delay_ms;
+  external Pointer<Utf8> doh_endpoints_ptr;
+  @Uint32()
+  external int doh_endpoints_len;
+  @Int32()
+  external int doh_format;
+  @Uint32()
+  external int dns_stale_grace_ms;
+  @Int32()
+  external int trust_store;
+  external Pointer<Uint8> trust_anchors_ptr;
+  @Uint32()
+  external int trust_anchors_len;
+  external Pointer<Uint8> spki_pins_ptr;
+  @Uint32()
+  external int spki_pins_len;
+  @Int32()
+  external int spki_pin_mode;
+  @Int32()
+  external int interactive_trust;
+  @Uint32()
+  external int trust_prompt_timeout_ms;
+  external Pointer<Uint8> client_cert_ptr;
+  @Uint32()
+  external int client_cert_len;
+  external Pointer<Uint8> client_key_ptr;
+  @Uint32()
+  external int client_key_len;
+  @Uint32()
+  external int handshake_timeout_ms;
+  @Int32()
+  external int tls_min_version;
+  external Pointer<Uint16> cipher_suites_ptr;
+  @Uint32()
+  external int cipher_suites_len;
+  external Pointer<Uint16> kx_groups_ptr;
+  @Uint32()
+  external int kx_groups_len;
+  @Int32()
+  external int tls_session_cache;
+  external Pointer<Utf8> key_log_path_ptr;
+  @Uint32()
+  external int key_log_path_len;
+  external Pointer<NativeFunction<_KeyLogLineNative>> key_log_callback;
+  external Pointer<Void> key_log_user_data;
+  @Int32()
+  external int ws_framing;
+  external Pointer<Utf8> ws_headers_ptr;
+  @Uint32()
+  external int ws_headers_len;
+  @Uint32()
+  external int ws_ping_interval_ms;
+  @Uint32()
+  external int ws_pong_timeout_ms;
+  @Int32()
+  external int ws_deflate;
 }
 
 /// Opaque handle
@@ -186,20 +296,37 @@ typedef _SendNative = Int32 Function(
   Pointer<Uint8> data_ptr,
   Uint32 data_len,
 );
+typedef _StartTlsNative = Int32 Function(TransportHandle handle);
+typedef _RestartStreamNative = Int32 Function(TransportHandle handle);
+typedef _IsSecureNative = Int32 Function(TransportHandle handle);
+typedef _ChannelBindingTypesNative = Int32 Function(TransportHandle handle);
+typedef _ChannelBindingNative = Int32 Function(TransportHandle handle,
+    Int32 kind, Pointer<Pointer<Uint8>> outPtr, Pointer<Uint32> outLen);
+typedef _TlsInfoNative = Int32 Function(TransportHandle handle,
+    Pointer<Pointer<Uint8>> outPtr, Pointer<Uint32> outLen);
+typedef _WsResponseHeadersNative = Int32 Function(TransportHandle handle,
+    Pointer<Pointer<Uint8>> outPtr, Pointer<Uint32> outLen);
+typedef _KeyLogLineNative = Void Function(
+    Pointer<Utf8> line, Pointer<Void> userData);
+typedef _KeyLogFreeNative = Void Function(Pointer<Utf8> line);
+typedef _DnsWarmNative = Int32 Function(TransportHandle handle);
+typedef _DnsFlushNative = Void Function();
+typedef _SetWorkerThreadsNative = Int32 Function(Uint32);
+typedef _TrustDecisionNative = Int32 Function(
+    TransportHandle handle, Int32 accept);
 typedef _DisconnectNative = Void Function(TransportHandle handle);
 typedef _DestroyNative = Void Function(TransportHandle handle);
-typedef _PollNative = Int32 Function(TransportHandle handle);
-typedef _PollClearNative = Void Function(TransportHandle handle);
-typedef _GetPolledStateNative = Int32 Function(TransportHandle handle);
-typedef _GetPolledStanzaNative = Void Function(
+typedef _NotifyNative = Void Function(Pointer<Void> userData);
+typedef _SetNotifyNative = Int32 Function(
   TransportHandle handle,
-  Pointer<Pointer<Uint8>> outPtr,
-  Pointer<Uint32> outLen,
+  Pointer<NativeFunction<_NotifyNative>> callback,
+  Pointer<Void> userData,
 );
-typedef _GetPolledErrorNative = Void Function(
+typedef _DrainNative = Int32 Function(
   TransportHandle handle,
-  Pointer<Int32> outCode,
-  Pointer<Pointer<Uint8>> outPtr,
+  Pointer<Uint8> buf,
+  Uint32 bufLen,
+  Uint32 maxEvents,
   Pointer<Uint32> outLen,
 );
 typedef _GetResolvedHostNative = Void Function(
@@ -216,13 +343,22 @@ typedef _GetLastErrorNative = Void Function(
 Pointer<NativeFunction<_CreateNative>>? _createFn;
 Pointer<NativeFunction<_ConnectNative>>? _connectFn;
 Pointer<NativeFunction<_SendNative>>? _sendFn;
+Pointer<NativeFunction<_StartTlsNative>>? _startTlsFn;
+Pointer<NativeFunction<_RestartStreamNative>>? _restartStreamFn;
+Pointer<NativeFunction<_IsSecureNative>>? _isSecureFn;
+Pointer<NativeFunction<_ChannelBindingTypesNative>>? _channelBindingTypesFn;
+Pointer<NativeFunction<_ChannelBindingNative>>? _channelBindingFn;
+Pointer<NativeFunction<_TlsInfoNative>>? _tlsInfoFn;
+Pointer<NativeFunction<_WsResponseHeadersNative>>? _wsResponseHeadersFn;
+Pointer<NativeFunction<_KeyLogFreeNative>>? _keyLogFreeFn;
+Pointer<NativeFunction<_DnsWarmNative>>? _dnsWarmFn;
+Pointer<NativeFunction<_DnsFlushNative>>? _dnsFlushFn;
+Pointer<NativeFunction<_SetWorkerThreadsNative>>? _setWorkerThreadsFn;
+Pointer<NativeFunction<_TrustDecisionNative>>? _trustDecisionFn;
 Pointer<NativeFunction<_DisconnectNative>>? _disconnectFn;
 Pointer<NativeFunction<_DestroyNative>>? _destroyFn;
-Pointer<NativeFunction<_PollNative>>? _pollFn;
-Pointer<NativeFunction<_PollClearNative>>? _pollClearFn;
-Pointer<NativeFunction<_GetPolledStateNative>>? _getPolledStateFn;
-Pointer<NativeFunction<_GetPolledStanzaNative>>? _getPolledStanzaFn;
-Pointer<NativeFunction<_GetPolledErrorNative>>? _getPolledErrorFn;
+Pointer<NativeFunction<_DrainNative>>? _drainFn;
+Pointer<NativeFunction<_SetNotifyNative>>? _setNotifyFn;
 Pointer<NativeFunction<_GetResolvedHostNative>>? _getResolvedHostFn;
 Pointer<NativeFunction<_GetLastErrorNative>>? _getLastErrorFn;
 
@@ -237,35 +373,100 @@ void _ensureBindings() {
   _connectFn ??=
       lib.lookup<NativeFunction<_ConnectNative>>('whixp_transport_connect');
   _sendFn ??= lib.lookup<NativeFunction<_SendNative>>('whixp_transport_send');
+  _startTlsFn ??=
+      lib.lookup<NativeFunction<_StartTlsNative>>('whixp_transport_starttls');
+  _restartStreamFn ??= lib.lookup<NativeFunction<_RestartStreamNative>>(
+      'whixp_transport_restart_stream');
+  _isSecureFn ??=
+      lib.lookup<NativeFunction<_IsSecureNative>>('whixp_transport_is_secure');
+  _channelBindingTypesFn ??=
+      lib.lookup<NativeFunction<_ChannelBindingTypesNative>>(
+          'whixp_transport_channel_binding_types');
+  _channelBindingFn ??= lib.lookup<NativeFunction<_ChannelBindingNative>>(
+      'whixp_transport_channel_binding');
+  _tlsInfoFn ??=
+      lib.lookup<NativeFunction<_TlsInfoNative>>('whixp_transport_tls_info');
+  _wsResponseHeadersFn ??=
+      lib.lookup<NativeFunction<_WsResponseHeadersNative>>(
+          'whixp_transport_ws_response_headers');
+  _keyLogFreeFn ??= lib.lookup<NativeFunction<_KeyLogFreeNative>>(
+      'whixp_transport_key_log_free');
+  _dnsWarmFn ??=
+      lib.lookup<NativeFunction<_DnsWarmNative>>('whixp_transport_dns_warm');
+  _dnsFlushFn ??=
+      lib.lookup<NativeFunction<_DnsFlushNative>>('whixp_transport_dns_flush');
+  _setWorkerThreadsFn ??= lib.lookup<NativeFunction<_SetWorkerThreadsNative>>(
+      'whixp_transport_set_worker_threads');
+  _trustDecisionFn ??= lib.lookup<NativeFunction<_TrustDecisionNative>>(
+      'whixp_transport_trust_decision');
   _disconnectFn ??= lib
       .lookup<NativeFunction<_DisconnectNative>>('whixp_transport_disconnect');
   _destroyFn ??=
       lib.lookup<NativeFunction<_DestroyNative>>('whixp_transport_destroy');
-  _pollFn ??= lib.lookup<NativeFunction<_PollNative>>('whixp_transport_poll');
-  _pollClearFn ??= lib
-      .lookup<NativeFunction<_PollClearNative>>('whixp_transport_poll_clear');
-  _getPolledStateFn ??= lib.lookup<NativeFunction<_GetPolledStateNative>>(
-      'whixp_transport_get_polled_state');
-  _getPolledStanzaFn ??= lib.lookup<NativeFunction<_GetPolledStanzaNative>>(
-      'whixp_transport_get_polled_stanza');
-  _getPolledErrorFn ??= lib.lookup<NativeFunction<_GetPolledErrorNative>>(
-      'whixp_transport_get_polled_error');
+  _drainFn ??=
+      lib.lookup<NativeFunction<_DrainNative>>('whixp_transport_drain');
+  _setNotifyFn ??= lib
+      .lookup<NativeFunction<_SetNotifyNative>>('whixp_transport_set_notify');
   _getResolvedHostFn ??= lib.lookup<NativeFunction<_GetResolvedHostNative>>(
       'whixp_transport_get_resolved_host');
   _getLastErrorFn ??= lib.lookup<NativeFunction<_GetLastErrorNative>>(
       'whixp_transport_get_last_error');
 }
 
-/// High-level wrapper for the Rust transport. Events are polled from main isolate (no callbacks from Rust threads).
+/// High-level wrapper for the Rust transport. Events are polled from main isolate (no callbacks from Rust threads other than the optional key log).
 class WhixpTransportNative {
-  WhixpTransportNative._(this._handle, this._sendPort);
+  WhixpTransportNative._(this._handle, this._sendPort, [this._keyLogCallable]);
 
   TransportHandle? _handle;
   final SendPort _sendPort;
   async.Timer? _pollTimer;
 
+  /// Native buffer events are drained into; grown when one event does not fit.
+  Pointer<Uint8>? _drainBuf;
+  int _drainBufLen = 64 * 1024;
+  final Pointer<Uint32> _drainLen = calloc<Uint32>();
+
+  /// Push mode: Rust calls this when the event queue becomes non-empty.
+  NativeCallable<_NotifyNative>? _notifyCallable;
+
+  /// Receives key log lines from Rust threads; closed on [destroy].
+  final NativeCallable<_KeyLogLineNative>? _keyLogCallable;
+
   /// Create transport (no callbacks). host = domain to resolve; Rust does SRV + connect.
   /// wsPath = WebSocket path (e.g. "/ws") or null for default "/ws"; only used when kind is WebSocket/WebSocketTls.
+  /// ipPreference = one of kIpPreferIpv4/kIpPreferIpv6/kIpv4Only/kIpv6Only;
+  /// overrides useIPv6 when set. attemptDelayMs = Happy Eyeballs stagger (0 = 250).
+  /// autoReconnect = let Rust reconnect (Reconnecting/Connected states) using the
+  /// retry* backoff instead of Dart's ReconnectionPolicy. 0 means Rust default.
+  /// dohEndpoints = DoH URLs tried in order when system DNS fails (empty =
+  /// Cloudflare); dohFormat = kDohFormatJson or kDohFormatWire (RFC 8484).
+  /// dnsStaleGraceMs = serve expired cached DNS answers this long while they
+  /// are refreshed in the background (0 = only within TTL).
+  /// trustStore = kTrustStoreBundled/System/BundledAndSystem; trustAnchors =
+  /// extra CA certificates (PEM bundle or one DER cert); spkiPins = SHA-256
+  /// digests (32 bytes each) of server SPKIs, checked per spkiPinMode
+  /// (kPinAndChain or kPinOnly).
+  /// interactiveTrust = on a certificate error, pause the handshake and post a
+  /// 'badCertificate' event (chain JSON); answer with [trustDecision] within
+  /// trustPromptTimeoutMs (0 = 60 s) or the certificate is rejected.
+  /// clientCert/clientKey = client certificate chain (PEM, or one DER cert) and
+  /// its key (PEM or PKCS#8 DER) for mutual TLS / SASL EXTERNAL; a key that
+  /// fails to load makes [connect] return 7.
+  /// tlsServerName = SNI only; certificates are verified against host.
+  /// handshakeTimeoutMs = limit for each TLS handshake and WebSocket upgrade
+  /// (0 = connectTimeoutMs).
+  /// tlsMinVersion = kTlsVersion12 or kTlsVersion13; cipherSuites/kxGroups =
+  /// IANA code points to allow (e.g. 0x1301, 0x001d; empty = defaults);
+  /// tlsSessionCache = kSessionCachePerHandle/Shared/Disabled for resumption.
+  /// keyLogPath/onKeyLogLine = append TLS secrets to a file / receive them in
+  /// NSS key log format (SSLKEYLOGFILE) for decrypting captures in Wireshark.
+  /// Debugging only: ignored unless the library is a debug build or was built
+  /// with the `keylog` feature. A file that cannot be opened fails the connect.
+  /// wsFraming = kWsFramingStream or kWsFramingRfc7395 (WebSocket kinds only).
+  /// wsHeaders = extra upgrade request headers (Authorization, Cookie, Origin,
+  /// User-Agent, ...). wsPingIntervalMs = ping after this much silence (0 =
+  /// off); the stream is dropped if nothing arrives within wsPongTimeoutMs
+  /// (0 = 10 s) of a ping. wsDeflate = offer permessage-deflate (RFC 7692).
   static WhixpTransportNative? create({
     required String host,
     required int port,
@@ -275,6 +476,37 @@ class WhixpTransportNative {
     String? service,
     bool useIPv6 = false,
     String? wsPath,
+    int? ipPreference,
+    int attemptDelayMs = 0,
+    bool autoReconnect = false,
+    int retryMaxAttempts = 0,
+    int retryInitialDelayMs = 0,
+    int retryMaxDelayMs = 0,
+    double retryMultiplier = 0,
+    double retryJitter = 0,
+    List<String> dohEndpoints = const [],
+    int dohFormat = kDohFormatJson,
+    int dnsStaleGraceMs = 0,
+    int trustStore = kTrustStoreBundled,
+    List<int>? trustAnchors,
+    List<List<int>> spkiPins = const [],
+    int spkiPinMode = kPinAndChain,
+    bool interactiveTrust = false,
+    int trustPromptTimeoutMs = 0,
+    List<int>? clientCert,
+    List<int>? clientKey,
+    int handshakeTimeoutMs = 0,
+    int tlsMinVersion = kTlsVersion12,
+    List<int> cipherSuites = const [],
+    List<int> kxGroups = const [],
+    int tlsSessionCache = kSessionCachePerHandle,
+    String? keyLogPath,
+    void Function(String line)? onKeyLogLine,
+    int wsFraming = kWsFramingStream,
+    Map<String, String> wsHeaders = const {},
+    int wsPingIntervalMs = 0,
+    int wsPongTimeoutMs = 0,
+    bool wsDeflate = false,
     required SendPort sendPort,
   }) {
     _loadLib();
@@ -291,12 +523,97 @@ class WhixpTransportNative {
       useIPv6,
       wsPath,
     );
+    config.ref
+      ..use_ipv6 = ipPreference ?? config.ref.use_ipv6
+      ..attempt_delay_ms = attemptDelayMs
+      ..auto_reconnect = autoReconnect ? 1 : 0
+      ..retry_max_attempts = retryMaxAttempts
+      ..retry_initial_delay_ms = retryInitialDelayMs
+      ..retry_max_delay_ms = retryMaxDelayMs
+      ..retry_multiplier = retryMultiplier
+      ..retry_jitter = retryJitter
+      ..doh_format = dohFormat
+      ..dns_stale_grace_ms = dnsStaleGraceMs
+      ..trust_store = trustStore
+      ..spki_pin_mode = spkiPinMode
+      ..interactive_trust = interactiveTrust ? 1 : 0
+      ..trust_prompt_timeout_ms = trustPromptTimeoutMs
+      ..handshake_timeout_ms = handshakeTimeoutMs
+      ..tls_min_version = tlsMinVersion
+      ..tls_session_cache = tlsSessionCache
+      ..ws_framing = wsFraming
+      ..ws_ping_interval_ms = wsPingIntervalMs
+      ..ws_pong_timeout_ms = wsPongTimeoutMs
+      ..ws_deflate = wsDeflate ? 1 : 0;
+    if (wsHeaders.isNotEmpty) {
+      final headers =
+          wsHeaders.entries.map((e) => '${e.key}: ${e.value}').join('\n');
+      config.ref
+        ..ws_headers_ptr = helper.allocString(headers)
+        ..ws_headers_len = utf8.encode(headers).length;
+    }
+    if (cipherSuites.isNotEmpty) {
+      config.ref
+        ..cipher_suites_ptr = helper.allocUint16s(cipherSuites)
+        ..cipher_suites_len = cipherSuites.length;
+    }
+    if (kxGroups.isNotEmpty) {
+      config.ref
+        ..kx_groups_ptr = helper.allocUint16s(kxGroups)
+        ..kx_groups_len = kxGroups.length;
+    }
+    if (trustAnchors != null && trustAnchors.isNotEmpty) {
+      config.ref
+        ..trust_anchors_ptr = helper.allocBytes(trustAnchors)
+        ..trust_anchors_len = trustAnchors.length;
+    }
+    if (clientCert != null && clientCert.isNotEmpty) {
+      config.ref
+        ..client_cert_ptr = helper.allocBytes(clientCert)
+        ..client_cert_len = clientCert.length;
+    }
+    if (clientKey != null && clientKey.isNotEmpty) {
+      config.ref
+        ..client_key_ptr = helper.allocBytes(clientKey)
+        ..client_key_len = clientKey.length;
+    }
+    if (spkiPins.isNotEmpty) {
+      final pins = [for (final pin in spkiPins) ...pin];
+      config.ref
+        ..spki_pins_ptr = helper.allocBytes(pins)
+        ..spki_pins_len = pins.length;
+    }
+    if (keyLogPath != null && keyLogPath.isNotEmpty) {
+      config.ref
+        ..key_log_path_ptr = helper.allocString(keyLogPath)
+        ..key_log_path_len = utf8.encode(keyLogPath).length;
+    }
+    NativeCallable<_KeyLogLineNative>? keyLogCallable;
+    if (onKeyLogLine != null) {
+      final free = _keyLogFreeFn!.asFunction<void Function(Pointer<Utf8>)>();
+      keyLogCallable = NativeCallable<_KeyLogLineNative>.listener(
+          (Pointer<Utf8> line, Pointer<Void> _) {
+        final text = line.toDartString();
+        free(line);
+        onKeyLogLine(text);
+      });
+      config.ref.key_log_callback = keyLogCallable.nativeFunction;
+    }
+    if (dohEndpoints.isNotEmpty) {
+      final endpoints = dohEndpoints.join('\n');
+      config.ref
+        ..doh_endpoints_ptr = helper.allocString(endpoints)
+        ..doh_endpoints_len = utf8.encode(endpoints).length;
+    }
     final handle = _createFn!
             .asFunction<TransportHandle Function(Pointer<CTransportConfig>)>()(
         config);
     helper.freeConfig(config);
-    if (handle == nullptr) return null;
-    return WhixpTransportNative._(handle, sendPort);
+    if (handle == nullptr) {
+      keyLogCallable?.close();
+      return null;
+    }
+    return WhixpTransportNative._(handle, sendPort, keyLogCallable);
   }
 
   /// Connect (blocking). Returns 0 on success; else error code. Use [lastError] for message.
@@ -306,6 +623,169 @@ class WhixpTransportNative {
     return _connectFn!.asFunction<int Function(TransportHandle)>()(_handle!);
   }
 
+  /// [connect] on a helper isolate while this isolate keeps polling, so a
+  /// 'badCertificate' event can be answered with [trustDecision] before the
+  /// handshake finishes. Use this with interactiveTrust; [push] as in
+  /// [startPolling].
+  Future<int> connectAsync({bool push = false}) {
+    if (_handle == null) return Future.value(-1);
+    _ensureBindings();
+    final address = _handle!.address;
+    startPolling(push: push);
+    return Isolate.run(() {
+      _ensureBindings();
+      return _connectFn!.asFunction<int Function(TransportHandle)>()(
+          Pointer.fromAddress(address));
+    });
+  }
+
+  /// Upgrade the live TCP stream to TLS (StartTLS). Call after `<proceed/>`.
+  /// Returns 0 on success (a TlsSuccess state follows); else error code. Use
+  /// [lastError] for message.
+  int startTls() {
+    if (_handle == null) return -1;
+    _ensureBindings();
+    return _startTlsFn!.asFunction<int Function(TransportHandle)>()(_handle!);
+  }
+
+  /// [startTls] on a helper isolate, like [connectAsync], so a
+  /// 'badCertificate' event can be answered during the handshake. Use this
+  /// with interactiveTrust.
+  Future<int> startTlsAsync() {
+    if (_handle == null) return Future.value(-1);
+    _ensureBindings();
+    final address = _handle!.address;
+    return Isolate.run(() {
+      _ensureBindings();
+      return _startTlsFn!.asFunction<int Function(TransportHandle)>()(
+          Pointer.fromAddress(address));
+    });
+  }
+
+  /// Stream restart after SASL success: the next incoming stream header is
+  /// reported as a 'header' event. Call before sending the new stream header.
+  int restartStream() {
+    if (_handle == null) return -1;
+    _ensureBindings();
+    return _restartStreamFn!
+        .asFunction<int Function(TransportHandle)>()(_handle!);
+  }
+
+  /// Whether the live stream is TLS. With XEP-0368 SRV lookup a DirectTls
+  /// transport can land on a StartTLS target, so check before skipping StartTLS.
+  bool get isSecure {
+    if (_handle == null) return false;
+    _ensureBindings();
+    return _isSecureFn!.asFunction<int Function(TransportHandle)>()(_handle!) ==
+        1;
+  }
+
+  /// Channel binding types the live TLS stream offers, as a bit set of
+  /// kChannelBindingTlsExporter (TLS 1.3 only) and
+  /// kChannelBindingTlsServerEndPoint. 0 when the stream is not TLS.
+  int get channelBindingTypes {
+    if (_handle == null) return 0;
+    _ensureBindings();
+    return _channelBindingTypesFn!
+        .asFunction<int Function(TransportHandle)>()(_handle!);
+  }
+
+  /// Channel binding data of the given [kind] for the SCRAM-PLUS `c=`
+  /// attribute, or null when unavailable (see [lastError]).
+  Uint8List? channelBinding(int kind) {
+    if (_handle == null) return null;
+    _ensureBindings();
+    final outPtr = calloc<Pointer<Uint8>>();
+    final outLen = calloc<Uint32>();
+    try {
+      final rc = _channelBindingFn!.asFunction<
+          int Function(TransportHandle, int, Pointer<Pointer<Uint8>>,
+              Pointer<Uint32>)>()(_handle!, kind, outPtr, outLen);
+      if (rc != 0 || outPtr.value == nullptr) return null;
+      return Uint8List.fromList(outPtr.value.asTypedList(outLen.value));
+    } finally {
+      calloc.free(outPtr);
+      calloc.free(outLen);
+    }
+  }
+
+  /// Negotiated TLS details: version, cipher_suite, kx_group, alpn, sni,
+  /// resumed and the peer chain (peer_certificates, leaf first). Null when
+  /// the stream is not TLS.
+  Map<String, dynamic>? get tlsInfo {
+    if (_handle == null) return null;
+    _ensureBindings();
+    final outPtr = calloc<Pointer<Uint8>>();
+    final outLen = calloc<Uint32>();
+    try {
+      final rc = _tlsInfoFn!.asFunction<
+          int Function(TransportHandle, Pointer<Pointer<Uint8>>,
+              Pointer<Uint32>)>()(_handle!, outPtr, outLen);
+      if (rc != 0 || outPtr.value == nullptr) return null;
+      final json = utf8.decode(outPtr.value.asTypedList(outLen.value));
+      return jsonDecode(json) as Map<String, dynamic>;
+    } finally {
+      calloc.free(outPtr);
+      calloc.free(outLen);
+    }
+  }
+
+  /// WebSocket upgrade response headers, lowercase name to values. Null when
+  /// the stream is not a WebSocket.
+  Map<String, List<String>>? get wsResponseHeaders {
+    if (_handle == null) return null;
+    _ensureBindings();
+    final outPtr = calloc<Pointer<Uint8>>();
+    final outLen = calloc<Uint32>();
+    try {
+      final rc = _wsResponseHeadersFn!.asFunction<
+          int Function(TransportHandle, Pointer<Pointer<Uint8>>,
+              Pointer<Uint32>)>()(_handle!, outPtr, outLen);
+      if (rc != 0 || outPtr.value == nullptr) return null;
+      final json = utf8.decode(outPtr.value.asTypedList(outLen.value));
+      return (jsonDecode(json) as Map<String, dynamic>).map(
+          (name, values) => MapEntry(name, List<String>.from(values as List)));
+    } finally {
+      calloc.free(outPtr);
+      calloc.free(outLen);
+    }
+  }
+
+  /// Answer a pending 'badCertificate' event: accept the certificate and let
+  /// the handshake continue, or reject it. Returns -1 when nothing is pending.
+  int trustDecision(bool accept) {
+    if (_handle == null) return -1;
+    _ensureBindings();
+    return _trustDecisionFn!.asFunction<int Function(TransportHandle, int)>()(
+        _handle!, accept ? 1 : 0);
+  }
+
+  /// Resolve SRV targets and addresses into the shared DNS cache without
+  /// connecting (blocking). Returns the number of targets, or -1 (see
+  /// [lastError]).
+  int warmDns() {
+    if (_handle == null) return -1;
+    _ensureBindings();
+    return _dnsWarmFn!.asFunction<int Function(TransportHandle)>()(_handle!);
+  }
+
+  /// Drop every cached DNS answer (shared by all transports), e.g. after a
+  /// network change.
+  static void flushDnsCache() {
+    if (_loadLib() == null) return;
+    _ensureBindings();
+    _dnsFlushFn!.asFunction<void Function()>()();
+  }
+
+  /// Number of native I/O worker threads shared by all transports (0 = one
+  /// per core, at most 4). Call before the first [connect]; false if the
+  /// runtime already runs with a different count.
+  static bool setWorkerThreads(int count) {
+    if (_loadLib() == null) return false;
+    _ensureBindings();
+    return _setWorkerThreadsFn!.asFunction<int Function(int)>()(count) == 0;
+  }
+
   /// Resolved host after connect (for SASL). Empty if not connected.
   String get resolvedHost {
     if (_handle == null) return '';
@@ -351,69 +831,78 @@ class WhixpTransportNative {
   }
 
   /// Start polling for events and posting to [_sendPort]. Call from main isolate after [connect] succeeds.
-  void startPolling() {
+  /// push = no timer: Rust wakes this isolate when the event queue goes from
+  /// empty to non-empty, so events arrive without polling latency or idle
+  /// wake-ups.
+  void startPolling({bool push = false}) {
     if (_handle == null) return;
     _pollTimer?.cancel();
+    _pollTimer = null;
+    if (push) {
+      _ensureBindings();
+      _notifyCallable ??= NativeCallable<_NotifyNative>.listener(
+        (Pointer<Void> _) => _drainPoll(),
+      );
+      _setNotify(_notifyCallable!.nativeFunction);
+      // Events queued before registering do not fire the callback.
+      _drainPoll();
+      return;
+    }
     _pollTimer = async.Timer.periodic(
       const Duration(milliseconds: 2),
       (_) => _drainPoll(),
     );
   }
 
+  /// Whether [startPolling] last chose push mode.
+  bool get pushMode => _notifyCallable != null && _pollTimer == null;
+
+  /// Take every queued event in one call (see whixp_transport_drain for the
+  /// record layout) and post them to [_sendPort].
   void _drainPoll() {
     if (_handle == null) return;
     _ensureBindings();
-    final poll = _pollFn!.asFunction<int Function(TransportHandle)>()(_handle!);
-    switch (poll) {
-      case 0:
-        return;
-      case 1:
-        final state = _getPolledStateFn!
-            .asFunction<int Function(TransportHandle)>()(_handle!);
-        _sendPort.send(['state', state]);
-        _pollClearFn!.asFunction<void Function(TransportHandle)>()(_handle!);
-      case 2:
-        final outPtr = calloc<Pointer<Uint8>>();
-        final outLen = calloc<Uint32>();
-        try {
-          _getPolledStanzaFn!.asFunction<
-              void Function(TransportHandle, Pointer<Pointer<Uint8>>,
-                  Pointer<Uint32>)>()(_handle!, outPtr, outLen);
-          final ptr = outPtr.value;
-          final len = outLen.value;
-          if (ptr != nullptr && len > 0) {
-            final list = ptr.asTypedList(len);
-            _sendPort.send(['stanza', utf8.decode(list)]);
-          }
-        } finally {
-          calloc.free(outPtr);
-          calloc.free(outLen);
-        }
-        _pollClearFn!.asFunction<void Function(TransportHandle)>()(_handle!);
-      case 3:
-        final outCode = calloc<Int32>();
-        final outPtr = calloc<Pointer<Uint8>>();
-        final outLen = calloc<Uint32>();
-        try {
-          _getPolledErrorFn!.asFunction<
-              void Function(
-                  TransportHandle,
-                  Pointer<Int32>,
-                  Pointer<Pointer<Uint8>>,
-                  Pointer<Uint32>)>()(_handle!, outCode, outPtr, outLen);
-          final code = outCode.value;
-          final ptr = outPtr.value;
-          final len = outLen.value;
-          final msg = (ptr != nullptr && len > 0)
-              ? utf8.decode(ptr.asTypedList(len))
-              : '';
-          _sendPort.send(['error', code, msg]);
-        } finally {
-          calloc.free(outCode);
-          calloc.free(outPtr);
-          calloc.free(outLen);
-        }
-        _pollClearFn!.asFunction<void Function(TransportHandle)>()(_handle!);
+    final drain = _drainFn!.asFunction<
+        int Function(
+            TransportHandle, Pointer<Uint8>, int, int, Pointer<Uint32>)>();
+    var buf = _drainBuf ??= calloc<Uint8>(_drainBufLen);
+    var count = drain(_handle!, buf, _drainBufLen, 0, _drainLen);
+    if (count == -2) {
+      calloc.free(buf);
+      _drainBufLen = _drainLen.value;
+      buf = _drainBuf = calloc<Uint8>(_drainBufLen);
+      count = drain(_handle!, buf, _drainBufLen, 0, _drainLen);
+    }
+    if (count <= 0) return;
+    // In push mode nothing fires again until the queue was empty, so come back
+    // for whatever did not fit.
+    if (_notifyCallable != null) async.Timer.run(_drainPoll);
+    final records = buf.asTypedList(_drainLen.value);
+    final view = ByteData.sublistView(records);
+    var pos = 0;
+    while (pos < records.length) {
+      final type = view.getUint32(pos, Endian.little);
+      final code = view.getInt32(pos + 4, Endian.little);
+      final len = view.getUint32(pos + 8, Endian.little);
+      final payload = records.sublist(pos + 12, pos + 12 + len);
+      pos += (12 + len + 3) & ~3;
+      switch (type) {
+        case 1:
+          _sendPort.send(['state', code]);
+        case 3:
+          _sendPort.send(['error', code, utf8.decode(payload)]);
+        default:
+          if (len == 0) continue;
+          final tag = switch (type) {
+            4 => 'header',
+            5 => 'badCertificate',
+            6 => 'open',
+            7 => 'close',
+            8 => 'seeOtherHost',
+            _ => 'stanza',
+          };
+          _sendPort.send([tag, utf8.decode(payload)]);
+      }
     }
   }
 
@@ -434,11 +923,18 @@ class WhixpTransportNative {
     }
   }
 
+  void _setNotify(Pointer<NativeFunction<_NotifyNative>> callback) {
+    _setNotifyFn!.asFunction<
+        int Function(TransportHandle, Pointer<NativeFunction<_NotifyNative>>,
+            Pointer<Void>)>()(_handle!, callback, nullptr);
+  }
+
   void disconnect() {
     if (_handle == null) return;
     _pollTimer?.cancel();
     _pollTimer = null;
     _ensureBindings();
+    if (_notifyCallable != null) _setNotify(nullptr);
     _disconnectFn!.asFunction<void Function(TransportHandle)>()(_handle!);
   }
 
@@ -447,8 +943,17 @@ class WhixpTransportNative {
     _pollTimer = null;
     if (_handle == null) return;
     _ensureBindings();
+    // Unregistered first: once set_notify returns the callback never runs again.
+    if (_notifyCallable != null) _setNotify(nullptr);
     _destroyFn!.asFunction<void Function(TransportHandle)>()(_handle!);
     _handle = null;
+    // Destroy waited (up to 2 s) for the connection's threads to stop calling back.
+    _keyLogCallable?.close();
+    _notifyCallable?.close();
+    _notifyCallable = null;
+    if (_drainBuf != null) calloc.free(_drainBuf!);
+    _drainBuf = null;
+    calloc.free(_drainLen);
   }
 }
 
@@ -458,6 +963,29 @@ class _TransportConfigHelper {
   Pointer<Utf8>? _tlsPtr;
   Pointer<Utf8>? _servicePtr;
   Pointer<Utf8>? _wsPathPtr;
+  final List<Pointer<NativeType>> _extra = [];
+
+  /// Native copy of [value] freed together with the config.
+  Pointer<Utf8> allocString(String value) {
+    final ptr = value.toNativeUtf8();
+    _extra.add(ptr);
+    return ptr;
+  }
+
+  /// Native copy of [bytes] freed together with the config.
+  Pointer<Uint8> allocBytes(List<int> bytes) {
+    final ptr = malloc<Uint8>(bytes.length);
+    ptr.asTypedList(bytes.length).setAll(0, bytes);
+    _extra.add(ptr);
+    return ptr;
+  }
+
+  Pointer<Uint16> allocUint16s(List<int> values) {
+    final ptr = malloc<Uint16>(values.length);
+    ptr.asTypedList(values.length).setAll(0, values);
+    _extra.add(ptr);
+    return ptr;
+  }
 
   Pointer<CTransportConfig> allocConfig(
     String host,
@@ -500,5 +1028,9 @@ class _TransportConfigHelper {
     if (_tlsPtr != null) malloc.free(_tlsPtr!);
     if (_servicePtr != null) malloc.free(_servicePtr!);
     if (_wsPathPtr != null) malloc.free(_wsPathPtr!);
+    for (final ptr in _extra) {
+      malloc.free(ptr);
+    }
+    _extra.clear();
   }
 }
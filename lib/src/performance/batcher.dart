@@ -97,6 +97,7 @@ class MessageBatcher {
     // Session establishment packets
     switch (packet.name) {
       case 'proceed':
+      case 'starttls':
       case 'bind':
       case 'session':
       case 'register':
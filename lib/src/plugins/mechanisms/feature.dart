@@ -217,6 +217,7 @@ class FeatureMechanisms {
     attemptedMechanisms.clear();
     StreamFeatures.supported.add('mechanisms');
     final transport = whixp.transport;
+    transport.connection.restartStream();
     transport.sendRaw(transport.streamHeader);
   }
 
@@ -50,10 +50,20 @@ class StartTLS extends Stanza {
     return element;
   }
 
-  /// Handle notification that the server supports TLS.
-  static bool handleStartTLS(Transport transport) {
+  /// Negotiates StartTLS (RFC 6120 5.4): asks the server, upgrades the socket
+  /// after `<proceed/>` and restarts the stream. False when the server refused
+  /// or the upgrade failed.
+  static Future<bool> handleStartTLS(Transport transport) async {
+    final proceed = await transport.sendAwait<TLSProceed, TLSFailure>(
+      'StartTLS Response Handler',
+      const StartTLS(),
+      'proceed',
+      failurePacket: 'failure',
+    );
+    if (proceed == null) return false;
+    if (!await transport.connection.startTLS()) return false;
     StreamFeatures.supported.add('starttls');
-    transport.send(const TLSProceed());
+    transport.sendRaw(transport.streamHeader);
     return true;
   }
 
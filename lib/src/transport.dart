@@ -87,6 +87,22 @@ class Transport {
     /// WebSocket path (e.g. "/ws" or "/xmpp-websocket"). Only used when [useWebSocket] is true. Defaults to "/ws".
     this.wsPath,
 
+    /// If `true`, carry XMPP over WebSocket as RFC 7395 specifies: the `xmpp`
+    /// subprotocol is required and each message holds one element. Only used
+    /// when [useWebSocket] is true. Defaults to `false`, which joins messages
+    /// into one stream as earlier versions did
+    bool useRfc7395Framing = false,
+
+    /// If `true`, a server certificate that fails verification pauses the TLS
+    /// handshake and emits a 'badCertificate' event with the chain details;
+    /// answer it with [trustDecision]. Defaults to `false`
+    bool interactiveTrust = false,
+
+    /// If `true`, the native transport wakes this isolate when events arrive
+    /// instead of being polled every 2 ms, so an idle connection costs no
+    /// wake-ups. Defaults to `true`
+    bool pushEvents = true,
+
     /// If `true`, periodically send a whitespace character over the wire to
     /// keep the connection alive
     this.pingKeepAlive = true,
@@ -162,6 +178,25 @@ class Transport {
             Log.instance.debug(
                 '[STANZA_RX] native stanza bytes -> ${raw.length} chars');
             _dataReceived(utf8.encode(raw));
+          case 'header':
+            final raw = message[1] as String;
+            Log.instance.debug('[STANZA_RX] native stream header -> $raw');
+            _dataReceived(utf8.encode(raw));
+          case 'open':
+          case 'close':
+            final raw = message[1] as String;
+            Log.instance.debug('[STANZA_RX] native framing -> $raw');
+            _dataReceived(utf8.encode(raw));
+          case 'seeOtherHost':
+            final uri = message[1] as String;
+            Log.instance.info('Server redirects to $uri (see-other-host)');
+            emit<String>('seeOtherHost', data: uri);
+            connection.abort(callback: _closeStreams);
+          case 'badCertificate':
+            final raw = message[1] as String;
+            Log.instance.warning('Server certificate needs a trust decision');
+            emit<Map<String, dynamic>>('badCertificate',
+                data: jsonDecode(raw) as Map<String, dynamic>);
           case 'error':
             Log.instance.debug('[STANZA_RX] native error -> ${message[2]}');
             connection.tearDownNative();
@@ -200,11 +235,16 @@ class Transport {
           service: service,
           useIPv6: useIPv6Resolving,
           wsPath: wsPathArg,
+          wsFraming:
+              useRfc7395Framing ? kWsFramingRfc7395 : kWsFramingStream,
+          interactiveTrust: interactiveTrust,
           sendPort: nativeSendPort,
         ),
         connectionTimeout: connectionTimeout,
         useIPv6WhenResolvingDNS: useIPv6,
         service: dnsService,
+        interactiveTrust: interactiveTrust,
+        pushEvents: pushEvents,
       ),
       (state) => emit<TransportState>('state', data: state),
       onConnectionStartCallback: () async {
@@ -448,6 +488,11 @@ class Transport {
     }
   }
 
+  /// Answers a pending 'badCertificate' event: `true` accepts the server
+  /// certificate and the handshake continues, `false` rejects it. Returns
+  /// `false` when no handshake is waiting for a decision.
+  bool trustDecision(bool accept) => connection.trustDecision(accept);
+
   /// Starts connection (DNS resolution then native transport connect).
   void connect() {
     callbacksBeforeStanzaSend.clear();
@@ -767,6 +812,7 @@ class Transport {
           data.name.startsWith('sasl') ||
           data.name.startsWith('sm') ||
           data.name == 'proceed' ||
+          data.name == 'starttls' ||
           data.name == 'bind' ||
           data.name == 'session' ||
           data.name == 'register';
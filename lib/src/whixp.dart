@@ -48,6 +48,21 @@ abstract class WhixpBase {
     /// WebSocket path (e.g. "/ws" or "/xmpp-websocket"). Only used when [useWebSocket] is true. Defaults to "/ws".
     String? wsPath,
 
+    /// If `true`, carry XMPP over WebSocket as RFC 7395 specifies: the `xmpp`
+    /// subprotocol is required and each message holds one element. Only used
+    /// when [useWebSocket] is true. Defaults to `false`, which joins messages
+    /// into one stream as earlier versions did
+    bool useRfc7395Framing = false,
+
+    /// If `true`, a server certificate that fails verification pauses the TLS
+    /// handshake and emits a 'badCertificate' event; answer it with
+    /// [Transport.trustDecision]. Defaults to `false`
+    bool interactiveTrust = false,
+
+    /// If `true`, the native transport wakes this isolate when events arrive
+    /// instead of being polled every 2 ms. Defaults to `true`
+    bool pushEvents = true,
+
     /// If `true`, periodically send a whitespace character over the wire to
     /// keep the connection alive
     bool pingKeepAlive = true,
@@ -133,6 +148,9 @@ abstract class WhixpBase {
       disableStartTLS: disableStartTLS,
       useWebSocket: useWebSocket,
       wsPath: wsPath,
+      useRfc7395Framing: useRfc7395Framing,
+      interactiveTrust: interactiveTrust,
+      pushEvents: pushEvents,
       boundJID: _boundJID,
       dnsService: dnsService,
       useTLS: useTLS,
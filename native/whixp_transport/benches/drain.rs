@@ -0,0 +1,170 @@
+//! Event delivery cost: `cargo bench --bench drain [-- <stanzas>]`.
+//! A local server sends a stream header and `<stanzas>` messages; once they are queued, each
+//! run takes them all through the FFI with one method: the poll / get / poll_clear cycle,
+//! whixp_transport_drain into a caller buffer, or whixp_transport_drain_owned + release.
+//! Calls from Rust cost next to nothing, so time here is mostly the event queue; from Dart
+//! every FFI call adds a crossing, which is what the call count shows.
+
+use std::io::Write;
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use whixp_transport::*;
+
+const HEADER: &[u8] = b"<stream:stream xmlns='jabber:client' \
+    xmlns:stream='http://etherx.jabber.org/streams' from='localhost' version='1.0'>";
+
+fn main() {
+    let stanzas: usize = std::env::args()
+        .skip(1)
+        .find(|a| !a.starts_with("--"))
+        .and_then(|a| a.parse().ok())
+        .unwrap_or(100_000);
+    let port = server(stanzas);
+
+    type Run = unsafe fn(*mut Handle, usize) -> usize;
+    let runs: [(&str, Run); 3] = [
+        ("poll cycle", poll_cycle),
+        ("drain (caller buffer)", drain_buffer),
+        ("drain (native arena)", drain_owned),
+    ];
+    for (name, run) in runs {
+        unsafe {
+            let handle = connect(port);
+            // Let the whole burst arrive and queue up before timing.
+            thread::sleep(Duration::from_millis(500) + Duration::from_micros(stanzas as u64 * 5));
+            let start = Instant::now();
+            let calls = run(handle, stanzas);
+            let elapsed = start.elapsed();
+            println!(
+                "{:<24} {} stanzas in {:>8.2} ms: {:>6.0} ns/event, {} FFI calls",
+                name,
+                stanzas,
+                elapsed.as_secs_f64() * 1e3,
+                elapsed.as_nanos() as f64 / stanzas as f64,
+                calls
+            );
+            whixp_transport_disconnect(handle);
+            whixp_transport_destroy(handle);
+        }
+    }
+}
+
+/// Listener that sends a header and `stanzas` messages to every connection, then idles.
+fn server(stanzas: usize) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let port = listener.local_addr().expect("local addr").port();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            thread::spawn(move || {
+                let mut burst = HEADER.to_vec();
+                for i in 0..stanzas {
+                    let stanza = format!(
+                        "<message id='{}' from='a@localhost/r'><body>catch-up {}</body></message>",
+                        i, i
+                    );
+                    burst.extend_from_slice(stanza.as_bytes());
+                }
+                let _ = stream.write_all(&burst);
+                thread::sleep(Duration::from_secs(3600));
+            });
+        }
+    });
+    port
+}
+
+unsafe fn connect(port: u16) -> *mut Handle {
+    let host = "127.0.0.1";
+    let mut config: CTransportConfig = std::mem::zeroed();
+    config.host_ptr = host.as_ptr() as *const _;
+    config.host_len = host.len() as u32;
+    config.port = port;
+    config.connect_timeout_ms = 5000;
+    let handle = whixp_transport_create(&config);
+    assert!(!handle.is_null(), "create");
+    assert_eq!(whixp_transport_connect(handle), 0, "connect");
+    handle
+}
+
+/// Consume events the way Dart did before drain; returns the FFI calls made.
+unsafe fn poll_cycle(handle: *mut Handle, stanzas: usize) -> usize {
+    let (mut seen, mut calls, mut bytes) = (0, 0, 0);
+    while seen < stanzas {
+        calls += 1;
+        match whixp_transport_poll(handle) {
+            0 => thread::yield_now(),
+            1 => {
+                whixp_transport_get_polled_state(handle);
+                whixp_transport_poll_clear(handle);
+                calls += 2;
+            }
+            3 => {
+                let (mut code, mut ptr, mut len) = (0, std::ptr::null(), 0);
+                whixp_transport_get_polled_error(handle, &mut code, &mut ptr, &mut len);
+                whixp_transport_poll_clear(handle);
+                calls += 2;
+            }
+            kind => {
+                let (mut ptr, mut len) = (std::ptr::null(), 0);
+                whixp_transport_get_polled_stanza(handle, &mut ptr, &mut len);
+                bytes += std::slice::from_raw_parts(ptr, len as usize).to_vec().len();
+                whixp_transport_poll_clear(handle);
+                calls += 2;
+                if kind == 2 {
+                    seen += 1;
+                }
+            }
+        }
+    }
+    assert!(bytes > 0);
+    calls
+}
+
+/// Records in `buf`: (type, payload length); payloads are copied out like Dart would.
+fn parse(buf: &[u8]) -> usize {
+    let (mut pos, mut stanzas) = (0, 0);
+    while pos < buf.len() {
+        let word = |at: usize| u32::from_le_bytes(buf[at..at + 4].try_into().unwrap());
+        let (kind, len) = (word(pos), word(pos + 8) as usize);
+        let payload = buf[pos + 12..pos + 12 + len].to_vec();
+        assert!(kind != 2 || !payload.is_empty());
+        stanzas += usize::from(kind == 2);
+        pos += (12 + len).next_multiple_of(4);
+    }
+    stanzas
+}
+
+unsafe fn drain_buffer(handle: *mut Handle, stanzas: usize) -> usize {
+    let mut buf = vec![0u8; 64 * 1024];
+    let (mut seen, mut calls) = (0, 0);
+    while seen < stanzas {
+        let mut len = 0;
+        calls += 1;
+        match whixp_transport_drain(handle, buf.as_mut_ptr(), buf.len() as u32, 0, &mut len) {
+            -2 => buf.resize(len as usize, 0),
+            0 => thread::yield_now(),
+            n if n > 0 => seen += parse(&buf[..len as usize]),
+            _ => panic!("drain failed"),
+        }
+    }
+    calls
+}
+
+unsafe fn drain_owned(handle: *mut Handle, stanzas: usize) -> usize {
+    let (mut seen, mut calls) = (0, 0);
+    while seen < stanzas {
+        let (mut ptr, mut len) = (std::ptr::null(), 0);
+        calls += 1;
+        match whixp_transport_drain_owned(handle, 1024, &mut ptr, &mut len) {
+            0 => thread::yield_now(),
+            n if n > 0 => {
+                seen += parse(std::slice::from_raw_parts(ptr, len as usize));
+                whixp_transport_drain_release(handle);
+                calls += 1;
+            }
+            _ => panic!("drain failed"),
+        }
+    }
+    calls
+}
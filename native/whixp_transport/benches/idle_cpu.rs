@@ -0,0 +1,74 @@
+//! Idle CPU of open connections: `cargo bench --bench idle_cpu [-- <connections> <seconds>]`.
+//! Opens plain TCP connections to a local listener that never sends, then reports the CPU
+//! time the process used while they sat idle. CPU time is read from /proc (Linux only).
+
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use whixp_transport::config::{TransportConfig, TransportKind};
+use whixp_transport::connection::{self, Connection};
+use whixp_transport::retry::RetryPolicy;
+
+fn main() {
+    let args: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|a| !a.starts_with("--"))
+        .collect();
+    let connections: usize = args.first().and_then(|a| a.parse().ok()).unwrap_or(50);
+    let seconds: u64 = args.get(1).and_then(|a| a.parse().ok()).unwrap_or(5);
+
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let port = listener.local_addr().expect("local addr").port();
+    thread::spawn(move || {
+        let mut held = Vec::new();
+        for stream in listener.incoming().flatten() {
+            held.push(stream);
+        }
+    });
+
+    let mut open = Vec::new();
+    for _ in 0..connections {
+        let config = TransportConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            kind: TransportKind::Tcp,
+            ..Default::default()
+        };
+        let mut conn = Connection::new(config, RetryPolicy::default());
+        let (event_tx, event_rx) = connection::event_channel(Default::default());
+        conn.connect_sync(event_tx).expect("connect");
+        open.push((conn, event_rx));
+    }
+    thread::sleep(Duration::from_millis(500));
+
+    let (cpu_start, wall_start) = (cpu_time(), Instant::now());
+    thread::sleep(Duration::from_secs(seconds));
+    let wall = wall_start.elapsed();
+    match (cpu_start, cpu_time()) {
+        (Some(start), Some(end)) => {
+            let cpu = end.saturating_sub(start);
+            println!(
+                "{} idle connections for {:.1} s: {:.2} s CPU ({:.1}% of one core)",
+                connections,
+                wall.as_secs_f64(),
+                cpu.as_secs_f64(),
+                100.0 * cpu.as_secs_f64() / wall.as_secs_f64()
+            );
+        }
+        _ => println!("CPU time is only measured on Linux"),
+    }
+
+    for (conn, _) in &open {
+        conn.shutdown();
+    }
+}
+
+/// User + system CPU time of this process (utime and stime in /proc/self/stat, 100 Hz ticks).
+fn cpu_time() -> Option<Duration> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // Fields after the parenthesised command name; utime and stime are fields 14 and 15.
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let ticks: u64 = fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+    Some(Duration::from_millis(ticks * 10))
+}
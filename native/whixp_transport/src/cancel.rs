@@ -0,0 +1,125 @@
+//! Ending a connect in progress from another thread (disconnect, destroy). The steps that
+//! block before there is a socket to shut down check it and are woken by it: DNS lookups on
+//! the system resolver, Happy Eyeballs' poller, and the loop over connection candidates.
+
+use std::future::{poll_fn, Future};
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+
+use polling::Poller;
+use tokio::sync::Notify;
+
+use crate::handshake::HandshakeError;
+use crate::runtime;
+
+/// Shared cancel flag. Clones refer to the same flag; `Default` is a flag nobody sets.
+#[derive(Clone, Default)]
+pub struct Cancel(Arc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    /// Pollers of Happy Eyeballs races in progress.
+    pollers: Mutex<Vec<Arc<Poller>>>,
+    /// Wakes DNS lookups waiting on the runtime.
+    notify: Notify,
+}
+
+impl std::fmt::Debug for Cancel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Cancel").field(&self.is_cancelled()).finish()
+    }
+}
+
+impl Cancel {
+    /// Set the flag and wake whatever waits on it.
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        self.0.notify.notify_waiters();
+        for poller in self.pollers().iter() {
+            let _ = poller.notify();
+        }
+    }
+
+    /// Clear the flag for a new connect.
+    pub fn reset(&self) {
+        self.0.cancelled.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Err once cancelled.
+    pub fn check(&self) -> Result<(), HandshakeError> {
+        if self.is_cancelled() {
+            return Err(disconnected());
+        }
+        Ok(())
+    }
+
+    /// Notify `poller` on cancel until the returned guard is dropped. Check the flag after
+    /// this: a cancel that came first did not notify it.
+    pub fn watch(&self, poller: &Arc<Poller>) -> Watch<'_> {
+        self.pollers().push(Arc::clone(poller));
+        Watch {
+            cancel: self,
+            poller: Arc::clone(poller),
+        }
+    }
+
+    /// `runtime::block_on(future)`, given up when cancelled.
+    pub fn block_on<T>(&self, future: impl Future<Output = T>) -> Result<T, HandshakeError> {
+        runtime::block_on(async {
+            let mut work = pin!(future);
+            let mut cancelled = pin!(self.cancelled());
+            poll_fn(|cx| {
+                if let Poll::Ready(out) = work.as_mut().poll(cx) {
+                    return Poll::Ready(Ok(out));
+                }
+                match cancelled.as_mut().poll(cx) {
+                    Poll::Ready(()) => Poll::Ready(Err(disconnected())),
+                    Poll::Pending => Poll::Pending,
+                }
+            })
+            .await
+        })
+    }
+
+    /// Resolves once the flag is set.
+    async fn cancelled(&self) {
+        loop {
+            let mut notified = pin!(self.0.notify.notified());
+            // Registered before the check, so a cancel in between still wakes it.
+            notified.as_mut().enable();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    fn pollers(&self) -> std::sync::MutexGuard<'_, Vec<Arc<Poller>>> {
+        self.0.pollers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn disconnected() -> HandshakeError {
+    HandshakeError::Connection("disconnected".into())
+}
+
+/// Keeps a poller registered with `Cancel::watch`.
+pub struct Watch<'a> {
+    cancel: &'a Cancel,
+    poller: Arc<Poller>,
+}
+
+impl Drop for Watch<'_> {
+    fn drop(&mut self) {
+        self.cancel
+            .pollers()
+            .retain(|p| !Arc::ptr_eq(p, &self.poller));
+    }
+}
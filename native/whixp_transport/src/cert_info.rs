@@ -0,0 +1,376 @@
+//! Certificate chain summary for the interactive trust prompt and the TLS details call:
+//! subject, issuer, SANs, validity and fingerprints, serialized as JSON for Dart. Reads just the X.509 fields it
+//! needs with a minimal DER walker (no full ASN.1 parser in the binary). Also picks the
+//! tls-server-end-point hash from a certificate's signature algorithm.
+
+use base64::Engine;
+use rustls::pki_types::CertificateDer;
+use serde::Serialize;
+
+use crate::tls::spki_sha256;
+
+/// Sent to Dart when verification fails and the handshake waits for a decision.
+#[derive(Serialize, Debug)]
+pub struct BadCertificate {
+    /// Why verification failed (rustls error text).
+    pub error: String,
+    /// Name the certificate was checked against.
+    pub server_name: String,
+    /// Leaf first, then intermediates as sent by the server.
+    pub chain: Vec<CertificateSummary>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CertificateSummary {
+    /// DER, base64 (standard alphabet).
+    pub der: String,
+    /// SHA-256 of the DER certificate, lowercase hex.
+    pub sha256: String,
+    /// SHA-256 of the DER SubjectPublicKeyInfo, lowercase hex (usable as a pin).
+    pub spki_sha256: String,
+    pub subject: String,
+    pub issuer: String,
+    /// DNS names, IP addresses, URIs and XMPP names (`xmppAddr:`, `SRVName:`).
+    pub sans: Vec<String>,
+    /// Validity period, seconds since the Unix epoch.
+    pub not_before: i64,
+    pub not_after: i64,
+}
+
+impl BadCertificate {
+    pub fn new(
+        error: String,
+        server_name: String,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+    ) -> Self {
+        let chain = std::iter::once(end_entity)
+            .chain(intermediates)
+            .map(summarize)
+            .collect();
+        Self {
+            error,
+            server_name,
+            chain,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, data);
+    let mut out = [0u8; 32];
+    out.copy_from_slice(digest.as_ref());
+    out
+}
+
+pub fn summarize(cert: &CertificateDer<'_>) -> CertificateSummary {
+    let fields = parse_tbs(cert.as_ref()).unwrap_or_default();
+    CertificateSummary {
+        der: base64::engine::general_purpose::STANDARD.encode(cert.as_ref()),
+        sha256: hex(&sha256(cert.as_ref())),
+        spki_sha256: spki_sha256(cert).map(|h| hex(&h)).unwrap_or_default(),
+        subject: fields.subject,
+        issuer: fields.issuer,
+        sans: fields.sans,
+        not_before: fields.not_before,
+        not_after: fields.not_after,
+    }
+}
+
+#[derive(Default)]
+struct TbsFields {
+    subject: String,
+    issuer: String,
+    sans: Vec<String>,
+    not_before: i64,
+    not_after: i64,
+}
+
+const SEQUENCE: u8 = 0x30;
+const OID: u8 = 0x06;
+const OCTET_STRING: u8 = 0x04;
+const BOOLEAN: u8 = 0x01;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+
+/// One DER TLV: (tag, contents, rest of input). Only definite lengths (DER).
+fn tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let len = rest[..n]
+            .iter()
+            .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+        (len, &rest[n..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+fn expect(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    match tlv(input)? {
+        (t, content, rest) if t == tag => Some((content, rest)),
+        _ => None,
+    }
+}
+
+fn parse_tbs(der: &[u8]) -> Option<TbsFields> {
+    let (cert, _) = expect(der, SEQUENCE)?;
+    let (tbs, _) = expect(cert, SEQUENCE)?;
+    let mut rest = tbs;
+    // version [0] EXPLICIT, optional
+    if let Some((0xa0, _, r)) = tlv(rest) {
+        rest = r;
+    }
+    let (_serial, _, r) = tlv(rest)?;
+    let (_, r) = expect(r, SEQUENCE)?; // signature algorithm
+    let (issuer, r) = expect(r, SEQUENCE)?;
+    let (validity, r) = expect(r, SEQUENCE)?;
+    let (subject, r) = expect(r, SEQUENCE)?;
+    let (_, mut r) = expect(r, SEQUENCE)?; // subjectPublicKeyInfo
+
+    let mut fields = TbsFields {
+        subject: name_to_string(subject),
+        issuer: name_to_string(issuer),
+        ..Default::default()
+    };
+    let (t1, not_before, v) = tlv(validity)?;
+    let (t2, not_after, _) = tlv(v)?;
+    fields.not_before = parse_time(t1, not_before).unwrap_or(0);
+    fields.not_after = parse_time(t2, not_after).unwrap_or(0);
+
+    // issuerUniqueID [1], subjectUniqueID [2], extensions [3]
+    while let Some((tag, content, next)) = tlv(r) {
+        if tag == 0xa3 {
+            if let Some((exts, _)) = expect(content, SEQUENCE) {
+                fields.sans = subject_alt_names(exts);
+            }
+        }
+        r = next;
+    }
+    Some(fields)
+}
+
+/// Hash for RFC 5929 tls-server-end-point from the certificate's signature algorithm.
+/// MD5 and SHA-1 map to SHA-256; RSASSA-PSS uses its hashAlgorithm parameter (SHA-1 when
+/// absent). None for algorithms without a single hash function (Ed25519, Ed448) or unknown ones.
+pub fn signature_hash(der: &[u8]) -> Option<&'static ring::digest::Algorithm> {
+    use ring::digest::{SHA256, SHA384, SHA512};
+    let (cert, _) = expect(der, SEQUENCE)?;
+    let (_tbs, rest) = expect(cert, SEQUENCE)?;
+    let (algorithm, _) = expect(rest, SEQUENCE)?;
+    let (oid, params) = expect(algorithm, OID)?;
+    let hash = match oid_to_string(oid).as_str() {
+        // md5/sha1/sha256/sha384/sha512 WithRSAEncryption
+        "1.2.840.113549.1.1.4" | "1.2.840.113549.1.1.5" | "1.2.840.113549.1.1.11" => &SHA256,
+        "1.2.840.113549.1.1.12" => &SHA384,
+        "1.2.840.113549.1.1.13" => &SHA512,
+        // ecdsa-with-SHA1/SHA256/SHA384/SHA512
+        "1.2.840.10045.4.1" | "1.2.840.10045.4.3.2" => &SHA256,
+        "1.2.840.10045.4.3.3" => &SHA384,
+        "1.2.840.10045.4.3.4" => &SHA512,
+        // RSASSA-PSS: params SEQUENCE { [0] hashAlgorithm AlgorithmIdentifier, ... }
+        "1.2.840.113549.1.1.10" => {
+            let hash_oid = expect(params, SEQUENCE)
+                .and_then(|(p, _)| expect(p, 0xa0))
+                .and_then(|(h, _)| expect(h, SEQUENCE))
+                .and_then(|(h, _)| expect(h, OID))
+                .map(|(oid, _)| oid_to_string(oid));
+            match hash_oid.as_deref() {
+                None | Some("1.3.14.3.2.26") | Some("2.16.840.1.101.3.4.2.1") => &SHA256,
+                Some("2.16.840.1.101.3.4.2.2") => &SHA384,
+                Some("2.16.840.1.101.3.4.2.3") => &SHA512,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    Some(hash)
+}
+
+/// Dotted-decimal OID.
+fn oid_to_string(oid: &[u8]) -> String {
+    let mut parts: Vec<u64> = Vec::new();
+    let mut value = 0u64;
+    for b in oid {
+        value = (value << 7) | (b & 0x7f) as u64;
+        if b & 0x80 == 0 {
+            if parts.is_empty() {
+                let first = (value / 40).min(2);
+                parts.push(first);
+                parts.push(value - first * 40);
+            } else {
+                parts.push(value);
+            }
+            value = 0;
+        }
+    }
+    parts
+        .iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn attribute_name(oid: &[u8]) -> String {
+    match oid {
+        [0x55, 0x04, 0x03] => "CN".into(),
+        [0x55, 0x04, 0x06] => "C".into(),
+        [0x55, 0x04, 0x07] => "L".into(),
+        [0x55, 0x04, 0x08] => "ST".into(),
+        [0x55, 0x04, 0x0a] => "O".into(),
+        [0x55, 0x04, 0x0b] => "OU".into(),
+        [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01] => "emailAddress".into(),
+        _ => oid_to_string(oid),
+    }
+}
+
+/// String value of a directory string (UTF8String, PrintableString, IA5String, ...).
+fn string_value(tag: u8, content: &[u8]) -> String {
+    match tag {
+        // BMPString: UTF-16BE
+        0x1e => {
+            let units: Vec<u16> = content
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(content).into_owned(),
+    }
+}
+
+/// X.501 Name as "CN=..., O=..." in encoding order.
+fn name_to_string(name: &[u8]) -> String {
+    let mut parts = Vec::new();
+    let mut rdns = name;
+    while let Some((0x31, rdn, next)) = tlv(rdns) {
+        let mut attrs = rdn;
+        while let Some((content, next_attr)) = expect(attrs, SEQUENCE) {
+            if let Some((oid, value)) = expect(content, OID) {
+                if let Some((tag, value, _)) = tlv(value) {
+                    parts.push(format!(
+                        "{}={}",
+                        attribute_name(oid),
+                        string_value(tag, value)
+                    ));
+                }
+            }
+            attrs = next_attr;
+        }
+        rdns = next;
+    }
+    parts.join(", ")
+}
+
+/// subjectAltName entries from the extensions SEQUENCE.
+fn subject_alt_names(mut exts: &[u8]) -> Vec<String> {
+    const SAN: &[u8] = &[0x55, 0x1d, 0x11];
+    while let Some((ext, next)) = expect(exts, SEQUENCE) {
+        exts = next;
+        let Some((oid, mut rest)) = expect(ext, OID) else {
+            continue;
+        };
+        if oid != SAN {
+            continue;
+        }
+        if let Some((BOOLEAN, _, r)) = tlv(rest) {
+            rest = r;
+        }
+        let Some((value, _)) = expect(rest, OCTET_STRING) else {
+            return Vec::new();
+        };
+        let Some((mut names, _)) = expect(value, SEQUENCE) else {
+            return Vec::new();
+        };
+        let mut out = Vec::new();
+        while let Some((tag, content, next)) = tlv(names) {
+            if let Some(name) = general_name(tag, content) {
+                out.push(name);
+            }
+            names = next;
+        }
+        return out;
+    }
+    Vec::new()
+}
+
+fn general_name(tag: u8, content: &[u8]) -> Option<String> {
+    const XMPP_ADDR: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x08, 0x05];
+    const SRV_NAME: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x08, 0x07];
+    match tag {
+        // otherName: type-id OID, value [0] EXPLICIT
+        0xa0 => {
+            let (oid, rest) = expect(content, OID)?;
+            let (inner, _) = expect(rest, 0xa0)?;
+            let (t, value, _) = tlv(inner)?;
+            let label = match oid {
+                XMPP_ADDR => "xmppAddr".to_string(),
+                SRV_NAME => "SRVName".to_string(),
+                _ => oid_to_string(oid),
+            };
+            Some(format!("{}:{}", label, string_value(t, value)))
+        }
+        0x81 => Some(format!("email:{}", String::from_utf8_lossy(content))),
+        0x82 => Some(String::from_utf8_lossy(content).into_owned()),
+        0x86 => Some(format!("URI:{}", String::from_utf8_lossy(content))),
+        0x87 => match content.len() {
+            4 => {
+                let b: [u8; 4] = content.try_into().ok()?;
+                Some(std::net::Ipv4Addr::from(b).to_string())
+            }
+            16 => {
+                let b: [u8; 16] = content.try_into().ok()?;
+                Some(std::net::Ipv6Addr::from(b).to_string())
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// UTCTime (YYMMDDHHMMSSZ) or GeneralizedTime (YYYYMMDDHHMMSSZ) to Unix seconds.
+fn parse_time(tag: u8, content: &[u8]) -> Option<i64> {
+    let s = std::str::from_utf8(content).ok()?;
+    let s = s.strip_suffix('Z')?;
+    let (year, rest) = match tag {
+        UTC_TIME => {
+            let yy: i64 = s.get(0..2)?.parse().ok()?;
+            // RFC 5280: 50..99 means 19xx.
+            (if yy >= 50 { 1900 + yy } else { 2000 + yy }, s.get(2..)?)
+        }
+        GENERALIZED_TIME => (s.get(0..4)?.parse().ok()?, s.get(4..)?),
+        _ => return None,
+    };
+    let num = |r: std::ops::Range<usize>| -> Option<i64> { rest.get(r)?.parse().ok() };
+    let (month, day) = (num(0..2)?, num(2..4)?);
+    let (hour, minute, second) = (num(4..6)?, num(6..8)?, num(8..10).unwrap_or(0));
+    Some(days_from_civil(year, month, day) * 86_400 + hour * 3_600 + minute * 60 + second)
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date (H. Hinnant's algorithm).
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = (m + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
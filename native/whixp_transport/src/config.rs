@@ -1,6 +1,7 @@
 //! Connection configuration: host, port, TLS, WebSocket, timeouts.
 //! Passed from Dart after DNS resolution (DNS stays on Dart side).
 
+use std::ffi::{c_char, c_void};
 use std::time::Duration;
 
 /// Transport type: TCP (with optional StartTLS), Direct TLS, or WebSocket.
@@ -14,6 +15,193 @@ pub enum TransportKind {
     WebSocketTls = 4,
 }
 
+/// Address family policy for connecting (C: `use_ipv6`). Both "prefer" modes race the two
+/// families (Happy Eyeballs, RFC 8305); the "only" modes never touch the other family.
+/// 0 and 1 keep the old boolean meaning (IPv6 off / on) as a preference.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpPreference {
+    PreferIpv4 = 0,
+    PreferIpv6 = 1,
+    Ipv4Only = 2,
+    Ipv6Only = 3,
+}
+
+impl IpPreference {
+    pub fn allows_v4(self) -> bool {
+        self != IpPreference::Ipv6Only
+    }
+
+    pub fn allows_v6(self) -> bool {
+        self != IpPreference::Ipv4Only
+    }
+
+    /// Whether IPv6 addresses are tried first.
+    pub fn v6_first(self) -> bool {
+        matches!(self, IpPreference::PreferIpv6 | IpPreference::Ipv6Only)
+    }
+}
+
+/// How XMPP is carried over WebSocket (C: `ws_framing`).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WsFraming {
+    /// Message payloads are joined into one byte stream and re-split into stanzas.
+    Stream = 0,
+    /// RFC 7395: the `xmpp` subprotocol is required, each message is one element, and
+    /// `<open/>`, `<close/>` and see-other-host are reported as their own events.
+    Rfc7395 = 1,
+}
+
+/// DoH message format (C: `doh_format`).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DohFormat {
+    /// `application/dns-json` GET (Cloudflare/Google JSON API).
+    Json = 0,
+    /// RFC 8484 `application/dns-message` POST; works with any standard DoH server.
+    Wire = 1,
+}
+
+/// DNS-over-HTTPS fallback settings, used when the system resolver fails.
+#[derive(Clone, Debug)]
+pub struct DohConfig {
+    /// Endpoint URLs (e.g. `https://dns.quad9.net/dns-query`), tried in order.
+    /// Empty uses Cloudflare.
+    pub endpoints: Vec<String>,
+    pub format: DohFormat,
+    /// Per request, for the connect and for the whole exchange. Resolving for a connection
+    /// uses its connect timeout.
+    pub timeout: Duration,
+}
+
+impl Default for DohConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: Vec::new(),
+            format: DohFormat::Json,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Root certificates servers are verified against (C: `trust_store`).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TrustStore {
+    /// Mozilla roots bundled via webpki-roots.
+    Bundled = 0,
+    /// The operating system trust store (feature `system-roots`).
+    System = 1,
+    /// Both of the above.
+    BundledAndSystem = 2,
+}
+
+/// How SPKI pins combine with chain validation (C: `spki_pin_mode`).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PinMode {
+    /// The chain must validate and a certificate in it must match a pin.
+    PinAndChain = 0,
+    /// The leaf key must match a pin; no chain or name validation (self-signed servers).
+    PinOnly = 1,
+}
+
+/// Lowest TLS version offered (C: `tls_min_version`).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TlsVersion {
+    Tls12 = 0,
+    Tls13 = 1,
+}
+
+/// Where TLS sessions are kept for resumption (C: `tls_session_cache`).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SessionCache {
+    /// One cache per handle: reconnects of that handle resume.
+    PerHandle = 0,
+    /// One process-wide cache keyed by server name, shared by all handles.
+    Shared = 1,
+    /// Full handshake every time.
+    Disabled = 2,
+}
+
+/// Server certificate verification settings.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TlsConfig {
+    pub trust_store: TrustStore,
+    /// Extra trust anchors: PEM (one or more certificates) or a single DER certificate.
+    /// Added to the roots from `trust_store`.
+    pub extra_roots: Vec<u8>,
+    /// SHA-256 digests of DER SubjectPublicKeyInfo. Empty = no pinning.
+    pub spki_pins: Vec<[u8; 32]>,
+    pub pin_mode: PinMode,
+    /// On a verification failure, pause the handshake and let Dart accept or reject the
+    /// certificate (trust-on-first-use) instead of failing right away.
+    pub interactive_trust: bool,
+    /// How long the handshake waits for Dart's decision before rejecting.
+    pub trust_prompt_timeout_ms: u32,
+    /// Client certificate chain for mutual TLS (SASL EXTERNAL, XEP-0178): PEM (leaf first)
+    /// or a single DER certificate. Empty = no client authentication.
+    pub client_cert: Vec<u8>,
+    /// Private key for `client_cert`: PEM (PKCS#8, PKCS#1 or SEC1) or PKCS#8 DER.
+    pub client_key: Vec<u8>,
+    pub min_version: TlsVersion,
+    /// Allowed cipher suites as IANA code points (e.g. 0x1301). Empty = rustls defaults.
+    pub cipher_suites: Vec<u16>,
+    /// Allowed key exchange groups as IANA code points (e.g. 0x001d = X25519), in preference
+    /// order. Empty = rustls defaults.
+    pub kx_groups: Vec<u16>,
+    pub session_cache: SessionCache,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            trust_store: TrustStore::Bundled,
+            extra_roots: Vec::new(),
+            spki_pins: Vec::new(),
+            pin_mode: PinMode::PinAndChain,
+            interactive_trust: false,
+            trust_prompt_timeout_ms: 60_000,
+            client_cert: Vec::new(),
+            client_key: Vec::new(),
+            min_version: TlsVersion::Tls12,
+            cipher_suites: Vec::new(),
+            kx_groups: Vec::new(),
+            session_cache: SessionCache::PerHandle,
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn trust_prompt_timeout(&self) -> Duration {
+        Duration::from_millis(self.trust_prompt_timeout_ms as u64)
+    }
+}
+
+/// C callback receiving one key log line (NUL-terminated, no newline) and its user data.
+/// The callee owns `line` and frees it with whixp_transport_key_log_free.
+pub type KeyLogCallback = extern "C" fn(line: *mut c_char, user_data: *mut c_void);
+
+/// Where TLS secrets are logged in NSS key log format (SSLKEYLOGFILE) for decrypting
+/// captures. Honoured only in debug builds or with feature `keylog`.
+#[derive(Clone, Debug, Default)]
+pub struct KeyLogConfig {
+    /// File the lines are appended to.
+    pub path: Option<String>,
+    pub callback: Option<KeyLogCallback>,
+    /// Passed back to `callback` unchanged.
+    pub user_data: usize,
+}
+
+impl KeyLogConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.path.is_some() || self.callback.is_some()
+    }
+}
+
 /// Configuration for the Rust transport layer.
 /// host = domain to resolve (SRV + A/AAAA in Rust); service = e.g. "xmpp-client".
 #[derive(Clone, Debug)]
@@ -23,12 +211,39 @@ pub struct TransportConfig {
     pub port: u16,
     pub kind: TransportKind,
     pub connect_timeout_ms: u32,
+    /// TLS and WebSocket upgrade handshake timeout (per handshake, after the TCP connect).
+    pub handshake_timeout_ms: u32,
+    /// SNI to send instead of `host`. Certificates are still verified against `host`.
     pub tls_server_name: Option<String>,
     /// SRV service name (e.g. "xmpp-client") or None to skip SRV.
     pub service: Option<String>,
-    pub use_ipv6: bool,
+    pub ip_preference: IpPreference,
+    /// Delay before starting the next connection attempt while one is pending
+    /// (RFC 8305 "Connection Attempt Delay").
+    pub attempt_delay_ms: u32,
     /// WebSocket path (e.g. "/ws" or "/xmpp-websocket")
     pub ws_path: Option<String>,
+    pub ws_framing: WsFraming,
+    /// Extra upgrade request headers (Authorization, Cookie, Origin, User-Agent, ...).
+    pub ws_headers: Vec<(String, String)>,
+    /// Offer RFC 7692 permessage-deflate.
+    pub ws_deflate: bool,
+    /// Ping the server after this long without inbound traffic. 0 = no pings.
+    pub ws_ping_interval_ms: u32,
+    /// Drop the connection when nothing arrives this long after a ping.
+    pub ws_pong_timeout_ms: u32,
+    /// Reconnect in Rust (DNS + connect again, per RetryPolicy) when the stream drops.
+    /// False leaves reconnecting to Dart's ReconnectionPolicy.
+    pub auto_reconnect: bool,
+    /// DoH fallback for SRV and A/AAAA lookups (feature `doh`).
+    pub doh: DohConfig,
+    /// How long an expired cached DNS answer may still be used while it is refreshed in the
+    /// background. 0 = only answers within their TTL.
+    pub dns_stale_grace_ms: u32,
+    /// Certificate verification for DirectTls, StartTLS and wss.
+    pub tls: TlsConfig,
+    /// TLS key log for debugging (off by default).
+    pub key_log: KeyLogConfig,
 }
 
 impl Default for TransportConfig {
@@ -38,10 +253,22 @@ impl Default for TransportConfig {
             port: 5222,
             kind: TransportKind::TcpStartTls,
             connect_timeout_ms: 2000,
+            handshake_timeout_ms: 10_000,
             tls_server_name: None,
             service: None,
-            use_ipv6: false,
+            ip_preference: IpPreference::PreferIpv6,
+            attempt_delay_ms: 250,
             ws_path: Some("/ws".to_string()),
+            ws_framing: WsFraming::Stream,
+            ws_headers: Vec::new(),
+            ws_deflate: false,
+            ws_ping_interval_ms: 0,
+            ws_pong_timeout_ms: 10_000,
+            auto_reconnect: false,
+            doh: DohConfig::default(),
+            dns_stale_grace_ms: 0,
+            tls: TlsConfig::default(),
+            key_log: KeyLogConfig::default(),
         }
     }
 }
@@ -50,4 +277,26 @@ impl TransportConfig {
     pub fn connect_timeout(&self) -> Duration {
         Duration::from_millis(self.connect_timeout_ms as u64)
     }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_millis(self.handshake_timeout_ms as u64)
+    }
+
+    pub fn attempt_delay(&self) -> Duration {
+        Duration::from_millis(self.attempt_delay_ms as u64)
+    }
+
+    pub fn dns_stale_grace(&self) -> Duration {
+        Duration::from_millis(self.dns_stale_grace_ms as u64)
+    }
+
+    /// WebSocket ping interval and pong timeout, or None when pings are off.
+    pub fn ws_keepalive(&self) -> Option<(Duration, Duration)> {
+        (self.ws_ping_interval_ms > 0).then(|| {
+            (
+                Duration::from_millis(self.ws_ping_interval_ms as u64),
+                Duration::from_millis(self.ws_pong_timeout_ms as u64),
+            )
+        })
+    }
 }
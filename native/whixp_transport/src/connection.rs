@@ -1,21 +1,29 @@
 //! Connection lifecycle: connect, send, receive loop, disconnect.
-//! Spawns a thread that does read loop (stanza framing) and send channel.
+//! Each connection is a task on the shared `runtime`: a worker services it on socket
+//! readiness and does both the read side (stanza framing) and the queued writes.
 
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::io::{Read, Write};
-use std::net::{TcpStream, ToSocketAddrs};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicIsize, Ordering};
 use std::sync::mpsc;
-use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
-
-use crate::config::{TransportConfig, TransportKind};
-use crate::dns;
-use crate::handshake::HandshakeError;
-use crate::retry::RetryPolicy;
-use crate::stanza::StreamFramer;
-use crate::tls;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, TryLockError};
+use std::time::{Duration, Instant};
+
+use rustls::KeyLog;
+
+use crate::cancel::Cancel;
+use crate::config::{TransportConfig, TransportKind, WsFraming};
+use crate::dns::{self, ResolveOptions, Target};
+use crate::handshake::{HandshakeError, HandshakeErrorCode};
+use crate::happy_eyeballs;
+use crate::keylog;
+use crate::retry::{self, RetryPolicy};
+use crate::runtime::{self, Registration, Step, Task};
+use crate::stanza::{Frame, StreamFramer};
+use crate::tls::{self, ChannelBinding, OnBadCert, ServerNames, TlsContext, TlsInfo, TrustPrompt};
 use crate::websocket;
 
 type Result<T> = std::result::Result<T, HandshakeError>;
@@ -34,11 +42,13 @@ pub enum TransportState {
 }
 
 /// Either TCP, TLS, or WebSocket stream so we can have one read/write loop.
+/// `Closed` is the placeholder left behind while a stream is taken out for StartTLS.
 enum StreamKind {
     Tcp(TcpStream),
-    Tls(tls::TlsStreamWrapper),
-    Ws(websocket::WsStream<TcpStream>),
-    WsTls(websocket::WsStream<tls::TlsStreamWrapper>),
+    Tls(Box<tls::TlsStreamWrapper>),
+    Ws(Box<websocket::WsStream<TcpStream>>),
+    WsTls(Box<websocket::WsStream<tls::TlsStreamWrapper>>),
+    Closed,
 }
 
 impl Read for StreamKind {
@@ -48,6 +58,57 @@ impl Read for StreamKind {
             StreamKind::Tls(s) => s.read(buf),
             StreamKind::Ws(s) => s.read(buf),
             StreamKind::WsTls(s) => s.read(buf),
+            StreamKind::Closed => Ok(0),
+        }
+    }
+}
+
+/// What one read of the live stream produced.
+enum Incoming {
+    /// Raw bytes for the stanza framer; 0 = end of stream.
+    Bytes(usize),
+    /// One whole WebSocket message (RFC 7395 framing).
+    Message(String),
+}
+
+impl StreamKind {
+    /// Read raw bytes into `buf`, or with `messages` on a WebSocket, one whole message.
+    fn read_incoming(&mut self, buf: &mut [u8], messages: bool) -> std::io::Result<Incoming> {
+        let message = match self {
+            StreamKind::Ws(s) if messages => s.read_message()?,
+            StreamKind::WsTls(s) if messages => s.read_message()?,
+            _ => return self.read(buf).map(Incoming::Bytes),
+        };
+        Ok(message.map_or(Incoming::Bytes(0), Incoming::Message))
+    }
+
+    /// The socket to wait on (None while `Closed`).
+    fn socket(&self) -> Option<&TcpStream> {
+        match self {
+            StreamKind::Tcp(s) => Some(s),
+            StreamKind::Tls(s) => Some(s.tcp()),
+            StreamKind::Ws(s) => Some(s.get_ref()),
+            StreamKind::WsTls(s) => Some(s.get_ref().tcp()),
+            StreamKind::Closed => None,
+        }
+    }
+
+    /// Whether output is buffered above the socket (TLS records, a WebSocket send).
+    fn wants_write(&self) -> bool {
+        match self {
+            StreamKind::Tls(s) => s.wants_write(),
+            StreamKind::Ws(s) => s.wants_write(),
+            StreamKind::WsTls(s) => s.wants_write() || s.get_ref().wants_write(),
+            StreamKind::Tcp(_) | StreamKind::Closed => false,
+        }
+    }
+
+    /// Run stream timers (WebSocket keepalive); returns when they are next due.
+    fn tick(&mut self) -> std::io::Result<Option<Duration>> {
+        match self {
+            StreamKind::Ws(s) => s.tick(),
+            StreamKind::WsTls(s) => s.tick(),
+            _ => Ok(None),
         }
     }
 }
@@ -59,6 +120,7 @@ impl Write for StreamKind {
             StreamKind::Tls(s) => s.write(buf),
             StreamKind::Ws(s) => s.write(buf),
             StreamKind::WsTls(s) => s.write(buf),
+            StreamKind::Closed => Err(std::io::ErrorKind::NotConnected.into()),
         }
     }
     fn flush(&mut self) -> std::io::Result<()> {
@@ -67,6 +129,7 @@ impl Write for StreamKind {
             StreamKind::Tls(s) => s.flush(),
             StreamKind::Ws(s) => s.flush(),
             StreamKind::WsTls(s) => s.flush(),
+            StreamKind::Closed => Ok(()),
         }
     }
 }
@@ -76,158 +139,875 @@ pub enum TransportEvent {
     State(i32),
     Stanza(String),
     Error(i32, String),
+    /// Incoming `<stream:stream ...>` header (initial or after a stream restart).
+    StreamHeader(String),
+    /// Server certificate failed verification; the handshake waits for a trust decision.
+    /// JSON, see `cert_info::BadCertificate`.
+    BadCertificate(String),
+    /// RFC 7395 `<open/>` from the server (`WsFraming::Rfc7395`).
+    Open(String),
+    /// RFC 7395 `<close/>` from the server (`WsFraming::Rfc7395`).
+    Close(String),
+    /// RFC 7395 `<close/>` redirecting to another URI (`WsFraming::Rfc7395`).
+    SeeOtherHost(String),
+}
+
+impl From<Frame> for TransportEvent {
+    fn from(frame: Frame) -> Self {
+        match frame {
+            Frame::StreamHeader(h) => TransportEvent::StreamHeader(h),
+            Frame::Stanza(s) => TransportEvent::Stanza(s),
+            Frame::Open(s) => TransportEvent::Open(s),
+            Frame::Close(s) => TransportEvent::Close(s),
+            Frame::SeeOtherHost(uri) => TransportEvent::SeeOtherHost(uri),
+        }
+    }
+}
+
+/// Hook run when a handle's event queue goes from empty to non-empty (push mode).
+#[derive(Default)]
+pub struct QueueNotifier {
+    hook: Mutex<Option<Box<dyn Fn() + Send>>>,
+}
+
+impl QueueNotifier {
+    /// Install or remove the hook. It runs under the same lock, so once this returns the
+    /// old hook is not running and never runs again. Events already queued do not fire it.
+    pub fn set(&self, hook: Option<Box<dyn Fn() + Send>>) {
+        *self.hook.lock().unwrap_or_else(|e| e.into_inner()) = hook;
+    }
+
+    fn fire(&self) {
+        if let Some(ref hook) = *self.hook.lock().unwrap_or_else(|e| e.into_inner()) {
+            hook();
+        }
+    }
+}
+
+/// Event queue of one connect. Counts queued events so `notifier` fires once per
+/// empty-to-non-empty transition.
+pub fn event_channel(notifier: Arc<QueueNotifier>) -> (EventSender, EventReceiver) {
+    let (tx, rx) = mpsc::channel();
+    let queued = Arc::new(AtomicIsize::new(0));
+    let sender = EventSender {
+        tx,
+        queued: Arc::clone(&queued),
+        notifier,
+    };
+    (sender, EventReceiver { rx, queued })
+}
+
+/// Sender for events; connection tasks use this instead of callbacks.
+#[derive(Clone)]
+pub struct EventSender {
+    tx: mpsc::Sender<TransportEvent>,
+    queued: Arc<AtomicIsize>,
+    notifier: Arc<QueueNotifier>,
+}
+
+impl EventSender {
+    pub fn send(
+        &self,
+        event: TransportEvent,
+    ) -> std::result::Result<(), mpsc::SendError<TransportEvent>> {
+        self.tx.send(event)?;
+        // Counted after the send, so the hook never runs before the event can be taken.
+        // A receiver that was faster leaves the count at -1 and this lands on 0.
+        if self.queued.fetch_add(1, Ordering::SeqCst) == 0 {
+            self.notifier.fire();
+        }
+        Ok(())
+    }
+}
+
+pub struct EventReceiver {
+    rx: mpsc::Receiver<TransportEvent>,
+    queued: Arc<AtomicIsize>,
+}
+
+impl EventReceiver {
+    pub fn try_recv(&self) -> std::result::Result<TransportEvent, mpsc::TryRecvError> {
+        let event = self.rx.try_recv()?;
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        Ok(event)
+    }
+
+    pub fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> std::result::Result<TransportEvent, mpsc::RecvTimeoutError> {
+        let event = self.rx.recv_timeout(timeout)?;
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        Ok(event)
+    }
+}
+
+/// Stops a connection from any thread without the `Connection` itself, which a blocking
+/// connect or StartTLS handshake may be holding.
+#[derive(Clone)]
+pub struct Closer {
+    shutdown: Arc<AtomicBool>,
+    trust: Arc<TrustPrompt>,
+    /// Duplicate of the live socket for `shutdown(Both)`; None while there is no stream.
+    socket: Arc<Mutex<Option<TcpStream>>>,
+    /// The connection's task, woken so it sees the flag while it has no socket (reconnect
+    /// backoff).
+    task: Arc<Mutex<Option<Registration>>>,
+    /// Ends the DNS lookups and TCP connects of a connect or reconnect dial in progress.
+    cancel: Cancel,
+}
+
+impl Closer {
+    /// Set the shutdown flag, reject a waiting trust prompt, end a connect's lookups and TCP
+    /// connects, and shut the socket down both ways, which wakes a blocked read (handshake)
+    /// and the connection's task.
+    pub fn close(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.cancel.cancel();
+        // A handshake paused on a trust prompt gives up instead of waiting for the timeout.
+        self.trust.decide(false);
+        if let Some(ref socket) = *self.socket.lock().unwrap_or_else(|e| e.into_inner()) {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+        if let Some(ref task) = *self.task.lock().unwrap_or_else(|e| e.into_inner()) {
+            task.wake();
+        }
+    }
+
+    /// Wake `task` on close from now on.
+    fn attach(&self, task: Registration) {
+        *self.task.lock().unwrap_or_else(|e| e.into_inner()) = Some(task);
+    }
+
+    /// Keep a duplicate of the new live socket, or drop the old one so it can close.
+    fn track(&self, socket: Option<&TcpStream>) {
+        let mut tracked = self.socket.lock().unwrap_or_else(|e| e.into_inner());
+        *tracked = socket.and_then(|s| s.try_clone().ok());
+        // Closed while this socket was being opened.
+        if self.shutdown.load(Ordering::SeqCst) {
+            if let Some(ref socket) = *tracked {
+                let _ = socket.shutdown(Shutdown::Both);
+            }
+        }
+    }
+}
+
+/// Write queue of the connection's task, plus its place in the runtime.
+struct Outbox {
+    tx: mpsc::Sender<Vec<u8>>,
+    reg: Registration,
+}
+
+/// Reads handled per service before queued writes and other connections get a turn.
+const READS_PER_WAKE: usize = 64;
+
+/// How long a new connect waits for the session of the previous one to stop.
+const PREVIOUS_JOIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The live stream, shared by the connection's task and callers on other threads (StartTLS,
+/// info calls). The task never blocks on the lock: finding it held, it marks itself waiting
+/// and sleeps, and the holder wakes it when the guard is dropped.
+struct SharedStream {
+    stream: Mutex<StreamKind>,
+    /// The task found the lock held and waits for a wake.
+    waiting: AtomicBool,
+    /// The connection's task, once spawned.
+    task: OnceLock<Registration>,
+}
+
+impl SharedStream {
+    fn new(stream: StreamKind) -> Self {
+        Self {
+            stream: Mutex::new(stream),
+            waiting: AtomicBool::new(false),
+            task: OnceLock::new(),
+        }
+    }
+
+    /// Wait for the lock. A holder that panicked leaves a whole `StreamKind` behind (at
+    /// worst `Closed`), so a poisoned lock is taken over rather than reported.
+    fn lock(&self) -> StreamGuard<'_> {
+        StreamGuard {
+            guard: Some(self.stream.lock().unwrap_or_else(|e| e.into_inner())),
+            shared: self,
+        }
+    }
+
+    /// The task's lock: None while someone else holds it, who then wakes the task on release.
+    fn try_lock(&self) -> Option<StreamGuard<'_>> {
+        // Set before trying, so a holder releasing in between still sees it.
+        self.waiting.store(true, Ordering::SeqCst);
+        let guard = match self.stream.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => return None,
+        };
+        self.waiting.store(false, Ordering::SeqCst);
+        Some(StreamGuard {
+            guard: Some(guard),
+            shared: self,
+        })
+    }
+}
+
+struct StreamGuard<'a> {
+    /// Always Some until dropped; released before the waiting task is woken.
+    guard: Option<MutexGuard<'a, StreamKind>>,
+    shared: &'a SharedStream,
+}
+
+impl Deref for StreamGuard<'_> {
+    type Target = StreamKind;
+
+    fn deref(&self) -> &StreamKind {
+        self.guard.as_ref().expect("stream guard")
+    }
+}
+
+impl DerefMut for StreamGuard<'_> {
+    fn deref_mut(&mut self) -> &mut StreamKind {
+        self.guard.as_mut().expect("stream guard")
+    }
+}
+
+impl Drop for StreamGuard<'_> {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        if self.shared.waiting.swap(false, Ordering::SeqCst) {
+            if let Some(task) = self.shared.task.get() {
+                task.wake();
+            }
+        }
+    }
+}
+
+/// Why servicing the stream stopped.
+enum Ended {
+    /// EOF, a read or write error, or a dead WebSocket peer.
+    Stream,
+    /// Shutdown was requested.
+    Shutdown,
+}
+
+/// Where the session is in its life.
+enum Phase {
+    /// Spawned; the socket is not in the poller yet.
+    Start,
+    Running,
+    /// Waiting out the retry delay before reconnect attempt `attempt` (from 0).
+    Backoff {
+        attempt: u32,
+        until: Instant,
+    },
+    /// A helper thread is dialing; it sends the result and wakes the task.
+    Reconnecting {
+        attempt: u32,
+        opened: mpsc::Receiver<Result<StreamKind>>,
+    },
+}
+
+/// One connection on the shared runtime: serviced by a worker when the socket is readable
+/// (or writable while output is pending), `send`/`shutdown` wake it, or a stream timer is
+/// due. The stream lock is only held for non-blocking reads and writes.
+struct Session {
+    stream: Arc<SharedStream>,
+    reg: Registration,
+    phase: Phase,
+    send_rx: mpsc::Receiver<Vec<u8>>,
+    /// Queued sends the stream has not taken yet, kept apart so each WebSocket send stays
+    /// one message.
+    out: VecDeque<Vec<u8>>,
+    /// How much of the front of `out` was written.
+    out_pos: usize,
+    framer: StreamFramer,
+    shutdown: Arc<AtomicBool>,
+    restart: Arc<AtomicBool>,
+    event_tx: EventSender,
+    ws_messages: bool,
+    config: TransportConfig,
+    retry: RetryPolicy,
+    tls_ctx: TlsContext,
+    closer: Closer,
+    /// Dropped with the session (and any reconnect dial); `Connection::join` waits for that.
+    alive: mpsc::Sender<()>,
+}
+
+impl Task for Session {
+    fn service(&mut self) -> Step {
+        match std::mem::replace(&mut self.phase, Phase::Running) {
+            Phase::Start => {
+                if !self.register() {
+                    return self.finish();
+                }
+            }
+            Phase::Running => {}
+            Phase::Backoff { attempt, until } => {
+                if self.shutdown.load(Ordering::SeqCst) {
+                    return self.finish();
+                }
+                if Instant::now() < until {
+                    self.phase = Phase::Backoff { attempt, until };
+                    return Step::Wait {
+                        timer: Some(until),
+                        busy: false,
+                    };
+                }
+                return self.dial(attempt);
+            }
+            Phase::Reconnecting { attempt, opened } => match opened.try_recv() {
+                Ok(Ok(new_stream)) => {
+                    if !self.reconnected(new_stream) {
+                        return self.finish();
+                    }
+                }
+                Ok(Err(e)) => {
+                    eprintln!("[Whixp] reconnect attempt {} failed: {}", attempt + 1, e);
+                    return self.retry_after(attempt + 1);
+                }
+                // The dial panicked.
+                Err(mpsc::TryRecvError::Disconnected) => return self.retry_after(attempt + 1),
+                Err(mpsc::TryRecvError::Empty) => {
+                    if self.shutdown.load(Ordering::SeqCst) {
+                        return self.finish();
+                    }
+                    self.phase = Phase::Reconnecting { attempt, opened };
+                    return Step::Wait {
+                        timer: None,
+                        busy: false,
+                    };
+                }
+            },
+        }
+        let mut buf = [0u8; 8192];
+        match self.pump(&mut buf) {
+            Ok(step) => step,
+            Err(Ended::Shutdown) => self.finish(),
+            Err(Ended::Stream) => self.recover(),
+        }
+    }
+
+    /// Close the connection for good (no reconnect) and report why.
+    fn abandoned(&mut self) {
+        self.closer.close();
+        let _ = self.event_tx.send(TransportEvent::Error(
+            HandshakeErrorCode::Connection as i32,
+            "connection task panicked".into(),
+        ));
+        self.finish();
+    }
 }
 
-/// Sender for events; connection threads use this instead of callbacks.
-pub type EventSender = mpsc::Sender<TransportEvent>;
+impl Session {
+    /// Add the live socket to the poller; false if that fails.
+    fn register(&self) -> bool {
+        let guard = self.stream.lock();
+        let Some(socket) = guard.socket() else {
+            return true;
+        };
+        // Safety: the socket is deleted from the poller (`finish`, `recover`, StartTLS)
+        // before it is dropped.
+        match unsafe { self.reg.add(socket) } {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("[Whixp] poll register failed: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Write what is queued, run timers, read what is there, then re-arm the poller.
+    fn pump(&mut self, buf: &mut [u8]) -> std::result::Result<Step, Ended> {
+        if self.shutdown.load(Ordering::SeqCst) {
+            return Err(Ended::Shutdown);
+        }
+        let stream = Arc::clone(&self.stream);
+        // Never block the worker: the holder (a StartTLS handshake, an info call) wakes
+        // the task when it lets go.
+        let Some(mut guard) = stream.try_lock() else {
+            return Ok(Step::Wait {
+                timer: None,
+                busy: false,
+            });
+        };
+        if matches!(*guard, StreamKind::Closed) {
+            // A failed StartTLS left the stream closed.
+            return Err(Ended::Stream);
+        }
+        // Checked under the lock: a swapped stream and its restart flag are seen together.
+        if self.restart.swap(false, Ordering::SeqCst) {
+            self.framer.restart();
+        }
+        self.out.extend(self.send_rx.try_iter());
+        if let Err(e) = self.write_out(&mut guard) {
+            self.write_failed(e);
+            return Err(Ended::Stream);
+        }
+        let timer = match guard.tick() {
+            Ok(timer) => timer.map(|t| Instant::now() + t),
+            Err(e) => {
+                eprintln!("[Whixp] read loop exit: {}", e);
+                return Err(Ended::Stream);
+            }
+        };
+        let mut busy = true;
+        for _ in 0..READS_PER_WAKE {
+            let n = match guard.read_incoming(buf, self.ws_messages) {
+                Ok(Incoming::Bytes(0)) => {
+                    eprintln!("[Whixp] read loop exit: EOF");
+                    return Err(Ended::Stream);
+                }
+                Ok(Incoming::Bytes(n)) => n,
+                Ok(Incoming::Message(msg)) => {
+                    let frame = websocket::classify_message(msg);
+                    let _ = self.event_tx.send(frame.into());
+                    continue;
+                }
+                Err(e) => match e.kind() {
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {
+                        busy = false;
+                        break;
+                    }
+                    std::io::ErrorKind::Interrupted => continue,
+                    kind => {
+                        eprintln!("[Whixp] read loop exit: error {:?}", kind);
+                        if tls::is_certificate_error(&e) {
+                            let _ = self.event_tx.send(TransportEvent::Error(
+                                HandshakeErrorCode::BadCertificate as i32,
+                                e.to_string(),
+                            ));
+                        }
+                        return Err(Ended::Stream);
+                    }
+                },
+            };
+            if let Ok(frames) = self.framer.push(&buf[..n]) {
+                for f in frames {
+                    let _ = self.event_tx.send(f.into());
+                }
+            }
+        }
+        let Some(socket) = guard.socket() else {
+            return Err(Ended::Stream);
+        };
+        let writable = !self.out.is_empty() || guard.wants_write();
+        if let Err(e) = self.reg.arm(socket, writable) {
+            eprintln!("[Whixp] poll re-arm failed: {}", e);
+            return Err(Ended::Stream);
+        }
+        Ok(Step::Wait { timer, busy })
+    }
+
+    /// Hand `out` to the stream until it would block, then flush what it buffered.
+    fn write_out(&mut self, stream: &mut StreamKind) -> std::io::Result<()> {
+        while let Some(data) = self.out.front() {
+            match stream.write(&data[self.out_pos..]) {
+                Ok(0) => break,
+                Ok(n) => {
+                    self.out_pos += n;
+                    if self.out_pos == data.len() {
+                        self.out.pop_front();
+                        self.out_pos = 0;
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        match stream.flush() {
+            Err(e) if e.kind() != std::io::ErrorKind::WouldBlock => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn write_failed(&self, e: std::io::Error) {
+        if self.config.auto_reconnect {
+            // The stream is reopened; Dart resends what it needs after the new session
+            // (e.g. XEP-0198).
+            let queued: usize = self.out.iter().map(Vec::len).sum();
+            eprintln!(
+                "[Whixp] write failed, dropped {} bytes: {}",
+                queued - self.out_pos,
+                e
+            );
+            return;
+        }
+        let code = if tls::is_certificate_error(&e) {
+            HandshakeErrorCode::BadCertificate
+        } else {
+            HandshakeErrorCode::Connection
+        };
+        let _ = self
+            .event_tx
+            .send(TransportEvent::Error(code as i32, e.to_string()));
+    }
+
+    /// Take the socket out of the poller and leave the task.
+    fn finish(&mut self) -> Step {
+        if let Some(socket) = self.stream.lock().socket() {
+            self.reg.delete(socket);
+        }
+        let _ = self
+            .event_tx
+            .send(TransportEvent::State(TransportState::Disconnected as i32));
+        Step::Done
+    }
+
+    /// The stream ended: reconnect when `auto_reconnect` is set. The retry delays are task
+    /// timers; each dial blocks, so it runs on a runtime helper thread and wakes the task
+    /// with the result.
+    fn recover(&mut self) -> Step {
+        if !self.config.auto_reconnect || self.shutdown.load(Ordering::SeqCst) {
+            return self.finish();
+        }
+        {
+            let mut guard = self.stream.lock();
+            if let Some(socket) = guard.socket() {
+                self.reg.delete(socket);
+            }
+            // Other callers see NotConnected while the new stream is being opened.
+            *guard = StreamKind::Closed;
+            self.closer.track(None);
+        }
+        let _ = self
+            .event_tx
+            .send(TransportEvent::State(TransportState::Reconnecting as i32));
+        self.retry_after(0)
+    }
+
+    /// Wait out the delay before reconnect attempt `attempt`, or give up when the retry
+    /// policy has no attempts left.
+    fn retry_after(&mut self, attempt: u32) -> Step {
+        if self.shutdown.load(Ordering::SeqCst) {
+            return self.finish();
+        }
+        let Some(delay) = retry::next_retry_delay(&self.retry, attempt) else {
+            let _ = self.event_tx.send(TransportEvent::State(
+                TransportState::ConnectionFailure as i32,
+            ));
+            return self.finish();
+        };
+        let until = Instant::now() + delay;
+        self.phase = Phase::Backoff { attempt, until };
+        Step::Wait {
+            timer: Some(until),
+            busy: false,
+        }
+    }
+
+    /// Open a new stream on a helper thread.
+    fn dial(&mut self, attempt: u32) -> Step {
+        let (tx, opened) = mpsc::channel();
+        let (config, tls_ctx) = (self.config.clone(), self.tls_ctx.clone());
+        let (reg, alive, closer) = (self.reg.clone(), self.alive.clone(), self.closer.clone());
+        runtime::spawn_blocking(move || {
+            let opened = open_stream(&config, &tls_ctx, &closer);
+            let _ = tx.send(opened.map(|(stream, _host)| stream));
+            reg.wake();
+            drop(alive);
+        });
+        self.phase = Phase::Reconnecting { attempt, opened };
+        Step::Wait {
+            timer: None,
+            busy: false,
+        }
+    }
+
+    /// Install a reopened stream; false if it cannot be polled.
+    fn reconnected(&mut self, new_stream: StreamKind) -> bool {
+        self.closer.track(new_stream.socket());
+        *self.stream.lock() = new_stream;
+        self.restart.store(false, Ordering::SeqCst);
+        self.framer.reset();
+        // Anything queued was meant for the old session.
+        self.out.extend(self.send_rx.try_iter());
+        let dropped: usize = self.out.drain(..).map(|d| d.len()).sum();
+        self.out_pos = 0;
+        if dropped > 0 {
+            eprintln!("[Whixp] reconnected, dropped {} queued bytes", dropped);
+        }
+        let _ = self
+            .event_tx
+            .send(TransportEvent::State(TransportState::Connected as i32));
+        self.register()
+    }
+}
 
 /// Internal connection context.
 pub struct Connection {
     config: TransportConfig,
-    #[allow(dead_code)]
+    /// Backoff for the native reconnect loop (used when `config.auto_reconnect`).
     retry: RetryPolicy,
     shutdown: Arc<AtomicBool>,
-    tx: RefCell<Option<mpsc::Sender<Vec<u8>>>>,
+    /// Set on stream restart (StartTLS, SASL success); the read loop restarts its framer.
+    restart: Arc<AtomicBool>,
+    tx: RefCell<Option<Outbox>>,
+    /// Live stream shared with the connection's task (for the in-place StartTLS swap).
+    stream: RefCell<Option<Arc<SharedStream>>>,
+    event_tx: RefCell<Option<EventSender>>,
+    /// Interactive trust decision for handshakes (used when `config.tls.interactive_trust`).
+    trust: Arc<TrustPrompt>,
+    /// TLS configs and sessions reconnects resume from (per `config.tls.session_cache`).
+    tls_configs: Option<Arc<tls::ClientConfigs>>,
+    /// Key log shared by every handshake of this handle (`config.key_log`), or why it
+    /// could not be opened; handshakes fail with that.
+    key_log: std::result::Result<Option<Arc<dyn KeyLog>>, String>,
+    closer: Closer,
+    /// Disconnects once the task and any reconnect dial are gone.
+    alive: RefCell<Option<mpsc::Receiver<()>>>,
 }
 
 impl Connection {
     pub fn new(config: TransportConfig, retry: RetryPolicy) -> Self {
+        let tls_configs = tls::client_configs(config.tls.session_cache);
+        let key_log = keylog::key_log(&config.key_log).map_err(|e| e.to_string());
+        let closer = Closer {
+            shutdown: Arc::new(AtomicBool::new(false)),
+            trust: Arc::new(TrustPrompt::default()),
+            socket: Arc::default(),
+            task: Arc::default(),
+            cancel: Cancel::default(),
+        };
         Self {
             config,
             retry,
-            shutdown: Arc::new(AtomicBool::new(false)),
+            shutdown: Arc::clone(&closer.shutdown),
+            restart: Arc::new(AtomicBool::new(false)),
             tx: RefCell::new(None),
+            stream: RefCell::new(None),
+            event_tx: RefCell::new(None),
+            trust: Arc::clone(&closer.trust),
+            tls_configs,
+            key_log,
+            closer,
+            alive: RefCell::new(None),
         }
     }
 
+    /// Closes this connection from other threads (shared with the handle).
+    pub fn closer(&self) -> Closer {
+        self.closer.clone()
+    }
+
+    /// After `shutdown`: wait until the connection's task and any reconnect dial are gone.
+    /// False if `timeout` passed first.
+    pub fn join(&self, timeout: Duration) -> bool {
+        match self.alive.borrow_mut().take() {
+            Some(alive) => matches!(
+                alive.recv_timeout(timeout),
+                Err(mpsc::RecvTimeoutError::Disconnected)
+            ),
+            None => true,
+        }
+    }
+
+    fn check_open(&self) -> Result<()> {
+        if self.shutdown.load(Ordering::SeqCst) {
+            return Err(HandshakeError::Connection("disconnected".into()));
+        }
+        Ok(())
+    }
+
+    /// Prompt answered by whixp_transport_trust_decision (shared with the handle).
+    pub fn trust_prompt(&self) -> Arc<TrustPrompt> {
+        Arc::clone(&self.trust)
+    }
+
     /// Resolve (SRV + A/AAAA) then connect. Returns resolved host on success for TLS SNI / SASL.
+    /// A session still running from an earlier connect is shut down and joined first, and a
+    /// disconnect before this call no longer applies.
     pub fn connect_sync(&mut self, event_tx: EventSender) -> Result<String> {
-        let (host, port) = dns::resolve_xmpp(
-            &self.config.host,
-            self.config.port,
-            self.config.service.as_deref(),
-            self.config.use_ipv6,
-        )?;
-        let timeout = self.config.connect_timeout();
-        let kind = self.config.kind;
-
-        let stream: StreamKind = match kind {
-            TransportKind::DirectTls => {
-                let s = tls::connect_direct(&host, port, false)?;
-                StreamKind::Tls(s)
-            }
-            TransportKind::Tcp | TransportKind::TcpStartTls => {
-                let addr = format!("{}:{}", host, port);
-                let mut addrs = addr
-                    .to_socket_addrs()
-                    .map_err(|e: std::io::Error| HandshakeError::Connection(e.to_string()))?;
-                let first = addrs
-                    .next()
-                    .ok_or_else(|| HandshakeError::Connection("no address".into()))?;
-                let tcp = TcpStream::connect_timeout(&first, timeout)
-                    .map_err(|e| HandshakeError::Connection(e.to_string()))?;
-                // Non-blocking read: we only hold the lock for one quick read() syscall, so the write
-                // thread can send (e.g. bind IQ) immediately instead of waiting for read timeout.
-                let _ = tcp.set_nonblocking(true);
-                let _ = tcp.set_write_timeout(Some(Duration::from_secs(10)));
-                StreamKind::Tcp(tcp)
-            }
-            TransportKind::WebSocket => {
-                let addr = format!("{}:{}", host, port);
-                let mut addrs = addr
-                    .to_socket_addrs()
-                    .map_err(|e: std::io::Error| HandshakeError::Connection(e.to_string()))?;
-                let first = addrs
-                    .next()
-                    .ok_or_else(|| HandshakeError::Connection("no address".into()))?;
-                let tcp = TcpStream::connect_timeout(&first, timeout)
-                    .map_err(|e| HandshakeError::Connection(e.to_string()))?;
-                // Keep stream blocking for WebSocket handshake (tungstenite does blocking read of 101 response).
-                let _ = tcp.set_write_timeout(Some(Duration::from_secs(10)));
-                let path = self.config.ws_path.as_deref().unwrap_or("/ws");
-                let mut ws = websocket::connect_websocket(&host, port, path, tcp)?;
-                // Non-blocking so read thread releases lock when no data; write thread can send stream header.
-                let _ = ws.set_tcp_nonblocking(true);
-                StreamKind::Ws(ws)
-            }
-            TransportKind::WebSocketTls => {
-                let tls_stream = tls::connect_direct(&host, port, false)?;
-                let path = self.config.ws_path.as_deref().unwrap_or("/ws");
-                let ws = websocket::connect_websocket_tls(&host, port, path, tls_stream)?;
-                StreamKind::WsTls(ws)
+        if self.alive.borrow().is_some() {
+            self.shutdown();
+            if !self.join(PREVIOUS_JOIN_TIMEOUT) {
+                return Err(HandshakeError::Connection(
+                    "previous connection did not stop in time".into(),
+                ));
             }
-        };
+        }
+        self.shutdown.store(false, Ordering::SeqCst);
+        self.closer.cancel.reset();
+        self.restart.store(false, Ordering::SeqCst);
+        self.stream.borrow_mut().take();
+        let tls_ctx = self.tls_context()?;
+        let prompt_tx = event_tx.clone();
+        self.trust.set_notifier(move |details| {
+            let _ = prompt_tx.send(TransportEvent::BadCertificate(details));
+        });
+        let (stream, host) = open_stream(&self.config, &tls_ctx, &self.closer)?;
+        // Disconnected while connecting: the stream is dropped here.
+        self.check_open()?;
+        self.closer.track(stream.socket());
 
         let _ = event_tx.send(TransportEvent::State(TransportState::Connected as i32));
 
         let (send_tx, send_rx) = mpsc::channel::<Vec<u8>>();
-        let shutdown = Arc::clone(&self.shutdown);
-        let stream = Arc::new(std::sync::Mutex::new(stream));
-
-        let stream_read = Arc::clone(&stream);
-        let shutdown_read = Arc::clone(&shutdown);
-        let event_tx_read = event_tx.clone();
-        thread::spawn(move || {
-            let mut framer = StreamFramer::new();
-            let mut buf = [0u8; 8192];
-            loop {
-                if shutdown_read.load(Ordering::SeqCst) {
-                    break;
-                }
-                let n = match stream_read.lock().unwrap().read(&mut buf) {
-                    Ok(0) => {
-                        eprintln!("[Whixp] read loop exit: EOF");
-                        break;
-                    }
-                    Ok(n) => n,
-                    Err(e) => {
-                        let kind = e.kind();
-                        if kind == std::io::ErrorKind::TimedOut
-                            || kind == std::io::ErrorKind::WouldBlock
-                            || kind == std::io::ErrorKind::Interrupted
-                        {
-                            std::thread::yield_now();
-                            continue;
-                        }
-                        eprintln!("[Whixp] read loop exit: error {:?}", kind);
-                        break;
-                    }
-                };
-                if let Ok(stanzas) = framer.push(&buf[..n]) {
-                    for s in stanzas {
-                        let _ = event_tx_read.send(TransportEvent::Stanza(s));
-                    }
-                }
-            }
-            let _ = event_tx_read.send(TransportEvent::State(TransportState::Disconnected as i32));
-        });
+        let (alive_tx, alive_rx) = mpsc::channel();
+        let stream = Arc::new(SharedStream::new(stream));
+        let session = |reg| Session {
+            stream: Arc::clone(&stream),
+            reg,
+            phase: Phase::Start,
+            send_rx,
+            out: VecDeque::new(),
+            out_pos: 0,
+            framer: StreamFramer::new(),
+            shutdown: Arc::clone(&self.shutdown),
+            restart: Arc::clone(&self.restart),
+            event_tx: event_tx.clone(),
+            ws_messages: self.config.ws_framing == WsFraming::Rfc7395,
+            config: self.config.clone(),
+            retry: self.retry.clone(),
+            tls_ctx,
+            closer: self.closer.clone(),
+            alive: alive_tx,
+        };
+        let reg = runtime::spawn(session);
+        let _ = stream.task.set(reg.clone());
+        self.closer.attach(reg.clone());
+        *self.alive.borrow_mut() = Some(alive_rx);
 
-        let stream_write = Arc::clone(&stream);
-        let shutdown_write = Arc::clone(&shutdown);
-        let event_tx_write = event_tx.clone();
-        thread::spawn(move || {
-            while !shutdown_write.load(Ordering::SeqCst) {
-                match send_rx.recv() {
-                    Ok(data) => {
-                        if let Err(e) = stream_write.lock().unwrap().write_all(&data) {
-                            let _ = event_tx_write.send(TransportEvent::Error(1, e.to_string()));
-                            break;
-                        }
-                        let _ = stream_write.lock().unwrap().flush();
-                    }
-                    Err(_) => break,
-                }
+        *self.tx.borrow_mut() = Some(Outbox { tx: send_tx, reg });
+        *self.stream.borrow_mut() = Some(stream);
+        *self.event_tx.borrow_mut() = Some(event_tx);
+        Ok(host)
+    }
+
+    /// Upgrade the live TCP stream to TLS in place. Call after the server sent `<proceed/>`.
+    /// Holds the stream lock for the whole handshake so the connection's task pauses,
+    /// then emits `TlsSuccess`. The caller sends a new stream header afterwards.
+    pub fn starttls(&self) -> Result<()> {
+        // Not checked against TcpStartTls only: a DirectTls config can land on an XEP-0368
+        // StartTLS target, and the stream variant below rejects anything that is not plain TCP.
+        if self.config.kind == TransportKind::Tcp {
+            return Err(HandshakeError::Tls(
+                "StartTLS is not enabled for this transport".into(),
+            ));
+        }
+        self.check_open()?;
+        let tls_ctx = self.tls_context()?;
+        let stream = self
+            .stream
+            .borrow()
+            .clone()
+            .ok_or_else(|| HandshakeError::Connection("not connected".into()))?;
+        let mut guard = stream.lock();
+        let tcp = match std::mem::replace(&mut *guard, StreamKind::Closed) {
+            StreamKind::Tcp(tcp) => tcp,
+            other => {
+                *guard = other;
+                return Err(HandshakeError::Tls("stream is not plain TCP".into()));
             }
-        });
+        };
+        // The upgrade drops the socket on failure, so it leaves the poller first.
+        if let Some(ref outbox) = *self.tx.borrow() {
+            outbox.reg.delete(&tcp);
+        }
+        // On failure the stream stays Closed: the task sees it and reports Disconnected.
+        let upgraded = tls::upgrade_tcp(
+            tcp,
+            &ServerNames::from(&self.config),
+            &self.config.tls,
+            &tls_ctx,
+            self.config.handshake_timeout(),
+        );
+        let tls_stream = match upgraded {
+            Ok(tls_stream) => tls_stream,
+            Err(e) => {
+                self.closer.track(None);
+                drop(guard);
+                self.wake();
+                return Err(e);
+            }
+        };
+        let _ = tls_stream.set_nonblocking(true);
+        if let Some(ref outbox) = *self.tx.borrow() {
+            // Safety: the task deletes the socket before the stream is replaced or dropped.
+            if let Err(e) = unsafe { outbox.reg.add(tls_stream.tcp()) } {
+                eprintln!("[Whixp] poll register failed: {}", e);
+            }
+        }
+        *guard = StreamKind::Tls(Box::new(tls_stream));
+        self.restart.store(true, Ordering::SeqCst);
+        drop(guard);
+        self.wake();
 
-        *self.tx.borrow_mut() = Some(send_tx);
-        Ok(host)
+        if let Some(ref tx) = *self.event_tx.borrow() {
+            let _ = tx.send(TransportEvent::State(TransportState::TlsSuccess as i32));
+        }
+        Ok(())
+    }
+
+    /// Stream restart (RFC 6120 4.3.3) after SASL success: the framer expects a new stream
+    /// header. Call before sending the new client header; buffered bytes are kept.
+    pub fn restart_stream(&self) -> Result<()> {
+        self.check_open()?;
+        let stream = self
+            .stream
+            .borrow()
+            .clone()
+            .ok_or_else(|| HandshakeError::Connection("not connected".into()))?;
+        // Taking the lock orders the flag after any read already in progress.
+        let _guard = stream.lock();
+        self.restart.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Whether the live stream is TLS (direct TLS, upgraded StartTLS, or wss).
+    pub fn is_secure(&self) -> bool {
+        match *self.stream.borrow() {
+            Some(ref s) => matches!(*s.lock(), StreamKind::Tls(_) | StreamKind::WsTls(_)),
+            None => false,
+        }
+    }
+
+    /// Negotiated TLS parameters of the live stream (None for plain TCP or ws).
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        self.with_tls(|tls| tls.info()).flatten()
+    }
+
+    /// Channel binding types available on the live TLS stream (empty for plain TCP or ws).
+    pub fn channel_bindings(&self) -> Vec<ChannelBinding> {
+        self.with_tls(|tls| tls.channel_bindings())
+            .unwrap_or_default()
+    }
+
+    /// Channel binding data of the live TLS stream (direct TLS, upgraded StartTLS, or wss).
+    pub fn channel_binding(&self, kind: ChannelBinding) -> Result<Vec<u8>> {
+        self.with_tls(|tls| tls.channel_binding(kind))
+            .unwrap_or_else(|| Err(HandshakeError::Tls("stream is not TLS".into())))
+    }
+
+    /// Upgrade response headers of the live WebSocket (None for TCP and TLS streams).
+    pub fn ws_response_headers(&self) -> Option<Vec<(String, String)>> {
+        let stream = self.stream.borrow().clone()?;
+        let guard = stream.lock();
+        match &*guard {
+            StreamKind::Ws(s) => Some(s.response_headers().to_vec()),
+            StreamKind::WsTls(s) => Some(s.response_headers().to_vec()),
+            _ => None,
+        }
+    }
+
+    fn with_tls<T>(&self, f: impl FnOnce(&tls::TlsStreamWrapper) -> T) -> Option<T> {
+        let stream = self.stream.borrow().clone()?;
+        let guard = stream.lock();
+        match &*guard {
+            StreamKind::Tls(s) => Some(f(s)),
+            StreamKind::WsTls(s) => Some(f(s.get_ref())),
+            _ => None,
+        }
     }
 
+    /// Queue `data` for the connection's task. Never waits for the stream lock.
     pub fn send(&self, data: &[u8]) -> Result<()> {
-        if let Some(ref tx) = *self.tx.borrow() {
-            tx.send(data.to_vec())
+        self.check_open()?;
+        if let Some(ref outbox) = *self.tx.borrow() {
+            outbox
+                .tx
+                .send(data.to_vec())
                 .map_err(|_| HandshakeError::Connection("send channel closed".into()))?;
+            outbox.reg.wake();
             Ok(())
         } else {
             Err(HandshakeError::Connection("not connected".into()))
@@ -235,7 +1015,251 @@ impl Connection {
     }
 
     pub fn shutdown(&self) {
-        self.shutdown.store(true, Ordering::SeqCst);
-        drop(self.tx.borrow_mut().take());
+        self.closer.close();
+        if let Some(outbox) = self.tx.borrow_mut().take() {
+            outbox.reg.wake();
+        }
+    }
+
+    /// Make the task look at the stream again (after a StartTLS swap).
+    fn wake(&self) {
+        if let Some(ref outbox) = *self.tx.borrow() {
+            outbox.reg.wake();
+        }
+    }
+
+    fn tls_context(&self) -> Result<TlsContext> {
+        let key_log = self.key_log.clone().map_err(HandshakeError::Tls)?;
+        let on_bad_cert = if self.config.tls.interactive_trust {
+            OnBadCert::Ask(
+                Arc::clone(&self.trust),
+                self.config.tls.trust_prompt_timeout(),
+            )
+        } else {
+            OnBadCert::Reject
+        };
+        Ok(TlsContext {
+            on_bad_cert,
+            configs: self.tls_configs.clone(),
+            key_log,
+        })
+    }
+
+    /// Resolve this config's targets and their addresses into the DNS cache without
+    /// connecting, so a later connect (or reconnect) skips the lookups. Returns the number
+    /// of targets.
+    pub fn warm_dns(&self) -> Result<usize> {
+        let cancel = &self.closer.cancel;
+        let targets = resolve_targets(&self.config, per_target_tls(self.config.kind), cancel)?;
+        let opts = resolve_options(&self.config, cancel);
+        for target in targets.iter().filter(|t| t.addrs.is_empty()) {
+            dns::resolve_host(&target.host, target.port, &opts)?;
+        }
+        Ok(targets.len())
+    }
+}
+
+/// XEP-0368: TLS transports look up _xmpps-* and _xmpp-* and use each target's own mode.
+fn per_target_tls(kind: TransportKind) -> bool {
+    matches!(kind, TransportKind::DirectTls | TransportKind::TcpStartTls)
+}
+
+/// Resolver options for `config`; `cancel` ends its lookups.
+fn resolve_options(config: &TransportConfig, cancel: &Cancel) -> ResolveOptions {
+    ResolveOptions {
+        cancel: cancel.clone(),
+        ..config.into()
+    }
+}
+
+/// Connection targets for `config`; `per_target_tls` as returned by [`per_target_tls`].
+fn resolve_targets(
+    config: &TransportConfig,
+    per_target_tls: bool,
+    cancel: &Cancel,
+) -> Result<Vec<Target>> {
+    dns::resolve_xmpp(
+        &config.host,
+        config.port,
+        config.service.as_deref(),
+        per_target_tls,
+        config.kind == TransportKind::DirectTls,
+        &resolve_options(config, cancel),
+    )
+}
+
+/// Resolve and open a stream for `config.kind`, handshakes included. Returns the stream and
+/// the resolved host. The socket goes to the SRV target; TLS uses `ServerNames` (SNI and the
+/// source domain for the certificate). `closer` ends it early.
+fn open_stream(
+    config: &TransportConfig,
+    tls_ctx: &TlsContext,
+    closer: &Closer,
+) -> Result<(StreamKind, String)> {
+    let per_target = per_target_tls(config.kind);
+    let targets = resolve_targets(config, per_target, &closer.cancel)?;
+    let (stream, target) = connect_candidates(&targets, config, closer, |tcp, target| {
+        let kind = match (per_target, target.direct_tls) {
+            (true, true) => TransportKind::DirectTls,
+            (true, false) => TransportKind::TcpStartTls,
+            (false, _) => config.kind,
+        };
+        handshake(tcp, kind, target, config, tls_ctx)
+    })?;
+    Ok((stream, target.host))
+}
+
+/// The TLS and WebSocket handshakes `kind` needs on a socket connected to `target`.
+fn handshake(
+    tcp: TcpStream,
+    kind: TransportKind,
+    target: &Target,
+    config: &TransportConfig,
+    tls_ctx: &TlsContext,
+) -> Result<StreamKind> {
+    let (host, port) = (target.host.as_str(), target.port);
+
+    let names = ServerNames::from(config);
+    let stream = match kind {
+        TransportKind::DirectTls => {
+            let s = tls::connect_direct(
+                tcp,
+                &names,
+                &config.tls,
+                tls_ctx,
+                &[tls::ALPN_XMPP_CLIENT],
+                config.handshake_timeout(),
+            )?;
+            // Non-blocking: the I/O worker waits on the poller, never inside read() or write().
+            let _ = s.set_nonblocking(true);
+            StreamKind::Tls(Box::new(s))
+        }
+        TransportKind::Tcp | TransportKind::TcpStartTls => {
+            // Non-blocking: the I/O worker waits on the poller, never inside read() or write().
+            let _ = tcp.set_nonblocking(true);
+            StreamKind::Tcp(tcp)
+        }
+        TransportKind::WebSocket => {
+            // Keep stream blocking for WebSocket handshake (tungstenite does blocking read of 101 response).
+            let mut ws = websocket::connect_websocket(host, port, tcp, config)?;
+            // Non-blocking after the upgrade, as for TCP.
+            let _ = ws.set_tcp_nonblocking(true);
+            StreamKind::Ws(Box::new(ws))
+        }
+        TransportKind::WebSocketTls => {
+            let tls_stream = tls::connect_direct(
+                tcp,
+                &names,
+                &config.tls,
+                tls_ctx,
+                &[],
+                config.handshake_timeout(),
+            )?;
+            let mut ws = websocket::connect_websocket_tls(host, port, tls_stream, config)?;
+            // Non-blocking after the upgrade, as for TCP.
+            let _ = ws.set_nonblocking(true);
+            StreamKind::WsTls(Box::new(ws))
+        }
+    };
+    Ok(stream)
+}
+
+/// Try each target in order until one opens: a TCP connect, then `open` for the handshakes.
+/// A target's addresses are raced with Happy Eyeballs; each target gets an equal share of
+/// what is left of the connect timeout, so one dead target cannot use it all. A failed
+/// handshake moves on to the next target too, except a certificate failure, which ends the
+/// attempt: a server for the domain was reached and could not prove who it is. Returns the
+/// stream with its target. When every target failed, the error lists why each one did, with
+/// the code of the last handshake error if any target got that far. Closing `closer` ends
+/// the lookups and connects in progress, and a handshake through the socket it tracks.
+fn connect_candidates<S>(
+    targets: &[Target],
+    config: &TransportConfig,
+    closer: &Closer,
+    mut open: impl FnMut(TcpStream, &Target) -> Result<S>,
+) -> Result<(S, Target)> {
+    const MIN_TARGET: Duration = Duration::from_millis(500);
+    let deadline = Instant::now() + config.connect_timeout();
+    let opts = resolve_options(config, &closer.cancel);
+    let mut failures: Vec<String> = Vec::new();
+    let mut handshake_err = None;
+    for (t, target) in targets.iter().enumerate() {
+        closer.cancel.check()?;
+        let addrs: Vec<SocketAddr> = if target.addrs.is_empty() {
+            match dns::resolve_host(&target.host, target.port, &opts) {
+                Ok(a) => a,
+                Err(e) => {
+                    failures.push(format!("{}:{}: {}", target.host, target.port, e));
+                    continue;
+                }
+            }
+        } else {
+            target
+                .addrs
+                .iter()
+                .map(|ip| SocketAddr::new(*ip, target.port))
+                .collect()
+        };
+        let addrs = happy_eyeballs::sort_addrs(&addrs, config.ip_preference);
+        if addrs.is_empty() {
+            failures.push(format!(
+                "{}:{}: no usable address",
+                target.host, target.port
+            ));
+            continue;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            failures.push(format!("{}:{}: overall timeout", target.host, target.port));
+            break;
+        }
+        let share = ((deadline - now) / (targets.len() - t) as u32).max(MIN_TARGET);
+        let target_deadline = deadline.min(now + share);
+        let connected = happy_eyeballs::connect(
+            &addrs,
+            config.attempt_delay(),
+            target_deadline,
+            &closer.cancel,
+        );
+        let tcp = match connected {
+            Ok((tcp, _addr)) => tcp,
+            Err(errs) => {
+                failures.extend(errs.into_iter().map(|e| format!("{} ({})", target.host, e)));
+                continue;
+            }
+        };
+        closer.track(Some(&tcp));
+        match open(tcp, target) {
+            Ok(stream) => return Ok((stream, target.clone())),
+            Err(e) => {
+                failures.push(format!("{}:{}: {}", target.host, target.port, e));
+                let certificate = matches!(e, HandshakeError::BadCertificate(_));
+                handshake_err = Some(e);
+                if certificate {
+                    break;
+                }
+            }
+        }
+    }
+    closer.track(None);
+    closer.cancel.check()?;
+    Err(candidates_failed(&failures, handshake_err))
+}
+
+/// One error listing every failed candidate. With `last` (the last handshake error) it keeps
+/// that error's code.
+fn candidates_failed(failures: &[String], last: Option<HandshakeError>) -> HandshakeError {
+    if failures.is_empty() {
+        return HandshakeError::Connection("no address".into());
+    }
+    let failures = failures.join("; ");
+    match last {
+        Some(last) => HandshakeError::Candidates {
+            failures,
+            last: Box::new(last),
+        },
+        None => {
+            HandshakeError::Connection(format!("all connection candidates failed: {}", failures))
+        }
     }
 }
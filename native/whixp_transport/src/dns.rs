@@ -1,177 +1,370 @@
 //! DNS resolution: local (system) resolver first, DoH fallback.
 //! Used for XMPP SRV (_xmpp-client._tcp.domain) and A/AAAA lookups.
+//! Answers go through the process-wide cache in `dns_cache`.
 
-use std::net::IpAddr;
-use std::sync::OnceLock;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
+use crate::cancel::Cancel;
+use crate::config::{DohConfig, IpPreference, TransportConfig};
+use crate::dns_cache::{self, CacheKey, CachedAnswer};
 use crate::handshake::HandshakeError;
+use trust_dns_resolver::config::LookupIpStrategy;
+use trust_dns_resolver::error::ResolveErrorKind;
 use trust_dns_resolver::TokioAsyncResolver;
 
-/// One-off runtime for blocking on async DNS. Shared to avoid spawning many runtimes.
-static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+/// How long to cache a "no such record" answer that carries no SOA negative TTL.
+const NEGATIVE_TTL: Duration = Duration::from_secs(60);
+/// How long to cache getaddrinfo results (they carry no TTL).
+const GETADDRINFO_TTL: Duration = Duration::from_secs(60);
 
-fn runtime() -> &'static tokio::runtime::Runtime {
-    RUNTIME.get_or_init(|| tokio::runtime::Runtime::new().expect("tokio runtime for DNS"))
+/// System resolvers, one per A/AAAA strategy, reused across lookups.
+static RESOLVERS: Mutex<Vec<(LookupIpStrategy, TokioAsyncResolver)>> = Mutex::new(Vec::new());
+
+/// Resolver settings for one connection's lookups.
+#[derive(Clone, Debug)]
+pub struct ResolveOptions {
+    pub ip: IpPreference,
+    pub doh: DohConfig,
+    /// How long past its TTL a cached answer may still be served while it is refreshed.
+    pub stale_grace: Duration,
+    /// Ends lookups in progress (disconnect during connect).
+    pub cancel: Cancel,
 }
 
-/// Resolve XMPP connection target: try SRV (if service given) then A/AAAA.
-/// Uses system resolver first, then DoH (Cloudflare) on failure.
-/// Returns (host, port) for use with TCP/TLS connect.
-/// If `service` is None, returns (domain, port) without SRV lookup.
-pub fn resolve_xmpp(
-    domain: &str,
-    port: u16,
-    service: Option<&str>,
-    use_ipv6: bool,
-) -> Result<(String, u16), HandshakeError> {
-    let domain = domain.trim_end_matches('.');
-    if domain.is_empty() {
-        return Err(HandshakeError::Connection("empty domain".into()));
+impl From<&TransportConfig> for ResolveOptions {
+    fn from(config: &TransportConfig) -> Self {
+        Self {
+            ip: config.ip_preference,
+            doh: DohConfig {
+                timeout: config.connect_timeout(),
+                ..config.doh.clone()
+            },
+            stale_grace: config.dns_stale_grace(),
+            cancel: Cancel::default(),
+        }
     }
+}
 
-    let service = service
-        .map(|s| s.trim_end_matches('.'))
-        .filter(|s| !s.is_empty());
-
-    // No SRV: use domain and port as-is (connect will do getaddrinfo).
-    let Some(srv_service) = service else {
-        return Ok((domain.to_string(), port));
+/// System resolver config with the A/AAAA strategy for `ip`: both families are queried in
+/// parallel unless one is excluded. Created once per strategy; must be called on the runtime.
+fn system_resolver(ip: IpPreference) -> Result<TokioAsyncResolver, HandshakeError> {
+    let strategy = match ip {
+        IpPreference::Ipv4Only => LookupIpStrategy::Ipv4Only,
+        IpPreference::Ipv6Only => LookupIpStrategy::Ipv6Only,
+        IpPreference::PreferIpv4 | IpPreference::PreferIpv6 => LookupIpStrategy::Ipv4AndIpv6,
     };
+    let mut resolvers = RESOLVERS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((_, r)) = resolvers.iter().find(|(s, _)| *s == strategy) {
+        return Ok(r.clone());
+    }
+    let (config, mut opts) = trust_dns_resolver::system_conf::read_system_conf()
+        .map_err(|e| HandshakeError::Connection(format!("system DNS config: {}", e)))?;
+    opts.ip_strategy = strategy;
+    let resolver = TokioAsyncResolver::tokio(config, opts);
+    resolvers.push((strategy, resolver.clone()));
+    Ok(resolver)
+}
+
+/// Drop cached answers and system resolvers (re-reading the system DNS config next time).
+pub fn flush() {
+    dns_cache::flush();
+    RESOLVERS.lock().unwrap_or_else(|e| e.into_inner()).clear();
+}
 
-    // Try system resolver first.
-    if let Ok((host, p)) = try_system_resolver(domain, port, srv_service, use_ipv6) {
-        return Ok((host, p));
+fn ttl_until(valid_until: Instant) -> Duration {
+    valid_until.saturating_duration_since(Instant::now())
+}
+
+/// Resolve `host` to socket addresses (A and AAAA in parallel). IP literals are returned as-is.
+pub fn resolve_host(
+    host: &str,
+    port: u16,
+    opts: &ResolveOptions,
+) -> Result<Vec<SocketAddr>, HandshakeError> {
+    if let Ok(addr) = host
+        .trim_matches(|c| c == '[' || c == ']')
+        .parse::<IpAddr>()
+    {
+        return Ok(vec![SocketAddr::new(addr, port)]);
     }
+    let ips = cached_ips(host, opts)?;
+    Ok(ips.into_iter().map(|a| SocketAddr::new(a, port)).collect())
+}
 
-    // Fallback: DoH (optional; enable with default features for smaller binary use --no-default-features).
-    #[cfg(feature = "doh")]
-    if let Ok((host, p)) = try_doh(domain, port, srv_service, use_ipv6) {
-        return Ok((host, p));
+/// A/AAAA for `host` through the cache.
+fn cached_ips(host: &str, opts: &ResolveOptions) -> Result<Vec<IpAddr>, HandshakeError> {
+    let key = CacheKey::Ips {
+        host: host.to_ascii_lowercase(),
+        v4: opts.ip.allows_v4(),
+        v6: opts.ip.allows_v6(),
+        doh: opts.doh.endpoints.clone(),
+    };
+    let (host, resolve_opts) = (host.to_string(), opts.clone());
+    let answer = dns_cache::get_or_resolve(key, opts.stale_grace, move || {
+        lookup_ips(&host, &resolve_opts).map(|(ips, ttl)| (CachedAnswer::Ips(ips), ttl))
+    })?;
+    match answer {
+        CachedAnswer::Ips(ips) => Ok(ips),
+        CachedAnswer::Srv(_) => Ok(Vec::new()),
     }
+}
 
-    // Last resort: no SRV, use domain:port.
-    Ok((domain.to_string(), port))
+/// SRV records for `name` through the cache. Empty means the name has no SRV records.
+fn cached_srv(name: &str, opts: &ResolveOptions) -> Result<Vec<SrvRecord>, HandshakeError> {
+    let key = CacheKey::Srv {
+        name: name.to_ascii_lowercase(),
+        doh: opts.doh.endpoints.clone(),
+    };
+    let (name, resolve_opts) = (name.to_string(), opts.clone());
+    let answer = dns_cache::get_or_resolve(key, opts.stale_grace, move || {
+        lookup_srv(&name, &resolve_opts).map(|(records, ttl)| (CachedAnswer::Srv(records), ttl))
+    })?;
+    match answer {
+        CachedAnswer::Srv(records) => Ok(records),
+        CachedAnswer::Ips(_) => Ok(Vec::new()),
+    }
 }
 
-/// System resolver (e.g. /etc/resolv.conf). SRV + A/AAAA.
-fn try_system_resolver(
-    domain: &str,
-    _default_port: u16,
-    service: &str,
-    _use_ipv6: bool,
-) -> Result<(String, u16), HandshakeError> {
-    let srv_name = format!("_{}._{}.{}", service, "tcp", domain);
-    let rt = runtime();
-    rt.block_on(async {
-        let resolver = TokioAsyncResolver::tokio_from_system_conf()
-            .map_err(|e| HandshakeError::Connection(format!("system DNS config: {}", e)))?;
-        let srv_lookup = resolver
-            .srv_lookup(srv_name.clone())
+/// Uncached A/AAAA: system resolver, then getaddrinfo when the system resolver config is
+/// unavailable (e.g. Android), then DoH. Stops at the next step once `opts.cancel` is set.
+fn lookup_ips(
+    host: &str,
+    opts: &ResolveOptions,
+) -> Result<(Vec<IpAddr>, Duration), HandshakeError> {
+    let looked_up = opts.cancel.block_on(async {
+        let resolver = system_resolver(opts.ip)?;
+        resolver
+            .lookup_ip(host)
             .await
-            .map_err(|e| HandshakeError::Connection(format!("SRV lookup {}: {}", srv_name, e)))?;
-        let mut records: Vec<_> = srv_lookup.iter().collect();
-        if records.is_empty() {
-            return Err(HandshakeError::Connection(format!(
-                "no SRV records for {}",
-                srv_name
-            )));
+            .map_err(|e| HandshakeError::Connection(format!("lookup {}: {}", host, e)))
+    })?;
+    if let Ok(lookup) = looked_up {
+        let addrs: Vec<IpAddr> = lookup.iter().collect();
+        if !addrs.is_empty() {
+            return Ok((addrs, ttl_until(lookup.valid_until())));
         }
-        records.sort_by(|a, b| {
-            a.priority()
-                .cmp(&b.priority())
-                .then_with(|| a.weight().cmp(&b.weight()))
-        });
-        for srv in &records {
-            let target = srv.target().to_utf8().trim_end_matches('.').to_string();
-            let port = srv.port();
-            let lookup = resolver.lookup_ip(target.clone()).await;
-            if let Ok(l) = lookup {
-                let ips: Vec<IpAddr> = l.iter().collect();
-                if !ips.is_empty() {
-                    return Ok((target, port));
-                }
+    }
+    let system_err = match (host, 0).to_socket_addrs() {
+        Ok(addrs) => {
+            let addrs: Vec<IpAddr> = addrs.map(|a| a.ip()).collect();
+            if !addrs.is_empty() {
+                return Ok((addrs, GETADDRINFO_TTL));
+            }
+            format!("{}: no addresses", host)
+        }
+        Err(e) => format!("{}: {}", host, e),
+    };
+    opts.cancel.check()?;
+    #[cfg(feature = "doh")]
+    let doh_result = crate::doh::query_ips(&opts.doh, host, opts.ip)
+        .map_err(|e| HandshakeError::Connection(format!("{}; {}", system_err, e)));
+    #[cfg(not(feature = "doh"))]
+    let doh_result = Err(HandshakeError::Connection(system_err));
+    doh_result
+}
+
+/// Uncached SRV: system resolver, then DoH if it fails. "No such record" from the system
+/// resolver is an answer (cached for its negative TTL), not a reason to ask DoH.
+fn lookup_srv(
+    name: &str,
+    opts: &ResolveOptions,
+) -> Result<(Vec<SrvRecord>, Duration), HandshakeError> {
+    let system = opts.cancel.block_on(async {
+        let resolver = system_resolver(opts.ip)?;
+        match resolver.srv_lookup(name).await {
+            Ok(lookup) => {
+                let records = lookup
+                    .iter()
+                    .map(|srv| SrvRecord {
+                        priority: srv.priority(),
+                        weight: srv.weight(),
+                        port: srv.port(),
+                        target: srv.target().to_utf8(),
+                        direct_tls: false,
+                    })
+                    .collect();
+                Ok((records, ttl_until(lookup.as_lookup().valid_until())))
             }
+            Err(e) => match e.kind() {
+                ResolveErrorKind::NoRecordsFound { negative_ttl, .. } => Ok((
+                    Vec::new(),
+                    negative_ttl.map_or(NEGATIVE_TTL, |t| Duration::from_secs(t as u64)),
+                )),
+                _ => Err(HandshakeError::Connection(format!(
+                    "SRV lookup {}: {}",
+                    name, e
+                ))),
+            },
         }
-        let first = records[0];
-        let first_target = first.target().to_utf8().trim_end_matches('.').to_string();
-        Ok((first_target, first.port()))
-    })
+    })?;
+    #[cfg(feature = "doh")]
+    let system = system.or_else(|e| {
+        opts.cancel.check()?;
+        crate::doh::query_srv(&opts.doh, name)
+            .map_err(|doh_err| HandshakeError::Connection(format!("{}; {}", e, doh_err)))
+    });
+    system
 }
 
-/// DoH (Cloudflare) fallback. SRV type = 33, A = 1, AAAA = 28.
-#[cfg(feature = "doh")]
-const DOH_URL: &str = "https://cloudflare-dns.com/dns-query";
+/// Connection target (an SRV target, or the domain itself), in the order it should be tried.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+    /// Addresses already resolved for `host`. Empty means connect resolves `host` itself.
+    pub addrs: Vec<IpAddr>,
+    /// TLS from the first byte (XEP-0368 `_xmpps-*` record) rather than StartTLS.
+    pub direct_tls: bool,
+}
 
-#[cfg(feature = "doh")]
-#[derive(serde::Deserialize)]
-struct DohResponse {
-    #[serde(default)]
-    status: u32,
-    #[serde(default)]
-    answer: Vec<DohAnswer>,
+impl Target {
+    pub fn unresolved(host: &str, port: u16, direct_tls: bool) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            addrs: Vec::new(),
+            direct_tls,
+        }
+    }
 }
 
-#[cfg(feature = "doh")]
-#[derive(serde::Deserialize)]
-struct DohAnswer {
-    #[serde(default)]
-    #[serde(rename = "type")]
-    typ: u32,
-    #[serde(default)]
-    data: String,
+/// One SRV answer from either resolver, tagged with whether its service is direct TLS.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+    pub direct_tls: bool,
 }
 
-#[cfg(feature = "doh")]
-fn try_doh(
-    domain: &str,
-    _default_port: u16,
-    service: &str,
-    _use_ipv6: bool,
-) -> Result<(String, u16), HandshakeError> {
-    let srv_name = format!("_{}._{}.{}", service, "tcp", domain);
-    let url_srv = format!("{}?name={}&type=SRV", DOH_URL, srv_name);
-    let resp = ureq::get(&url_srv)
-        .set("Accept", "application/dns-json")
-        .call()
-        .map_err(|e| HandshakeError::Connection(format!("DoH SRV request failed: {}", e)))?;
-    let body: DohResponse = resp
-        .into_json()
-        .map_err(|e| HandshakeError::Connection(format!("DoH JSON: {}", e)))?;
-    if body.status != 0 {
-        return Err(HandshakeError::Connection(format!(
-            "DoH SRV status {}",
-            body.status
-        )));
+/// SRV services to query for `service`: (name, direct_tls). With `xep0368`, an `xmpp-*` or
+/// `xmpps-*` service is paired with its counterpart so both record sets are merged.
+fn srv_services(service: &str, xep0368: bool, direct_tls: bool) -> Vec<(String, bool)> {
+    let base = service
+        .strip_prefix("xmpps-")
+        .or_else(|| service.strip_prefix("xmpp-"));
+    match base {
+        Some(base) if xep0368 => vec![
+            (format!("xmpps-{}", base), true),
+            (format!("xmpp-{}", base), false),
+        ],
+        Some(_) => vec![(service.to_string(), service.starts_with("xmpps-"))],
+        None => vec![(service.to_string(), direct_tls)],
     }
-    // Parse SRV: data = "priority weight port target"
-    let mut srv_list: Vec<(u16, u16, u16, String)> = Vec::new();
-    for a in &body.answer {
-        if a.typ != 33 {
-            continue;
+}
+
+/// Order records per RFC 2782 using a process-random source. Used by every resolver.
+fn order_srv(records: &mut Vec<SrvRecord>) {
+    let ordered = order_srv_with(std::mem::take(records), |max| {
+        (crate::retry::random_unit() * (max as f64 + 1.0)) as u32
+    });
+    *records = ordered;
+}
+
+/// RFC 2782 target selection: lowest priority first; within a priority, repeatedly pick a
+/// record with probability proportional to its weight, weight-0 records first in line so
+/// they are picked only rarely when heavier ones exist. `rand(max)` must return a value in
+/// `0..=max`; inject a fixed sequence to get a deterministic order.
+pub fn order_srv_with(
+    mut records: Vec<SrvRecord>,
+    mut rand: impl FnMut(u32) -> u32,
+) -> Vec<SrvRecord> {
+    // Stable sort keeps weight-0 records ahead within their priority group.
+    records.sort_by_key(|r| (r.priority, r.weight != 0));
+    let mut out = Vec::with_capacity(records.len());
+    let mut rest = records.into_iter().peekable();
+    while let Some(first) = rest.next() {
+        let priority = first.priority;
+        let mut group = vec![first];
+        while let Some(r) = rest.next_if(|r| r.priority == priority) {
+            group.push(r);
         }
-        let parts: Vec<&str> = a.data.trim().split_whitespace().collect();
-        if parts.len() >= 4 {
-            if let (Ok(pri), Ok(_w), Ok(port)) = (
-                parts[0].parse::<u16>(),
-                parts[1].parse::<u16>(),
-                parts[2].parse::<u16>(),
-            ) {
-                let target = parts[3].trim_end_matches('.').to_string();
-                srv_list.push((pri, _w, port, target));
-            }
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|r| r.weight as u32).sum();
+            let pick = rand(total).min(total);
+            let mut running = 0u32;
+            let idx = group
+                .iter()
+                .position(|r| {
+                    running += r.weight as u32;
+                    running >= pick
+                })
+                .unwrap_or(0);
+            out.push(group.remove(idx));
         }
     }
-    if srv_list.is_empty() {
-        return Err(HandshakeError::Connection(format!(
-            "DoH: no SRV records for {}",
-            srv_name
-        )));
+    out
+}
+
+/// Resolve XMPP connection targets: try SRV (if service given) then A/AAAA.
+/// Uses system resolver first, then DoH (`opts.doh` endpoints, Cloudflare if none) on failure;
+/// answers are cached per record name (see `dns_cache`).
+/// Returns every SRV target in order, each with its addresses, for use with TCP/TLS connect.
+/// With `xep0368`, `_xmpps-*` (direct TLS) and `_xmpp-*` (StartTLS) records are merged.
+/// If `service` is None (or SRV fails), returns the domain and port without SRV lookup;
+/// `direct_tls` is the mode for that fallback target.
+pub fn resolve_xmpp(
+    domain: &str,
+    port: u16,
+    service: Option<&str>,
+    xep0368: bool,
+    direct_tls: bool,
+    opts: &ResolveOptions,
+) -> Result<Vec<Target>, HandshakeError> {
+    let domain = domain.trim_end_matches('.');
+    if domain.is_empty() {
+        return Err(HandshakeError::Connection("empty domain".into()));
+    }
+
+    let service = service
+        .map(|s| s.trim_end_matches('.'))
+        .filter(|s| !s.is_empty());
+
+    // No SRV: use domain and port as-is (connect resolves it).
+    let fallback = || Ok(vec![Target::unresolved(domain, port, direct_tls)]);
+    let Some(srv_service) = service else {
+        return fallback();
+    };
+
+    let mut records = Vec::new();
+    for (service, direct_tls) in srv_services(srv_service, xep0368, direct_tls) {
+        if let Ok(found) = cached_srv(&srv_name(&service, domain), opts) {
+            records.extend(found.into_iter().map(|r| SrvRecord { direct_tls, ..r }));
+        }
+    }
+    opts.cancel.check()?;
+    order_srv(&mut records);
+    let mut targets = Vec::with_capacity(records.len());
+    for srv in &records {
+        let Some(host) = srv_target_name(&srv.target) else {
+            continue;
+        };
+        opts.cancel.check()?;
+        // A failed lookup keeps the target with no addresses; connect resolves it again.
+        let addrs = cached_ips(&host, opts).unwrap_or_default();
+        targets.push(Target {
+            host,
+            port: srv.port,
+            addrs,
+            direct_tls: srv.direct_tls,
+        });
     }
-    srv_list.sort_by_key(|(p, w, _, _)| (*p, *w));
-    for (_pri, _weight, port, target) in srv_list {
-        // Optional: resolve target via DoH A/AAAA to check reachability. For simplicity
-        // we just return first SRV (host, port); connect will resolve the hostname.
-        return Ok((target, port));
+    if targets.is_empty() {
+        // No SRV records (or only "."): use domain:port.
+        return fallback();
     }
-    Err(HandshakeError::Connection("DoH: no valid SRV data".into()))
+    Ok(targets)
+}
+
+/// SRV target "." means the service is decidedly not available at this domain (RFC 2782).
+fn srv_target_name(target: &str) -> Option<String> {
+    let t = target.trim_end_matches('.');
+    (!t.is_empty()).then(|| t.to_string())
+}
+
+fn srv_name(service: &str, domain: &str) -> String {
+    format!("_{}._{}.{}", service, "tcp", domain)
 }
@@ -0,0 +1,165 @@
+//! Process-wide DNS answer cache shared by all handles, keyed by SRV record name and by
+//! hostname (with the DoH endpoints the lookup used). Entries live for their record TTL; after that they may still be served for a
+//! grace window (stale-while-revalidate) while one background refresh runs.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+use crate::dns::SrvRecord;
+use crate::handshake::HandshakeError;
+use crate::runtime;
+
+/// Upper bound on any TTL so a bogus record cannot pin an answer for days.
+const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What an answer is cached under. `doh` is the DoH endpoint list the lookup falls back to
+/// (empty = the default endpoint): handles configured with different endpoints, such as a
+/// split-horizon resolver, do not share answers.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CacheKey {
+    /// SRV record name, e.g. `_xmpp-client._tcp.example.com`.
+    Srv { name: String, doh: Vec<String> },
+    /// A/AAAA for a host; the families queried are part of the key.
+    Ips {
+        host: String,
+        v4: bool,
+        v6: bool,
+        doh: Vec<String>,
+    },
+}
+
+/// Cached answer. An empty SRV list is a negative answer ("no such record").
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CachedAnswer {
+    Srv(Vec<SrvRecord>),
+    Ips(Vec<IpAddr>),
+}
+
+struct Entry {
+    answer: CachedAnswer,
+    expires: Instant,
+    refreshing: bool,
+}
+
+/// DNS answer cache. Every handle uses `DnsCache::global()`; tests build their own.
+#[derive(Clone, Default)]
+pub struct DnsCache {
+    entries: Arc<Mutex<HashMap<CacheKey, Entry>>>,
+}
+
+static GLOBAL: OnceLock<DnsCache> = OnceLock::new();
+
+impl DnsCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The process-wide cache.
+    pub fn global() -> &'static DnsCache {
+        GLOBAL.get_or_init(DnsCache::new)
+    }
+
+    fn entries(&self) -> MutexGuard<'_, HashMap<CacheKey, Entry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Return the cached answer for `key`, or run `resolve` and cache what it returns for its
+    /// TTL. An expired entry younger than `stale_grace` is returned as-is and `resolve` runs
+    /// on a runtime helper thread instead (at most one refresh per key at a time). A failed
+    /// refresh keeps the stale entry until the grace window ends.
+    pub fn get_or_resolve<F>(
+        &self,
+        key: CacheKey,
+        stale_grace: Duration,
+        resolve: F,
+    ) -> Result<CachedAnswer, HandshakeError>
+    where
+        F: FnOnce() -> Result<(CachedAnswer, Duration), HandshakeError> + Send + 'static,
+    {
+        self.get_or_resolve_at(key, stale_grace, Instant::now(), resolve)
+    }
+
+    /// `get_or_resolve` with `now` as the current time. Answers resolved for this call,
+    /// including a background refresh, expire their TTL after `now`.
+    pub fn get_or_resolve_at<F>(
+        &self,
+        key: CacheKey,
+        stale_grace: Duration,
+        now: Instant,
+        resolve: F,
+    ) -> Result<CachedAnswer, HandshakeError>
+    where
+        F: FnOnce() -> Result<(CachedAnswer, Duration), HandshakeError> + Send + 'static,
+    {
+        {
+            let mut entries = self.entries();
+            if let Some(entry) = entries.get_mut(&key) {
+                if now < entry.expires {
+                    return Ok(entry.answer.clone());
+                }
+                if now < entry.expires + stale_grace {
+                    if !entry.refreshing {
+                        entry.refreshing = true;
+                        let (cache, key) = (self.clone(), key.clone());
+                        runtime::spawn_blocking(move || cache.refresh(key, now, resolve));
+                    }
+                    return Ok(entry.answer.clone());
+                }
+                entries.remove(&key);
+            }
+        }
+        let (answer, ttl) = resolve()?;
+        self.insert(key, answer.clone(), now + ttl.min(MAX_TTL));
+        Ok(answer)
+    }
+
+    fn refresh<F>(&self, key: CacheKey, now: Instant, resolve: F)
+    where
+        F: FnOnce() -> Result<(CachedAnswer, Duration), HandshakeError>,
+    {
+        match resolve() {
+            Ok((answer, ttl)) => self.insert(key, answer, now + ttl.min(MAX_TTL)),
+            Err(e) => {
+                debug!("DNS refresh for {:?} failed: {}", key, e);
+                if let Some(entry) = self.entries().get_mut(&key) {
+                    entry.refreshing = false;
+                }
+            }
+        }
+    }
+
+    fn insert(&self, key: CacheKey, answer: CachedAnswer, expires: Instant) {
+        let entry = Entry {
+            answer,
+            expires,
+            refreshing: false,
+        };
+        self.entries().insert(key, entry);
+    }
+
+    /// Drop every cached answer.
+    pub fn flush(&self) {
+        self.entries().clear();
+    }
+}
+
+/// `get_or_resolve` on the process-wide cache.
+pub fn get_or_resolve<F>(
+    key: CacheKey,
+    stale_grace: Duration,
+    resolve: F,
+) -> Result<CachedAnswer, HandshakeError>
+where
+    F: FnOnce() -> Result<(CachedAnswer, Duration), HandshakeError> + Send + 'static,
+{
+    DnsCache::global().get_or_resolve(key, stale_grace, resolve)
+}
+
+/// Drop every answer in the process-wide cache.
+pub fn flush() {
+    DnsCache::global().flush();
+}
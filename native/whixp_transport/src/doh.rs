@@ -0,0 +1,256 @@
+//! DNS-over-HTTPS fallback: JSON (`application/dns-json`) or RFC 8484 wire format
+//! (`application/dns-message`). Endpoints come from the config and are tried in order.
+//! SRV type = 33, A = 1, AAAA = 28.
+
+use std::io::Read;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use trust_dns_resolver::proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_resolver::proto::rr::{Name, RData, RecordType};
+
+use crate::config::{DohConfig, DohFormat, IpPreference};
+use crate::dns::SrvRecord;
+use crate::handshake::HandshakeError;
+
+/// Used when the config lists no endpoint.
+pub const DEFAULT_DOH_URL: &str = "https://cloudflare-dns.com/dns-query";
+
+/// Largest DNS message we accept (RFC 8484 responses fit in one DNS message).
+const MAX_RESPONSE: u64 = 65_535;
+
+/// Cache lifetime when an answer carries no record to take a TTL from.
+const DEFAULT_TTL: u32 = 60;
+
+/// Answer record we care about, independent of the wire or JSON format.
+enum Answer {
+    Srv(SrvRecord),
+    Ip(IpAddr),
+}
+
+/// Records of one query and the smallest TTL among them.
+struct Answers {
+    records: Vec<Answer>,
+    ttl: u32,
+}
+
+impl Answers {
+    fn new(records: Vec<(Answer, u32)>) -> Self {
+        let ttl = records.iter().map(|(_, t)| *t).min().unwrap_or(DEFAULT_TTL);
+        Self {
+            records: records.into_iter().map(|(a, _)| a).collect(),
+            ttl,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct DohResponse {
+    #[serde(default, rename = "Status")]
+    status: u32,
+    #[serde(default, rename = "Answer")]
+    answer: Vec<DohAnswer>,
+}
+
+#[derive(serde::Deserialize)]
+struct DohAnswer {
+    #[serde(default, rename = "type")]
+    typ: u16,
+    #[serde(default)]
+    data: String,
+    #[serde(default, rename = "TTL")]
+    ttl: u32,
+}
+
+/// SRV records for `name` (e.g. `_xmpp-client._tcp.example.com`) and their TTL.
+/// Records come back with `direct_tls` unset; the caller knows the service.
+pub fn query_srv(
+    doh: &DohConfig,
+    name: &str,
+) -> Result<(Vec<SrvRecord>, Duration), HandshakeError> {
+    let answers = query(doh, name, RecordType::SRV)?;
+    let records = answers
+        .records
+        .into_iter()
+        .filter_map(|a| match a {
+            Answer::Srv(r) => Some(r),
+            Answer::Ip(_) => None,
+        })
+        .collect();
+    Ok((records, Duration::from_secs(answers.ttl as u64)))
+}
+
+/// A and AAAA records for `host`, limited to the families `ip` allows, and their TTL.
+pub fn query_ips(
+    doh: &DohConfig,
+    host: &str,
+    ip: IpPreference,
+) -> Result<(Vec<IpAddr>, Duration), HandshakeError> {
+    let mut types = Vec::with_capacity(2);
+    if ip.allows_v6() {
+        types.push(RecordType::AAAA);
+    }
+    if ip.allows_v4() {
+        types.push(RecordType::A);
+    }
+    let mut addrs = Vec::new();
+    let mut ttl = u32::MAX;
+    let mut errors = Vec::new();
+    for rtype in types {
+        match query(doh, host, rtype) {
+            Ok(answers) => {
+                if !answers.records.is_empty() {
+                    ttl = ttl.min(answers.ttl);
+                }
+                addrs.extend(answers.records.into_iter().filter_map(|a| match a {
+                    Answer::Ip(ip) => Some(ip),
+                    Answer::Srv(_) => None,
+                }));
+            }
+            Err(e) => errors.push(e.to_string()),
+        }
+    }
+    if addrs.is_empty() {
+        errors.push(format!("DoH: no A/AAAA records for {}", host));
+        return Err(HandshakeError::Connection(errors.join("; ")));
+    }
+    Ok((addrs, Duration::from_secs(ttl as u64)))
+}
+
+/// Ask each endpoint in turn until one answers.
+fn query(doh: &DohConfig, name: &str, rtype: RecordType) -> Result<Answers, HandshakeError> {
+    let default = [DEFAULT_DOH_URL.to_string()];
+    let endpoints = if doh.endpoints.is_empty() {
+        &default[..]
+    } else {
+        &doh.endpoints[..]
+    };
+    // A stalled endpoint must not hold up the connect.
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(doh.timeout)
+        .timeout(doh.timeout)
+        .build();
+    let mut errors = Vec::new();
+    for endpoint in endpoints {
+        let res = match doh.format {
+            DohFormat::Json => query_json(&agent, endpoint, name, rtype),
+            DohFormat::Wire => query_wire(&agent, endpoint, name, rtype),
+        };
+        match res {
+            Ok(answers) => return Ok(answers),
+            Err(e) => errors.push(format!("{}: {}", endpoint, e)),
+        }
+    }
+    Err(HandshakeError::Connection(format!(
+        "DoH {} {} failed: {}",
+        rtype,
+        name,
+        errors.join("; ")
+    )))
+}
+
+/// GET with `name` and `type` added to the endpoint's query string (which may have its own).
+fn query_json(
+    agent: &ureq::Agent,
+    endpoint: &str,
+    name: &str,
+    rtype: RecordType,
+) -> Result<Answers, String> {
+    let resp = agent
+        .get(endpoint)
+        .query("name", name)
+        .query("type", &rtype.to_string())
+        .set("Accept", "application/dns-json")
+        .call()
+        .map_err(|e| format!("request failed: {}", e))?;
+    let body: DohResponse = resp.into_json().map_err(|e| format!("JSON: {}", e))?;
+    if body.status != 0 {
+        return Err(format!("status {}", body.status));
+    }
+    let mut out = Vec::new();
+    for a in &body.answer {
+        if a.typ != u16::from(rtype) {
+            continue;
+        }
+        match rtype {
+            RecordType::SRV => {
+                // data = "priority weight port target"
+                let parts: Vec<&str> = a.data.split_whitespace().collect();
+                if parts.len() >= 4 {
+                    if let (Ok(priority), Ok(weight), Ok(port)) = (
+                        parts[0].parse::<u16>(),
+                        parts[1].parse::<u16>(),
+                        parts[2].parse::<u16>(),
+                    ) {
+                        let srv = SrvRecord {
+                            priority,
+                            weight,
+                            port,
+                            target: parts[3].to_string(),
+                            direct_tls: false,
+                        };
+                        out.push((Answer::Srv(srv), a.ttl));
+                    }
+                }
+            }
+            _ => {
+                if let Ok(ip) = a.data.trim().parse::<IpAddr>() {
+                    out.push((Answer::Ip(ip), a.ttl));
+                }
+            }
+        }
+    }
+    Ok(Answers::new(out))
+}
+
+/// RFC 8484 POST with a binary DNS query. The message ID is 0 as the RFC recommends for caching.
+fn query_wire(
+    agent: &ureq::Agent,
+    endpoint: &str,
+    name: &str,
+    rtype: RecordType,
+) -> Result<Answers, String> {
+    let qname = Name::from_ascii(name).map_err(|e| format!("name {}: {}", name, e))?;
+    let mut msg = Message::new();
+    msg.set_id(0)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(Query::query(qname, rtype));
+    let bytes = msg.to_vec().map_err(|e| format!("encode: {}", e))?;
+    let resp = agent
+        .post(endpoint)
+        .set("Content-Type", "application/dns-message")
+        .set("Accept", "application/dns-message")
+        .send_bytes(&bytes)
+        .map_err(|e| format!("request failed: {}", e))?;
+    let mut body = Vec::new();
+    resp.into_reader()
+        .take(MAX_RESPONSE)
+        .read_to_end(&mut body)
+        .map_err(|e| format!("read: {}", e))?;
+    let answer = Message::from_vec(&body).map_err(|e| format!("decode: {}", e))?;
+    if answer.response_code() != ResponseCode::NoError {
+        return Err(format!("rcode {}", answer.response_code()));
+    }
+    let records = answer
+        .answers()
+        .iter()
+        .filter_map(|r| {
+            let record = match r.data()? {
+                RData::SRV(srv) if rtype == RecordType::SRV => Answer::Srv(SrvRecord {
+                    priority: srv.priority(),
+                    weight: srv.weight(),
+                    port: srv.port(),
+                    target: srv.target().to_utf8(),
+                    direct_tls: false,
+                }),
+                RData::A(a) if rtype == RecordType::A => Answer::Ip(IpAddr::V4(a.0)),
+                RData::AAAA(a) if rtype == RecordType::AAAA => Answer::Ip(IpAddr::V6(a.0)),
+                _ => return None,
+            };
+            Some((record, r.ttl()))
+        })
+        .collect();
+    Ok(Answers::new(records))
+}
@@ -0,0 +1,84 @@
+//! Table of live FFI handles. C holds a token (slot index + generation), never a pointer:
+//! a call with a destroyed handle, or with one whose slot was reused, finds nothing instead
+//! of freed memory, and a call in progress keeps its handle alive through destroy.
+
+use std::sync::{Arc, Mutex};
+
+/// Low half of a token: slot index + 1 (0 is never a valid token). High half: generation.
+const INDEX_BITS: u32 = usize::BITS / 2;
+const INDEX_MASK: usize = (1 << INDEX_BITS) - 1;
+
+struct Slot<T> {
+    generation: usize,
+    value: Option<Arc<T>>,
+}
+
+pub struct HandleTable<T> {
+    table: Mutex<Table<T>>,
+}
+
+struct Table<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<usize>,
+}
+
+impl<T> HandleTable<T> {
+    pub const fn new() -> Self {
+        Self {
+            table: Mutex::new(Table {
+                slots: Vec::new(),
+                free: Vec::new(),
+            }),
+        }
+    }
+
+    /// Store `value`; returns its token, or None when the table is full.
+    pub fn insert(&self, value: T) -> Option<usize> {
+        let mut table = self.table.lock().unwrap_or_else(|e| e.into_inner());
+        let index = match table.free.pop() {
+            Some(index) => index,
+            None if table.slots.len() < INDEX_MASK => {
+                table.slots.push(Slot {
+                    generation: 0,
+                    value: None,
+                });
+                table.slots.len() - 1
+            }
+            None => return None,
+        };
+        let slot = &mut table.slots[index];
+        slot.value = Some(Arc::new(value));
+        Some(slot.generation << INDEX_BITS | (index + 1))
+    }
+
+    /// The value behind `token` if it is still live.
+    pub fn get(&self, token: usize) -> Option<Arc<T>> {
+        let table = self.table.lock().unwrap_or_else(|e| e.into_inner());
+        let slot = table.slots.get((token & INDEX_MASK).checked_sub(1)?)?;
+        if slot.generation != token >> INDEX_BITS {
+            return None;
+        }
+        slot.value.clone()
+    }
+
+    /// Take the value out; the token (and any copy of it) is dead afterwards.
+    pub fn remove(&self, token: usize) -> Option<Arc<T>> {
+        let mut table = self.table.lock().unwrap_or_else(|e| e.into_inner());
+        let index = (token & INDEX_MASK).checked_sub(1)?;
+        let slot = table.slots.get_mut(index)?;
+        if slot.generation != token >> INDEX_BITS || slot.value.is_none() {
+            return None;
+        }
+        let value = slot.value.take();
+        // Wraps after 2^(bits/2) reuses of one slot; a token that old could alias.
+        slot.generation = (slot.generation + 1) & (usize::MAX >> INDEX_BITS);
+        table.free.push(index);
+        value
+    }
+}
+
+impl<T> Default for HandleTable<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -23,6 +23,17 @@ pub enum HandshakeError {
 
     #[error("invalid certificate: {0}")]
     BadCertificate(String),
+
+    #[error("invalid client key: {0}")]
+    ClientKey(String),
+
+    /// Every connection candidate failed, at least one in its handshake; `last` is the last
+    /// such handshake error, whose code this reports.
+    #[error("all connection candidates failed: {failures}")]
+    Candidates {
+        failures: String,
+        last: Box<HandshakeError>,
+    },
 }
 
 /// C-friendly error code for FFI.
@@ -36,6 +47,7 @@ pub enum HandshakeErrorCode {
     Timeout = 4,
     SeeOtherHost = 5,
     BadCertificate = 6,
+    ClientKey = 7,
 }
 
 impl From<&HandshakeError> for HandshakeErrorCode {
@@ -47,6 +59,8 @@ impl From<&HandshakeError> for HandshakeErrorCode {
             HandshakeError::Timeout(_) => HandshakeErrorCode::Timeout,
             HandshakeError::SeeOtherHost(_) => HandshakeErrorCode::SeeOtherHost,
             HandshakeError::BadCertificate(_) => HandshakeErrorCode::BadCertificate,
+            HandshakeError::ClientKey(_) => HandshakeErrorCode::ClientKey,
+            HandshakeError::Candidates { last, .. } => last.as_ref().into(),
         }
     }
 }
@@ -0,0 +1,169 @@
+//! Happy Eyeballs v2 (RFC 8305): race TCP connects across IPv6 and IPv4.
+//! Addresses are interleaved by family and attempts start staggered; the first socket wins.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use polling::{Event, Events, Poller};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::cancel::Cancel;
+use crate::config::IpPreference;
+
+/// Filter `addrs` by `pref` and interleave the families, preferred family first
+/// (RFC 8305 section 4, "First Address Family Count" of 1). Order within a family is kept.
+pub fn sort_addrs(addrs: &[SocketAddr], pref: IpPreference) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.iter().partition(|a| a.is_ipv6());
+    let v6 = if pref.allows_v6() { v6 } else { Vec::new() };
+    let v4 = if pref.allows_v4() { v4 } else { Vec::new() };
+    let (first, second) = if pref.v6_first() { (v6, v4) } else { (v4, v6) };
+    let mut out = Vec::with_capacity(first.len() + second.len());
+    let mut first = first.into_iter();
+    let mut second = second.into_iter();
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => break,
+            (a, b) => out.extend(a.into_iter().chain(b)),
+        }
+    }
+    out
+}
+
+/// Connect to the first reachable address. A new attempt starts every `attempt_delay`, or
+/// at once when the previous one fails; all attempts end at `deadline`. The attempts are
+/// non-blocking connects watched by one poller on the calling thread; losing sockets are
+/// closed when it returns. `cancel` ends them all at once. On failure returns one message
+/// per address.
+pub fn connect(
+    addrs: &[SocketAddr],
+    attempt_delay: Duration,
+    deadline: Instant,
+    cancel: &Cancel,
+) -> Result<(TcpStream, SocketAddr), Vec<String>> {
+    let poller = Arc::new(Poller::new().map_err(|e| vec![format!("poller: {}", e)])?);
+    let _watch = cancel.watch(&poller);
+    let mut attempts = Attempts {
+        poller: &poller,
+        pending: HashMap::new(),
+    };
+    let mut events = Events::new();
+    let mut failures: Vec<String> = Vec::new();
+    let mut next = 0;
+    let mut next_start = Instant::now();
+    loop {
+        if cancel.is_cancelled() {
+            failures.push("cancelled".into());
+            return Err(failures);
+        }
+        let now = Instant::now();
+        if next < addrs.len() && now >= next_start {
+            let addr = addrs[next];
+            match attempts.start(next, addr) {
+                Ok(Some(tcp)) => return Ok((tcp, addr)),
+                Ok(None) => next_start = now + attempt_delay,
+                Err(e) => {
+                    failures.push(format!("{}: {}", addr, e));
+                    // RFC 8305 section 5: start the next attempt as soon as one fails.
+                    next_start = now;
+                }
+            }
+            next += 1;
+            continue;
+        }
+        if attempts.pending.is_empty() && next >= addrs.len() {
+            return Err(failures);
+        }
+        if now >= deadline {
+            let mut timed_out: Vec<usize> = attempts.pending.keys().copied().collect();
+            timed_out.sort_unstable();
+            timed_out.extend(next..addrs.len());
+            for i in timed_out {
+                failures.push(format!("{}: overall timeout", addrs[i]));
+            }
+            return Err(failures);
+        }
+        let wait_until = if next < addrs.len() {
+            next_start.min(deadline)
+        } else {
+            deadline
+        };
+        events.clear();
+        if let Err(e) = poller.wait(&mut events, Some(wait_until - now)) {
+            if e.kind() != io::ErrorKind::Interrupted {
+                failures.push(format!("poll: {}", e));
+                return Err(failures);
+            }
+        }
+        for key in events.iter().map(|e| e.key).collect::<Vec<_>>() {
+            match attempts.finish(key) {
+                Some(Ok(tcp)) => return Ok((tcp, addrs[key])),
+                Some(Err(e)) => {
+                    failures.push(format!("{}: {}", addrs[key], e));
+                    next_start = Instant::now();
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+/// Connects in flight, keyed by their index in the address list.
+struct Attempts<'a> {
+    poller: &'a Poller,
+    pending: HashMap<usize, Socket>,
+}
+
+impl Attempts<'_> {
+    /// Start a non-blocking connect; `Some` if it completed at once.
+    fn start(&mut self, key: usize, addr: SocketAddr) -> io::Result<Option<TcpStream>> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+        match socket.connect(&addr.into()) {
+            Ok(()) => return established(socket).map(Some),
+            Err(e) if in_progress(&e) => {}
+            Err(e) => return Err(e),
+        }
+        // Safety: every socket is deleted from the poller before it is dropped (`finish`,
+        // `Drop`).
+        unsafe { self.poller.add(&socket, Event::writable(key))? };
+        self.pending.insert(key, socket);
+        Ok(None)
+    }
+
+    /// The socket for `key` is writable: the connect completed or failed.
+    fn finish(&mut self, key: usize) -> Option<io::Result<TcpStream>> {
+        let socket = self.pending.remove(&key)?;
+        let _ = self.poller.delete(&socket);
+        Some(match socket.take_error() {
+            Ok(Some(e)) | Err(e) => Err(e),
+            Ok(None) => established(socket),
+        })
+    }
+}
+
+impl Drop for Attempts<'_> {
+    fn drop(&mut self) {
+        for socket in self.pending.values() {
+            let _ = self.poller.delete(socket);
+        }
+    }
+}
+
+/// A connected socket, back in blocking mode.
+fn established(socket: Socket) -> io::Result<TcpStream> {
+    socket.peer_addr()?;
+    socket.set_nonblocking(false)?;
+    Ok(socket.into())
+}
+
+/// A non-blocking connect was started: EINPROGRESS on Unix, WSAEWOULDBLOCK on Windows.
+fn in_progress(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    if e.raw_os_error() == Some(libc::EINPROGRESS) {
+        return true;
+    }
+    e.kind() == io::ErrorKind::WouldBlock
+}
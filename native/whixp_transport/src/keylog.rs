@@ -0,0 +1,96 @@
+//! TLS key log in NSS format (what SSLKEYLOGFILE produces), so captures of DirectTls,
+//! StartTLS and wss sessions can be decrypted in Wireshark. Off unless configured; compiled
+//! into debug builds, and into release builds only with feature `keylog`.
+
+use std::sync::Arc;
+
+use rustls::KeyLog;
+
+use crate::config::KeyLogConfig;
+use crate::handshake::HandshakeError;
+
+/// Key log for `config`, or None when it is off (or not compiled in). A key log file that
+/// cannot be opened is `HandshakeError::Tls`.
+#[cfg(any(debug_assertions, feature = "keylog"))]
+pub fn key_log(config: &KeyLogConfig) -> Result<Option<Arc<dyn KeyLog>>, HandshakeError> {
+    if !config.is_enabled() {
+        return Ok(None);
+    }
+    Ok(Some(Arc::new(imp::NssKeyLog::new(config)?)))
+}
+
+#[cfg(not(any(debug_assertions, feature = "keylog")))]
+pub fn key_log(config: &KeyLogConfig) -> Result<Option<Arc<dyn KeyLog>>, HandshakeError> {
+    if config.is_enabled() {
+        tracing::warn!("TLS key log requested but not compiled in (enable feature `keylog`)");
+    }
+    Ok(None)
+}
+
+#[cfg(any(debug_assertions, feature = "keylog"))]
+mod imp {
+    use std::ffi::{c_void, CString};
+    use std::fs::{File, OpenOptions};
+    use std::io::Write;
+    use std::sync::Mutex;
+
+    use rustls::KeyLog;
+    use tracing::warn;
+
+    use crate::cert_info::hex;
+    use crate::config::{KeyLogCallback, KeyLogConfig};
+    use crate::handshake::HandshakeError;
+
+    /// Appends `LABEL <client_random> <secret>` lines to a file and/or hands them to a callback.
+    pub struct NssKeyLog {
+        file: Option<Mutex<File>>,
+        callback: Option<KeyLogCallback>,
+        user_data: usize,
+    }
+
+    impl NssKeyLog {
+        pub fn new(config: &KeyLogConfig) -> Result<Self, HandshakeError> {
+            let file = match config.path.as_deref() {
+                Some(path) => Some(
+                    OpenOptions::new()
+                        .append(true)
+                        .create(true)
+                        .open(path)
+                        .map_err(|e| HandshakeError::Tls(format!("key log {}: {}", path, e)))?,
+                ),
+                None => None,
+            };
+            Ok(Self {
+                file: file.map(Mutex::new),
+                callback: config.callback,
+                user_data: config.user_data,
+            })
+        }
+    }
+
+    impl std::fmt::Debug for NssKeyLog {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("NssKeyLog")
+                .field("file", &self.file.is_some())
+                .field("callback", &self.callback.is_some())
+                .finish()
+        }
+    }
+
+    impl KeyLog for NssKeyLog {
+        fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+            let line = format!("{} {} {}", label, hex(client_random), hex(secret));
+            if let Some(file) = &self.file {
+                let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+                if let Err(e) = writeln!(file, "{}", line) {
+                    warn!("key log write failed: {}", e);
+                }
+            }
+            if let Some(callback) = self.callback {
+                if let Ok(line) = CString::new(line) {
+                    callback(line.into_raw(), self.user_data as *mut c_void);
+                }
+            }
+        }
+    }
+}
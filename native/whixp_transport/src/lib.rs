@@ -4,36 +4,84 @@
 
 #![allow(clippy::missing_safety_doc)]
 
+pub mod cancel;
+pub mod cert_info;
 pub mod config;
 pub mod connection;
 pub mod dns;
+pub mod dns_cache;
+#[cfg(feature = "doh")]
+pub mod doh;
+pub mod handles;
 pub mod handshake;
+pub mod happy_eyeballs;
+pub mod keylog;
 pub mod retry;
+pub mod runtime;
 pub mod stanza;
 pub mod tls;
 pub mod websocket;
+pub mod ws_deflate;
 
+use std::collections::BTreeMap;
+use std::ffi::{c_void, CString};
 use std::os::raw::c_char;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use config::{TransportConfig, TransportKind};
-use connection::{Connection, TransportEvent};
+use config::{
+    DohConfig, DohFormat, IpPreference, KeyLogCallback, KeyLogConfig, PinMode, SessionCache,
+    TlsConfig, TlsVersion, TransportConfig, TransportKind, TrustStore, WsFraming,
+};
+use connection::{Closer, Connection, EventReceiver, QueueNotifier, TransportEvent};
+use handles::HandleTable;
 use handshake::HandshakeErrorCode;
 use retry::RetryPolicy;
+use tls::{ChannelBinding, TrustPrompt};
 
-/// Opaque handle. Dart stores this and passes back to every FFI call.
+/// How long destroy waits for the connection's task and any reconnect dial to stop.
+const DESTROY_JOIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+static HANDLES: HandleTable<Handle> = HandleTable::new();
+
+/// Opaque handle. Dart stores this and passes back to every FFI call. The pointer value is a
+/// token into `HANDLES` and is never dereferenced: calls with a destroyed handle do nothing
+/// (or return their error value).
 pub struct Handle {
     connection: Mutex<Option<Connection>>,
-    event_rx: Mutex<Option<std::sync::mpsc::Receiver<TransportEvent>>>,
+    /// Closes the connection without its lock, which a blocking connect or StartTLS holds.
+    closer: Closer,
+    event_rx: Mutex<Option<EventReceiver>>,
+    /// Push mode hook (whixp_transport_set_notify), kept across connects.
+    notifier: Arc<QueueNotifier>,
     pending: Mutex<Option<TransportEvent>>,
     /// Resolved host after connect (for SASL/service name).
     resolved_host: Mutex<Option<String>>,
     /// Last connect error message when connect returns non-OK.
     last_error: Mutex<Option<String>>,
+    /// Answered without taking the connection lock, which a paused StartTLS holds.
+    trust: Arc<TrustPrompt>,
+    /// Last data returned by whixp_transport_channel_binding.
+    channel_binding: Mutex<Vec<u8>>,
+    /// Last JSON returned by whixp_transport_tls_info.
+    tls_info: Mutex<String>,
+    /// Last JSON returned by whixp_transport_ws_response_headers.
+    ws_response_headers: Mutex<String>,
+    /// Records handed out by whixp_transport_drain_owned until whixp_transport_drain_release.
+    drain_arena: Mutex<DrainArena>,
+}
+
+/// Native-owned drain buffer, reused across drains.
+#[derive(Default)]
+struct DrainArena {
+    buf: Vec<u8>,
+    /// Handed to the caller and not released yet.
+    held: bool,
 }
 
 /// C-compatible config. host = domain to resolve; service = SRV name (e.g. xmpp-client) or null.
 /// ws_path = WebSocket path (e.g. "/ws") or null to use default "/ws".
+/// auto_reconnect = 1 to reconnect in Rust with the retry_* policy (0 in a retry_* field = default).
 #[repr(C)]
 pub struct CTransportConfig {
     pub host_ptr: *const c_char,
@@ -41,13 +89,87 @@ pub struct CTransportConfig {
     pub port: u16,
     pub kind: i32,
     pub connect_timeout_ms: u32,
+    /// SNI to send (null = host). The certificate is always verified against host.
     pub tls_server_name_ptr: *const c_char,
     pub tls_server_name_len: u32,
     pub service_ptr: *const c_char,
     pub service_len: u32,
+    /// IpPreference: 0 = prefer IPv4, 1 = prefer IPv6 (both raced), 2 = IPv4 only, 3 = IPv6 only.
     pub use_ipv6: i32,
     pub ws_path_ptr: *const c_char,
     pub ws_path_len: u32,
+    pub auto_reconnect: i32,
+    pub retry_max_attempts: u32,
+    pub retry_initial_delay_ms: u32,
+    pub retry_max_delay_ms: u32,
+    pub retry_multiplier: f64,
+    /// Fraction of each delay randomized, 0.0..=1.0 (0 = no jitter).
+    pub retry_jitter: f64,
+    /// Happy Eyeballs delay between staggered connection attempts (0 = 250 ms).
+    pub attempt_delay_ms: u32,
+    /// DoH endpoint URLs separated by '\n', tried in order (null/empty = Cloudflare).
+    pub doh_endpoints_ptr: *const c_char,
+    pub doh_endpoints_len: u32,
+    /// DohFormat: 0 = JSON, 1 = RFC 8484 wire format.
+    pub doh_format: i32,
+    /// Serve expired cached DNS answers for this long while refreshing (0 = TTL only).
+    pub dns_stale_grace_ms: u32,
+    /// TrustStore: 0 = bundled Mozilla roots, 1 = OS trust store, 2 = both.
+    pub trust_store: i32,
+    /// Extra trust anchors: PEM bundle or one DER certificate (null = none).
+    pub trust_anchors_ptr: *const u8,
+    pub trust_anchors_len: u32,
+    /// SPKI pins: concatenated 32-byte SHA-256 digests of DER SubjectPublicKeyInfo (null = none).
+    pub spki_pins_ptr: *const u8,
+    pub spki_pins_len: u32,
+    /// PinMode: 0 = pin plus chain validation, 1 = pin only.
+    pub spki_pin_mode: i32,
+    /// 1 = on a certificate failure, pause the handshake and emit a bad-certificate event
+    /// (poll code 5) until whixp_transport_trust_decision is called.
+    pub interactive_trust: i32,
+    /// How long a paused handshake waits for the decision (0 = 60 s), then rejects.
+    pub trust_prompt_timeout_ms: u32,
+    /// Client certificate chain for mutual TLS: PEM (leaf first) or one DER certificate
+    /// (null = no client auth).
+    pub client_cert_ptr: *const u8,
+    pub client_cert_len: u32,
+    /// Its private key: PEM or PKCS#8 DER. A key that fails to load makes connect/starttls
+    /// return HandshakeErrorCode::ClientKey.
+    pub client_key_ptr: *const u8,
+    pub client_key_len: u32,
+    /// Timeout for each TLS handshake and WebSocket upgrade (0 = connect_timeout_ms).
+    pub handshake_timeout_ms: u32,
+    /// TlsVersion: 0 = TLS 1.2 and 1.3, 1 = TLS 1.3 only.
+    pub tls_min_version: i32,
+    /// Allowed cipher suites, IANA code points (null = rustls defaults). Length in entries.
+    pub cipher_suites_ptr: *const u16,
+    pub cipher_suites_len: u32,
+    /// Allowed key exchange groups, IANA code points in preference order (null = defaults).
+    pub kx_groups_ptr: *const u16,
+    pub kx_groups_len: u32,
+    /// SessionCache: 0 = per handle, 1 = shared by all handles, 2 = no resumption.
+    pub tls_session_cache: i32,
+    /// File to append TLS secrets to in NSS key log format (SSLKEYLOGFILE) for Wireshark
+    /// (null = none). Ignored unless built in debug or with feature `keylog`. A file that
+    /// cannot be opened makes connect/starttls return HandshakeErrorCode::Tls.
+    pub key_log_path_ptr: *const c_char,
+    pub key_log_path_len: u32,
+    /// Called with each key log line (null = none); free the line with
+    /// whixp_transport_key_log_free. May run on any thread. Same build condition as above.
+    pub key_log_callback: Option<KeyLogCallback>,
+    pub key_log_user_data: *mut c_void,
+    /// WsFraming: 0 = stanzas re-split from the message stream, 1 = RFC 7395 (`xmpp`
+    /// subprotocol required; open/close/see-other-host are poll codes 6/7/8).
+    pub ws_framing: i32,
+    /// Extra WebSocket upgrade headers as "Name: value" lines separated by '\n' (null = none).
+    pub ws_headers_ptr: *const c_char,
+    pub ws_headers_len: u32,
+    /// Ping after this long without inbound WebSocket traffic (0 = no pings).
+    pub ws_ping_interval_ms: u32,
+    /// Close the stream when nothing arrives this long after a ping (0 = 10000).
+    pub ws_pong_timeout_ms: u32,
+    /// 1 = offer permessage-deflate (RFC 7692).
+    pub ws_deflate: i32,
 }
 
 fn kind_from_c(k: i32) -> TransportKind {
@@ -61,6 +183,132 @@ fn kind_from_c(k: i32) -> TransportKind {
     }
 }
 
+fn ip_preference_from_c(v: i32) -> IpPreference {
+    match v {
+        0 => IpPreference::PreferIpv4,
+        2 => IpPreference::Ipv4Only,
+        3 => IpPreference::Ipv6Only,
+        _ => IpPreference::PreferIpv6,
+    }
+}
+
+fn retry_from_c(c: &CTransportConfig) -> RetryPolicy {
+    let d = RetryPolicy::default();
+    RetryPolicy {
+        max_attempts: if c.retry_max_attempts == 0 {
+            d.max_attempts
+        } else {
+            c.retry_max_attempts
+        },
+        initial_delay_ms: if c.retry_initial_delay_ms == 0 {
+            d.initial_delay_ms
+        } else {
+            c.retry_initial_delay_ms as u64
+        },
+        max_delay_ms: if c.retry_max_delay_ms == 0 {
+            d.max_delay_ms
+        } else {
+            c.retry_max_delay_ms as u64
+        },
+        multiplier: if c.retry_multiplier > 0.0 {
+            c.retry_multiplier
+        } else {
+            d.multiplier
+        },
+        jitter: c.retry_jitter.clamp(0.0, 1.0),
+    }
+}
+
+unsafe fn doh_from_c(c: &CTransportConfig) -> DohConfig {
+    let endpoints = ptr_to_string(c.doh_endpoints_ptr, c.doh_endpoints_len)
+        .split('\n')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(str::to_string)
+        .collect();
+    DohConfig {
+        endpoints,
+        format: if c.doh_format == 1 {
+            DohFormat::Wire
+        } else {
+            DohFormat::Json
+        },
+        ..Default::default()
+    }
+}
+
+unsafe fn ptr_to_bytes(ptr: *const u8, len: u32) -> Vec<u8> {
+    if ptr.is_null() || len == 0 {
+        return Vec::new();
+    }
+    std::slice::from_raw_parts(ptr, len as usize).to_vec()
+}
+
+unsafe fn ptr_to_u16s(ptr: *const u16, len: u32) -> Vec<u16> {
+    if ptr.is_null() || len == 0 {
+        return Vec::new();
+    }
+    std::slice::from_raw_parts(ptr, len as usize).to_vec()
+}
+
+unsafe fn tls_from_c(c: &CTransportConfig) -> TlsConfig {
+    TlsConfig {
+        trust_store: match c.trust_store {
+            1 => TrustStore::System,
+            2 => TrustStore::BundledAndSystem,
+            _ => TrustStore::Bundled,
+        },
+        extra_roots: ptr_to_bytes(c.trust_anchors_ptr, c.trust_anchors_len),
+        spki_pins: ptr_to_bytes(c.spki_pins_ptr, c.spki_pins_len)
+            .chunks_exact(32)
+            .filter_map(|pin| pin.try_into().ok())
+            .collect(),
+        pin_mode: if c.spki_pin_mode == 1 {
+            PinMode::PinOnly
+        } else {
+            PinMode::PinAndChain
+        },
+        interactive_trust: c.interactive_trust != 0,
+        trust_prompt_timeout_ms: if c.trust_prompt_timeout_ms == 0 {
+            60_000
+        } else {
+            c.trust_prompt_timeout_ms
+        },
+        client_cert: ptr_to_bytes(c.client_cert_ptr, c.client_cert_len),
+        client_key: ptr_to_bytes(c.client_key_ptr, c.client_key_len),
+        min_version: if c.tls_min_version == 1 {
+            TlsVersion::Tls13
+        } else {
+            TlsVersion::Tls12
+        },
+        cipher_suites: ptr_to_u16s(c.cipher_suites_ptr, c.cipher_suites_len),
+        kx_groups: ptr_to_u16s(c.kx_groups_ptr, c.kx_groups_len),
+        session_cache: match c.tls_session_cache {
+            1 => SessionCache::Shared,
+            2 => SessionCache::Disabled,
+            _ => SessionCache::PerHandle,
+        },
+    }
+}
+
+unsafe fn key_log_from_c(c: &CTransportConfig) -> KeyLogConfig {
+    let path = ptr_to_string(c.key_log_path_ptr, c.key_log_path_len);
+    KeyLogConfig {
+        path: (!path.is_empty()).then_some(path),
+        callback: c.key_log_callback,
+        user_data: c.key_log_user_data as usize,
+    }
+}
+
+unsafe fn ws_headers_from_c(c: &CTransportConfig) -> Vec<(String, String)> {
+    ptr_to_string(c.ws_headers_ptr, c.ws_headers_len)
+        .split('\n')
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
 unsafe fn ptr_to_string(ptr: *const c_char, len: u32) -> String {
     if ptr.is_null() || len == 0 {
         return String::new();
@@ -70,7 +318,7 @@ unsafe fn ptr_to_string(ptr: *const c_char, len: u32) -> String {
 }
 
 /// Create a transport handle. Returns null on failure.
-/// No callbacks; Dart polls for events via whixp_transport_poll.
+/// Dart polls for events via whixp_transport_poll; the only callback is the optional key log.
 #[no_mangle]
 pub unsafe extern "C" fn whixp_transport_create(config: *const CTransportConfig) -> *mut Handle {
     let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
@@ -92,7 +340,7 @@ pub unsafe extern "C" fn whixp_transport_create(config: *const CTransportConfig)
         } else {
             Some(ptr_to_string(c.service_ptr as *const c_char, c.service_len))
         };
-        let use_ipv6 = c.use_ipv6 != 0;
+        let ip_preference = ip_preference_from_c(c.use_ipv6);
         let ws_path = if c.ws_path_ptr.is_null() || c.ws_path_len == 0 {
             Some("/ws".to_string())
         } else {
@@ -103,38 +351,79 @@ pub unsafe extern "C" fn whixp_transport_create(config: *const CTransportConfig)
             port: c.port,
             kind: kind_from_c(c.kind),
             connect_timeout_ms: c.connect_timeout_ms,
+            handshake_timeout_ms: if c.handshake_timeout_ms == 0 {
+                c.connect_timeout_ms
+            } else {
+                c.handshake_timeout_ms
+            },
             tls_server_name: tls_sni,
             service,
-            use_ipv6,
+            ip_preference,
+            attempt_delay_ms: if c.attempt_delay_ms == 0 {
+                250
+            } else {
+                c.attempt_delay_ms
+            },
             ws_path,
+            ws_framing: if c.ws_framing == 1 {
+                WsFraming::Rfc7395
+            } else {
+                WsFraming::Stream
+            },
+            ws_headers: ws_headers_from_c(c),
+            ws_deflate: c.ws_deflate != 0,
+            ws_ping_interval_ms: c.ws_ping_interval_ms,
+            ws_pong_timeout_ms: if c.ws_pong_timeout_ms == 0 {
+                10_000
+            } else {
+                c.ws_pong_timeout_ms
+            },
+            auto_reconnect: c.auto_reconnect != 0,
+            doh: doh_from_c(c),
+            dns_stale_grace_ms: c.dns_stale_grace_ms,
+            tls: tls_from_c(c),
+            key_log: key_log_from_c(c),
         };
-        let retry = RetryPolicy::default();
+        let retry = retry_from_c(c);
         let connection = Connection::new(config, retry);
+        let trust = connection.trust_prompt();
         let handle = Handle {
+            closer: connection.closer(),
             connection: Mutex::new(Some(connection)),
             event_rx: Mutex::new(None),
+            notifier: Arc::default(),
             pending: Mutex::new(None),
             resolved_host: Mutex::new(None),
             last_error: Mutex::new(None),
+            trust,
+            channel_binding: Mutex::new(Vec::new()),
+            tls_info: Mutex::new(String::new()),
+            drain_arena: Mutex::new(DrainArena::default()),
+            ws_response_headers: Mutex::new(String::new()),
         };
-        Box::into_raw(Box::new(handle))
+        HANDLES
+            .insert(handle)
+            .map_or(std::ptr::null_mut(), |token| token as *mut Handle)
     }));
     result.unwrap_or(std::ptr::null_mut())
 }
 
-/// Connect. Creates event channel and starts I/O threads. Returns 0 on success, else HandshakeErrorCode.
+/// Connect. Creates event channel and hands the connection to the shared I/O runtime. Returns 0 on success, else HandshakeErrorCode.
+/// TLS handshakes (DirectTls, WebSocketTls) complete here, so an interactive trust prompt
+/// pauses this call.
+/// Connecting again, with or without whixp_transport_disconnect in between, closes the
+/// previous connection first.
 /// Panics are caught so we never unwind across FFI (which would abort the process).
 #[no_mangle]
 pub unsafe extern "C" fn whixp_transport_connect(handle: *mut Handle) -> i32 {
     let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-        if handle.is_null() {
+        let Some(handle_ref) = lookup(handle) else {
             return HandshakeErrorCode::Connection as i32;
-        }
-        let handle_ref = &*handle;
+        };
         if let Ok(mut last_err) = handle_ref.last_error.lock() {
             *last_err = None;
         }
-        let (event_tx, event_rx) = std::sync::mpsc::channel();
+        let (event_tx, event_rx) = connection::event_channel(Arc::clone(&handle_ref.notifier));
         if let Ok(mut rx_guard) = handle_ref.event_rx.lock() {
             *rx_guard = Some(event_rx);
         }
@@ -166,13 +455,18 @@ pub unsafe extern "C" fn whixp_transport_connect(handle: *mut Handle) -> i32 {
 }
 
 /// Poll next event. Call from Dart main isolate only.
-/// Returns: 0 = none, 1 = state (call whixp_transport_get_polled_state), 2 = stanza (call whixp_transport_get_polled_stanza then whixp_transport_poll_clear), 3 = error (call whixp_transport_get_polled_error then whixp_transport_poll_clear).
+/// Returns: 0 = none, 1 = state (call whixp_transport_get_polled_state), 2 = stanza (call whixp_transport_get_polled_stanza then whixp_transport_poll_clear), 3 = error (call whixp_transport_get_polled_error then whixp_transport_poll_clear),
+/// 4 = stream header (call whixp_transport_get_polled_stanza then whixp_transport_poll_clear),
+/// 5 = bad certificate, handshake paused (JSON via whixp_transport_get_polled_stanza, then
+/// whixp_transport_poll_clear; answer with whixp_transport_trust_decision),
+/// 6 = RFC 7395 `<open/>`, 7 = `<close/>`, 8 = see-other-host URI (WsFraming::Rfc7395; all via
+/// whixp_transport_get_polled_stanza then whixp_transport_poll_clear).
+/// whixp_transport_drain takes many events per call instead.
 #[no_mangle]
 pub unsafe extern "C" fn whixp_transport_poll(handle: *mut Handle) -> i32 {
-    if handle.is_null() {
+    let Some(handle) = lookup(handle) else {
         return 0;
-    }
-    let handle = &*handle;
+    };
     let mut pending = match handle.pending.lock() {
         Ok(p) => p,
         Err(_) => return 0,
@@ -186,32 +480,193 @@ pub unsafe extern "C" fn whixp_transport_poll(handle: *mut Handle) -> i32 {
             }
         }
     }
-    match pending.as_ref() {
-        Some(TransportEvent::State(_)) => 1,
-        Some(TransportEvent::Stanza(_)) => 2,
-        Some(TransportEvent::Error(_, _)) => 3,
-        None => 0,
+    pending.as_ref().map_or(0, |ev| event_parts(ev).0)
+}
+
+/// Poll code, code (state or error code, else 0) and payload of an event.
+fn event_parts(ev: &TransportEvent) -> (i32, i32, &str) {
+    match ev {
+        TransportEvent::State(s) => (1, *s, ""),
+        TransportEvent::Stanza(s) => (2, 0, s),
+        TransportEvent::Error(code, msg) => (3, *code, msg),
+        TransportEvent::StreamHeader(s) => (4, 0, s),
+        TransportEvent::BadCertificate(s) => (5, 0, s),
+        TransportEvent::Open(s) => (6, 0, s),
+        TransportEvent::Close(s) => (7, 0, s),
+        TransportEvent::SeeOtherHost(s) => (8, 0, s),
+    }
+}
+
+/// Drain record header: type (poll code) u32, code i32, payload length u32; little-endian.
+const DRAIN_HEADER_LEN: usize = 12;
+
+/// Largest drain arena kept between drains.
+const DRAIN_ARENA_KEEP: usize = 1 << 20;
+
+/// Bytes one record takes: header, payload, padding to a multiple of 4.
+fn drain_record_len(payload_len: usize) -> usize {
+    (DRAIN_HEADER_LEN + payload_len).next_multiple_of(4)
+}
+
+fn drain_encode(ev: &TransportEvent, out: &mut [u8]) {
+    let (kind, code, payload) = event_parts(ev);
+    out[0..4].copy_from_slice(&(kind as u32).to_le_bytes());
+    out[4..8].copy_from_slice(&code.to_le_bytes());
+    out[8..12].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    let end = DRAIN_HEADER_LEN + payload.len();
+    out[DRAIN_HEADER_LEN..end].copy_from_slice(payload.as_bytes());
+    out[end..].fill(0);
+}
+
+/// Take up to `max_events` (0 = all) pending events, the one parked by poll first. `fits`
+/// gets each record's length; an event it rejects stays parked for the next call.
+fn drain_events(
+    handle: &Handle,
+    max_events: u32,
+    mut fits: impl FnMut(usize) -> bool,
+) -> Vec<TransportEvent> {
+    let mut events = Vec::new();
+    let Ok(mut pending) = handle.pending.lock() else {
+        return events;
+    };
+    let rx_guard = handle.event_rx.lock().ok();
+    let rx = rx_guard.as_ref().and_then(|g| g.as_ref());
+    while max_events == 0 || events.len() < max_events as usize {
+        let Some(ev) = pending
+            .take()
+            .or_else(|| rx.and_then(|rx| rx.try_recv().ok()))
+        else {
+            break;
+        };
+        if !fits(drain_record_len(event_parts(&ev).2.len())) {
+            *pending = Some(ev);
+            break;
+        }
+        events.push(ev);
+    }
+    events
+}
+
+/// Move up to `max_events` (0 = as many as fit) pending events into `buf` in one call,
+/// instead of a poll / get / poll_clear cycle per event. Each record is a 12-byte header
+/// (type = poll code u32, code i32 = state or error code else 0, payload length u32;
+/// little-endian), the UTF-8 payload, then zero padding to a multiple of 4.
+/// Returns the number of records and sets `out_len` to the bytes written; -2 when the next
+/// event does not fit into an empty `buf` (`out_len` = bytes it needs); -1 on bad arguments.
+#[no_mangle]
+pub unsafe extern "C" fn whixp_transport_drain(
+    handle: *mut Handle,
+    buf: *mut u8,
+    buf_len: u32,
+    max_events: u32,
+    out_len: *mut u32,
+) -> i32 {
+    if buf.is_null() || out_len.is_null() {
+        return -1;
+    }
+    let Some(handle) = lookup(handle) else {
+        return -1;
+    };
+    let buf = std::slice::from_raw_parts_mut(buf, buf_len as usize);
+    let mut used = 0;
+    let mut too_big = 0;
+    let events = drain_events(&handle, max_events, |len| {
+        if used + len > buf.len() {
+            too_big = len;
+            return false;
+        }
+        used += len;
+        true
+    });
+    if events.is_empty() && too_big > buf.len() {
+        *out_len = too_big as u32;
+        return -2;
+    }
+    let mut pos = 0;
+    for ev in &events {
+        let len = drain_record_len(event_parts(ev).2.len());
+        drain_encode(ev, &mut buf[pos..pos + len]);
+        pos += len;
     }
+    *out_len = pos as u32;
+    events.len() as i32
+}
+
+/// Like whixp_transport_drain, into a native-owned buffer: `out_ptr`/`out_len` point at the
+/// records until whixp_transport_drain_release. Returns the number of records (0: nothing to
+/// release), or -1 on bad arguments or while the previous buffer is not released.
+#[no_mangle]
+pub unsafe extern "C" fn whixp_transport_drain_owned(
+    handle: *mut Handle,
+    max_events: u32,
+    out_ptr: *mut *const u8,
+    out_len: *mut u32,
+) -> i32 {
+    if out_ptr.is_null() || out_len.is_null() {
+        return -1;
+    }
+    let Some(handle) = lookup(handle) else {
+        return -1;
+    };
+    let Ok(mut arena) = handle.drain_arena.lock() else {
+        return -1;
+    };
+    if arena.held {
+        return -1;
+    }
+    let events = drain_events(&handle, max_events, |_| true);
+    if events.is_empty() {
+        *out_ptr = std::ptr::null();
+        *out_len = 0;
+        return 0;
+    }
+    arena.buf.clear();
+    for ev in &events {
+        let pos = arena.buf.len();
+        arena
+            .buf
+            .resize(pos + drain_record_len(event_parts(ev).2.len()), 0);
+        drain_encode(ev, &mut arena.buf[pos..]);
+    }
+    arena.held = true;
+    *out_ptr = arena.buf.as_ptr();
+    *out_len = arena.buf.len() as u32;
+    events.len() as i32
+}
+
+/// Hand the whixp_transport_drain_owned buffer back; its pointer is invalid afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn whixp_transport_drain_release(handle: *mut Handle) {
+    let Some(handle) = lookup(handle) else {
+        return;
+    };
+    if let Ok(mut arena) = handle.drain_arena.lock() {
+        arena.held = false;
+        // Keep the allocation for the next drain unless a burst made it large.
+        if arena.buf.capacity() > DRAIN_ARENA_KEEP {
+            arena.buf = Vec::new();
+        }
+    };
 }
 
 /// Clear current pending event so next poll returns the next one.
 #[no_mangle]
 pub unsafe extern "C" fn whixp_transport_poll_clear(handle: *mut Handle) {
-    if handle.is_null() {
+    let Some(handle) = lookup(handle) else {
         return;
-    }
-    if let Ok(mut pending) = (*handle).pending.lock() {
+    };
+    if let Ok(mut pending) = handle.pending.lock() {
         *pending = None;
-    }
+    };
 }
 
 /// Get polled state (only valid after poll returned 1). Returns state code.
 #[no_mangle]
 pub unsafe extern "C" fn whixp_transport_get_polled_state(handle: *mut Handle) -> i32 {
-    if handle.is_null() {
+    let Some(handle) = lookup(handle) else {
         return 0;
-    }
-    if let Ok(pending) = (*handle).pending.lock() {
+    };
+    if let Ok(pending) = handle.pending.lock() {
         if let Some(TransportEvent::State(s)) = *pending {
             return s;
         }
@@ -219,22 +674,34 @@ pub unsafe extern "C" fn whixp_transport_get_polled_state(handle: *mut Handle) -
     0
 }
 
-/// Get polled stanza (only valid after poll returned 2). Ptr valid until poll_clear.
+/// Get polled stanza, stream header, bad-certificate JSON or RFC 7395 framing payload (only
+/// valid after poll returned 2, 4, 5, 6, 7 or 8). Ptr valid until poll_clear or destroy.
 #[no_mangle]
 pub unsafe extern "C" fn whixp_transport_get_polled_stanza(
     handle: *mut Handle,
     out_ptr: *mut *const u8,
     out_len: *mut u32,
 ) {
-    if handle.is_null() || out_ptr.is_null() || out_len.is_null() {
+    if out_ptr.is_null() || out_len.is_null() {
         return;
     }
-    if let Ok(pending) = (*handle).pending.lock() {
-        if let Some(TransportEvent::Stanza(ref s)) = *pending {
+    let Some(handle) = lookup(handle) else {
+        return;
+    };
+    if let Ok(pending) = handle.pending.lock() {
+        if let Some(
+            TransportEvent::Stanza(ref s)
+            | TransportEvent::StreamHeader(ref s)
+            | TransportEvent::BadCertificate(ref s)
+            | TransportEvent::Open(ref s)
+            | TransportEvent::Close(ref s)
+            | TransportEvent::SeeOtherHost(ref s),
+        ) = *pending
+        {
             *out_ptr = s.as_ptr();
             *out_len = s.len() as u32;
         }
-    }
+    };
 }
 
 /// Get polled error (only valid after poll returned 3).
@@ -245,16 +712,19 @@ pub unsafe extern "C" fn whixp_transport_get_polled_error(
     out_ptr: *mut *const u8,
     out_len: *mut u32,
 ) {
-    if handle.is_null() || out_code.is_null() || out_ptr.is_null() || out_len.is_null() {
+    if out_code.is_null() || out_ptr.is_null() || out_len.is_null() {
         return;
     }
-    if let Ok(pending) = (*handle).pending.lock() {
+    let Some(handle) = lookup(handle) else {
+        return;
+    };
+    if let Ok(pending) = handle.pending.lock() {
         if let Some(TransportEvent::Error(code, ref msg)) = *pending {
             *out_code = code;
             *out_ptr = msg.as_ptr();
             *out_len = msg.len() as u32;
         }
-    }
+    };
 }
 
 /// Send UTF-8 XML bytes. Dart encodes stanza to string then to UTF-8.
@@ -264,10 +734,12 @@ pub unsafe extern "C" fn whixp_transport_send(
     data_ptr: *const u8,
     data_len: u32,
 ) -> i32 {
-    if handle.is_null() || data_ptr.is_null() {
+    if data_ptr.is_null() {
         return -1;
     }
-    let handle = &*handle;
+    let Some(handle) = lookup(handle) else {
+        return -1;
+    };
     let guard = match handle.connection.lock() {
         Ok(g) => g,
         Err(_) => return -1,
@@ -283,17 +755,330 @@ pub unsafe extern "C" fn whixp_transport_send(
     }
 }
 
+/// Upgrade the connection to TLS in place (StartTLS). Call after `<proceed/>` was received.
+/// Returns 0 on success, else HandshakeErrorCode. On success a TlsSuccess state event is queued;
+/// Dart then restarts the stream by sending a new stream header.
+/// With interactive_trust the handshake (and this call) can pause on a bad certificate until
+/// whixp_transport_trust_decision is called from another thread, or the prompt times out.
+#[no_mangle]
+pub unsafe extern "C" fn whixp_transport_starttls(handle: *mut Handle) -> i32 {
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let Some(handle_ref) = lookup(handle) else {
+            return HandshakeErrorCode::Connection as i32;
+        };
+        let guard = match handle_ref.connection.lock() {
+            Ok(g) => g,
+            Err(_) => return HandshakeErrorCode::Connection as i32,
+        };
+        let conn = match guard.as_ref() {
+            Some(c) => c,
+            None => return HandshakeErrorCode::Connection as i32,
+        };
+        match conn.starttls() {
+            Ok(()) => HandshakeErrorCode::Ok as i32,
+            Err(e) => {
+                if let Ok(mut last_err) = handle_ref.last_error.lock() {
+                    *last_err = Some(e.to_string());
+                }
+                let code: HandshakeErrorCode = (&e).into();
+                code as i32
+            }
+        }
+    }));
+    result.unwrap_or(HandshakeErrorCode::Connection as i32)
+}
+
+/// Stream restart after SASL success (RFC 6120): the next incoming header is framed as a
+/// stream header event. Call before sending the new stream header. Not needed after
+/// whixp_transport_starttls, which restarts the stream itself. Returns 0 on success, -1 if not connected.
+#[no_mangle]
+pub unsafe extern "C" fn whixp_transport_restart_stream(handle: *mut Handle) -> i32 {
+    let Some(handle) = lookup(handle) else {
+        return -1;
+    };
+    let guard = match handle.connection.lock() {
+        Ok(g) => g,
+        Err(_) => return -1,
+    };
+    match guard.as_ref().map(|c| c.restart_stream()) {
+        Some(Ok(())) => 0,
+        _ => -1,
+    }
+}
+
+/// Returns 1 if the live stream is TLS (direct TLS, after StartTLS, or wss), else 0.
+/// With XEP-0368 a DirectTls config may connect to a StartTLS target, so Dart checks this
+/// to decide whether to negotiate StartTLS.
+#[no_mangle]
+pub unsafe extern "C" fn whixp_transport_is_secure(handle: *mut Handle) -> i32 {
+    let Some(handle) = lookup(handle) else {
+        return 0;
+    };
+    let secure = match handle.connection.lock() {
+        Ok(guard) => guard.as_ref().map(|c| c.is_secure() as i32).unwrap_or(0),
+        Err(_) => 0,
+    };
+    secure
+}
+
+/// Channel binding type bits for whixp_transport_channel_binding_types / _channel_binding.
+fn channel_binding_bit(kind: ChannelBinding) -> i32 {
+    match kind {
+        ChannelBinding::TlsExporter => 1,
+        ChannelBinding::TlsServerEndPoint => 2,
+    }
+}
+
+/// Channel binding types the live TLS stream offers, as bits: 1 = tls-exporter (RFC 9266,
+/// TLS 1.3 only), 2 = tls-server-end-point (RFC 5929). 0 when not TLS or not connected.
+#[no_mangle]
+pub unsafe extern "C" fn whixp_transport_channel_binding_types(handle: *mut Handle) -> i32 {
+    let Some(handle) = lookup(handle) else {
+        return 0;
+    };
+    let types = match handle.connection.lock() {
+        Ok(guard) => guard
+            .as_ref()
+            .map(|c| {
+                c.channel_bindings()
+                    .into_iter()
+                    .map(channel_binding_bit)
+                    .sum()
+            })
+            .unwrap_or(0),
+        Err(_) => 0,
+    };
+    types
+}
+
+/// Channel binding data for SCRAM-PLUS: kind = 1 (tls-exporter) or 2 (tls-server-end-point).
+/// Works on direct TLS, upgraded StartTLS and wss streams. Returns 0 and sets out_ptr/out_len
+/// (valid until the next call or destroy), or -1 (see whixp_transport_get_last_error).
+#[no_mangle]
+pub unsafe extern "C" fn whixp_transport_channel_binding(
+    handle: *mut Handle,
+    kind: i32,
+    out_ptr: *mut *const u8,
+    out_len: *mut u32,
+) -> i32 {
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        if out_ptr.is_null() || out_len.is_null() {
+            return -1;
+        }
+        let Some(handle_ref) = lookup(handle) else {
+            return -1;
+        };
+        let kind = match kind {
+            1 => ChannelBinding::TlsExporter,
+            2 => ChannelBinding::TlsServerEndPoint,
+            _ => return -1,
+        };
+        let data = match handle_ref.connection.lock() {
+            Ok(guard) => match guard.as_ref() {
+                Some(c) => c.channel_binding(kind),
+                None => return -1,
+            },
+            Err(_) => return -1,
+        };
+        match data {
+            Ok(data) => {
+                let Ok(mut buf) = handle_ref.channel_binding.lock() else {
+                    return -1;
+                };
+                *buf = data;
+                *out_ptr = buf.as_ptr();
+                *out_len = buf.len() as u32;
+                0
+            }
+            Err(e) => {
+                if let Ok(mut last_err) = handle_ref.last_error.lock() {
+                    *last_err = Some(e.to_string());
+                }
+                -1
+            }
+        }
+    }));
+    result.unwrap_or(-1)
+}
+
+/// Negotiated TLS details of the live stream as JSON: version, cipher_suite, cipher_suite_id,
+/// kx_group, alpn, sni, resumed, and peer_certificates (leaf first: der base64, sha256,
+/// spki_sha256, subject, issuer, sans, not_before, not_after). Returns 0 and sets
+/// out_ptr/out_len (valid until the next call or destroy), or -1 if the stream is not TLS.
+#[no_mangle]
+pub unsafe extern "C" fn whixp_transport_tls_info(
+    handle: *mut Handle,
+    out_ptr: *mut *const u8,
+    out_len: *mut u32,
+) -> i32 {
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        if out_ptr.is_null() || out_len.is_null() {
+            return -1;
+        }
+        let Some(handle_ref) = lookup(handle) else {
+            return -1;
+        };
+        let info = match handle_ref.connection.lock() {
+            Ok(guard) => guard.as_ref().and_then(|c| c.tls_info()),
+            Err(_) => None,
+        };
+        let (Some(info), Ok(mut buf)) = (info, handle_ref.tls_info.lock()) else {
+            return -1;
+        };
+        *buf = info.to_json();
+        *out_ptr = buf.as_ptr();
+        *out_len = buf.len() as u32;
+        0
+    }));
+    result.unwrap_or(-1)
+}
+
+/// WebSocket upgrade response headers of the live stream as a JSON object mapping each
+/// lowercase header name to its values. Returns 0 and sets out_ptr/out_len (valid until the
+/// next call or destroy), or -1 if the stream is not a WebSocket.
+#[no_mangle]
+pub unsafe extern "C" fn whixp_transport_ws_response_headers(
+    handle: *mut Handle,
+    out_ptr: *mut *const u8,
+    out_len: *mut u32,
+) -> i32 {
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        if out_ptr.is_null() || out_len.is_null() {
+            return -1;
+        }
+        let Some(handle_ref) = lookup(handle) else {
+            return -1;
+        };
+        let headers = match handle_ref.connection.lock() {
+            Ok(guard) => guard.as_ref().and_then(|c| c.ws_response_headers()),
+            Err(_) => None,
+        };
+        let (Some(headers), Ok(mut buf)) = (headers, handle_ref.ws_response_headers.lock()) else {
+            return -1;
+        };
+        let mut map: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (name, value) in headers {
+            map.entry(name).or_default().push(value);
+        }
+        *buf = serde_json::to_string(&map).unwrap_or_default();
+        *out_ptr = buf.as_ptr();
+        *out_len = buf.len() as u32;
+        0
+    }));
+    result.unwrap_or(-1)
+}
+
+/// Answer a paused handshake (poll code 5): accept = 1 continues it with the presented
+/// certificate, 0 aborts it with HandshakeErrorCode::BadCertificate. Safe to call from any
+/// thread. Returns 0, or -1 if no handshake is waiting.
+#[no_mangle]
+pub unsafe extern "C" fn whixp_transport_trust_decision(handle: *mut Handle, accept: i32) -> i32 {
+    let Some(handle) = lookup(handle) else {
+        return -1;
+    };
+    if handle.trust.decide(accept != 0) {
+        0
+    } else {
+        -1
+    }
+}
+
+/// Resolve the handle's targets into the process-wide DNS cache without connecting (blocking).
+/// Call before whixp_transport_connect; blocks while a connect is running on this handle.
+/// Returns the number of targets, or -1 on failure (see whixp_transport_get_last_error).
+#[no_mangle]
+pub unsafe extern "C" fn whixp_transport_dns_warm(handle: *mut Handle) -> i32 {
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let Some(handle_ref) = lookup(handle) else {
+            return -1;
+        };
+        let guard = match handle_ref.connection.lock() {
+            Ok(g) => g,
+            Err(_) => return -1,
+        };
+        let conn = match guard.as_ref() {
+            Some(c) => c,
+            None => return -1,
+        };
+        match conn.warm_dns() {
+            Ok(n) => n as i32,
+            Err(e) => {
+                if let Ok(mut last_err) = handle_ref.last_error.lock() {
+                    *last_err = Some(e.to_string());
+                }
+                -1
+            }
+        }
+    }));
+    result.unwrap_or(-1)
+}
+
+/// Drop every cached DNS answer (all handles), e.g. after a network change.
+#[no_mangle]
+pub extern "C" fn whixp_transport_dns_flush() {
+    dns::flush();
+}
+
+/// Push mode callback: called with its user data when the handle's event queue goes from
+/// empty to non-empty. Runs on a native I/O thread; it should only signal (e.g. post to a
+/// port or wake a loop) and must not call into the transport.
+pub type NotifyCallback = extern "C" fn(user_data: *mut c_void);
+
+/// Register (or with a null callback, remove) the push mode callback; safe while I/O runs.
+/// Once this returns the previous callback is not running and is never called again.
+/// Events already queued do not fire it: drain once after registering. Returns 0, or -1
+/// for a null handle.
+#[no_mangle]
+pub unsafe extern "C" fn whixp_transport_set_notify(
+    handle: *mut Handle,
+    callback: Option<NotifyCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    let Some(handle) = lookup(handle) else {
+        return -1;
+    };
+    let user_data = user_data as usize;
+    let hook = callback
+        .map(|cb| -> Box<dyn Fn() + Send> { Box::new(move || cb(user_data as *mut c_void)) });
+    handle.notifier.set(hook);
+    0
+}
+
+/// Set the number of shared I/O worker threads driving all connections (0 = one per core,
+/// at most 4). Call before the first connect. Returns 0, or -1 if the runtime already runs
+/// with a different count.
+#[no_mangle]
+pub extern "C" fn whixp_transport_set_worker_threads(count: u32) -> i32 {
+    if runtime::configure(count as usize) {
+        0
+    } else {
+        -1
+    }
+}
+
+/// Free a line passed to the key log callback.
+#[no_mangle]
+pub unsafe extern "C" fn whixp_transport_key_log_free(line: *mut c_char) {
+    if !line.is_null() {
+        drop(CString::from_raw(line));
+    }
+}
+
 /// Disconnect and close socket.
 #[no_mangle]
 pub unsafe extern "C" fn whixp_transport_disconnect(handle: *mut Handle) {
-    if handle.is_null() {
+    let Some(handle) = lookup(handle) else {
         return;
-    }
-    let handle = &*handle;
-    if let Ok(guard) = handle.connection.lock() {
-        if let Some(conn) = guard.as_ref() {
-            conn.shutdown();
-        }
+    };
+    handle.closer.close();
+    // Waits out a connect or StartTLS in progress, which the closer has already failed, so
+    // a connection it just opened is shut down too.
+    let guard = match handle.connection.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    if let Some(conn) = guard.as_ref() {
+        conn.shutdown();
     }
 }
 
@@ -304,10 +1089,13 @@ pub unsafe extern "C" fn whixp_transport_get_resolved_host(
     out_ptr: *mut *const u8,
     out_len: *mut u32,
 ) {
-    if handle.is_null() || out_ptr.is_null() || out_len.is_null() {
+    if out_ptr.is_null() || out_len.is_null() {
         return;
     }
-    if let Ok(guard) = (*handle).resolved_host.lock() {
+    let Some(handle) = lookup(handle) else {
+        return;
+    };
+    if let Ok(guard) = handle.resolved_host.lock() {
         if let Some(ref s) = *guard {
             *out_ptr = s.as_ptr();
             *out_len = s.len() as u32;
@@ -315,20 +1103,23 @@ pub unsafe extern "C" fn whixp_transport_get_resolved_host(
             *out_ptr = std::ptr::null();
             *out_len = 0;
         }
-    }
+    };
 }
 
-/// Get last connect error message (when connect returned non-OK). Ptr valid until next connect or destroy.
+/// Get last connect or StartTLS error message (when connect/starttls returned non-OK). Ptr valid until next connect or destroy.
 #[no_mangle]
 pub unsafe extern "C" fn whixp_transport_get_last_error(
     handle: *mut Handle,
     out_ptr: *mut *const u8,
     out_len: *mut u32,
 ) {
-    if handle.is_null() || out_ptr.is_null() || out_len.is_null() {
+    if out_ptr.is_null() || out_len.is_null() {
         return;
     }
-    if let Ok(guard) = (*handle).last_error.lock() {
+    let Some(handle) = lookup(handle) else {
+        return;
+    };
+    if let Ok(guard) = handle.last_error.lock() {
         if let Some(ref s) = *guard {
             *out_ptr = s.as_ptr();
             *out_len = s.len() as u32;
@@ -336,13 +1127,35 @@ pub unsafe extern "C" fn whixp_transport_get_last_error(
             *out_ptr = std::ptr::null();
             *out_len = 0;
         }
-    }
+    };
 }
 
-/// Destroy handle and free memory. Call after disconnect.
+/// Destroy handle: close the connection, wait (up to 2 s) for its task and any reconnect
+/// dial to stop, then free it. Later calls with this handle, including destroy, do nothing.
 #[no_mangle]
 pub unsafe extern "C" fn whixp_transport_destroy(handle: *mut Handle) {
-    if !handle.is_null() {
-        let _ = Box::from_raw(handle);
-    }
+    let Some(handle) = HANDLES.remove(handle as usize) else {
+        return;
+    };
+    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        handle.notifier.set(None);
+        handle.closer.close();
+        // Waits out a connect or StartTLS in progress; the closed socket makes that quick.
+        let conn = match handle.connection.lock() {
+            Ok(mut guard) => guard.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        };
+        if let Some(conn) = conn {
+            conn.shutdown();
+            if !conn.join(DESTROY_JOIN_TIMEOUT) {
+                eprintln!("[Whixp] destroy: connection did not stop in time");
+            }
+        }
+    }));
+    // Freed here, or when the last call still using it returns.
+}
+
+/// Live handle behind an FFI token, or None if it was destroyed (or never existed).
+fn lookup(handle: *mut Handle) -> Option<Arc<Handle>> {
+    HANDLES.get(handle as usize)
 }
@@ -1,6 +1,8 @@
 //! Retry / reconnection policy: backoff, max attempts.
 //! Config is supplied from Dart; this module applies it on connection failure.
 
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::time::Duration;
 use tracing::debug;
 
@@ -12,6 +14,9 @@ pub struct RetryPolicy {
     pub initial_delay_ms: u64,
     pub max_delay_ms: u64,
     pub multiplier: f64,
+    /// Fraction of each delay that is randomized (0.0 = none, 0.5 = +/-50%), so many
+    /// clients dropped at once do not reconnect in lockstep.
+    pub jitter: f64,
 }
 
 impl Default for RetryPolicy {
@@ -21,6 +26,7 @@ impl Default for RetryPolicy {
             initial_delay_ms: 500,
             max_delay_ms: 60_000,
             multiplier: 2.0,
+            jitter: 0.0,
         }
     }
 }
@@ -38,6 +44,28 @@ impl RetryPolicy {
     pub fn should_retry(&self, attempt: u32) -> bool {
         attempt < self.max_attempts
     }
+
+    /// Spread `delay` by +/- `jitter`; `unit` is a random value in [0, 1).
+    pub fn apply_jitter(&self, delay: Duration, unit: f64) -> Duration {
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return delay;
+        }
+        let factor = 1.0 + jitter * (2.0 * unit - 1.0);
+        Duration::from_millis((delay.as_millis() as f64 * factor) as u64)
+    }
+}
+
+/// Random value in [0, 1) from the std hasher's per-process random keys (no rand dependency).
+pub fn random_unit() -> f64 {
+    let mut h = RandomState::new().build_hasher();
+    h.write_u64(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0),
+    );
+    (h.finish() >> 11) as f64 / (1u64 << 53) as f64
 }
 
 /// Called when a connection attempt fails; returns next delay or None to stop.
@@ -45,7 +73,7 @@ pub fn next_retry_delay(policy: &RetryPolicy, attempt: u32) -> Option<Duration>
     if !policy.should_retry(attempt) {
         return None;
     }
-    let delay = policy.delay_for_attempt(attempt);
+    let delay = policy.apply_jitter(policy.delay_for_attempt(attempt), random_unit());
     debug!(attempt, delay_ms = delay.as_millis(), "retry scheduled");
     Some(delay)
 }
@@ -0,0 +1,322 @@
+//! Shared native runtime: a fixed pool of I/O worker threads drives every connection.
+//! Each worker sleeps on one poller (`polling`: epoll, kqueue or IOCP) for all sockets
+//! assigned to it, so a connection costs its buffers and a table slot, not threads.
+//!
+//! Work that blocks never runs on a worker. Connecting (DNS, TCP, handshakes) blocks the
+//! thread that calls connect or StartTLS. Reconnect dials and stale DNS cache refreshes go
+//! to a bounded pool of helper threads (`spawn_blocking`): at most `HELPERS_MAX` run at
+//! once, more jobs queue, and idle helpers exit. Reconnect backoff is a task timer, not a
+//! sleeping thread. DNS lookups block on a current-thread tokio runtime with no threads of
+//! its own, driven by whichever thread is looking up. Happy Eyeballs races its connects on
+//! the calling thread.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::net::TcpStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use polling::{Event, Events, Poller};
+
+/// Default worker count: one per core, at most this many.
+const DEFAULT_WORKERS_MAX: usize = 4;
+
+/// Requested worker count (0 = default); read when the runtime starts.
+static WORKERS: AtomicUsize = AtomicUsize::new(0);
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+static DNS: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+static HELPERS: OnceLock<Helpers> = OnceLock::new();
+
+/// Most helper threads running blocking jobs at once; further jobs wait in the queue.
+const HELPERS_MAX: usize = 8;
+/// A helper with no job for this long exits.
+const HELPER_IDLE: Duration = Duration::from_secs(10);
+
+/// Set the number of I/O worker threads (0 = one per core, at most 4). Takes effect when the
+/// runtime starts, on the first connect. False if it already runs with another count.
+pub fn configure(workers: usize) -> bool {
+    WORKERS.store(workers, Ordering::SeqCst);
+    RUNTIME
+        .get()
+        .is_none_or(|rt| workers == 0 || rt.workers.len() == workers)
+}
+
+/// Number of worker threads (starts the runtime).
+pub fn worker_count() -> usize {
+    runtime().workers.len()
+}
+
+/// Block the calling thread on `future` (DNS). Concurrent callers take turns driving the
+/// runtime's I/O; no threads of its own.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    DNS.get_or_init(|| {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("tokio runtime for DNS")
+    })
+    .block_on(future)
+}
+
+/// Run `job` on a helper thread (blocking work such as a reconnect dial). Starts a helper
+/// when none is idle and fewer than `HELPERS_MAX` exist; otherwise the job queues.
+pub fn spawn_blocking(job: impl FnOnce() + Send + 'static) {
+    let helpers = HELPERS.get_or_init(|| Helpers {
+        state: Mutex::new(HelperState::default()),
+        ready: Condvar::new(),
+    });
+    let mut state = helpers.lock();
+    state.queue.push_back(Box::new(job));
+    if state.queue.len() > state.idle && state.threads < HELPERS_MAX {
+        state.threads += 1;
+        thread::Builder::new()
+            .name("whixp-helper".to_string())
+            .spawn(move || helpers.run())
+            .expect("spawn helper thread");
+    } else {
+        helpers.ready.notify_one();
+    }
+}
+
+struct Helpers {
+    state: Mutex<HelperState>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct HelperState {
+    queue: VecDeque<Box<dyn FnOnce() + Send>>,
+    threads: usize,
+    /// Helpers waiting for a job.
+    idle: usize,
+}
+
+impl Helpers {
+    fn lock(&self) -> MutexGuard<'_, HelperState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn run(&self) {
+        let mut state = self.lock();
+        loop {
+            if let Some(job) = state.queue.pop_front() {
+                drop(state);
+                if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    eprintln!("[Whixp] helper job panicked");
+                }
+                state = self.lock();
+                continue;
+            }
+            state.idle += 1;
+            let (guard, waited) = self
+                .ready
+                .wait_timeout(state, HELPER_IDLE)
+                .unwrap_or_else(|e| e.into_inner());
+            state = guard;
+            state.idle -= 1;
+            if waited.timed_out() && state.queue.is_empty() {
+                state.threads -= 1;
+                return;
+            }
+        }
+    }
+}
+
+/// What a task wants after being serviced.
+pub enum Step {
+    /// Sleep until the socket is ready, the task is woken, or `timer` is due. `busy`:
+    /// service again without waiting (input was left unread).
+    Wait { timer: Option<Instant>, busy: bool },
+    /// Remove the task. Its socket must already be deleted from the poller.
+    Done,
+}
+
+/// A connection driven by a worker.
+pub trait Task: Send {
+    /// Called once when spawned, then when its socket is ready, it is woken, or its timer
+    /// is due. Must not block.
+    fn service(&mut self) -> Step;
+
+    /// `service` panicked and the task is about to be dropped: release its socket and tell
+    /// whoever consumes its events. Must not block.
+    fn abandoned(&mut self) {}
+}
+
+/// A task's place in the runtime, usable from any thread.
+#[derive(Clone)]
+pub struct Registration {
+    worker: Arc<Worker>,
+    key: usize,
+}
+
+impl Registration {
+    /// Have the worker service the task soon.
+    pub fn wake(&self) {
+        self.worker.inbox().woken.push(self.key);
+        let _ = self.worker.poller.notify();
+    }
+
+    /// Watch `socket` for reads (oneshot; re-armed by `arm`).
+    ///
+    /// # Safety
+    /// `socket` must be deleted (`delete`) before it is dropped.
+    pub unsafe fn add(&self, socket: &TcpStream) -> io::Result<()> {
+        self.worker.poller.add(socket, Event::readable(self.key))
+    }
+
+    /// Re-arm `socket` for reads, and writes when `writable`.
+    pub fn arm(&self, socket: &TcpStream, writable: bool) -> io::Result<()> {
+        self.worker
+            .poller
+            .modify(socket, Event::new(self.key, true, writable))
+    }
+
+    pub fn delete(&self, socket: &TcpStream) {
+        let _ = self.worker.poller.delete(socket);
+    }
+}
+
+/// Hand a task to the next worker (round robin). `make` gets the task's registration.
+pub fn spawn<T: Task + 'static>(make: impl FnOnce(Registration) -> T) -> Registration {
+    let rt = runtime();
+    let n = rt.next.fetch_add(1, Ordering::Relaxed);
+    let worker = Arc::clone(&rt.workers[n % rt.workers.len()]);
+    let reg = Registration {
+        worker: Arc::clone(&worker),
+        key: rt.next_key.fetch_add(1, Ordering::Relaxed),
+    };
+    let task = Box::new(make(reg.clone()));
+    worker.inbox().added.push((reg.key, task));
+    let _ = worker.poller.notify();
+    reg
+}
+
+struct Runtime {
+    workers: Vec<Arc<Worker>>,
+    /// Round-robin position for `spawn`.
+    next: AtomicUsize,
+    next_key: AtomicUsize,
+}
+
+fn runtime() -> &'static Runtime {
+    RUNTIME.get_or_init(|| {
+        let count = match WORKERS.load(Ordering::SeqCst) {
+            0 => thread::available_parallelism()
+                .map_or(1, |n| n.get())
+                .min(DEFAULT_WORKERS_MAX),
+            n => n,
+        };
+        let workers = (0..count)
+            .map(|i| {
+                let worker = Arc::new(Worker {
+                    poller: Poller::new().expect("poller for I/O worker"),
+                    inbox: Mutex::new(Inbox::default()),
+                });
+                let run = Arc::clone(&worker);
+                thread::Builder::new()
+                    .name(format!("whixp-io-{}", i))
+                    .spawn(move || run.run())
+                    .expect("spawn I/O worker");
+                worker
+            })
+            .collect();
+        Runtime {
+            workers,
+            next: AtomicUsize::new(0),
+            next_key: AtomicUsize::new(0),
+        }
+    })
+}
+
+struct Worker {
+    poller: Poller,
+    inbox: Mutex<Inbox>,
+}
+
+/// Handed to a worker from other threads.
+#[derive(Default)]
+struct Inbox {
+    added: Vec<(usize, Box<dyn Task>)>,
+    woken: Vec<usize>,
+}
+
+struct Slot {
+    task: Box<dyn Task>,
+    timer: Option<Instant>,
+    busy: bool,
+}
+
+impl Worker {
+    fn inbox(&self) -> MutexGuard<'_, Inbox> {
+        self.inbox.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn run(&self) {
+        let mut tasks: HashMap<usize, Slot> = HashMap::new();
+        let mut events = Events::new();
+        let mut due: Vec<usize> = Vec::new();
+        loop {
+            let inbox = std::mem::take(&mut *self.inbox());
+            for (key, task) in inbox.added {
+                let slot = Slot {
+                    task,
+                    timer: None,
+                    busy: true,
+                };
+                tasks.insert(key, slot);
+            }
+            due.extend(inbox.woken);
+            due.extend(events.iter().map(|e| e.key));
+            let now = Instant::now();
+            due.extend(
+                tasks
+                    .iter()
+                    .filter(|(_, s)| s.busy || s.timer.is_some_and(|t| t <= now))
+                    .map(|(key, _)| *key),
+            );
+            due.sort_unstable();
+            due.dedup();
+            for key in due.drain(..) {
+                let Some(slot) = tasks.get_mut(&key) else {
+                    continue;
+                };
+                match panic::catch_unwind(AssertUnwindSafe(|| slot.task.service())) {
+                    Ok(Step::Wait { timer, busy }) => {
+                        slot.timer = timer;
+                        slot.busy = busy;
+                    }
+                    Ok(Step::Done) => {
+                        tasks.remove(&key);
+                    }
+                    Err(_) => {
+                        eprintln!("[Whixp] connection task panicked, dropped");
+                        let _ = panic::catch_unwind(AssertUnwindSafe(|| slot.task.abandoned()));
+                        tasks.remove(&key);
+                    }
+                }
+            }
+
+            let timeout = if tasks.values().any(|s| s.busy) {
+                Some(Duration::ZERO)
+            } else {
+                let now = Instant::now();
+                tasks
+                    .values()
+                    .filter_map(|s| s.timer)
+                    .min()
+                    .map(|t| t.saturating_duration_since(now))
+            };
+            events.clear();
+            if let Err(e) = self.poller.wait(&mut events, timeout) {
+                if e.kind() != io::ErrorKind::Interrupted {
+                    eprintln!("[Whixp] poll failed: {}", e);
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+        }
+    }
+}
@@ -1,8 +1,9 @@
 //! Stanza processing: stream framing (split bytes into XML stanzas) and optional parsing.
 //! Emits one stanza at a time to Dart via callback (UTF-8 XML string or structured).
 //!
-//! Covers RFC 6120/6121 (core, stream, SASL), XEP-0198 (stream management), TLS, and
-//! common XEPs so we don't add stanza types one-by-one.
+//! Framing is element-depth based, so any top-level element (RFC 6120/6121 stanzas, SASL,
+//! TLS, XEP-0198, ...) is split correctly without a list of known tag names, including
+//! stanzas that nest other stanzas (MAM results, carbons, `<forwarded/>`).
 
 use thiserror::Error;
 
@@ -14,158 +15,321 @@ pub enum StanzaError {
     Parse(String),
 }
 
-/// Buffers incoming bytes and splits on stanza boundaries (closing tags and self-closing root elements).
+/// One framed unit of the incoming stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Frame {
+    /// Opening `<stream:stream ...>` tag (with XML declaration, if sent). Every stream
+    /// restart (after StartTLS or SASL success) produces a new one.
+    StreamHeader(String),
+    /// Complete top-level element, or the `</stream:stream>` footer.
+    Stanza(String),
+    /// RFC 7395 `<open/>` (WebSocket framing only).
+    Open(String),
+    /// RFC 7395 `<close/>` without a redirect (WebSocket framing only).
+    Close(String),
+    /// URI from an RFC 7395 `<close see-other-host='...'/>` (WebSocket framing only).
+    SeeOtherHost(String),
+}
+
+/// Markup construct the tokenizer is currently inside. Positions are buffer offsets of the `<`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Token {
+    /// Character data between tags.
+    Text,
+    /// `<name ...>` or `<name .../>`; `quote` is the open attribute quote, if any.
+    StartTag { start: usize, quote: Option<u8> },
+    /// `</name>`.
+    EndTag { start: usize },
+    /// `<!-- ... -->`.
+    Comment { start: usize },
+    /// `<![CDATA[ ... ]]>`.
+    CData { start: usize },
+    /// `<? ... ?>` (XML declaration or processing instruction).
+    Pi { start: usize },
+    /// `<!DOCTYPE ...>` and other declarations.
+    Decl { start: usize },
+}
+
+/// Buffers incoming bytes and splits on stanza boundaries by tracking element depth from the
+/// stream root. Depth 0 is outside `<stream:stream>`; top-level elements (stanzas) open at
+/// depth 1. Without a stream root (e.g. WebSocket framing) elements at depth 0 are stanzas.
 /// Handles stream header in Rust for stream:error and see-other-host.
 pub struct StreamFramer {
     buffer: Vec<u8>,
     depth: i32,
     in_stream_header: bool,
+    /// Offset up to which the buffer has been tokenized.
+    scan: usize,
+    token: Token,
+    /// Offset where the current top-level chunk (stanza or stream header) begins.
+    chunk_start: Option<usize>,
+    /// A stream header was framed and no stanza followed yet (restart already happened).
+    fresh_stream: bool,
 }
 
-/// All known top-level closing tags (RFC 6120, 6121, SASL, TLS, XEP-0198, stream errors, etc.).
-/// Order doesn't matter for correctness; we take the leftmost match.
-const CLOSE_TAGS: &[&str] = &[
-    // Core stanzas (RFC 6120, 6121)
-    "</iq>",
-    "</message>",
-    "</presence>",
-    // Stream (RFC 6120)
-    "</stream:features>",
-    "</stream:stream>",
-    "</stream>",
-    "</stream:error>",
-    "</error>",
-    // SASL (RFC 6120)
-    "</challenge>",
-    "</success>",
-    "</failure>",
-    "</auth>",
-    "</response>",
-    "</abort>",
-    // TLS (XEP-0035 / RFC 6120)
-    "</starttls>",
-    "</proceed>",
-    // Stream management (XEP-0198)
-    "</failed>",
-    "</enabled>",
-    "</resumed>",
-    "</enable>",
-    "</resume>",
-    // Bind / session (RFC 6120)
-    "</bind>",
-    "</session>",
-    // Common XEPs
-    "</handshake>", // component protocol
-    "</body>",      // BOSH
-    "</open>",      // BOSH
-    "</close>",     // BOSH
-];
-
-/// Root-level elements that are typically self-closing: <r/> <a/> <enabled/> <proceed/> etc.
-/// We look for "<NAME " or "<NAME>" then the matching "/>".
-const SELF_CLOSING_ROOT: &[&str] = &[
-    "r", "a", // XEP-0198 ack request/response
-    "enabled", "failed", "resumed", "enable", "resume",  // XEP-0198
-    "proceed", // TLS
-];
-
 impl Default for StreamFramer {
     fn default() -> Self {
         Self {
             buffer: Vec::new(),
             depth: 0,
             in_stream_header: true,
+            scan: 0,
+            token: Token::Text,
+            chunk_start: None,
+            fresh_stream: false,
+        }
+    }
+}
+
+/// Classify the markup starting at `b[0] == b'<'`. Returns None if more bytes are needed.
+fn classify(b: &[u8], start: usize) -> Option<Token> {
+    const COMMENT: &[u8] = b"<!--";
+    const CDATA: &[u8] = b"<![CDATA[";
+    match b.get(1)? {
+        b'/' => Some(Token::EndTag { start }),
+        b'?' => Some(Token::Pi { start }),
+        b'!' => {
+            if b.starts_with(COMMENT) {
+                Some(Token::Comment { start })
+            } else if b.starts_with(CDATA) {
+                Some(Token::CData { start })
+            } else if COMMENT.starts_with(b) || CDATA.starts_with(b) {
+                None
+            } else {
+                Some(Token::Decl { start })
+            }
         }
+        _ => Some(Token::StartTag { start, quote: None }),
     }
 }
 
+/// Element name of a start or end tag beginning at `tag` (`<name` or `</name`).
+fn tag_name(tag: &[u8]) -> &[u8] {
+    let from = if tag.get(1) == Some(&b'/') { 2 } else { 1 };
+    let rest = &tag[from.min(tag.len())..];
+    let end = rest
+        .iter()
+        .position(|c| c.is_ascii_whitespace() || *c == b'/' || *c == b'>')
+        .unwrap_or(rest.len());
+    &rest[..end]
+}
+
+/// `<stream:stream>` (any prefix) or unprefixed `<stream>`.
+fn is_stream_root(name: &[u8]) -> bool {
+    name == b"stream" || name.ends_with(b":stream")
+}
+
 impl StreamFramer {
     pub fn new() -> Self {
         Self::default()
     }
 
-    /// Push bytes; returns completed stanza XML strings (UTF-8).
-    pub fn push(&mut self, data: &[u8]) -> Result<Vec<String>, StanzaError> {
+    /// Push bytes; returns completed frames (stream headers and stanza XML strings, UTF-8).
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<Frame>, StanzaError> {
         self.buffer.extend(data);
         let mut out = Vec::new();
-        loop {
-            match self.take_next_stanza()? {
-                Some(s) => out.push(s),
-                None => break,
-            }
+        while let Some(s) = self.take_next_stanza()? {
+            out.push(s);
         }
+        self.compact();
         Ok(out)
     }
 
-    /// Try to extract one full stanza from buffer. Returns None if incomplete.
-    fn take_next_stanza(&mut self) -> Result<Option<String>, StanzaError> {
-        let s = match std::str::from_utf8(&self.buffer) {
-            Ok(x) => x,
-            Err(_) => return Ok(None),
-        };
+    /// Depth at which top-level elements open: 1 inside a stream root, 0 without one.
+    fn stanza_depth(&self) -> i32 {
+        if self.in_stream_header {
+            0
+        } else {
+            1
+        }
+    }
 
-        // 1) Leftmost closing tag
-        let mut best_end: Option<usize> = None;
-        for tag in CLOSE_TAGS {
-            if let Some(idx) = s.find(tag) {
-                let end = idx + tag.len();
-                if best_end.map(|e| end < e).unwrap_or(true) {
-                    best_end = Some(end);
+    /// Tokenize from `scan` until one full top-level element (or stream header / footer) is
+    /// complete. Returns None if the buffer ends first; state is kept for the next push.
+    fn take_next_stanza(&mut self) -> Result<Option<Frame>, StanzaError> {
+        while self.scan < self.buffer.len() {
+            let i = self.scan;
+            let c = self.buffer[i];
+            match self.token {
+                Token::Text => {
+                    if c == b'<' {
+                        match classify(&self.buffer[i..], i) {
+                            Some(t) => self.token = t,
+                            None => return Ok(None),
+                        }
+                    }
+                }
+                Token::StartTag { start, quote } => match (quote, c) {
+                    (Some(q), _) if c == q => {
+                        self.token = Token::StartTag { start, quote: None };
+                    }
+                    (Some(_), _) => {}
+                    (None, b'"') | (None, b'\'') => {
+                        self.token = Token::StartTag {
+                            start,
+                            quote: Some(c),
+                        };
+                    }
+                    (None, b'>') => {
+                        self.token = Token::Text;
+                        self.scan = i + 1;
+                        let self_closing = self.buffer[i - 1] == b'/';
+                        if let Some(chunk) = self.on_start_tag(start, i + 1, self_closing) {
+                            return Ok(Some(chunk));
+                        }
+                        continue;
+                    }
+                    _ => {}
+                },
+                Token::EndTag { start } => {
+                    if c == b'>' {
+                        self.token = Token::Text;
+                        self.scan = i + 1;
+                        if let Some(chunk) = self.on_end_tag(start, i + 1) {
+                            return Ok(Some(chunk));
+                        }
+                        continue;
+                    }
+                }
+                Token::Comment { start } => {
+                    if c == b'>' && i >= start + 6 && &self.buffer[i - 2..i] == b"--" {
+                        self.token = Token::Text;
+                    }
+                }
+                Token::CData { start } => {
+                    if c == b'>' && i >= start + 11 && &self.buffer[i - 2..i] == b"]]" {
+                        self.token = Token::Text;
+                    }
+                }
+                Token::Pi { start } => {
+                    if c == b'>' && i >= start + 3 && self.buffer[i - 1] == b'?' {
+                        self.token = Token::Text;
+                        // Keep the XML declaration together with the stream header.
+                        if self.depth == 0 && self.in_stream_header && self.chunk_start.is_none() {
+                            self.chunk_start = Some(start);
+                        }
+                    }
+                }
+                Token::Decl { .. } => {
+                    if c == b'>' {
+                        self.token = Token::Text;
+                    }
                 }
             }
+            self.scan = i + 1;
         }
+        Ok(None)
+    }
 
-        // 2) Leftmost self-closing root element
-        for name in SELF_CLOSING_ROOT {
-            let open1 = format!("<{} ", name);
-            let open2 = format!("<{}>", name);
-            let start = s.find(&open1).or_else(|| s.find(&open2));
-            if let Some(start) = start {
-                let after_open = start + open1.len().max(open2.len());
-                if let Some(rel) = s[after_open..].find("/>") {
-                    let end = after_open + rel + 2;
-                    if best_end.map(|e| end < e).unwrap_or(true) {
-                        best_end = Some(end);
-                    }
-                }
+    /// A start tag ended at `end`. Returns a frame if it completed a top-level element or
+    /// was a stream header. A stream header seen where a stanza would start is a restart
+    /// by the server, so it is framed even if `restart` was not called.
+    fn on_start_tag(&mut self, start: usize, end: usize, self_closing: bool) -> Option<Frame> {
+        let level = self.stanza_depth();
+        if self.depth == level {
+            if self.chunk_start.is_none() || self.depth > 0 {
+                self.chunk_start = Some(start);
+            }
+            if !self_closing && is_stream_root(tag_name(&self.buffer[start..end])) {
+                self.in_stream_header = false;
+                self.depth = 1;
+                self.fresh_stream = true;
+                return Some(Frame::StreamHeader(self.take_chunk(end)));
             }
+            if self_closing {
+                self.fresh_stream = false;
+                return Some(Frame::Stanza(self.take_chunk(end)));
+            }
+        }
+        if !self_closing {
+            self.depth += 1;
         }
+        None
+    }
 
-        if let Some(end) = best_end {
-            let chunk = s[..end].to_string();
-            self.buffer.drain(..end);
-            return Ok(Some(chunk));
+    /// An end tag ended at `end`. Returns a chunk if it closed a top-level element or the
+    /// stream root (`</stream:stream>` is emitted on its own).
+    fn on_end_tag(&mut self, start: usize, end: usize) -> Option<Frame> {
+        let level = self.stanza_depth();
+        if self.depth == 0 {
+            // Stray end tag outside any element; nothing to close.
+            return None;
+        }
+        if self.depth == level {
+            // Closing the stream root itself.
+            self.depth = 0;
+            self.in_stream_header = true;
+            self.chunk_start = Some(start);
+            self.fresh_stream = false;
+            return Some(Frame::Stanza(self.take_chunk(end)));
         }
+        self.depth -= 1;
+        if self.depth == level {
+            self.fresh_stream = false;
+            return Some(Frame::Stanza(self.take_chunk(end)));
+        }
+        None
+    }
 
-        // 3) Stream header: optional <?xml ...?> then <stream:stream ...> or <stream ...>
-        if self.in_stream_header {
-            let rest = s.trim_start();
-            let rest = if rest.starts_with("<?xml") {
-                rest.find('>')
-                    .map(|i| rest[i + 1..].trim_start())
-                    .unwrap_or(rest)
-            } else {
-                rest
-            };
-            if rest.starts_with("<stream:stream") || rest.starts_with("<stream ") {
-                if let Some(open_brace) = rest.find('<') {
-                    let from = s.len() - rest.len() + open_brace;
-                    if let Some(end) = s[from..].find('>') {
-                        let end = from + end;
-                        let chunk = s[..=end].to_string();
-                        self.buffer.drain(..=end);
-                        self.in_stream_header = false;
-                        return Ok(Some(chunk));
-                    }
-                }
-            }
+    /// Copy out `chunk_start..end` and mark it consumed. The buffer is compacted after push.
+    fn take_chunk(&mut self, end: usize) -> String {
+        let start = self.chunk_start.take().unwrap_or(0);
+        String::from_utf8_lossy(&self.buffer[start..end]).into_owned()
+    }
+
+    /// Drop bytes that belong to no pending chunk so the buffer only holds the partial stanza.
+    fn compact(&mut self) {
+        let mut cut = self.chunk_start.unwrap_or(self.scan);
+        cut = match self.token {
+            Token::Text => cut,
+            Token::StartTag { start, .. }
+            | Token::EndTag { start }
+            | Token::Comment { start }
+            | Token::CData { start }
+            | Token::Pi { start }
+            | Token::Decl { start } => cut.min(start),
+        };
+        if cut == 0 {
+            return;
         }
-        Ok(None)
+        self.buffer.drain(..cut);
+        self.scan -= cut;
+        if let Some(ref mut s) = self.chunk_start {
+            *s -= cut;
+        }
+        self.token = match self.token {
+            Token::Text => Token::Text,
+            Token::StartTag { start, quote } => Token::StartTag {
+                start: start - cut,
+                quote,
+            },
+            Token::EndTag { start } => Token::EndTag { start: start - cut },
+            Token::Comment { start } => Token::Comment { start: start - cut },
+            Token::CData { start } => Token::CData { start: start - cut },
+            Token::Pi { start } => Token::Pi { start: start - cut },
+            Token::Decl { start } => Token::Decl { start: start - cut },
+        };
+    }
+
+    /// Stream restart (RFC 6120 4.3.3): expect a new stream header next. Unlike `reset`,
+    /// bytes already buffered are kept and re-framed from the current position. No-op if
+    /// the new header was already framed.
+    pub fn restart(&mut self) {
+        if self.fresh_stream {
+            return;
+        }
+        self.depth = 0;
+        self.in_stream_header = true;
+        self.chunk_start = None;
     }
 
     pub fn reset(&mut self) {
         self.buffer.clear();
         self.depth = 0;
         self.in_stream_header = true;
+        self.scan = 0;
+        self.token = Token::Text;
+        self.chunk_start = None;
+        self.fresh_stream = false;
     }
 }
@@ -1,15 +1,26 @@
 //! TLS connection and StartTLS upgrade.
 //! Uses rustls (pure Rust) so Windows cross-build needs no MinGW/OpenSSL.
 
+use std::collections::HashMap;
 use std::io::{Read, Write};
 use std::net::TcpStream;
-use std::sync::{Arc, Once};
+use std::sync::{Arc, Condvar, Mutex, Once, OnceLock};
+use std::time::{Duration, Instant};
 
-use rustls::pki_types::ServerName;
-use rustls::ClientConfig;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::{Resumption, WantsClientCert, WebPkiServerVerifier};
+use rustls::crypto::{CryptoProvider, WebPkiSupportedAlgorithms};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, ConfigBuilder, DigitallySignedStruct, KeyLog, RootCertStore,
+};
 use thiserror::Error;
 use webpki_roots::TLS_SERVER_ROOTS;
 
+use crate::cert_info::{self, BadCertificate};
+use crate::config::{PinMode, SessionCache, TlsConfig, TlsVersion, TransportConfig, TrustStore};
+
 /// Install rustls crypto provider once (no Rust main in cdylib). Only run when TLS is used.
 static RUSTLS_INIT: Once = Once::new();
 
@@ -32,6 +43,199 @@ pub enum TlsError {
 /// Wrapper around a TLS stream (TcpStream + rustls).
 pub struct TlsStreamWrapper {
     inner: rustls::StreamOwned<rustls::ClientConnection, TcpStream>,
+    /// SNI sent in the ClientHello (None for an IP address, which is never sent).
+    sni: Option<String>,
+}
+
+impl TlsStreamWrapper {
+    /// Drive the handshake to completion on a blocking socket within `timeout` overall (a
+    /// deadline, so a server trickling bytes cannot stretch it). Time spent processing what
+    /// was read is added back to the deadline: that is where the verifier waits on a trust
+    /// prompt, which has its own timeout. Afterwards the stream can be switched to
+    /// non-blocking for the read loop.
+    pub fn complete_handshake(&mut self, timeout: Duration) -> Result<(), HandshakeError> {
+        let sock = &mut self.inner.sock;
+        let conn = &mut self.inner.conn;
+        sock.set_nonblocking(false)
+            .map_err(|e| HandshakeError::Connection(e.to_string()))?;
+        let mut deadline = Instant::now() + timeout;
+        let timed_out = || HandshakeError::Timeout(timeout.as_millis() as u64);
+        // Not complete_io: it keeps reading until the handshake is done, so the timeouts
+        // could not be lowered between reads.
+        while conn.is_handshaking() || conn.wants_write() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(timed_out());
+            }
+            // Each blocking read or write gets what is left of the deadline.
+            let _ = sock.set_read_timeout(Some(left));
+            let _ = sock.set_write_timeout(Some(left));
+            let step = if conn.wants_write() {
+                conn.write_tls(sock).map(|_| Duration::ZERO)
+            } else {
+                read_handshake(conn, sock)
+            };
+            match step {
+                Ok(paused) => deadline += paused,
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::TimedOut
+                        || e.kind() == std::io::ErrorKind::WouldBlock =>
+                {
+                    return Err(timed_out());
+                }
+                Err(e) => return Err(handshake_error(e)),
+            }
+        }
+        let _ = sock.set_read_timeout(None);
+        let _ = sock.set_write_timeout(Some(timeout));
+        Ok(())
+    }
+
+    /// Read timeout of the underlying TCP socket (bounds blocking reads such as a WebSocket
+    /// upgrade on top of this stream).
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.inner.sock.set_read_timeout(timeout)
+    }
+
+    /// Set the underlying TCP socket to non-blocking (same as the plain TCP read loop).
+    pub fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        self.inner.sock.set_nonblocking(nonblocking)
+    }
+
+    /// The TCP socket under the TLS session (registered with the I/O loop's poller).
+    pub fn tcp(&self) -> &TcpStream {
+        &self.inner.sock
+    }
+
+    /// Whether TLS records are waiting for the socket to become writable.
+    pub fn wants_write(&self) -> bool {
+        self.inner.conn.wants_write()
+    }
+
+    /// What the handshake negotiated; None while it is still running.
+    pub fn info(&self) -> Option<TlsInfo> {
+        let conn = &self.inner.conn;
+        if conn.is_handshaking() {
+            return None;
+        }
+        let suite = conn.negotiated_cipher_suite()?.suite();
+        let version = match conn.protocol_version()? {
+            rustls::ProtocolVersion::TLSv1_2 => "TLSv1.2".to_string(),
+            rustls::ProtocolVersion::TLSv1_3 => "TLSv1.3".to_string(),
+            other => format!("{:?}", other),
+        };
+        Some(TlsInfo {
+            version,
+            cipher_suite: format!("{:?}", suite),
+            cipher_suite_id: u16::from(suite),
+            kx_group: conn
+                .negotiated_key_exchange_group()
+                .map(|g| format!("{:?}", g.name())),
+            alpn: conn
+                .alpn_protocol()
+                .map(|p| String::from_utf8_lossy(p).into_owned()),
+            sni: self.sni.clone(),
+            resumed: conn.handshake_kind() == Some(rustls::HandshakeKind::Resumed),
+            peer_certificates: conn
+                .peer_certificates()
+                .unwrap_or_default()
+                .iter()
+                .map(cert_info::summarize)
+                .collect(),
+        })
+    }
+
+    /// Channel binding types this connection can provide.
+    pub fn channel_bindings(&self) -> Vec<ChannelBinding> {
+        let conn = &self.inner.conn;
+        if conn.is_handshaking() {
+            return Vec::new();
+        }
+        let mut out = Vec::new();
+        if conn.protocol_version() == Some(rustls::ProtocolVersion::TLSv1_3) {
+            out.push(ChannelBinding::TlsExporter);
+        }
+        if server_end_point(conn).is_some() {
+            out.push(ChannelBinding::TlsServerEndPoint);
+        }
+        out
+    }
+
+    /// Channel binding data for `kind`, as used in the SCRAM-PLUS `c=` attribute.
+    pub fn channel_binding(&self, kind: ChannelBinding) -> Result<Vec<u8>, HandshakeError> {
+        if !self.channel_bindings().contains(&kind) {
+            return Err(HandshakeError::Tls(format!(
+                "{} channel binding is not available on this connection",
+                kind.name()
+            )));
+        }
+        let conn = &self.inner.conn;
+        match kind {
+            ChannelBinding::TlsExporter => conn
+                .export_keying_material([0u8; 32], b"EXPORTER-Channel-Binding", Some(&[]))
+                .map(|out| out.to_vec())
+                .map_err(|e| HandshakeError::Tls(e.to_string())),
+            ChannelBinding::TlsServerEndPoint => server_end_point(conn)
+                .ok_or_else(|| HandshakeError::Tls("no server certificate".into())),
+        }
+    }
+}
+
+/// Negotiated parameters of a TLS connection, serialized as JSON for Dart.
+#[derive(serde::Serialize, Debug)]
+pub struct TlsInfo {
+    /// "TLSv1.2" or "TLSv1.3".
+    pub version: String,
+    /// rustls name, e.g. "TLS13_AES_256_GCM_SHA384".
+    pub cipher_suite: String,
+    /// IANA code point of the cipher suite.
+    pub cipher_suite_id: u16,
+    /// Key exchange group, e.g. "X25519" (None when resumed without a key exchange).
+    pub kx_group: Option<String>,
+    pub alpn: Option<String>,
+    pub sni: Option<String>,
+    /// Whether the session was resumed instead of a full handshake.
+    pub resumed: bool,
+    /// Server chain as sent, leaf first, with DER and SHA-256 fingerprints.
+    pub peer_certificates: Vec<cert_info::CertificateSummary>,
+}
+
+impl TlsInfo {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Channel binding types (RFC 5056) for SCRAM-PLUS / XEP-0440.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelBinding {
+    /// RFC 9266. Offered on TLS 1.3 only: rustls does not report whether a TLS 1.2 session
+    /// used the extended master secret, which RFC 9266 requires there.
+    TlsExporter,
+    /// RFC 5929: hash of the server certificate.
+    TlsServerEndPoint,
+}
+
+impl ChannelBinding {
+    /// Name as registered with IANA and used in XEP-0440 `<channel-binding type=.../>`.
+    pub fn name(self) -> &'static str {
+        match self {
+            ChannelBinding::TlsExporter => "tls-exporter",
+            ChannelBinding::TlsServerEndPoint => "tls-server-end-point",
+        }
+    }
+}
+
+/// RFC 5929 tls-server-end-point: the leaf certificate hashed with its signature's hash
+/// (MD5 and SHA-1 become SHA-256). None when the algorithm has no single hash (Ed25519).
+fn server_end_point(conn: &rustls::ClientConnection) -> Option<Vec<u8>> {
+    let leaf = conn.peer_certificates()?.first()?;
+    let algorithm = cert_info::signature_hash(leaf.as_ref())?;
+    Some(
+        ring::digest::digest(algorithm, leaf.as_ref())
+            .as_ref()
+            .to_vec(),
+    )
 }
 
 impl Read for TlsStreamWrapper {
@@ -49,13 +253,626 @@ impl Write for TlsStreamWrapper {
     }
 }
 
-fn make_config_default_roots() -> Result<ClientConfig, TlsError> {
+/// Whether an I/O error from a TLS stream is a rejected server certificate (chain, name, pin).
+pub fn is_certificate_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.get_ref()
+            .and_then(|inner| inner.downcast_ref::<rustls::Error>()),
+        Some(rustls::Error::InvalidCertificate(_))
+    )
+}
+
+/// One read of handshake records; returns how long processing them took. On a protocol
+/// error the alert for the peer is sent before returning it (as `complete_io` does).
+fn read_handshake(
+    conn: &mut rustls::ClientConnection,
+    sock: &mut TcpStream,
+) -> std::io::Result<Duration> {
+    if conn.read_tls(sock)? == 0 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    let start = Instant::now();
+    if let Err(e) = conn.process_new_packets() {
+        let _ = conn.write_tls(sock);
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+    }
+    Ok(start.elapsed())
+}
+
+/// Certificate rejections become BadCertificate; anything else is a TLS error.
+fn handshake_error(e: std::io::Error) -> HandshakeError {
+    if is_certificate_error(&e) {
+        HandshakeError::BadCertificate(e.to_string())
+    } else {
+        HandshakeError::Tls(e.to_string())
+    }
+}
+
+/// Certificates from PEM (any number of CERTIFICATE blocks) or a single DER certificate.
+pub fn parse_certificates(data: &[u8]) -> Result<Vec<CertificateDer<'static>>, HandshakeError> {
+    if data.is_empty() {
+        return Ok(Vec::new());
+    }
+    if data.windows(10).any(|w| w == b"-----BEGIN") {
+        let certs = CertificateDer::pem_slice_iter(data)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| HandshakeError::Tls(format!("PEM certificate: {}", e)))?;
+        if certs.is_empty() {
+            return Err(HandshakeError::Tls("PEM contains no certificate".into()));
+        }
+        return Ok(certs);
+    }
+    Ok(vec![CertificateDer::from(data.to_vec())])
+}
+
+/// SHA-256 of the certificate's DER SubjectPublicKeyInfo (the value pinned by `spki_pins`).
+pub fn spki_sha256(cert: &CertificateDer<'_>) -> Option<[u8; 32]> {
+    let cert = webpki::EndEntityCert::try_from(cert).ok()?;
+    Some(cert_info::sha256(cert.subject_public_key_info().as_ref()))
+}
+
+/// OS trust store, loaded once per process (reading it can take tens of milliseconds).
+#[cfg(feature = "system-roots")]
+fn add_system_roots(store: &mut RootCertStore) -> Result<(), HandshakeError> {
+    static SYSTEM_ROOTS: std::sync::OnceLock<Vec<CertificateDer<'static>>> =
+        std::sync::OnceLock::new();
+    let certs = SYSTEM_ROOTS.get_or_init(|| rustls_native_certs::load_native_certs().certs);
+    let (added, _ignored) = store.add_parsable_certificates(certs.iter().cloned());
+    if added == 0 {
+        return Err(HandshakeError::Tls(
+            "no usable certificates in the system trust store".into(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "system-roots"))]
+fn add_system_roots(_store: &mut RootCertStore) -> Result<(), HandshakeError> {
+    Err(HandshakeError::Tls(
+        "system trust store requires the system-roots feature".into(),
+    ))
+}
+
+fn root_store(tls: &TlsConfig) -> Result<RootCertStore, HandshakeError> {
+    let mut store = RootCertStore::empty();
+    if matches!(
+        tls.trust_store,
+        TrustStore::Bundled | TrustStore::BundledAndSystem
+    ) {
+        store.extend(TLS_SERVER_ROOTS.iter().cloned());
+    }
+    if matches!(
+        tls.trust_store,
+        TrustStore::System | TrustStore::BundledAndSystem
+    ) {
+        add_system_roots(&mut store)?;
+    }
+    for cert in parse_certificates(&tls.extra_roots)? {
+        store
+            .add(cert)
+            .map_err(|e| HandshakeError::Tls(format!("trust anchor: {}", e)))?;
+    }
+    Ok(store)
+}
+
+/// What to do when the server certificate fails verification.
+#[derive(Clone)]
+pub enum OnBadCert {
+    Reject,
+    /// Skip verification entirely (testing only).
+    AcceptAll,
+    /// Pause the handshake and ask Dart (trust-on-first-use).
+    Ask(Arc<TrustPrompt>, Duration),
+}
+
+/// Per-handle TLS state that outlives one connection: the bad-certificate policy and the
+/// client configs (with their session caches) reconnects resume from.
+#[derive(Clone)]
+pub struct TlsContext {
+    pub on_bad_cert: OnBadCert,
+    /// None = no resumption (`SessionCache::Disabled`).
+    pub configs: Option<Arc<ClientConfigs>>,
+    /// Where handshake secrets go (debug key log); None = not logged.
+    pub key_log: Option<Arc<dyn KeyLog>>,
+}
+
+/// Everything a built `ClientConfig` depends on.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct ConfigKey {
+    tls: TlsConfig,
+    sni: String,
+    verify: String,
+    alpn: Vec<Vec<u8>>,
+    /// `OnBadCert` variant; for `Ask` the address of the handle's prompt.
+    on_bad_cert: usize,
+    /// Address of the handle's key log (0 = none).
+    key_log: usize,
+}
+
+/// Distinct configs kept per cache before it is cleared.
+const MAX_CONFIGS: usize = 64;
+
+/// Client configs already built, each with its own in-memory session store. rustls only
+/// resumes a session with the config (verifier and client certificate) that created it, so
+/// resumption needs the same `Arc<ClientConfig>` again rather than just a shared store.
+#[derive(Default)]
+pub struct ClientConfigs(Mutex<HashMap<ConfigKey, Arc<ClientConfig>>>);
+
+/// Config cache for `cache`. `PerHandle` gets a new one; call once per handle and keep it.
+pub fn client_configs(cache: SessionCache) -> Option<Arc<ClientConfigs>> {
+    static SHARED: OnceLock<Arc<ClientConfigs>> = OnceLock::new();
+    match cache {
+        SessionCache::Disabled => None,
+        SessionCache::PerHandle => Some(Arc::default()),
+        SessionCache::Shared => Some(SHARED.get_or_init(Arc::default).clone()),
+    }
+}
+
+/// The cached config for this handshake, or a new one (cached unless resumption is off).
+fn client_config(
+    tls: &TlsConfig,
+    ctx: &TlsContext,
+    names: &ServerNames,
+    alpn: &[&[u8]],
+) -> Result<Arc<ClientConfig>, HandshakeError> {
+    let build = || -> Result<ClientConfig, HandshakeError> {
+        let mut config = make_config(tls, &ctx.on_bad_cert, names)?;
+        config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+        if let Some(key_log) = &ctx.key_log {
+            config.key_log = key_log.clone();
+        }
+        Ok(config)
+    };
+    let Some(configs) = &ctx.configs else {
+        let mut config = build()?;
+        config.resumption = Resumption::disabled();
+        return Ok(Arc::new(config));
+    };
+    let key = ConfigKey {
+        tls: tls.clone(),
+        sni: names.sni.clone(),
+        verify: names.verify.clone(),
+        alpn: alpn.iter().map(|p| p.to_vec()).collect(),
+        on_bad_cert: match &ctx.on_bad_cert {
+            OnBadCert::Reject => 0,
+            OnBadCert::AcceptAll => 1,
+            OnBadCert::Ask(prompt, _) => Arc::as_ptr(prompt) as usize,
+        },
+        key_log: ctx
+            .key_log
+            .as_ref()
+            .map_or(0, |k| Arc::as_ptr(k) as *const () as usize),
+    };
+    let mut map = configs.0.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(config) = map.get(&key) {
+        return Ok(config.clone());
+    }
+    let config = Arc::new(build()?);
+    if map.len() >= MAX_CONFIGS {
+        map.clear();
+    }
+    map.insert(key, config.clone());
+    Ok(config)
+}
+
+/// The ring provider limited to the configured cipher suites and key exchange groups.
+fn crypto_provider(tls: &TlsConfig) -> Result<CryptoProvider, HandshakeError> {
+    let mut provider = rustls::crypto::ring::default_provider();
+    if !tls.cipher_suites.is_empty() {
+        provider
+            .cipher_suites
+            .retain(|s| tls.cipher_suites.contains(&u16::from(s.suite())));
+        if provider.cipher_suites.is_empty() {
+            return Err(HandshakeError::Tls(
+                "none of the configured cipher suites is supported".into(),
+            ));
+        }
+    }
+    if !tls.kx_groups.is_empty() {
+        let available = std::mem::take(&mut provider.kx_groups);
+        provider.kx_groups = tls
+            .kx_groups
+            .iter()
+            .filter_map(|code| {
+                available
+                    .iter()
+                    .find(|g| u16::from(g.name()) == *code)
+                    .copied()
+            })
+            .collect();
+        if provider.kx_groups.is_empty() {
+            return Err(HandshakeError::Tls(
+                "none of the configured key exchange groups is supported".into(),
+            ));
+        }
+    }
+    Ok(provider)
+}
+
+const TLS13_ONLY: &[&rustls::SupportedProtocolVersion] = &[&rustls::version::TLS13];
+
+fn protocol_versions(tls: &TlsConfig) -> &'static [&'static rustls::SupportedProtocolVersion] {
+    match tls.min_version {
+        TlsVersion::Tls12 => rustls::DEFAULT_VERSIONS,
+        TlsVersion::Tls13 => TLS13_ONLY,
+    }
+}
+
+/// Verifier for `tls`: WebPKI against the configured roots, or SPKI pinning.
+fn make_verifier(tls: &TlsConfig) -> Result<Arc<dyn ServerCertVerifier>, HandshakeError> {
+    let provider = CryptoProvider::get_default()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(rustls::crypto::ring::default_provider()));
+    let chain = match (tls.spki_pins.is_empty(), tls.pin_mode) {
+        (false, PinMode::PinOnly) => None,
+        _ => Some(
+            WebPkiServerVerifier::builder_with_provider(
+                Arc::new(root_store(tls)?),
+                provider.clone(),
+            )
+            .build()
+            .map_err(|e| HandshakeError::Tls(e.to_string()))?,
+        ),
+    };
+    if tls.spki_pins.is_empty() {
+        if let Some(chain) = chain {
+            return Ok(chain);
+        }
+    }
+    Ok(Arc::new(PinnedVerifier {
+        chain,
+        pins: tls.spki_pins.clone(),
+        algorithms: provider.signature_verification_algorithms,
+    }))
+}
+
+/// Names for one TLS handshake. The socket target (an SRV host) is not one of them: RFC 6125
+/// (and RFC 6120 13.7.2.1) checks XMPP certificates against the source domain.
+#[derive(Clone, Debug)]
+pub struct ServerNames {
+    /// Sent as SNI: `tls_server_name` when set, else the source domain.
+    pub sni: String,
+    /// Name the certificate must be valid for: the source domain.
+    pub verify: String,
+}
+
+impl From<&TransportConfig> for ServerNames {
+    fn from(config: &TransportConfig) -> Self {
+        let sni = config
+            .tls_server_name
+            .as_deref()
+            .filter(|name| !name.is_empty())
+            .unwrap_or(&config.host);
+        Self {
+            sni: sni.to_string(),
+            verify: config.host.clone(),
+        }
+    }
+}
+
+fn server_name(name: &str) -> Result<ServerName<'static>, HandshakeError> {
+    ServerName::try_from(name.to_string())
+        .map_err(|_| HandshakeError::Tls(format!("invalid server name: {}", name)))
+}
+
+fn make_config(
+    tls: &TlsConfig,
+    on_bad_cert: &OnBadCert,
+    names: &ServerNames,
+) -> Result<ClientConfig, HandshakeError> {
     ensure_rustls_provider();
-    let mut root_store = rustls::RootCertStore::empty();
-    root_store.extend(TLS_SERVER_ROOTS.iter().cloned());
-    Ok(ClientConfig::builder()
-        .with_root_certificates(root_store)
-        .with_no_client_auth())
+    let mut verifier: Arc<dyn ServerCertVerifier> = match on_bad_cert {
+        OnBadCert::AcceptAll => Arc::new(AllowAnyVerifier),
+        OnBadCert::Reject => make_verifier(tls)?,
+        OnBadCert::Ask(prompt, timeout) => Arc::new(InteractiveVerifier {
+            inner: make_verifier(tls)?,
+            prompt: prompt.clone(),
+            timeout: *timeout,
+        }),
+    };
+    if !names.sni.eq_ignore_ascii_case(&names.verify) {
+        verifier = Arc::new(NamedVerifier {
+            inner: verifier,
+            name: server_name(&names.verify)?,
+        });
+    }
+    let builder = ClientConfig::builder_with_provider(Arc::new(crypto_provider(tls)?))
+        .with_protocol_versions(protocol_versions(tls))
+        .map_err(|e| HandshakeError::Tls(format!("TLS policy: {}", e)))?;
+    with_client_auth(
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(verifier),
+        tls,
+    )
+}
+
+/// Finish the config with the client certificate from `tls`, if any. Key problems (unparsable,
+/// unsupported, not matching the certificate) are `HandshakeError::ClientKey`.
+fn with_client_auth(
+    builder: ConfigBuilder<ClientConfig, WantsClientCert>,
+    tls: &TlsConfig,
+) -> Result<ClientConfig, HandshakeError> {
+    if tls.client_cert.is_empty() && tls.client_key.is_empty() {
+        return Ok(builder.with_no_client_auth());
+    }
+    let chain = parse_certificates(&tls.client_cert)?;
+    if chain.is_empty() {
+        return Err(HandshakeError::ClientKey(
+            "client key given without a certificate".into(),
+        ));
+    }
+    let key = parse_private_key(&tls.client_key)?;
+    builder
+        .with_client_auth_cert(chain, key)
+        .map_err(|e| HandshakeError::ClientKey(e.to_string()))
+}
+
+/// Private key from PEM (PKCS#8, PKCS#1 or SEC1) or PKCS#8 DER.
+fn parse_private_key(data: &[u8]) -> Result<PrivateKeyDer<'static>, HandshakeError> {
+    if data.is_empty() {
+        return Err(HandshakeError::ClientKey(
+            "client certificate given without a key".into(),
+        ));
+    }
+    if data.windows(10).any(|w| w == b"-----BEGIN") {
+        return PrivateKeyDer::from_pem_slice(data)
+            .map_err(|e| HandshakeError::ClientKey(format!("PEM key: {}", e)));
+    }
+    Ok(PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+        data.to_vec(),
+    )))
+}
+
+/// Receives the JSON details when a trust prompt opens.
+type Notifier = Box<dyn Fn(String) + Send>;
+
+/// Pending trust decision, shared between the verifier (waiting inside the handshake) and the
+/// FFI (Dart's answer). One prompt per connection; the handshake thread blocks in `ask`.
+#[derive(Default)]
+pub struct TrustPrompt {
+    state: Mutex<PromptState>,
+    decided: Condvar,
+    notify: Mutex<Option<Notifier>>,
+}
+
+#[derive(Default)]
+struct PromptState {
+    pending: bool,
+    decision: Option<bool>,
+}
+
+impl TrustPrompt {
+    /// Called with the JSON details (see `cert_info::BadCertificate`) when a prompt opens.
+    pub fn set_notifier(&self, notify: impl Fn(String) + Send + 'static) {
+        *self.notify.lock().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(notify));
+    }
+
+    /// Publish `details` and block until `decide` is called or `timeout` passes (= reject).
+    fn ask(&self, details: String, timeout: Duration) -> bool {
+        {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.pending = true;
+            state.decision = None;
+        }
+        if let Some(ref notify) = *self.notify.lock().unwrap_or_else(|e| e.into_inner()) {
+            notify(details);
+        }
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(accept) = state.decision.take() {
+                state.pending = false;
+                return accept;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                state.pending = false;
+                return false;
+            }
+            state = self
+                .decided
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    /// Answer the open prompt. Returns false if no handshake is waiting.
+    pub fn decide(&self, accept: bool) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if !state.pending {
+            return false;
+        }
+        state.decision = Some(accept);
+        self.decided.notify_all();
+        true
+    }
+}
+
+/// Runs the normal verifier; on a certificate error asks Dart and continues if accepted.
+/// Signatures are still checked by the inner verifier, so an accepted certificate's key must
+/// belong to the server.
+struct InteractiveVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    prompt: Arc<TrustPrompt>,
+    timeout: Duration,
+}
+
+impl std::fmt::Debug for InteractiveVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InteractiveVerifier")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl ServerCertVerifier for InteractiveVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        ) {
+            Err(rustls::Error::InvalidCertificate(err)) => {
+                let details = BadCertificate::new(
+                    rustls::Error::InvalidCertificate(err.clone()).to_string(),
+                    server_name.to_str().into_owned(),
+                    end_entity,
+                    intermediates,
+                );
+                if self.prompt.ask(details.to_json(), self.timeout) {
+                    Ok(ServerCertVerified::assertion())
+                } else {
+                    Err(rustls::Error::InvalidCertificate(err))
+                }
+            }
+            other => other,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Verifies the certificate against a fixed name instead of the SNI the connection was
+/// opened with (used when `tls_server_name` differs from the source domain).
+#[derive(Debug)]
+struct NamedVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    name: ServerName<'static>,
+}
+
+impl ServerCertVerifier for NamedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner
+            .verify_server_cert(end_entity, intermediates, &self.name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// SPKI pinning. With `chain` the normal WebPKI checks run first and any certificate the
+/// server sent may match a pin; without it only the leaf is checked. Handshake signatures are
+/// always verified, so a pinned leaf proves the server holds the key.
+#[derive(Debug)]
+struct PinnedVerifier {
+    chain: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<[u8; 32]>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let candidates = match self.chain {
+            Some(ref chain) => {
+                chain.verify_server_cert(
+                    end_entity,
+                    intermediates,
+                    server_name,
+                    ocsp_response,
+                    now,
+                )?;
+                intermediates
+            }
+            None => &[],
+        };
+        let pinned = std::iter::once(end_entity)
+            .chain(candidates)
+            .filter_map(spki_sha256)
+            .any(|hash| self.pins.contains(&hash));
+        if pinned {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
 }
 
 /// Accepts any server certificate (for testing / bad-cert callback from Dart).
@@ -101,50 +918,53 @@ impl rustls::client::danger::ServerCertVerifier for AllowAnyVerifier {
     }
 }
 
-fn make_config_allow_invalid() -> ClientConfig {
-    ensure_rustls_provider();
-    ClientConfig::builder()
-        .dangerous()
-        .with_custom_certificate_verifier(Arc::new(AllowAnyVerifier))
-        .with_no_client_auth()
-}
+/// ALPN protocol for XMPP client-to-server direct TLS (XEP-0368), so port-443
+/// multiplexers can route the connection.
+pub const ALPN_XMPP_CLIENT: &[u8] = b"xmpp-client";
 
-/// Connect to host:port over TLS (direct TLS).
+/// Start direct TLS on an already connected socket. `names` gives the SNI and the name the
+/// certificate is checked against; `tls` selects trust anchors, pins and policy; `ctx` the
+/// bad-certificate handling and session cache; `alpn` is offered as-is (empty = no ALPN).
+/// The handshake is completed within `timeout` before returning.
 pub fn connect_direct(
-    host: &str,
-    port: u16,
-    accept_bad_cert: bool,
+    tcp: TcpStream,
+    names: &ServerNames,
+    tls: &TlsConfig,
+    ctx: &TlsContext,
+    alpn: &[&[u8]],
+    timeout: Duration,
 ) -> Result<TlsStreamWrapper, HandshakeError> {
-    let tcp =
-        TcpStream::connect((host, port)).map_err(|e| HandshakeError::Connection(e.to_string()))?;
-    let config = if accept_bad_cert {
-        make_config_allow_invalid()
-    } else {
-        make_config_default_roots().map_err(|e| HandshakeError::Tls(e.to_string()))?
-    };
-    let server_name: ServerName<'static> = ServerName::try_from(host.to_string())
-        .map_err(|_| HandshakeError::Tls("invalid server name".into()))?;
-    let conn = rustls::ClientConnection::new(Arc::new(config), server_name)
-        .map_err(|e| HandshakeError::Tls(e.to_string()))?;
-    let stream = rustls::StreamOwned::new(conn, tcp);
-    Ok(TlsStreamWrapper { inner: stream })
+    let config = client_config(tls, ctx, names, alpn)?;
+    handshake(tcp, config, names, timeout)
 }
 
-/// Upgrade existing TCP stream to TLS (StartTLS).
+/// Upgrade existing TCP stream to TLS (StartTLS). Call after the server sent `<proceed/>`;
+/// the handshake is completed before returning so errors surface here, not in the read loop.
 pub fn upgrade_tcp(
     tcp: TcpStream,
-    host: &str,
-    accept_bad_cert: bool,
+    names: &ServerNames,
+    tls: &TlsConfig,
+    ctx: &TlsContext,
+    timeout: Duration,
 ) -> Result<TlsStreamWrapper, HandshakeError> {
-    let config = if accept_bad_cert {
-        make_config_allow_invalid()
-    } else {
-        make_config_default_roots().map_err(|e| HandshakeError::Tls(e.to_string()))?
-    };
-    let server_name: ServerName<'static> = ServerName::try_from(host.to_string())
-        .map_err(|_| HandshakeError::Tls("invalid server name".into()))?;
-    let conn = rustls::ClientConnection::new(Arc::new(config), server_name)
+    let config = client_config(tls, ctx, names, &[])?;
+    handshake(tcp, config, names, timeout)
+}
+
+fn handshake(
+    tcp: TcpStream,
+    config: Arc<ClientConfig>,
+    names: &ServerNames,
+    timeout: Duration,
+) -> Result<TlsStreamWrapper, HandshakeError> {
+    let conn = rustls::ClientConnection::new(config, server_name(&names.sni)?)
         .map_err(|e| HandshakeError::Tls(e.to_string()))?;
     let stream = rustls::StreamOwned::new(conn, tcp);
-    Ok(TlsStreamWrapper { inner: stream })
+    let sni = match server_name(&names.sni)? {
+        ServerName::DnsName(_) => Some(names.sni.clone()),
+        _ => None,
+    };
+    let mut wrapper = TlsStreamWrapper { inner: stream, sni };
+    wrapper.complete_handshake(timeout)?;
+    Ok(wrapper)
 }
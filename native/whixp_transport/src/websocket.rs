@@ -1,14 +1,30 @@
-//! WebSocket transport for XMPP (RFC 7395 style: one stanza per frame or concatenated).
-//! Uses tungstenite over TCP or existing TLS stream; exposes Read/Write for the stanza loop.
+//! WebSocket transport for XMPP. `WsFraming::Stream` exposes the messages as a byte stream
+//! for the stanza framer; `WsFraming::Rfc7395` negotiates the `xmpp` subprotocol and keeps
+//! one element per message in both directions.
+//! Uses tungstenite over TCP or existing TLS stream, with optional permessage-deflate
+//! (`ws_deflate`), custom upgrade headers and client pings.
 
 use std::io::{Read, Write};
+use std::time::{Duration, Instant};
 
-use tungstenite::client::client;
+use quick_xml::events::Event;
+use tungstenite::client::{client, IntoClientRequest};
+use tungstenite::error::ProtocolError;
+use tungstenite::http::{HeaderName, HeaderValue};
 use tungstenite::Message;
 use tungstenite::WebSocket;
 
+use crate::config::{TransportConfig, WsFraming};
 use crate::handshake::HandshakeError;
+use crate::stanza::{Frame, StreamFramer};
 use crate::tls;
+use crate::ws_deflate::{self, DeflateParams, DeflateStream};
+
+/// RFC 7395 WebSocket subprotocol.
+const SUBPROTOCOL: &str = "xmpp";
+
+/// Namespace of the RFC 7395 `<open/>` and `<close/>` elements.
+const FRAMING_NS: &[u8] = b"urn:ietf:params:xml:ns:xmpp-framing";
 
 /// Build WebSocket request URL from host, port, path, and TLS flag.
 fn ws_url(host: &str, port: u16, path: &str, use_tls: bool) -> String {
@@ -23,29 +39,136 @@ fn ws_url(host: &str, port: u16, path: &str, use_tls: bool) -> String {
 
 /// Wraps tungstenite::WebSocket so we can use it as std::io::Read + Write (one message = one chunk).
 pub struct WsStream<S> {
-    ws: WebSocket<S>,
+    ws: WebSocket<DeflateStream<S>>,
     read_buf: Vec<u8>,
     read_pos: usize,
+    /// RFC 7395: splits written bytes so each element goes out as its own message.
+    out_framer: Option<StreamFramer>,
+    /// Upgrade response headers, names lowercased.
+    response_headers: Vec<(String, String)>,
+    /// Ping interval and pong timeout.
+    keepalive: Option<(Duration, Duration)>,
+    last_rx: Instant,
+    /// When the unanswered ping went out.
+    ping_sent: Option<Instant>,
+    /// A send hit WouldBlock; tungstenite holds the rest until flushed.
+    pending_flush: bool,
 }
 
 impl<S> WsStream<S>
 where
     S: Read + Write,
 {
-    fn new(ws: WebSocket<S>) -> Self {
+    fn new(
+        ws: WebSocket<DeflateStream<S>>,
+        response_headers: Vec<(String, String)>,
+        config: &TransportConfig,
+    ) -> Self {
         Self {
             ws,
             read_buf: Vec::new(),
             read_pos: 0,
+            out_framer: (config.ws_framing == WsFraming::Rfc7395).then(StreamFramer::new),
+            response_headers,
+            keepalive: config.ws_keepalive(),
+            last_rx: Instant::now(),
+            ping_sent: None,
+            pending_flush: false,
+        }
+    }
+
+    /// Next whole text or binary message (RFC 7395 framing). None when the server closed.
+    pub fn read_message(&mut self) -> std::io::Result<Option<String>> {
+        loop {
+            match self.next_message()? {
+                msg @ (Message::Text(_) | Message::Binary(_)) => {
+                    return Ok(Some(String::from_utf8_lossy(&msg.into_data()).into_owned()))
+                }
+                Message::Close(_) | Message::Frame(_) => return Ok(None),
+                Message::Ping(_) | Message::Pong(_) => {}
+            }
         }
     }
+
+    /// Read one message. Any inbound message counts as a pong.
+    fn next_message(&mut self) -> std::io::Result<Message> {
+        let msg = self.ws.read().map_err(io_error)?;
+        self.last_rx = Instant::now();
+        self.ping_sent = None;
+        Ok(msg)
+    }
+
+    /// Keepalive timer: fails once a ping went unanswered for the pong timeout and sends one
+    /// when the stream has been idle for the interval. Returns how long until it is due
+    /// again (None when pings are off).
+    pub fn tick(&mut self) -> std::io::Result<Option<Duration>> {
+        let Some((interval, pong_timeout)) = self.keepalive else {
+            return Ok(None);
+        };
+        if let Some(sent) = self.ping_sent {
+            let waited = sent.elapsed();
+            if waited >= pong_timeout {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionAborted,
+                    format!("no WebSocket pong within {} ms", pong_timeout.as_millis()),
+                ));
+            }
+            return Ok(Some(pong_timeout - waited));
+        }
+        let idle = self.last_rx.elapsed();
+        if idle < interval {
+            return Ok(Some(interval - idle));
+        }
+        self.ping_sent = Some(Instant::now());
+        self.send(Message::Ping(Default::default()))?;
+        Ok(Some(pong_timeout))
+    }
+
+    /// Queue and send `msg`; on WouldBlock tungstenite keeps it until the next flush.
+    fn send(&mut self, msg: Message) -> std::io::Result<()> {
+        match self.ws.send(msg) {
+            Err(tungstenite::Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                self.pending_flush = true;
+                Ok(())
+            }
+            r => r.map_err(io_error),
+        }
+    }
+
+    fn send_text(&mut self, text: String) -> std::io::Result<()> {
+        self.send(Message::text(text))
+    }
+}
+
+impl<S> WsStream<S> {
+    /// Whether a send is waiting for the socket to become writable.
+    pub fn wants_write(&self) -> bool {
+        self.pending_flush
+    }
+
+    /// The transport under the WebSocket (for TLS details such as channel binding).
+    pub fn get_ref(&self) -> &S {
+        self.ws.get_ref().get_ref()
+    }
+
+    /// Upgrade response headers as (lowercase name, value), in order.
+    pub fn response_headers(&self) -> &[(String, String)] {
+        &self.response_headers
+    }
 }
 
-/// Set underlying TCP stream to non-blocking after handshake so the read/write threads
-/// don't deadlock (read would otherwise hold the lock until server sends).
+/// Set underlying TCP stream to non-blocking after handshake; the connection's I/O thread
+/// waits for readiness instead of blocking in read.
 impl WsStream<std::net::TcpStream> {
     pub fn set_tcp_nonblocking(&mut self, nonblocking: bool) -> std::io::Result<()> {
-        self.ws.get_mut().set_nonblocking(nonblocking)
+        self.ws.get_mut().get_mut().set_nonblocking(nonblocking)
+    }
+}
+
+/// Same for wss.
+impl WsStream<tls::TlsStreamWrapper> {
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> std::io::Result<()> {
+        self.ws.get_ref().get_ref().set_nonblocking(nonblocking)
     }
 }
 
@@ -54,21 +177,15 @@ where
     S: Read + Write,
 {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        if self.read_pos >= self.read_buf.len() {
-            let msg = self.ws.read().map_err(|e| {
-                if let tungstenite::Error::Io(ioe) = e {
-                    ioe
-                } else {
-                    std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
-                }
-            })?;
+        while self.read_pos >= self.read_buf.len() {
+            let msg = self.next_message()?;
             match msg {
                 Message::Text(_) | Message::Binary(_) => {
                     self.read_buf = msg.into_data().to_vec();
                     self.read_pos = 0;
                 }
                 Message::Close(_) | Message::Frame(_) => return Ok(0),
-                Message::Ping(_) | Message::Pong(_) => return self.read(buf),
+                Message::Ping(_) | Message::Pong(_) => {}
             }
         }
         let from = &self.read_buf[self.read_pos..];
@@ -84,41 +201,198 @@ where
     S: Read + Write,
 {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let s = String::from_utf8_lossy(buf).into_owned();
-        self.ws
-            .send(Message::text(s))
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        let Some(framer) = &mut self.out_framer else {
+            self.send_text(String::from_utf8_lossy(buf).into_owned())?;
+            return Ok(buf.len());
+        };
+        let frames = framer
+            .push(buf)
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        for frame in frames {
+            if let Frame::Stanza(s) | Frame::StreamHeader(s) = frame {
+                self.send_text(s)?;
+            }
+        }
         Ok(buf.len())
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        self.ws
-            .flush()
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+        let result = self.ws.flush().map_err(io_error);
+        self.pending_flush =
+            matches!(&result, Err(e) if e.kind() == std::io::ErrorKind::WouldBlock);
+        result
+    }
+}
+
+fn io_error(e: tungstenite::Error) -> std::io::Error {
+    if let tungstenite::Error::Io(ioe) = e {
+        ioe
+    } else {
+        std::io::Error::other(e.to_string())
+    }
+}
+
+/// Classify one RFC 7395 message: `<open/>`, `<close/>` (see-other-host when it carries a
+/// redirect URI), or a stanza.
+pub fn classify_message(msg: String) -> Frame {
+    enum Kind {
+        Open,
+        Close,
+        SeeOtherHost(String),
+        Stanza,
+    }
+    let mut reader = quick_xml::Reader::from_str(&msg);
+    let kind = loop {
+        match reader.read_event() {
+            Ok(Event::Start(e) | Event::Empty(e)) => {
+                let framing = matches!(
+                    e.try_get_attribute("xmlns"),
+                    Ok(Some(ns)) if ns.value.as_ref() == FRAMING_NS
+                );
+                let name = e.local_name();
+                break match name.as_ref() {
+                    b"open" if framing => Kind::Open,
+                    b"close" if framing => match e.try_get_attribute("see-other-host") {
+                        Ok(Some(uri)) => match uri.unescape_value() {
+                            Ok(uri) => Kind::SeeOtherHost(uri.into_owned()),
+                            Err(_) => Kind::Close,
+                        },
+                        _ => Kind::Close,
+                    },
+                    _ => Kind::Stanza,
+                };
+            }
+            Ok(Event::Eof) | Err(_) => break Kind::Stanza,
+            Ok(_) => {}
+        }
+    };
+    match kind {
+        Kind::Open => Frame::Open(msg),
+        Kind::Close => Frame::Close(msg),
+        Kind::SeeOtherHost(uri) => Frame::SeeOtherHost(uri),
+        Kind::Stanza => Frame::Stanza(msg),
     }
 }
 
+/// Upgrade `stream` to a WebSocket at `url` within `timeout`, sending `config.ws_headers`.
+/// RFC 7395 framing asks for the `xmpp` subprotocol (tungstenite fails the upgrade if the
+/// server does not agree); `ws_deflate` offers permessage-deflate and turns it on when the
+/// server accepts.
+fn upgrade<S: Read + Write>(
+    url: String,
+    stream: S,
+    config: &TransportConfig,
+) -> Result<WsStream<S>, HandshakeError> {
+    let mut request = url
+        .into_client_request()
+        .map_err(|e| HandshakeError::Connection(e.to_string()))?;
+    let headers = request.headers_mut();
+    for (name, value) in &config.ws_headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| HandshakeError::Connection(format!("WebSocket header {}: {}", name, e)))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|e| HandshakeError::Connection(format!("WebSocket header {}: {}", name, e)))?;
+        headers.append(name, value);
+    }
+    if config.ws_framing == WsFraming::Rfc7395 {
+        headers.insert(
+            "Sec-WebSocket-Protocol",
+            HeaderValue::from_static(SUBPROTOCOL),
+        );
+    }
+    if config.ws_deflate {
+        headers.insert(
+            "Sec-WebSocket-Extensions",
+            HeaderValue::from_static(ws_deflate::OFFER),
+        );
+    }
+    let timeout = config.handshake_timeout();
+    let (mut ws, response) =
+        client(request, DeflateStream::new(stream)).map_err(|e| upgrade_error(e, timeout))?;
+    let response_headers: Vec<(String, String)> = response
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+            (name.as_str().to_string(), value)
+        })
+        .collect();
+    let accepted = response_headers
+        .iter()
+        .filter(|(name, _)| name == "sec-websocket-extensions")
+        .find_map(|(_, value)| DeflateParams::from_response(value));
+    match accepted {
+        Some(params) if config.ws_deflate => ws.get_mut().enable(params),
+        Some(_) => {
+            return Err(HandshakeError::Connection(
+                "server enabled permessage-deflate without an offer".to_string(),
+            ))
+        }
+        None => {}
+    }
+    Ok(WsStream::new(ws, response_headers, config))
+}
+
 /// Connect WebSocket (no TLS) over existing TCP stream. Caller must have already connected
-/// the stream to host:port. Returns a stream that implements Read + Write (XMPP stanzas as text frames).
+/// the stream to host:port. Path, framing, headers, compression and pings come from
+/// `config`; the upgrade must complete within its handshake timeout. Returns a stream that
+/// implements Read + Write (XMPP stanzas as text frames).
 pub fn connect_websocket(
     host: &str,
     port: u16,
-    path: &str,
     stream: std::net::TcpStream,
+    config: &TransportConfig,
 ) -> Result<WsStream<std::net::TcpStream>, HandshakeError> {
-    let url = ws_url(host, port, path, false);
-    let (ws, _) = client(url, stream).map_err(|e| HandshakeError::Connection(e.to_string()))?;
-    Ok(WsStream::new(ws))
+    let url = ws_url(host, port, ws_path(config), false);
+    let timeout = config.handshake_timeout();
+    let _ = stream.set_read_timeout(Some(timeout));
+    let _ = stream.set_write_timeout(Some(timeout));
+    let ws = upgrade(url, stream, config)?;
+    let _ = ws.get_ref().set_read_timeout(None);
+    Ok(ws)
 }
 
 /// Connect WebSocket over TLS (wss). Uses existing TLS stream from tls::connect_direct.
+/// As connect_websocket otherwise.
 pub fn connect_websocket_tls(
     host: &str,
     port: u16,
-    path: &str,
     tls_stream: tls::TlsStreamWrapper,
+    config: &TransportConfig,
 ) -> Result<WsStream<tls::TlsStreamWrapper>, HandshakeError> {
-    let url = ws_url(host, port, path, true);
-    let (ws, _) = client(url, tls_stream).map_err(|e| HandshakeError::Connection(e.to_string()))?;
-    Ok(WsStream::new(ws))
+    let url = ws_url(host, port, ws_path(config), true);
+    let _ = tls_stream.set_read_timeout(Some(config.handshake_timeout()));
+    let ws = upgrade(url, tls_stream, config)?;
+    let _ = ws.get_ref().set_read_timeout(None);
+    Ok(ws)
+}
+
+fn ws_path(config: &TransportConfig) -> &str {
+    config.ws_path.as_deref().unwrap_or("/ws")
+}
+
+/// A read timeout during the upgrade surfaces as an interrupted handshake (WouldBlock) or a
+/// TimedOut I/O error depending on the platform; both are `Timeout`.
+fn upgrade_error<S: Read + Write>(
+    e: tungstenite::HandshakeError<tungstenite::ClientHandshake<S>>,
+    timeout: Duration,
+) -> HandshakeError {
+    match e {
+        tungstenite::HandshakeError::Interrupted(_) => {
+            HandshakeError::Timeout(timeout.as_millis() as u64)
+        }
+        tungstenite::HandshakeError::Failure(tungstenite::Error::Io(e))
+            if e.kind() == std::io::ErrorKind::TimedOut
+                || e.kind() == std::io::ErrorKind::WouldBlock =>
+        {
+            HandshakeError::Timeout(timeout.as_millis() as u64)
+        }
+        tungstenite::HandshakeError::Failure(tungstenite::Error::Protocol(
+            ProtocolError::SecWebSocketSubProtocolError(e),
+        )) => HandshakeError::Stream(format!(
+            "server did not accept the {} WebSocket subprotocol: {}",
+            SUBPROTOCOL, e
+        )),
+        tungstenite::HandshakeError::Failure(e) => HandshakeError::Connection(e.to_string()),
+    }
 }
@@ -0,0 +1,415 @@
+//! RFC 7692 permessage-deflate for the WebSocket transport. tungstenite has no extension
+//! support, so `DeflateStream` sits between it and the socket: compressed frames from the
+//! server are inflated (RSV1 cleared) before tungstenite parses them, and unfragmented data
+//! frames tungstenite writes are deflated (RSV1 set) on the way out. The shim hands
+//! tungstenite the upgrade response only up to the end of its headers, so frames that arrive
+//! with it are never read past the inflater. Until `enable` is called bytes pass through.
+
+use std::io::{self, Read, Write};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+/// `Sec-WebSocket-Extensions` offer. No `client_max_window_bits`: the miniz backend always
+/// compresses with a 32 KiB window.
+pub const OFFER: &str = "permessage-deflate";
+
+/// Tail every compressed message ends with once flushed (RFC 7692 7.2.1).
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Largest inflated message, as tungstenite's default `max_message_size`.
+const MAX_MESSAGE: usize = 64 << 20;
+
+/// Parameters the server accepted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeflateParams {
+    /// Reset the inflater after each message.
+    pub server_no_context_takeover: bool,
+    /// Reset the deflater after each message.
+    pub client_no_context_takeover: bool,
+    /// False when the server limited our window below 15 bits (we cannot); messages are
+    /// then sent uncompressed, which RFC 7692 allows.
+    pub compress_outgoing: bool,
+}
+
+impl DeflateParams {
+    /// Parse the server's `Sec-WebSocket-Extensions` value. None when it did not accept
+    /// permessage-deflate.
+    pub fn from_response(header: &str) -> Option<Self> {
+        let ext = header
+            .split(',')
+            .find(|e| e.split(';').next().map(str::trim) == Some("permessage-deflate"))?;
+        let mut params = DeflateParams {
+            compress_outgoing: true,
+            ..Default::default()
+        };
+        for param in ext.split(';').skip(1) {
+            let (name, value) = match param.split_once('=') {
+                Some((n, v)) => (n.trim(), Some(v.trim().trim_matches('"'))),
+                None => (param.trim(), None),
+            };
+            match name {
+                "server_no_context_takeover" => params.server_no_context_takeover = true,
+                "client_no_context_takeover" => params.client_no_context_takeover = true,
+                "client_max_window_bits" if value.is_some_and(|v| v != "15") => {
+                    params.compress_outgoing = false;
+                }
+                _ => {}
+            }
+        }
+        Some(params)
+    }
+}
+
+/// WebSocket frame header (RFC 6455 5.2).
+struct Header {
+    first: u8,
+    mask: Option<[u8; 4]>,
+    len: usize,
+    payload: usize,
+}
+
+impl Header {
+    fn fin(&self) -> bool {
+        self.first & 0x80 != 0
+    }
+
+    fn rsv1(&self) -> bool {
+        self.first & 0x40 != 0
+    }
+
+    fn opcode(&self) -> u8 {
+        self.first & 0x0f
+    }
+
+    /// Header at the start of `b`; Ok(None) if more bytes are needed.
+    fn parse(b: &[u8]) -> io::Result<Option<Header>> {
+        if b.len() < 2 {
+            return Ok(None);
+        }
+        let (payload, mut len) = match b[1] & 0x7f {
+            126 => match b.get(2..4) {
+                Some(n) => (u16::from_be_bytes([n[0], n[1]]) as u64, 4),
+                None => return Ok(None),
+            },
+            127 => match b.get(2..10) {
+                Some(n) => (u64::from_be_bytes(n.try_into().unwrap_or_default()), 10),
+                None => return Ok(None),
+            },
+            n => (n as u64, 2),
+        };
+        if payload > MAX_MESSAGE as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "WebSocket frame too large",
+            ));
+        }
+        let mask = if b[1] & 0x80 != 0 {
+            let Some(m) = b.get(len..len + 4) else {
+                return Ok(None);
+            };
+            len += 4;
+            Some([m[0], m[1], m[2], m[3]])
+        } else {
+            None
+        };
+        Ok(Some(Header {
+            first: b[0],
+            mask,
+            len,
+            payload: payload as usize,
+        }))
+    }
+
+    fn write(out: &mut Vec<u8>, first: u8, mask: Option<[u8; 4]>, payload: usize) {
+        out.push(first);
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        if payload < 126 {
+            out.push(mask_bit | payload as u8);
+        } else if payload <= u16::MAX as usize {
+            out.push(mask_bit | 126);
+            out.extend_from_slice(&(payload as u16).to_be_bytes());
+        } else {
+            out.push(mask_bit | 127);
+            out.extend_from_slice(&(payload as u64).to_be_bytes());
+        }
+        if let Some(m) = mask {
+            out.extend_from_slice(&m);
+        }
+    }
+}
+
+fn apply_mask(data: &mut [u8], mask: [u8; 4]) {
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+}
+
+/// Stream under tungstenite that applies permessage-deflate once enabled.
+pub struct DeflateStream<S> {
+    inner: S,
+    /// Still reading the HTTP upgrade response.
+    in_handshake: bool,
+    params: Option<DeflateParams>,
+    /// Socket bytes not yet a whole frame.
+    rx_raw: Vec<u8>,
+    /// Frames rewritten for tungstenite, read from `rx_pos`.
+    rx_ready: Vec<u8>,
+    rx_pos: usize,
+    /// Inside a fragmented compressed message.
+    rx_compressed: bool,
+    inflater: Decompress,
+    /// Bytes from tungstenite not yet a whole frame.
+    tx_raw: Vec<u8>,
+    /// Rewritten frames not yet accepted by the socket.
+    tx_ready: Vec<u8>,
+    deflater: Compress,
+}
+
+impl<S> DeflateStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            in_handshake: true,
+            params: None,
+            rx_raw: Vec::new(),
+            rx_ready: Vec::new(),
+            rx_pos: 0,
+            rx_compressed: false,
+            inflater: Decompress::new(false),
+            tx_raw: Vec::new(),
+            tx_ready: Vec::new(),
+            deflater: Compress::new(Compression::default(), false),
+        }
+    }
+
+    /// Start compressing with what the server accepted. Call right after the upgrade.
+    pub fn enable(&mut self, params: DeflateParams) {
+        self.params = Some(params);
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Hand out `rx_raw` up to `limit` bytes.
+    fn take_raw(&mut self, buf: &mut [u8], limit: usize) -> usize {
+        let n = limit.min(buf.len());
+        buf[..n].copy_from_slice(&self.rx_raw[..n]);
+        self.rx_raw.drain(..n);
+        n
+    }
+
+    /// Move whole frames from `rx_raw` to `rx_ready`, inflating compressed ones.
+    fn decode_frames(&mut self, params: DeflateParams) -> io::Result<()> {
+        while let Some(h) = Header::parse(&self.rx_raw)? {
+            let end = h.len + h.payload;
+            if self.rx_raw.len() < end {
+                break;
+            }
+            let compressed = match h.opcode() {
+                0 => self.rx_compressed,
+                1 | 2 => h.rsv1(),
+                _ => false,
+            };
+            if h.opcode() < 8 {
+                self.rx_compressed = compressed && !h.fin();
+            }
+            if compressed {
+                let mut input = self.rx_raw[h.len..end].to_vec();
+                if let Some(m) = h.mask {
+                    apply_mask(&mut input, m);
+                }
+                if h.fin() {
+                    input.extend_from_slice(&TAIL);
+                }
+                let data = inflate(&mut self.inflater, &input)?;
+                Header::write(&mut self.rx_ready, h.first & !0x40, None, data.len());
+                self.rx_ready.extend_from_slice(&data);
+                if h.fin() && params.server_no_context_takeover {
+                    self.inflater.reset(false);
+                }
+            } else {
+                self.rx_ready.extend_from_slice(&self.rx_raw[..end]);
+            }
+            self.rx_raw.drain(..end);
+        }
+        Ok(())
+    }
+
+    /// Move whole frames from `tx_raw` to `tx_ready`, deflating unfragmented data frames.
+    fn encode_frames(&mut self, params: DeflateParams) -> io::Result<()> {
+        while let Some(h) = Header::parse(&self.tx_raw)? {
+            let end = h.len + h.payload;
+            if self.tx_raw.len() < end {
+                break;
+            }
+            let data_frame = matches!(h.opcode(), 1 | 2) && h.fin();
+            if params.compress_outgoing && data_frame && h.payload > 0 {
+                let mut payload = self.tx_raw[h.len..end].to_vec();
+                if let Some(m) = h.mask {
+                    apply_mask(&mut payload, m);
+                }
+                let mut data = deflate(&mut self.deflater, &payload)?;
+                if let Some(m) = h.mask {
+                    apply_mask(&mut data, m);
+                }
+                Header::write(&mut self.tx_ready, h.first | 0x40, h.mask, data.len());
+                self.tx_ready.extend_from_slice(&data);
+                if params.client_no_context_takeover {
+                    self.deflater.reset();
+                }
+            } else {
+                self.tx_ready.extend_from_slice(&self.tx_raw[..end]);
+            }
+            self.tx_raw.drain(..end);
+        }
+        Ok(())
+    }
+}
+
+impl<S: Write> DeflateStream<S> {
+    /// Write out `tx_ready` as far as the socket takes it.
+    fn drain(&mut self) -> io::Result<()> {
+        while !self.tx_ready.is_empty() {
+            match self.inner.write(&self.tx_ready)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => {
+                    self.tx_ready.drain(..n);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read> DeflateStream<S> {
+    /// Append what the socket has to `rx_raw`; false at end of stream.
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut chunk = [0u8; 8192];
+        let n = self.inner.read(&mut chunk)?;
+        self.rx_raw.extend_from_slice(&chunk[..n]);
+        Ok(n > 0)
+    }
+
+    /// Upgrade response bytes, never past the blank line ending its headers.
+    fn read_handshake(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(end) = self.rx_raw.windows(4).position(|w| w == b"\r\n\r\n") {
+                let n = self.take_raw(buf, end + 4);
+                self.in_handshake = n < end + 4;
+                return Ok(n);
+            }
+            if !self.fill()? {
+                let len = self.rx_raw.len();
+                return Ok(self.take_raw(buf, len));
+            }
+        }
+    }
+}
+
+impl<S: Read> Read for DeflateStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.in_handshake {
+            return self.read_handshake(buf);
+        }
+        let Some(params) = self.params else {
+            if self.rx_raw.is_empty() {
+                return self.inner.read(buf);
+            }
+            let len = self.rx_raw.len();
+            return Ok(self.take_raw(buf, len));
+        };
+        while self.rx_pos >= self.rx_ready.len() {
+            self.rx_ready.clear();
+            self.rx_pos = 0;
+            self.decode_frames(params)?;
+            if self.rx_ready.is_empty() && !self.fill()? {
+                return Ok(0);
+            }
+        }
+        let from = &self.rx_ready[self.rx_pos..];
+        let n = from.len().min(buf.len());
+        buf[..n].copy_from_slice(&from[..n]);
+        self.rx_pos += n;
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for DeflateStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(params) = self.params else {
+            return self.inner.write(buf);
+        };
+        self.tx_raw.extend_from_slice(buf);
+        self.encode_frames(params)?;
+        // Whatever the socket does not take now goes out on the next write or flush.
+        match self.drain() {
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock => return Err(e),
+            _ => {}
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.drain()?;
+        self.inner.flush()
+    }
+}
+
+/// Make sure `out` has room for at least `min` more bytes.
+fn reserve(out: &mut Vec<u8>, min: usize) {
+    if out.capacity() - out.len() < min {
+        out.reserve(out.len().max(min * 4));
+    }
+}
+
+/// Inflate one frame's worth of a raw deflate stream.
+fn inflate(inflater: &mut Decompress, input: &[u8]) -> io::Result<Vec<u8>> {
+    let start = inflater.total_in();
+    let mut out = Vec::new();
+    loop {
+        reserve(&mut out, 4096);
+        let consumed = (inflater.total_in() - start) as usize;
+        let produced = out.len();
+        let status = inflater
+            .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if out.len() > MAX_MESSAGE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "inflated WebSocket message too large",
+            ));
+        }
+        let now = (inflater.total_in() - start) as usize;
+        let done = now >= input.len() && out.len() < out.capacity();
+        let stalled = now == consumed && out.len() == produced;
+        if done || stalled || status == Status::StreamEnd {
+            return Ok(out);
+        }
+    }
+}
+
+/// Deflate one message with a sync flush, without the trailing `TAIL`.
+fn deflate(deflater: &mut Compress, input: &[u8]) -> io::Result<Vec<u8>> {
+    let start = deflater.total_in();
+    let mut out = Vec::with_capacity(input.len() / 2 + 64);
+    loop {
+        reserve(&mut out, 64);
+        let consumed = (deflater.total_in() - start) as usize;
+        deflater
+            .compress_vec(&input[consumed..], &mut out, FlushCompress::Sync)
+            .map_err(io::Error::other)?;
+        let now = (deflater.total_in() - start) as usize;
+        if now >= input.len() && out.len() < out.capacity() {
+            break;
+        }
+    }
+    if out.ends_with(&TAIL) {
+        out.truncate(out.len() - TAIL.len());
+    }
+    Ok(out)
+}
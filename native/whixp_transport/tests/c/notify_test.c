@@ -0,0 +1,220 @@
+/*
+ * Push mode harness: whixp_transport_set_notify must fire exactly once each time the event
+ * queue goes from empty to non-empty, never while unregistered, and survive registration
+ * churn while I/O runs. Built and run by `make test-notify` (POSIX).
+ */
+#include <arpa/inet.h>
+#include <netinet/in.h>
+#include <pthread.h>
+#include <stdatomic.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/socket.h>
+#include <time.h>
+#include <unistd.h>
+
+/* Leading fields of CTransportConfig (src/lib.rs). Fields are only appended and zero means
+ * default, so the rest of the zeroed buffer below stands in for them. */
+struct config_prefix {
+    const char *host_ptr;
+    uint32_t host_len;
+    uint16_t port;
+    int32_t kind;
+    uint32_t connect_timeout_ms;
+};
+
+typedef void (*notify_cb)(void *user_data);
+
+extern void *whixp_transport_create(const void *config);
+extern int32_t whixp_transport_connect(void *handle);
+extern int32_t whixp_transport_set_notify(void *handle, notify_cb cb, void *user_data);
+extern int32_t whixp_transport_drain(void *handle, uint8_t *buf, uint32_t buf_len,
+                                     uint32_t max_events, uint32_t *out_len);
+extern void whixp_transport_disconnect(void *handle);
+extern void whixp_transport_destroy(void *handle);
+
+#define ROUNDS 200
+#define CHURN 20000
+
+static const char HEADER[] =
+    "<stream:stream xmlns='jabber:client' "
+    "xmlns:stream='http://etherx.jabber.org/streams' from='localhost' version='1.0'>";
+
+static atomic_int fired;
+static atomic_int churning;
+static void *handle;
+static uint8_t buf[1 << 16];
+
+static void on_ready(void *user_data) {
+    atomic_fetch_add((atomic_int *)user_data, 1);
+}
+
+static void fail(const char *what, int got, int want) {
+    fprintf(stderr, "FAIL: %s: got %d, want %d\n", what, got, want);
+    exit(1);
+}
+
+static void sleep_ms(int ms) {
+    struct timespec ts = {ms / 1000, (long)(ms % 1000) * 1000000L};
+    nanosleep(&ts, NULL);
+}
+
+/* Wait until `fired` reaches `want`, then a little longer to catch extra calls. */
+static void expect_fired(const char *what, int want) {
+    for (int i = 0; i < 2000 && atomic_load(&fired) < want; i++) {
+        sleep_ms(1);
+    }
+    sleep_ms(20);
+    int got = atomic_load(&fired);
+    if (got != want) {
+        fail(what, got, want);
+    }
+}
+
+/* Drain until the queue is empty; returns the number of stanzas taken. */
+static int drain_all(void) {
+    int stanzas = 0;
+    for (;;) {
+        uint32_t len = 0;
+        int n = whixp_transport_drain(handle, buf, sizeof buf, 0, &len);
+        if (n < 0) {
+            fail("drain", n, 0);
+        }
+        if (n == 0) {
+            return stanzas;
+        }
+        for (uint32_t pos = 0; pos < len;) {
+            uint32_t type, plen;
+            memcpy(&type, buf + pos, 4);
+            memcpy(&plen, buf + pos + 8, 4);
+            stanzas += type == 2;
+            pos += (12 + plen + 3) & ~3u;
+        }
+    }
+}
+
+/* Send `count` stanzas as separate writes, so they may reach the queue one by one. */
+static void burst(int peer, int count) {
+    char stanza[96];
+    for (int i = 0; i < count; i++) {
+        int n = snprintf(stanza, sizeof stanza, "<message id='%d'><body>hi</body></message>", i);
+        if (write(peer, stanza, (size_t)n) != n) {
+            fail("write", 0, n);
+        }
+    }
+}
+
+/* Stream of stanzas until the churn is over. */
+static void *sender(void *arg) {
+    int peer = *(int *)arg;
+    while (atomic_load(&churning)) {
+        burst(peer, 1);
+        usleep(50);
+    }
+    return NULL;
+}
+
+int main(void) {
+    int listener = socket(AF_INET, SOCK_STREAM, 0);
+    struct sockaddr_in addr = {0};
+    addr.sin_family = AF_INET;
+    addr.sin_addr.s_addr = htonl(INADDR_LOOPBACK);
+    socklen_t addr_len = sizeof addr;
+    if (bind(listener, (struct sockaddr *)&addr, sizeof addr) != 0 || listen(listener, 1) != 0 ||
+        getsockname(listener, (struct sockaddr *)&addr, &addr_len) != 0) {
+        perror("listen");
+        return 1;
+    }
+
+    static union {
+        struct config_prefix c;
+        unsigned char zero[4096];
+    } config;
+    config.c.host_ptr = "127.0.0.1";
+    config.c.host_len = 9;
+    config.c.port = ntohs(addr.sin_port);
+    config.c.connect_timeout_ms = 5000;
+    handle = whixp_transport_create(&config);
+    if (!handle) {
+        fail("create", 0, 1);
+    }
+    whixp_transport_set_notify(handle, on_ready, &fired);
+    if (whixp_transport_connect(handle) != 0) {
+        fail("connect", 1, 0);
+    }
+    int peer = accept(listener, NULL, NULL);
+
+    /* The Connected state event was the first transition. */
+    int want = 1;
+    expect_fired("after connect", want);
+    drain_all();
+    if (write(peer, HEADER, sizeof HEADER - 1) != (ssize_t)(sizeof HEADER - 1)) {
+        fail("write header", 0, 1);
+    }
+    expect_fired("after header", ++want);
+    drain_all();
+
+    for (int round = 0; round < ROUNDS; round++) {
+        int count = 1 + round % 5;
+        burst(peer, count);
+        expect_fired("burst", ++want);
+        int got = 0;
+        while (got < count) {
+            got += drain_all();
+            if (got < count) {
+                sleep_ms(1);
+            }
+        }
+        if (got != count) {
+            fail("stanzas drained", got, count);
+        }
+        /* Stanzas that arrived after the first drain made their own transition. */
+        want = atomic_load(&fired);
+    }
+
+    /* Unregistered: events queue up silently. */
+    whixp_transport_set_notify(handle, NULL, NULL);
+    burst(peer, 3);
+    sleep_ms(100);
+    if (atomic_load(&fired) != want) {
+        fail("fired while unregistered", atomic_load(&fired), want);
+    }
+    /* Registering with events queued does not fire either. */
+    whixp_transport_set_notify(handle, on_ready, &fired);
+    sleep_ms(50);
+    if (atomic_load(&fired) != want) {
+        fail("fired on register", atomic_load(&fired), want);
+    }
+    if (drain_all() != 3) {
+        fail("stanzas queued while unregistered", 0, 3);
+    }
+
+    /* Registration churn while stanzas keep arriving. */
+    atomic_store(&churning, 1);
+    pthread_t thread;
+    pthread_create(&thread, NULL, sender, &peer);
+    for (int i = 0; i < CHURN; i++) {
+        whixp_transport_set_notify(handle, (i & 1) ? NULL : on_ready, &fired);
+        if (i % 64 == 0) {
+            drain_all();
+        }
+    }
+    atomic_store(&churning, 0);
+    pthread_join(thread, NULL);
+    whixp_transport_set_notify(handle, on_ready, &fired);
+    sleep_ms(100);
+    drain_all();
+
+    want = atomic_load(&fired) + 1;
+    burst(peer, 4);
+    expect_fired("after churn", want);
+
+    whixp_transport_disconnect(handle);
+    whixp_transport_destroy(handle);
+    close(peer);
+    close(listener);
+    printf("notify: ok (%d callbacks)\n", atomic_load(&fired));
+    return 0;
+}
@@ -0,0 +1,154 @@
+//! Channel binding data on TLS 1.3 and TLS 1.2, over direct TLS and an upgraded StartTLS
+//! stream: the advertised types, tls-exporter equal to the server's exported keying
+//! material, and tls-server-end-point equal to the hash of the leaf certificate.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use common::{issued, server_config_versions, Pki};
+use rustls::{ServerConnection, StreamOwned, SupportedProtocolVersion};
+use whixp_transport::config::{TlsConfig, TransportConfig, TransportKind};
+use whixp_transport::connection::{self, Connection, EventReceiver, TransportEvent};
+use whixp_transport::retry::RetryPolicy;
+use whixp_transport::tls::ChannelBinding;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const LABEL: &[u8] = b"EXPORTER-Channel-Binding";
+const STARTTLS: &str = "<starttls xmlns='urn:ietf:params:xml:ns:xmpp-tls'/>";
+const PROCEED: &str = "<proceed xmlns='urn:ietf:params:xml:ns:xmpp-tls'/>";
+
+/// Loopback server offering only `version`, negotiating StartTLS first if `starttls`.
+/// Sends its RFC 9266 exporter output (None on TLS 1.2) once the handshake is done and
+/// holds the connection until the peer closes it.
+fn server(
+    pki: &Pki,
+    version: &'static SupportedProtocolVersion,
+    starttls: bool,
+) -> (u16, mpsc::Receiver<Option<Vec<u8>>>) {
+    let config = server_config_versions(pki, &[version]);
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let port = listener.local_addr().expect("local addr").port();
+    let (tx, exported) = mpsc::channel();
+    thread::spawn(move || {
+        let (mut tcp, _) = listener.accept().expect("accept");
+        let mut buf = [0u8; 1024];
+        if starttls {
+            let mut got = Vec::new();
+            while !got.windows(9).any(|w| w == b"<starttls") {
+                match tcp.read(&mut buf) {
+                    Ok(n @ 1..) => got.extend_from_slice(&buf[..n]),
+                    _ => return,
+                }
+            }
+            tcp.write_all(PROCEED.as_bytes()).expect("proceed");
+        }
+        let conn = ServerConnection::new(config).expect("server connection");
+        let mut tls = StreamOwned::new(conn, tcp);
+        while tls.conn.is_handshaking() {
+            tls.conn
+                .complete_io(&mut tls.sock)
+                .expect("server handshake");
+        }
+        let material = match tls.conn.protocol_version() {
+            Some(rustls::ProtocolVersion::TLSv1_3) => Some(
+                tls.conn
+                    .export_keying_material([0u8; 32], LABEL, Some(&[]))
+                    .expect("export")
+                    .to_vec(),
+            ),
+            _ => None,
+        };
+        let _ = tx.send(material);
+        while matches!(tls.read(&mut buf), Ok(n) if n > 0) {}
+    });
+    (port, exported)
+}
+
+fn wait_for_proceed(events: &EventReceiver) {
+    loop {
+        match events.recv_timeout(TIMEOUT).expect("event") {
+            TransportEvent::Stanza(s) if s == PROCEED => return,
+            TransportEvent::Error(code, message) => panic!("error {}: {}", code, message),
+            _ => {}
+        }
+    }
+}
+
+/// Connect over TLS `version` (directly or by StartTLS) and check both binding types.
+fn check(version: &'static SupportedProtocolVersion, kind: TransportKind) {
+    let pki = issued(&["localhost"]);
+    let (port, exported) = server(&pki, version, kind == TransportKind::TcpStartTls);
+    let config = TransportConfig {
+        host: "localhost".to_string(),
+        port,
+        kind,
+        tls: TlsConfig {
+            extra_roots: pki.anchor_pem.as_bytes().to_vec(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut conn = Connection::new(config, RetryPolicy::default());
+    let (event_tx, events) = connection::event_channel(Default::default());
+    conn.connect_sync(event_tx).expect("connect");
+    if kind == TransportKind::TcpStartTls {
+        assert!(conn.channel_bindings().is_empty(), "bindings in plaintext");
+        conn.send(STARTTLS.as_bytes()).expect("send");
+        wait_for_proceed(&events);
+        conn.starttls().expect("upgrade");
+    }
+    let exported = exported.recv_timeout(TIMEOUT).expect("server handshake");
+
+    let end_point = ring::digest::digest(&ring::digest::SHA256, pki.leaf().as_ref());
+    assert_eq!(
+        conn.channel_binding(ChannelBinding::TlsServerEndPoint)
+            .expect("tls-server-end-point"),
+        end_point.as_ref()
+    );
+    match exported {
+        Some(material) => {
+            assert_eq!(
+                conn.channel_bindings(),
+                [
+                    ChannelBinding::TlsExporter,
+                    ChannelBinding::TlsServerEndPoint
+                ]
+            );
+            assert_eq!(
+                conn.channel_binding(ChannelBinding::TlsExporter)
+                    .expect("tls-exporter"),
+                material
+            );
+        }
+        None => {
+            assert_eq!(conn.channel_bindings(), [ChannelBinding::TlsServerEndPoint]);
+            assert!(conn.channel_binding(ChannelBinding::TlsExporter).is_err());
+        }
+    }
+    conn.shutdown();
+}
+
+#[test]
+fn direct_tls13() {
+    check(&rustls::version::TLS13, TransportKind::DirectTls);
+}
+
+#[test]
+fn direct_tls12() {
+    check(&rustls::version::TLS12, TransportKind::DirectTls);
+}
+
+#[test]
+fn starttls_tls13() {
+    check(&rustls::version::TLS13, TransportKind::TcpStartTls);
+}
+
+#[test]
+fn starttls_tls12() {
+    check(&rustls::version::TLS12, TransportKind::TcpStartTls);
+}
@@ -0,0 +1,275 @@
+//! Helpers shared by the integration tests: locally generated certificates (rcgen) and a
+//! loopback rustls server.
+
+#![allow(dead_code)]
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{
+    HandshakeKind, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+    SupportedProtocolVersion,
+};
+
+/// A server certificate chain with its key, and the PEM a client trusts it by.
+pub struct Pki {
+    /// The issuing CA, or the leaf itself when self-signed.
+    pub anchor_pem: String,
+    pub chain: Vec<CertificateDer<'static>>,
+    /// The leaf's private key as PKCS#8 PEM.
+    pub key_pem: String,
+    key: Vec<u8>,
+}
+
+impl Pki {
+    pub fn key(&self) -> PrivateKeyDer<'static> {
+        PrivatePkcs8KeyDer::from(self.key.clone()).into()
+    }
+
+    /// The leaf's private key as PKCS#8 DER.
+    pub fn key_der(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn leaf(&self) -> &CertificateDer<'static> {
+        &self.chain[0]
+    }
+}
+
+/// Leaf for `names`, issued by a new CA.
+pub fn issued(names: &[&str]) -> Pki {
+    let ca_key = KeyPair::generate().expect("CA key");
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).expect("CA params");
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, "whixp test CA");
+    let ca = ca_params.self_signed(&ca_key).expect("CA certificate");
+
+    let key = KeyPair::generate().expect("leaf key");
+    let leaf = leaf_params(names)
+        .signed_by(&key, &ca, &ca_key)
+        .expect("leaf certificate");
+    Pki {
+        anchor_pem: ca.pem(),
+        chain: vec![leaf.der().clone()],
+        key_pem: key.serialize_pem(),
+        key: key.serialize_der(),
+    }
+}
+
+/// Self-signed leaf for `names`.
+pub fn self_signed(names: &[&str]) -> Pki {
+    let key = KeyPair::generate().expect("key");
+    let cert = leaf_params(names).self_signed(&key).expect("certificate");
+    Pki {
+        anchor_pem: cert.pem(),
+        chain: vec![cert.der().clone()],
+        key_pem: key.serialize_pem(),
+        key: key.serialize_der(),
+    }
+}
+
+fn leaf_params(names: &[&str]) -> CertificateParams {
+    let names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+    CertificateParams::new(names).expect("leaf params")
+}
+
+pub fn server_config(pki: &Pki) -> Arc<ServerConfig> {
+    server_config_versions(pki, rustls::DEFAULT_VERSIONS)
+}
+
+/// As `server_config`, offering only `versions`.
+pub fn server_config_versions(
+    pki: &Pki,
+    versions: &[&'static SupportedProtocolVersion],
+) -> Arc<ServerConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(versions)
+        .expect("protocol versions")
+        .with_no_client_auth()
+        .with_single_cert(pki.chain.clone(), pki.key())
+        .expect("server certificate");
+    Arc::new(config)
+}
+
+/// As `server_config`, also requiring a client certificate issued by `client_ca`.
+pub fn client_auth_config(pki: &Pki, client_ca: &Pki) -> Arc<ServerConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut roots = RootCertStore::empty();
+    let ca = CertificateDer::from_pem_slice(client_ca.anchor_pem.as_bytes()).expect("CA PEM");
+    roots.add(ca).expect("client CA");
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .expect("client verifier");
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .expect("protocol versions")
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(pki.chain.clone(), pki.key())
+        .expect("server certificate");
+    Arc::new(config)
+}
+
+/// Loopback TLS server. `handshakes` gets each connection's handshake kind (or error);
+/// after a handshake the server writes `READY` and echoes what it reads until the peer
+/// closes the connection.
+pub struct TlsServer {
+    pub port: u16,
+    pub handshakes: mpsc::Receiver<Result<HandshakeKind, String>>,
+}
+
+/// Written by `TlsServer` once the handshake is done; reading it also takes in the
+/// TLS 1.3 session tickets sent right after the handshake.
+pub const READY: &[u8] = b"ok";
+
+pub fn tls_server(pki: &Pki) -> TlsServer {
+    tls_server_with(server_config(pki))
+}
+
+/// `tls_server` with its own server config.
+pub fn tls_server_with(config: Arc<ServerConfig>) -> TlsServer {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let port = listener.local_addr().expect("local addr").port();
+    let (tx, handshakes) = mpsc::channel();
+    thread::spawn(move || {
+        for tcp in listener.incoming().flatten() {
+            let (config, tx) = (Arc::clone(&config), tx.clone());
+            thread::spawn(move || serve_tls(tcp, config, tx));
+        }
+    });
+    TlsServer { port, handshakes }
+}
+
+/// One server-side TLS connection on `tcp` (also used after a plaintext StartTLS exchange).
+pub fn serve_tls(
+    tcp: TcpStream,
+    config: Arc<ServerConfig>,
+    tx: mpsc::Sender<Result<HandshakeKind, String>>,
+) {
+    let conn = ServerConnection::new(config).expect("server connection");
+    let mut tls = StreamOwned::new(conn, tcp);
+    while tls.conn.is_handshaking() {
+        if let Err(e) = tls.conn.complete_io(&mut tls.sock) {
+            let _ = tx.send(Err(e.to_string()));
+            return;
+        }
+    }
+    let kind = tls.conn.handshake_kind().expect("handshake kind");
+    let _ = tx.send(Ok(kind));
+    if tls.write_all(READY).and_then(|_| tls.flush()).is_err() {
+        return;
+    }
+    let mut buf = [0u8; 1024];
+    while let Ok(n @ 1..) = tls.read(&mut buf) {
+        if tls.write_all(&buf[..n]).and_then(|_| tls.flush()).is_err() {
+            return;
+        }
+    }
+}
+
+/// Loopback XMPP server that negotiates StartTLS in plaintext, then serves TLS as
+/// `tls_server` does. It reads until the client's `<starttls/>` and answers with `reply`
+/// (which should end in `<proceed/>`) in a single write.
+pub fn starttls_server(pki: &Pki, reply: Vec<u8>) -> TlsServer {
+    starttls_server_with(server_config(pki), reply)
+}
+
+/// `starttls_server` with its own server config.
+pub fn starttls_server_with(config: Arc<ServerConfig>, reply: Vec<u8>) -> TlsServer {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let port = listener.local_addr().expect("local addr").port();
+    let (tx, handshakes) = mpsc::channel();
+    thread::spawn(move || {
+        for mut tcp in listener.incoming().flatten() {
+            let (config, tx, reply) = (Arc::clone(&config), tx.clone(), reply.clone());
+            thread::spawn(move || {
+                let (mut got, mut buf) = (Vec::new(), [0u8; 1024]);
+                while !got.windows(9).any(|w| w == b"<starttls") {
+                    match tcp.read(&mut buf) {
+                        Ok(n @ 1..) => got.extend_from_slice(&buf[..n]),
+                        _ => return,
+                    }
+                }
+                if tcp.write_all(&reply).is_ok() {
+                    serve_tls(tcp, config, tx);
+                }
+            });
+        }
+    });
+    TlsServer { port, handshakes }
+}
+
+/// Accepts connections and never writes to (nor closes) them.
+pub fn silent_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let port = listener.local_addr().expect("local addr").port();
+    thread::spawn(move || {
+        let mut open = Vec::new();
+        for tcp in listener.incoming().flatten() {
+            open.push(tcp);
+        }
+    });
+    port
+}
+
+/// A listener whose accept queue is full, so connects to `addr` neither complete nor fail.
+/// Drop it to close the queue.
+pub struct Blackhole {
+    pub addr: SocketAddr,
+    _listener: socket2::Socket,
+    _queued: TcpStream,
+}
+
+/// Backlog 0 with one connection never accepted: further SYNs are dropped.
+pub fn blackhole() -> Blackhole {
+    use socket2::{Domain, Socket, Type};
+    let listener = Socket::new(Domain::IPV4, Type::STREAM, None).expect("socket");
+    let any: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    listener.bind(&any.into()).expect("bind");
+    listener.listen(0).expect("listen");
+    let addr = listener
+        .local_addr()
+        .ok()
+        .and_then(|a| a.as_socket())
+        .expect("local addr");
+    let queued = TcpStream::connect(addr).expect("fill the accept queue");
+    Blackhole {
+        addr,
+        _listener: listener,
+        _queued: queued,
+    }
+}
+
+/// Answers the client's first bytes (a ClientHello) with the header of a 16 KiB TLS record,
+/// then sends its body one byte every `interval`: each read on the client returns before a
+/// per-read timeout longer than `interval` fires, but the handshake never completes.
+pub fn trickle_server(interval: Duration) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let port = listener.local_addr().expect("local addr").port();
+    thread::spawn(move || {
+        for mut tcp in listener.incoming().flatten() {
+            thread::spawn(move || {
+                let mut buf = [0u8; 4096];
+                if !matches!(tcp.read(&mut buf), Ok(n) if n > 0) {
+                    return;
+                }
+                if tcp.write_all(b"\x16\x03\x03\x40\x00").is_err() {
+                    return;
+                }
+                while tcp.write_all(b"\x00").is_ok() {
+                    thread::sleep(interval);
+                }
+            });
+        }
+    });
+    port
+}
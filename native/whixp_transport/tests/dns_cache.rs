@@ -0,0 +1,198 @@
+//! DNS answer cache on its own instance with an injected clock: answers live for their TTL,
+//! an expired answer is served within the stale grace while one background refresh runs,
+//! a failed refresh keeps it, `flush` drops everything, and the DoH endpoints are part of
+//! the key.
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use whixp_transport::dns_cache::{CacheKey, CachedAnswer, DnsCache};
+use whixp_transport::handshake::HandshakeError;
+
+const TTL: Duration = Duration::from_secs(60);
+const GRACE: Duration = Duration::from_secs(30);
+
+fn key(host: &str) -> CacheKey {
+    CacheKey::Ips {
+        host: host.to_string(),
+        v4: true,
+        v6: true,
+        doh: Vec::new(),
+    }
+}
+
+fn ips(last: u8) -> CachedAnswer {
+    CachedAnswer::Ips(vec![IpAddr::from([192, 0, 2, last])])
+}
+
+/// Resolver answering `ips(last)` for `TTL` and counting its calls.
+fn resolver(
+    calls: &Arc<AtomicUsize>,
+    last: u8,
+) -> impl FnOnce() -> Result<(CachedAnswer, Duration), HandshakeError> + Send + 'static {
+    let calls = Arc::clone(calls);
+    move || {
+        calls.fetch_add(1, Ordering::SeqCst);
+        Ok((ips(last), TTL))
+    }
+}
+
+/// Ask at `now` within the grace window until the background refresh has landed.
+fn wait_for_refresh(cache: &DnsCache, key: &CacheKey, now: Instant, want: &CachedAnswer) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let answer = cache
+            .get_or_resolve_at(key.clone(), GRACE, now, || {
+                panic!("resolved in the foreground")
+            })
+            .expect("answer");
+        if answer == *want {
+            return;
+        }
+        assert!(Instant::now() < deadline, "refresh never landed");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn answer_lives_for_its_ttl() {
+    let (cache, calls) = (DnsCache::new(), Arc::new(AtomicUsize::new(0)));
+    let t0 = Instant::now();
+    let get = |at: Instant, last: u8| {
+        cache
+            .get_or_resolve_at(key("ttl.test"), Duration::ZERO, at, resolver(&calls, last))
+            .expect("answer")
+    };
+    assert_eq!(get(t0, 1), ips(1));
+    assert_eq!(get(t0 + TTL - Duration::from_secs(1), 2), ips(1));
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    // Expired without a grace window: resolved again before answering.
+    assert_eq!(get(t0 + TTL, 3), ips(3));
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn stale_answer_within_grace() {
+    let (cache, calls) = (DnsCache::new(), Arc::new(AtomicUsize::new(0)));
+    let key = key("stale.test");
+    let t0 = Instant::now();
+    cache
+        .get_or_resolve_at(key.clone(), GRACE, t0, resolver(&calls, 1))
+        .expect("answer");
+
+    let stale = t0 + TTL + GRACE / 2;
+    let answer = cache
+        .get_or_resolve_at(key.clone(), GRACE, stale, resolver(&calls, 2))
+        .expect("answer");
+    assert_eq!(answer, ips(1), "served the stale answer");
+    wait_for_refresh(&cache, &key, stale, &ips(2));
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    // The refreshed answer lives for its TTL from the request that refreshed it; past
+    // that and the grace window, the next request waits for a new answer.
+    let gone = stale + TTL + GRACE;
+    let answer = cache
+        .get_or_resolve_at(key, GRACE, gone, resolver(&calls, 3))
+        .expect("answer");
+    assert_eq!(answer, ips(3));
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn one_refresh_at_a_time() {
+    let (cache, calls) = (DnsCache::new(), Arc::new(AtomicUsize::new(0)));
+    let key = key("single.test");
+    let t0 = Instant::now();
+    cache
+        .get_or_resolve_at(key.clone(), GRACE, t0, resolver(&calls, 1))
+        .expect("answer");
+
+    // A refresh that fails once released.
+    let (release, released) = mpsc::channel::<()>();
+    let (failed_tx, failed) = mpsc::channel();
+    let blocked_calls = Arc::clone(&calls);
+    let stale = t0 + TTL + Duration::from_secs(1);
+    let answer = cache
+        .get_or_resolve_at(key.clone(), GRACE, stale, move || {
+            blocked_calls.fetch_add(1, Ordering::SeqCst);
+            let _ = released.recv();
+            let _ = failed_tx.send(());
+            Err(HandshakeError::Connection("refresh failed".into()))
+        })
+        .expect("answer");
+    assert_eq!(answer, ips(1));
+    for last in 2..5 {
+        let answer = cache
+            .get_or_resolve_at(key.clone(), GRACE, stale, resolver(&calls, last))
+            .expect("answer");
+        assert_eq!(answer, ips(1));
+    }
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while calls.load(Ordering::SeqCst) < 2 {
+        assert!(Instant::now() < deadline, "refresh never started");
+        thread::sleep(Duration::from_millis(10));
+    }
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(calls.load(Ordering::SeqCst), 2, "started a second refresh");
+
+    // The failure keeps the stale answer and lets the next request refresh again.
+    drop(release);
+    failed
+        .recv_timeout(Duration::from_secs(5))
+        .expect("refresh ended");
+    while calls.load(Ordering::SeqCst) < 3 {
+        let answer = cache
+            .get_or_resolve_at(key.clone(), GRACE, stale, resolver(&calls, 5))
+            .expect("answer");
+        assert_eq!(answer, ips(1));
+        assert!(Instant::now() < deadline, "no refresh after the failure");
+        thread::sleep(Duration::from_millis(10));
+    }
+    wait_for_refresh(&cache, &key, stale, &ips(5));
+}
+
+#[test]
+fn flush_drops_answers() {
+    let (cache, calls) = (DnsCache::new(), Arc::new(AtomicUsize::new(0)));
+    let t0 = Instant::now();
+    for last in [1, 2] {
+        let answer = cache
+            .get_or_resolve_at(key("flush.test"), GRACE, t0, resolver(&calls, last))
+            .expect("answer");
+        assert_eq!(answer, ips(1));
+    }
+    cache.flush();
+    let answer = cache
+        .get_or_resolve_at(key("flush.test"), GRACE, t0, resolver(&calls, 3))
+        .expect("answer");
+    assert_eq!(answer, ips(3));
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn doh_endpoints_are_part_of_the_key() {
+    let (cache, calls) = (DnsCache::new(), Arc::new(AtomicUsize::new(0)));
+    let t0 = Instant::now();
+    let with_doh = |endpoints: &[&str]| CacheKey::Ips {
+        host: "doh.test".to_string(),
+        v4: true,
+        v6: true,
+        doh: endpoints.iter().map(|e| e.to_string()).collect(),
+    };
+    let inside = with_doh(&["https://dns.internal.example/dns-query"]);
+    let public = with_doh(&[]);
+    let first = cache
+        .get_or_resolve_at(inside.clone(), GRACE, t0, resolver(&calls, 1))
+        .expect("answer");
+    let second = cache
+        .get_or_resolve_at(public, GRACE, t0, resolver(&calls, 2))
+        .expect("answer");
+    assert_eq!((first, second), (ips(1), ips(2)));
+    let again = cache
+        .get_or_resolve_at(inside, GRACE, t0, resolver(&calls, 3))
+        .expect("answer");
+    assert_eq!(again, ips(1));
+}
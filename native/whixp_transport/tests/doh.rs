@@ -0,0 +1,162 @@
+//! DNS-over-HTTPS against a local HTTP stand-in: JSON and RFC 8484 wire format, endpoints
+//! that carry their own query string, and a stalled endpoint bounded by the timeout.
+
+#![cfg(feature = "doh")]
+
+mod common;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, TcpListener};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use trust_dns_resolver::proto::op::{Message, MessageType};
+use trust_dns_resolver::proto::rr::rdata::SRV;
+use trust_dns_resolver::proto::rr::{Name, RData, Record, RecordType};
+use whixp_transport::config::{DohConfig, DohFormat, IpPreference};
+use whixp_transport::doh;
+
+/// Request line and body of one HTTP request.
+type Request = (String, Vec<u8>);
+
+/// HTTP/1.1 stand-in: answers every request with `respond` (content type, body) and reports
+/// it on the returned channel.
+fn http_server(respond: fn(&Request) -> (&'static str, Vec<u8>)) -> (u16, mpsc::Receiver<Request>) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let port = listener.local_addr().expect("local addr").port();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for tcp in listener.incoming().flatten() {
+            let mut reader = BufReader::new(tcp);
+            let mut line = String::new();
+            let mut content_length = 0;
+            reader.read_line(&mut line).expect("request line");
+            let request_line = line.trim_end().to_string();
+            loop {
+                line.clear();
+                reader.read_line(&mut line).expect("header");
+                let header = line.trim_end().to_ascii_lowercase();
+                if header.is_empty() {
+                    break;
+                }
+                if let Some(len) = header.strip_prefix("content-length:") {
+                    content_length = len.trim().parse().expect("content length");
+                }
+            }
+            let mut body = vec![0u8; content_length];
+            reader.read_exact(&mut body).expect("body");
+            let request = (request_line, body);
+            let (content_type, answer) = respond(&request);
+            let mut tcp = reader.into_inner();
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                content_type,
+                answer.len()
+            );
+            let _ = tcp.write_all(head.as_bytes());
+            let _ = tcp.write_all(&answer);
+            let _ = tx.send(request);
+        }
+    });
+    (port, rx)
+}
+
+fn json_answer((request_line, _): &Request) -> (&'static str, Vec<u8>) {
+    let body = if request_line.contains("type=SRV") {
+        r#"{"Status":0,"Answer":[{"type":33,"data":"5 10 5223 xmpp.example.com.","TTL":120}]}"#
+    } else {
+        r#"{"Status":0,"Answer":[{"type":1,"data":"192.0.2.7","TTL":300}]}"#
+    };
+    ("application/dns-json", body.as_bytes().to_vec())
+}
+
+fn wire_answer((_, body): &Request) -> (&'static str, Vec<u8>) {
+    let query = Message::from_vec(body).expect("DNS query");
+    let question = query.queries()[0].clone();
+    assert_eq!(question.query_type(), RecordType::SRV);
+    let mut answer = Message::new();
+    answer
+        .set_id(query.id())
+        .set_message_type(MessageType::Response)
+        .add_query(question.clone());
+    let target = Name::from_ascii("xmpp.example.com.").unwrap();
+    let srv = SRV::new(5, 10, 5223, target);
+    answer.add_answer(Record::from_rdata(
+        question.name().clone(),
+        120,
+        RData::SRV(srv),
+    ));
+    ("application/dns-message", answer.to_vec().expect("encode"))
+}
+
+fn config(endpoints: Vec<String>, format: DohFormat) -> DohConfig {
+    DohConfig {
+        endpoints,
+        format,
+        timeout: Duration::from_millis(500),
+    }
+}
+
+#[test]
+fn json_keeps_the_endpoint_query_string() {
+    let (port, requests) = http_server(json_answer);
+    let endpoint = format!("http://127.0.0.1:{}/resolve?key=abc", port);
+    let doh = config(vec![endpoint], DohFormat::Json);
+
+    let (ips, ttl) = doh::query_ips(&doh, "example.com", IpPreference::Ipv4Only).expect("A");
+    assert_eq!(ips, vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7))]);
+    assert_eq!(ttl, Duration::from_secs(300));
+    let (request_line, _) = requests.recv().unwrap();
+    assert_eq!(
+        request_line,
+        "GET /resolve?key=abc&name=example.com&type=A HTTP/1.1"
+    );
+
+    let (records, ttl) = doh::query_srv(&doh, "_xmpp-client._tcp.example.com").expect("SRV");
+    assert_eq!(records.len(), 1);
+    assert_eq!(
+        (records[0].priority, records[0].weight, records[0].port),
+        (5, 10, 5223)
+    );
+    assert_eq!(records[0].target, "xmpp.example.com.");
+    assert_eq!(ttl, Duration::from_secs(120));
+}
+
+#[test]
+fn wire_format_srv() {
+    let (port, requests) = http_server(wire_answer);
+    let endpoint = format!("http://127.0.0.1:{}/dns-query", port);
+    let doh = config(vec![endpoint], DohFormat::Wire);
+
+    let (records, ttl) = doh::query_srv(&doh, "_xmpp-client._tcp.example.com").expect("SRV");
+    assert_eq!(records.len(), 1);
+    assert_eq!(
+        (records[0].priority, records[0].weight, records[0].port),
+        (5, 10, 5223)
+    );
+    assert_eq!(records[0].target, "xmpp.example.com.");
+    assert_eq!(ttl, Duration::from_secs(120));
+    let (request_line, _) = requests.recv().unwrap();
+    assert_eq!(request_line, "POST /dns-query HTTP/1.1");
+}
+
+#[test]
+fn stalled_endpoint_times_out() {
+    let stalled = format!("http://127.0.0.1:{}/dns-query", common::silent_server());
+    let doh = config(vec![stalled.clone()], DohFormat::Json);
+    let start = Instant::now();
+    assert!(doh::query_ips(&doh, "example.com", IpPreference::Ipv4Only).is_err());
+    assert!(
+        start.elapsed() < Duration::from_secs(3),
+        "took {:?}",
+        start.elapsed()
+    );
+
+    // The next endpoint still gets its turn.
+    let (port, _requests) = http_server(json_answer);
+    let working = format!("http://127.0.0.1:{}/dns-query", port);
+    let doh = config(vec![stalled, working], DohFormat::Json);
+    let (ips, _) = doh::query_ips(&doh, "example.com", IpPreference::Ipv4Only).expect("A");
+    assert_eq!(ips, vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7))]);
+}
@@ -0,0 +1,165 @@
+//! Fallback across SRV targets (seeded into the DNS cache) when the handshake fails, not only
+//! the TCP connect: a target that drops or stalls the TLS handshake hands over to the next
+//! one, while a certificate failure ends the attempt.
+
+mod common;
+
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+use common::{issued, silent_server, tls_server, Pki};
+use whixp_transport::config::{TlsConfig, TransportConfig, TransportKind};
+use whixp_transport::connection::{self, Connection};
+use whixp_transport::dns::SrvRecord;
+use whixp_transport::dns_cache::{self, CacheKey, CachedAnswer};
+use whixp_transport::handshake::{HandshakeError, HandshakeErrorCode};
+use whixp_transport::retry::RetryPolicy;
+
+const HANDSHAKE_TIMEOUT_MS: u32 = 300;
+const TTL: Duration = Duration::from_secs(3600);
+
+/// Accepts connections and closes them at once.
+fn closing_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let port = listener.local_addr().expect("local addr").port();
+    thread::spawn(move || {
+        for tcp in listener.incoming().flatten() {
+            drop(tcp);
+        }
+    });
+    port
+}
+
+fn seed(key: CacheKey, answer: CachedAnswer) {
+    dns_cache::get_or_resolve(key, Duration::ZERO, move || Ok((answer, TTL))).expect("seed");
+}
+
+/// Cache key of SRV record `name` for the default DoH endpoints.
+fn srv_key(name: String) -> CacheKey {
+    CacheKey::Srv {
+        name,
+        doh: Vec::new(),
+    }
+}
+
+/// Direct TLS SRV targets for `domain` on loopback, in order of `ports`.
+fn seed_targets(domain: &str, ports: &[u16]) {
+    let records = ports
+        .iter()
+        .enumerate()
+        .map(|(i, &port)| SrvRecord {
+            priority: i as u16,
+            weight: 0,
+            port,
+            target: "127.0.0.1.".to_string(),
+            direct_tls: true,
+        })
+        .collect();
+    let xmpps = format!("_xmpps-client._tcp.{}", domain);
+    seed(srv_key(xmpps), CachedAnswer::Srv(records));
+    let xmpp = format!("_xmpp-client._tcp.{}", domain);
+    seed(srv_key(xmpp), CachedAnswer::Srv(Vec::new()));
+}
+
+/// Direct TLS connect to `domain`, trusting `pki`'s CA.
+fn connect(domain: &str, pki: &Pki) -> Result<String, HandshakeError> {
+    let config = TransportConfig {
+        host: domain.to_string(),
+        port: 5223,
+        kind: TransportKind::DirectTls,
+        service: Some("xmpp-client".to_string()),
+        handshake_timeout_ms: HANDSHAKE_TIMEOUT_MS,
+        tls: TlsConfig {
+            extra_roots: pki.anchor_pem.as_bytes().to_vec(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut conn = Connection::new(config, RetryPolicy::default());
+    let (event_tx, _events) = connection::event_channel(Default::default());
+    let result = conn.connect_sync(event_tx);
+    conn.shutdown();
+    result
+}
+
+#[test]
+fn dropped_handshake_moves_to_next_target() {
+    let domain = "dropped.fallback.test";
+    let pki = issued(&[domain]);
+    let server = tls_server(&pki);
+    seed_targets(domain, &[closing_server(), server.port]);
+    assert_eq!(connect(domain, &pki).expect("second target"), "127.0.0.1");
+    server
+        .handshakes
+        .recv_timeout(Duration::from_secs(5))
+        .expect("handshake")
+        .expect("handshake succeeded");
+}
+
+#[test]
+fn stalled_handshake_moves_to_next_target() {
+    let domain = "stalled.fallback.test";
+    let pki = issued(&[domain]);
+    let server = tls_server(&pki);
+    seed_targets(domain, &[silent_server(), server.port]);
+    connect(domain, &pki).expect("second target");
+}
+
+#[test]
+fn bad_certificate_ends_the_attempt() {
+    let domain = "badcert.fallback.test";
+    let pki = issued(&[domain]);
+    let wrong = issued(&["other.example"]);
+    let (impostor, server) = (tls_server(&wrong), tls_server(&pki));
+    seed_targets(domain, &[impostor.port, server.port]);
+    let err = connect(domain, &pki).expect_err("impostor certificate");
+    assert_eq!(
+        HandshakeErrorCode::from(&err),
+        HandshakeErrorCode::BadCertificate
+    );
+    let message = err.to_string();
+    let impostor = format!("127.0.0.1:{}", impostor.port);
+    assert!(message.contains(&impostor), "{}", message);
+    let next = format!("127.0.0.1:{}", server.port);
+    assert!(!message.contains(&next), "{}", message);
+    assert!(
+        server
+            .handshakes
+            .recv_timeout(Duration::from_millis(300))
+            .is_err(),
+        "tried the next target"
+    );
+}
+
+#[test]
+fn all_handshakes_failing_reports_the_last_error() {
+    let domain = "allfail.fallback.test";
+    let pki = issued(&[domain]);
+    let (closing, silent) = (closing_server(), silent_server());
+    seed_targets(domain, &[closing, silent]);
+    let err = connect(domain, &pki).expect_err("no target completes the handshake");
+    assert_eq!(HandshakeErrorCode::from(&err), HandshakeErrorCode::Timeout);
+    let message = err.to_string();
+    assert!(
+        message.starts_with("all connection candidates failed: "),
+        "{}",
+        message
+    );
+    let failures: Vec<&str> = message["all connection candidates failed: ".len()..]
+        .split("; ")
+        .collect();
+    assert_eq!(failures.len(), 2, "{}", message);
+    assert!(
+        failures[0].starts_with(&format!("127.0.0.1:{}: ", closing)),
+        "{}",
+        message
+    );
+    assert_eq!(
+        failures[1],
+        format!(
+            "127.0.0.1:{}: timeout after {}ms",
+            silent, HANDSHAKE_TIMEOUT_MS
+        )
+    );
+}
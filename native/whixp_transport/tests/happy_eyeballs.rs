@@ -0,0 +1,90 @@
+//! Happy Eyeballs connects on loopback: the reachable address wins over refused and
+//! unanswered ones, and a deadline or a cancel ends attempts that never complete.
+
+mod common;
+
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::blackhole;
+use whixp_transport::cancel::Cancel;
+use whixp_transport::happy_eyeballs;
+
+const DELAY: Duration = Duration::from_millis(250);
+
+/// An address nothing listens on.
+fn refused() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    listener.local_addr().expect("local addr")
+}
+
+#[test]
+fn reachable_address_wins() {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let open = listener.local_addr().expect("local addr");
+    let addrs = [refused(), refused(), open];
+    let start = Instant::now();
+    let (tcp, addr) = happy_eyeballs::connect(
+        &addrs,
+        DELAY,
+        start + Duration::from_secs(5),
+        &Cancel::default(),
+    )
+    .expect("connect");
+    assert_eq!(addr, open);
+    assert_eq!(tcp.peer_addr().expect("peer"), open);
+    // Refusals start the next attempt at once instead of after the attempt delay.
+    assert!(start.elapsed() < DELAY, "took {:?}", start.elapsed());
+}
+
+#[test]
+fn all_refused() {
+    let addrs = [refused(), refused()];
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let failures = happy_eyeballs::connect(&addrs, DELAY, deadline, &Cancel::default())
+        .expect_err("no listener");
+    assert_eq!(failures.len(), 2);
+    for (failure, addr) in failures.iter().zip(addrs) {
+        assert!(failure.starts_with(&addr.to_string()), "{}", failure);
+    }
+}
+
+#[test]
+fn deadline_ends_pending_attempts() {
+    let blackhole = blackhole();
+    let start = Instant::now();
+    let deadline = start + Duration::from_millis(300);
+    let result = happy_eyeballs::connect(&[blackhole.addr], DELAY, deadline, &Cancel::default());
+    let failures = result.expect_err("unreachable");
+    assert!(
+        start.elapsed() < Duration::from_secs(2),
+        "took {:?}",
+        start.elapsed()
+    );
+    assert_eq!(failures.len(), 1);
+    assert!(failures[0].ends_with("overall timeout"), "{}", failures[0]);
+}
+
+#[test]
+fn cancel_ends_pending_attempts() {
+    let blackhole = blackhole();
+    let cancel = Cancel::default();
+    let canceller = cancel.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        canceller.cancel();
+    });
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(30);
+    let result = happy_eyeballs::connect(&[blackhole.addr], DELAY, deadline, &cancel);
+    assert_eq!(
+        result.expect_err("cancelled"),
+        vec!["cancelled".to_string()]
+    );
+    assert!(
+        start.elapsed() < Duration::from_secs(2),
+        "took {:?}",
+        start.elapsed()
+    );
+}
@@ -0,0 +1,103 @@
+//! TLS key log (debug builds, or feature `keylog`): a direct TLS handshake with
+//! `KeyLogConfig.path` set appends NSS key log lines to the file, and a file that cannot be
+//! opened fails the connect instead of leaving the log silently off.
+
+#![cfg(any(debug_assertions, feature = "keylog"))]
+
+mod common;
+
+use std::path::PathBuf;
+use std::{fs, process};
+
+use common::{issued, tls_server, Pki};
+use whixp_transport::config::{KeyLogConfig, TlsConfig, TransportConfig, TransportKind};
+use whixp_transport::connection::{self, Connection};
+use whixp_transport::handshake::{HandshakeError, HandshakeErrorCode};
+use whixp_transport::retry::RetryPolicy;
+
+/// A path in the temp directory no other test run uses.
+fn log_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("whixp-keylog-{}-{}.txt", process::id(), name));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+fn connect(pki: &Pki, port: u16, path: &str) -> Result<Connection, HandshakeError> {
+    let config = TransportConfig {
+        host: "localhost".to_string(),
+        port,
+        kind: TransportKind::DirectTls,
+        tls: TlsConfig {
+            extra_roots: pki.anchor_pem.as_bytes().to_vec(),
+            ..Default::default()
+        },
+        key_log: KeyLogConfig {
+            path: Some(path.to_string()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut conn = Connection::new(config, RetryPolicy::default());
+    let (event_tx, _events) = connection::event_channel(Default::default());
+    conn.connect_sync(event_tx)?;
+    Ok(conn)
+}
+
+fn is_hex(s: &str) -> bool {
+    s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+#[test]
+fn handshake_secrets_are_written() {
+    let pki = issued(&["localhost"]);
+    let server = tls_server(&pki);
+    let path = log_path("written");
+    let conn = connect(&pki, server.port, path.to_str().expect("path")).expect("handshake");
+    conn.shutdown();
+
+    let log = fs::read_to_string(&path).expect("key log file");
+    let _ = fs::remove_file(&path);
+    let handshake: Vec<Vec<&str>> = log
+        .lines()
+        .map(|line| line.split(' ').collect::<Vec<_>>())
+        .filter(|fields| fields[0] == "CLIENT_HANDSHAKE_TRAFFIC_SECRET")
+        .collect();
+    assert_eq!(handshake.len(), 1, "key log:\n{}", log);
+    let [_, random, secret] = handshake[0][..] else {
+        panic!("malformed line: {:?}", handshake[0]);
+    };
+    // 32-byte client random; SHA-256 or SHA-384 sized secret.
+    assert!(
+        random.len() == 64 && is_hex(random),
+        "client random {}",
+        random
+    );
+    assert!(
+        matches!(secret.len(), 64 | 96) && is_hex(secret),
+        "secret {}",
+        secret
+    );
+    // Every secret of the handshake is logged under the same client random.
+    for line in log.lines() {
+        assert_eq!(line.split(' ').nth(1), Some(random), "line {}", line);
+    }
+    assert!(log
+        .lines()
+        .any(|line| line.starts_with("CLIENT_TRAFFIC_SECRET_0 ")));
+}
+
+#[test]
+fn unopenable_file_fails_the_connect() {
+    let pki = issued(&["localhost"]);
+    let server = tls_server(&pki);
+    let dir = log_path("missing-dir");
+    let path = dir.join("keys.txt");
+    let path = path.to_str().expect("path");
+    match connect(&pki, server.port, path) {
+        Err(e) => {
+            assert_eq!(HandshakeErrorCode::from(&e), HandshakeErrorCode::Tls);
+            assert!(e.to_string().contains(path), "{}", e);
+        }
+        Ok(_) => panic!("connected with a key log file that cannot be opened"),
+    }
+}
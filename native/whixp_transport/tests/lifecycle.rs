@@ -0,0 +1,217 @@
+//! Handle lifecycle through the FFI: destroy closes the socket under a blocked StartTLS
+//! handshake and joins the connection's task, disconnect waits that handshake out and shuts
+//! the connection down, disconnect ends a connect still waiting on its TCP connect, a handle
+//! connects again after disconnect (closing the previous connection either way), and calls
+//! with a destroyed handle (even one whose table slot was reused) fail instead of touching
+//! freed memory.
+
+mod common;
+
+use std::io::Read;
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{blackhole, silent_server};
+use whixp_transport::*;
+
+unsafe fn create(port: u16, kind: i32) -> *mut Handle {
+    let host = "127.0.0.1";
+    let mut config: CTransportConfig = std::mem::zeroed();
+    config.host_ptr = host.as_ptr() as *const _;
+    config.host_len = host.len() as u32;
+    config.port = port;
+    config.kind = kind;
+    config.connect_timeout_ms = 5000;
+    // Long enough that only destroy can end the handshake within the test.
+    config.handshake_timeout_ms = 60_000;
+    let handle = whixp_transport_create(&config);
+    assert!(!handle.is_null(), "create");
+    handle
+}
+
+#[test]
+fn destroy_wakes_a_blocked_starttls() {
+    unsafe {
+        let handle = create(silent_server(), 1);
+        assert_eq!(whixp_transport_connect(handle), 0, "connect");
+        // Pointers are not Send; the token is just a number.
+        let token = handle as usize;
+        let starttls = thread::spawn(move || {
+            let start = Instant::now();
+            let code = whixp_transport_starttls(token as *mut Handle);
+            (code, start.elapsed())
+        });
+        thread::sleep(Duration::from_millis(300));
+
+        let start = Instant::now();
+        whixp_transport_destroy(handle);
+        assert!(
+            start.elapsed() < Duration::from_secs(3),
+            "destroy took too long"
+        );
+        let (code, took) = starttls.join().expect("starttls thread");
+        assert_ne!(code, 0, "starttls succeeded against a silent server");
+        assert!(
+            took < Duration::from_secs(5),
+            "starttls blocked for {:?}",
+            took
+        );
+    }
+}
+
+#[test]
+fn disconnect_during_a_blocked_starttls() {
+    unsafe {
+        let handle = create(silent_server(), 1);
+        assert_eq!(whixp_transport_connect(handle), 0, "connect");
+        let token = handle as usize;
+        let starttls = thread::spawn(move || whixp_transport_starttls(token as *mut Handle));
+        thread::sleep(Duration::from_millis(300));
+
+        let start = Instant::now();
+        whixp_transport_disconnect(handle);
+        assert!(
+            start.elapsed() < Duration::from_secs(3),
+            "disconnect took too long"
+        );
+        assert_ne!(starttls.join().expect("starttls thread"), 0);
+        let stanza = b"<presence/>";
+        assert_eq!(
+            whixp_transport_send(handle, stanza.as_ptr(), stanza.len() as u32),
+            -1,
+            "send after disconnect"
+        );
+        whixp_transport_destroy(handle);
+    }
+}
+
+#[test]
+fn disconnect_during_a_blocked_connect() {
+    unsafe {
+        let blackhole = blackhole();
+        let handle = create(blackhole.addr.port(), 0);
+        let token = handle as usize;
+        let connect = thread::spawn(move || {
+            let start = Instant::now();
+            let code = whixp_transport_connect(token as *mut Handle);
+            (code, start.elapsed())
+        });
+        thread::sleep(Duration::from_millis(300));
+
+        let start = Instant::now();
+        whixp_transport_disconnect(handle);
+        assert!(
+            start.elapsed() < Duration::from_secs(1),
+            "disconnect took {:?}",
+            start.elapsed()
+        );
+        let (code, took) = connect.join().expect("connect thread");
+        assert_ne!(code, 0, "connected to a full accept queue");
+        assert!(
+            took < Duration::from_secs(2),
+            "connect blocked for {:?}",
+            took
+        );
+        whixp_transport_destroy(handle);
+    }
+}
+
+#[test]
+fn connect_again() {
+    unsafe {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let handle = create(listener.local_addr().unwrap().port(), 0);
+        let stanza = b"<presence/>";
+        let send = || whixp_transport_send(handle, stanza.as_ptr(), stanza.len() as u32);
+
+        assert_eq!(whixp_transport_connect(handle), 0, "connect");
+        let (mut first, _) = listener.accept().expect("accept");
+        whixp_transport_disconnect(handle);
+        assert_eq!(send(), -1, "send after disconnect");
+        assert_eq!(
+            whixp_transport_connect(handle),
+            0,
+            "connect after disconnect"
+        );
+        let (mut second, _) = listener.accept().expect("accept");
+        assert_eq!(send(), 0, "send after reconnecting");
+
+        // Connecting over a live connection closes it.
+        assert_eq!(
+            whixp_transport_connect(handle),
+            0,
+            "connect while connected"
+        );
+        let (_third, _) = listener.accept().expect("accept");
+        let mut buf = [0u8; 64];
+        for (name, peer) in [("first", &mut first), ("second", &mut second)] {
+            peer.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+            let mut got = Vec::new();
+            loop {
+                match peer.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => got.extend_from_slice(&buf[..n]),
+                    Err(e) => panic!("{} connection left open: {}", name, e),
+                }
+            }
+            if name == "second" {
+                assert_eq!(got, stanza, "sent on the second connection");
+            }
+        }
+        whixp_transport_destroy(handle);
+    }
+}
+
+#[test]
+fn calls_after_destroy_fail() {
+    unsafe {
+        let port = silent_server();
+        let stale = create(port, 0);
+        assert_eq!(whixp_transport_connect(stale), 0, "connect");
+        whixp_transport_disconnect(stale);
+        let stanza = b"<presence/>";
+        assert_eq!(
+            whixp_transport_send(stale, stanza.as_ptr(), stanza.len() as u32),
+            -1,
+            "send after disconnect"
+        );
+        whixp_transport_destroy(stale);
+
+        // Likely lands in the slot `stale` had; the old token must not reach it.
+        let live = create(port, 0);
+        assert_ne!(live, stale);
+        assert_eq!(whixp_transport_connect(live), 0, "connect");
+
+        assert_eq!(
+            whixp_transport_send(stale, stanza.as_ptr(), stanza.len() as u32),
+            -1
+        );
+        assert_eq!(whixp_transport_poll(stale), 0);
+        let mut buf = [0u8; 256];
+        let mut len = 0;
+        assert_eq!(
+            whixp_transport_drain(stale, buf.as_mut_ptr(), buf.len() as u32, 0, &mut len),
+            -1
+        );
+        assert_ne!(whixp_transport_connect(stale), 0);
+        assert_ne!(whixp_transport_starttls(stale), 0);
+        assert_eq!(whixp_transport_restart_stream(stale), -1);
+        whixp_transport_disconnect(stale);
+        whixp_transport_destroy(stale);
+        whixp_transport_destroy(std::ptr::null_mut());
+        whixp_transport_destroy(0x1234 as *mut Handle);
+
+        // None of that touched the live handle.
+        assert_eq!(
+            whixp_transport_send(live, stanza.as_ptr(), stanza.len() as u32),
+            0
+        );
+        whixp_transport_disconnect(live);
+        whixp_transport_destroy(live);
+        assert_eq!(
+            whixp_transport_send(live, stanza.as_ptr(), stanza.len() as u32),
+            -1
+        );
+    }
+}
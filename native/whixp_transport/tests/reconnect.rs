@@ -0,0 +1,110 @@
+//! Native reconnects: retry delays are task timers and each dial runs on a runtime helper
+//! thread. A dropped stream is reopened, a policy with no attempts left reports a
+//! connection failure, and shutdown during a long retry delay ends the task at once.
+
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use whixp_transport::config::{TransportConfig, TransportKind};
+use whixp_transport::connection::{
+    self, Connection, EventReceiver, TransportEvent, TransportState,
+};
+use whixp_transport::retry::RetryPolicy;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Closes the first connection right away and holds the later ones, each reported on the
+/// returned channel. With `once`, stops listening after the first connection.
+fn dropping_server(once: bool) -> (u16, mpsc::Receiver<TcpStream>) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let port = listener.local_addr().expect("local addr").port();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let (first, _) = listener.accept().expect("accept");
+        drop(first);
+        if once {
+            return;
+        }
+        for tcp in listener.incoming().flatten() {
+            if tx.send(tcp).is_err() {
+                return;
+            }
+        }
+    });
+    (port, rx)
+}
+
+fn connect(port: u16, retry: RetryPolicy) -> (Connection, EventReceiver) {
+    let config = TransportConfig {
+        host: "127.0.0.1".to_string(),
+        port,
+        kind: TransportKind::Tcp,
+        auto_reconnect: true,
+        ..Default::default()
+    };
+    let mut conn = Connection::new(config, retry);
+    let (event_tx, events) = connection::event_channel(Default::default());
+    conn.connect_sync(event_tx).expect("connect");
+    (conn, events)
+}
+
+fn policy(max_attempts: u32, initial_delay_ms: u64) -> RetryPolicy {
+    RetryPolicy {
+        max_attempts,
+        initial_delay_ms,
+        ..Default::default()
+    }
+}
+
+/// Wait for state `want`, skipping other events.
+fn expect_state(events: &EventReceiver, want: TransportState) {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match events.recv_timeout(left) {
+            Ok(TransportEvent::State(s)) if s == want as i32 => return,
+            Ok(_) => {}
+            Err(e) => panic!("waiting for {:?}: {}", want, e),
+        }
+    }
+}
+
+#[test]
+fn dropped_stream_is_reopened() {
+    let (port, accepted) = dropping_server(false);
+    let (conn, events) = connect(port, policy(3, 50));
+    expect_state(&events, TransportState::Connected);
+    expect_state(&events, TransportState::Reconnecting);
+    expect_state(&events, TransportState::Connected);
+    accepted.recv_timeout(TIMEOUT).expect("second connection");
+    conn.shutdown();
+    assert!(conn.join(TIMEOUT), "task still running");
+}
+
+#[test]
+fn gives_up_when_attempts_run_out() {
+    let (port, _accepted) = dropping_server(true);
+    let (conn, events) = connect(port, policy(2, 50));
+    expect_state(&events, TransportState::Reconnecting);
+    expect_state(&events, TransportState::ConnectionFailure);
+    expect_state(&events, TransportState::Disconnected);
+    assert!(conn.join(TIMEOUT), "task still running");
+}
+
+#[test]
+fn shutdown_during_retry_delay() {
+    let (port, _accepted) = dropping_server(true);
+    let (conn, events) = connect(port, policy(3, 60_000));
+    expect_state(&events, TransportState::Reconnecting);
+    let start = Instant::now();
+    conn.shutdown();
+    assert!(conn.join(TIMEOUT), "task still running");
+    assert!(
+        start.elapsed() < Duration::from_secs(1),
+        "shutdown took {:?}",
+        start.elapsed()
+    );
+    expect_state(&events, TransportState::Disconnected);
+}
@@ -0,0 +1,95 @@
+//! RFC 2782 SRV ordering with injected randomness: priority groups in order, weighted
+//! selection within a group, and the zero-weight rules.
+
+use std::collections::VecDeque;
+
+use whixp_transport::dns::{order_srv_with, SrvRecord};
+
+fn record(target: &str, priority: u16, weight: u16) -> SrvRecord {
+    SrvRecord {
+        priority,
+        weight,
+        port: 5222,
+        target: target.to_string(),
+        direct_tls: false,
+    }
+}
+
+fn targets(records: &[SrvRecord]) -> Vec<&str> {
+    records.iter().map(|r| r.target.as_str()).collect()
+}
+
+/// Orders `records` with `picks` as the random values, checking each against the `max` asked
+/// for; returns the order and every `max` in turn.
+fn order(records: Vec<SrvRecord>, picks: &[u32]) -> (Vec<SrvRecord>, Vec<u32>) {
+    let mut picks: VecDeque<u32> = picks.iter().copied().collect();
+    let mut asked = Vec::new();
+    let ordered = order_srv_with(records, |max| {
+        asked.push(max);
+        let pick = picks
+            .pop_front()
+            .expect("more random values asked for than given");
+        assert!(pick <= max, "pick {} above max {}", pick, max);
+        pick
+    });
+    assert!(picks.is_empty(), "unused random values: {:?}", picks);
+    (ordered, asked)
+}
+
+#[test]
+fn lower_priority_first() {
+    let records = vec![
+        record("c", 30, 5),
+        record("a", 10, 5),
+        record("b", 20, 5),
+        record("a2", 10, 5),
+    ];
+    let (ordered, asked) = order(records, &[0, 0, 0, 0]);
+    let priorities: Vec<u16> = ordered.iter().map(|r| r.priority).collect();
+    assert_eq!(priorities, vec![10, 10, 20, 30]);
+    // Weights are summed per priority group only.
+    assert_eq!(asked, vec![10, 5, 5, 5]);
+}
+
+#[test]
+fn selection_is_proportional_to_weight() {
+    let group = || vec![record("a", 0, 10), record("b", 0, 30), record("c", 0, 60)];
+
+    // Each value in 0..=sum picks the first record whose running weight reaches it.
+    let mut first = [0u32; 3];
+    for pick in 0..=100 {
+        let (ordered, _) = order(group(), &[pick, 0, 0]);
+        first[(ordered[0].target.as_bytes()[0] - b'a') as usize] += 1;
+    }
+    assert_eq!(first, [11, 30, 60]);
+
+    // The picked record leaves the group; the rest are drawn from what is left.
+    let (ordered, asked) = order(group(), &[40, 11, 0]);
+    assert_eq!(targets(&ordered), vec!["b", "c", "a"]);
+    assert_eq!(asked, vec![100, 70, 10]);
+}
+
+#[test]
+fn zero_weight_records() {
+    // Zero-weight records are first in line, so they are picked only on a zero.
+    let mixed = || vec![record("heavy", 0, 10), record("zero", 0, 0)];
+    let (ordered, _) = order(mixed(), &[0, 0]);
+    assert_eq!(targets(&ordered), vec!["zero", "heavy"]);
+    for pick in 1..=10 {
+        let (ordered, _) = order(mixed(), &[pick, 0]);
+        assert_eq!(targets(&ordered), vec!["heavy", "zero"], "pick {}", pick);
+    }
+
+    // All zero: nothing to weigh, records keep their order.
+    let zeros = vec![record("x", 0, 0), record("y", 0, 0), record("z", 0, 0)];
+    let (ordered, asked) = order(zeros, &[0, 0, 0]);
+    assert_eq!(targets(&ordered), vec!["x", "y", "z"]);
+    assert_eq!(asked, vec![0, 0, 0]);
+}
+
+#[test]
+fn out_of_range_randomness_is_clamped() {
+    let records = vec![record("a", 0, 1), record("b", 0, 1)];
+    let ordered = order_srv_with(records, |_| u32::MAX);
+    assert_eq!(targets(&ordered), vec!["b", "a"]);
+}
@@ -0,0 +1,122 @@
+//! Stream framing: stanzas that nest elements of their own name, self-closing top-level
+//! elements, markup whose content holds `>`, input split across reads, and stream restarts.
+
+use whixp_transport::stanza::{Frame, StreamFramer};
+
+const HEADER: &str = "<?xml version='1.0'?><stream:stream xmlns='jabber:client' \
+    xmlns:stream='http://etherx.jabber.org/streams' from='example.com' id='s1' version='1.0'>";
+
+fn stanza(xml: &str) -> Frame {
+    Frame::Stanza(xml.to_string())
+}
+
+/// Frames from `input` pushed in one read, after a stream header.
+fn frames(input: &str) -> Vec<Frame> {
+    let mut framer = StreamFramer::new();
+    let header = framer.push(HEADER.as_bytes()).expect("header");
+    assert_eq!(header, vec![Frame::StreamHeader(HEADER.to_string())]);
+    framer.push(input.as_bytes()).expect("push")
+}
+
+#[test]
+fn nested_element_with_the_stanza_name() {
+    let forwarded = "<message from='a@example.com'><result xmlns='urn:xmpp:mam:2'>\
+        <forwarded xmlns='urn:xmpp:forward:0'><message from='b@example.com'>\
+        <body>inner</body></message></forwarded></result></message>";
+    let after = "<presence/>";
+    assert_eq!(
+        frames(&format!("{}{}", forwarded, after)),
+        vec![stanza(forwarded), stanza(after)]
+    );
+}
+
+#[test]
+fn self_closing_top_level_elements() {
+    let input = "<presence/><iq type='result' id='1'/><r xmlns='urn:xmpp:sm:3' />\
+        <iq type='get' id='2'><ping xmlns='urn:xmpp:ping'/></iq>";
+    assert_eq!(
+        frames(input),
+        vec![
+            stanza("<presence/>"),
+            stanza("<iq type='result' id='1'/>"),
+            stanza("<r xmlns='urn:xmpp:sm:3' />"),
+            stanza("<iq type='get' id='2'><ping xmlns='urn:xmpp:ping'/></iq>"),
+        ]
+    );
+}
+
+#[test]
+fn markup_holding_angle_brackets() {
+    let message = "<message id='a>b'><body><![CDATA[1 > 0 </message> <presence/>]]></body>\
+        <!-- </message> > --><?note </message> > ?><subject title=\"x/>y\">s</subject></message>";
+    assert_eq!(frames(message), vec![stanza(message)]);
+}
+
+#[test]
+fn split_across_reads() {
+    let stanzas = [
+        "<message><body><![CDATA[a]]>b]]></body><!-- c --></message>",
+        "<presence/>",
+        "<iq id='x'><query xmlns='jabber:iq:roster'><item jid='a@b'/></query></iq>",
+        "</stream:stream>",
+    ];
+    let input = format!("{}{}", HEADER, stanzas.concat());
+    let mut expected = vec![Frame::StreamHeader(HEADER.to_string())];
+    expected.extend(stanzas.iter().map(|s| stanza(s)));
+
+    for size in [1, 2, 3, 7, 64] {
+        let mut framer = StreamFramer::new();
+        let mut out = Vec::new();
+        for chunk in input.as_bytes().chunks(size) {
+            out.extend(framer.push(chunk).expect("push"));
+        }
+        assert_eq!(out, expected, "reads of {} bytes", size);
+    }
+}
+
+#[test]
+fn restart_frames_the_new_header() {
+    let second = "<stream:stream xmlns='jabber:client' \
+        xmlns:stream='http://etherx.jabber.org/streams' id='s2' version='1.0'>";
+    let mut framer = StreamFramer::new();
+    framer.push(HEADER.as_bytes()).expect("header");
+    assert_eq!(
+        framer
+            .push(b"<success xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>")
+            .unwrap(),
+        vec![stanza(
+            "<success xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>"
+        )]
+    );
+
+    // The new header may already be partly buffered when the restart happens.
+    assert!(framer.push(&second.as_bytes()[..20]).unwrap().is_empty());
+    framer.restart();
+    assert_eq!(
+        framer.push(&second.as_bytes()[20..]).unwrap(),
+        vec![Frame::StreamHeader(second.to_string())]
+    );
+    // Restarting again before any stanza changes nothing.
+    framer.restart();
+    assert_eq!(
+        framer.push(b"<presence/>").unwrap(),
+        vec![stanza("<presence/>")]
+    );
+}
+
+#[test]
+fn header_without_restart_is_still_framed() {
+    let second = "<stream:stream id='s2'>";
+    let input = format!(
+        "<proceed xmlns='urn:ietf:params:xml:ns:xmpp-tls'/>{}<presence/>",
+        second
+    );
+    assert_eq!(
+        frames(&input),
+        vec![
+            stanza("<proceed xmlns='urn:ietf:params:xml:ns:xmpp-tls'/>"),
+            Frame::StreamHeader(second.to_string()),
+            stanza("<presence/>"),
+        ]
+    );
+}
@@ -0,0 +1,96 @@
+//! StartTLS upgrade in place: plaintext stream negotiation on a live connection, then
+//! `starttls()` switches the same socket to TLS, reports `TlsSuccess`, and stanzas flow
+//! over TLS. Frames the server sent in the same write as `<proceed/>` all arrive first.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{issued, starttls_server};
+use whixp_transport::config::{TlsConfig, TransportConfig, TransportKind};
+use whixp_transport::connection::{
+    self, Connection, EventReceiver, TransportEvent, TransportState,
+};
+use whixp_transport::retry::RetryPolicy;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const HEADER: &str = "<stream:stream xmlns='jabber:client' \
+    xmlns:stream='http://etherx.jabber.org/streams' version='1.0'>";
+const FEATURES: &str = "<stream:features>\
+    <starttls xmlns='urn:ietf:params:xml:ns:xmpp-tls'><required/></starttls>\
+    </stream:features>";
+const STARTTLS: &str = "<starttls xmlns='urn:ietf:params:xml:ns:xmpp-tls'/>";
+const PROCEED: &str = "<proceed xmlns='urn:ietf:params:xml:ns:xmpp-tls'/>";
+
+/// Next event that is not a state change other than `TlsSuccess`.
+fn next(events: &EventReceiver) -> TransportEvent {
+    loop {
+        match events.recv_timeout(TIMEOUT).expect("event") {
+            TransportEvent::State(s) if s != TransportState::TlsSuccess as i32 => continue,
+            TransportEvent::Error(code, message) => panic!("error {}: {}", code, message),
+            event => return event,
+        }
+    }
+}
+
+fn expect_header(events: &EventReceiver) {
+    match next(events) {
+        TransportEvent::StreamHeader(h) => assert_eq!(h, HEADER),
+        _ => panic!("expected a stream header"),
+    }
+}
+
+fn expect_stanza(events: &EventReceiver, stanza: &str) {
+    match next(events) {
+        TransportEvent::Stanza(s) => assert_eq!(s, stanza),
+        _ => panic!("expected {}", stanza),
+    }
+}
+
+#[test]
+fn upgrade_in_place() {
+    let pki = issued(&["localhost"]);
+    let reply = format!("{}{}{}", HEADER, FEATURES, PROCEED);
+    let server = starttls_server(&pki, reply.into_bytes());
+    let config = TransportConfig {
+        host: "localhost".to_string(),
+        port: server.port,
+        kind: TransportKind::TcpStartTls,
+        tls: TlsConfig {
+            extra_roots: pki.anchor_pem.as_bytes().to_vec(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut conn = Connection::new(config, RetryPolicy::default());
+    let (event_tx, events) = connection::event_channel(Default::default());
+    conn.connect_sync(event_tx).expect("plain TCP connect");
+    assert!(!conn.is_secure());
+
+    conn.send(format!("{}{}", HEADER, STARTTLS).as_bytes())
+        .expect("send in plaintext");
+    // Header, features and `<proceed/>` came in one write; none is held back.
+    expect_header(&events);
+    expect_stanza(&events, FEATURES);
+    expect_stanza(&events, PROCEED);
+
+    conn.starttls().expect("upgrade");
+    match next(&events) {
+        TransportEvent::State(s) => assert_eq!(s, TransportState::TlsSuccess as i32),
+        _ => panic!("expected TlsSuccess"),
+    }
+    assert!(conn.is_secure());
+    server
+        .handshakes
+        .recv_timeout(TIMEOUT)
+        .expect("server handshake")
+        .expect("server handshake succeeded");
+
+    // The server echoes over TLS: a new stream and a stanza come back framed.
+    let iq = "<iq type='get' id='1'><ping xmlns='urn:xmpp:ping'/></iq>";
+    conn.send(format!("{}{}", HEADER, iq).as_bytes())
+        .expect("send over TLS");
+    expect_header(&events);
+    expect_stanza(&events, iq);
+    conn.shutdown();
+}
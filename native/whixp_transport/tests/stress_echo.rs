@@ -0,0 +1,145 @@
+//! Stress: hundreds of loopback connections against a local echo server, all driven by the
+//! shared runtime's worker threads. Each connection sends a stream header and a run of
+//! stanzas and must get every one back, in order, without adding threads.
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use whixp_transport::config::{TransportConfig, TransportKind};
+use whixp_transport::connection::{self, Connection, TransportEvent, TransportState};
+use whixp_transport::retry::RetryPolicy;
+use whixp_transport::runtime;
+
+const CONNECTIONS: usize = 300;
+const STANZAS: usize = 20;
+const WORKERS: usize = 2;
+
+const HEADER: &str = "<stream:stream xmlns='jabber:client' \
+     xmlns:stream='http://etherx.jabber.org/streams' to='localhost' version='1.0'>";
+
+/// Echo server on one current-thread runtime; returns its port.
+fn echo_server() -> u16 {
+    let (port_tx, port_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("echo runtime");
+        rt.block_on(async move {
+            let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+            port_tx.send(listener.local_addr().unwrap().port()).unwrap();
+            loop {
+                let Ok((mut socket, _)) = listener.accept().await else {
+                    continue;
+                };
+                tokio::spawn(async move {
+                    let (mut rx, mut tx) = socket.split();
+                    let _ = tokio::io::copy(&mut rx, &mut tx).await;
+                    let _ = tx.shutdown().await;
+                });
+            }
+        });
+    });
+    port_rx.recv().expect("echo server port")
+}
+
+/// Threads of this process (Linux only).
+fn thread_count() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("Threads:"))?;
+    line["Threads:".len()..].trim().parse().ok()
+}
+
+fn stanza(conn: usize, i: usize) -> String {
+    format!(
+        "<message id='{}-{}'><body>{}</body></message>",
+        conn,
+        i,
+        "x".repeat(i * 16)
+    )
+}
+
+#[test]
+fn hundreds_of_connections_share_the_runtime() {
+    assert!(runtime::configure(WORKERS));
+    assert_eq!(runtime::worker_count(), WORKERS);
+    let port = echo_server();
+    let threads_before = thread_count();
+
+    let mut open = Vec::new();
+    for _ in 0..CONNECTIONS {
+        let config = TransportConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            kind: TransportKind::Tcp,
+            ..Default::default()
+        };
+        let mut conn = Connection::new(config, RetryPolicy::default());
+        let (event_tx, event_rx) = connection::event_channel(Default::default());
+        conn.connect_sync(event_tx).expect("connect");
+        open.push((conn, event_rx));
+    }
+    for (n, (conn, _)) in open.iter().enumerate() {
+        conn.send(HEADER.as_bytes()).expect("send header");
+        for i in 0..STANZAS {
+            conn.send(stanza(n, i).as_bytes()).expect("send stanza");
+        }
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(30);
+    for (n, (_, events)) in open.iter().enumerate() {
+        let mut header = false;
+        let mut received = 0;
+        while received < STANZAS {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            match events.recv_timeout(wait) {
+                Ok(TransportEvent::StreamHeader(h)) => {
+                    assert!(h.starts_with("<stream:stream"), "connection {}: {}", n, h);
+                    header = true;
+                }
+                Ok(TransportEvent::Stanza(s)) => {
+                    assert_eq!(s, stanza(n, received), "connection {}", n);
+                    received += 1;
+                }
+                Ok(TransportEvent::State(_)) => {}
+                Ok(TransportEvent::Error(code, msg)) => {
+                    panic!("connection {}: error {} {}", n, code, msg)
+                }
+                Ok(_) => {}
+                Err(_) => panic!(
+                    "connection {}: {} of {} stanzas echoed",
+                    n, received, STANZAS
+                ),
+            }
+        }
+        assert!(header, "connection {}: no stream header", n);
+    }
+
+    // Connecting runs on this thread (a dial may briefly spawn one); none stay behind.
+    thread::sleep(Duration::from_millis(200));
+    if let (Some(before), Some(after)) = (threads_before, thread_count()) {
+        assert!(
+            after <= before,
+            "{} connections added {} threads",
+            CONNECTIONS,
+            after - before
+        );
+    }
+
+    for (conn, _) in &open {
+        conn.shutdown();
+    }
+    for (n, (_, events)) in open.iter().enumerate() {
+        let wait = deadline.saturating_duration_since(Instant::now());
+        loop {
+            match events.recv_timeout(wait) {
+                Ok(TransportEvent::State(s)) if s == TransportState::Disconnected as i32 => break,
+                Ok(_) => {}
+                Err(_) => panic!("connection {}: no Disconnected after shutdown", n),
+            }
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! A task whose `service` panics is told it was abandoned before the worker drops it, and
+//! the worker goes on servicing its other tasks.
+
+use std::sync::mpsc;
+use std::time::Duration;
+
+use whixp_transport::runtime::{self, Step, Task};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reports each call on `calls`; panics in `service` when `panics` is set.
+struct Probe {
+    panics: bool,
+    calls: mpsc::Sender<&'static str>,
+}
+
+impl Task for Probe {
+    fn service(&mut self) -> Step {
+        let _ = self.calls.send("service");
+        if self.panics {
+            panic!("probe task panics");
+        }
+        Step::Wait {
+            timer: None,
+            busy: false,
+        }
+    }
+
+    fn abandoned(&mut self) {
+        let _ = self.calls.send("abandoned");
+    }
+}
+
+fn spawn(panics: bool) -> (runtime::Registration, mpsc::Receiver<&'static str>) {
+    let (calls, rx) = mpsc::channel();
+    let reg = runtime::spawn(|_| Probe { panics, calls });
+    (reg, rx)
+}
+
+#[test]
+fn panicking_task_is_abandoned_and_others_keep_running() {
+    // One worker, so both tasks share it.
+    runtime::configure(1);
+    let (healthy_reg, healthy) = spawn(false);
+    assert_eq!(healthy.recv_timeout(TIMEOUT), Ok("service"));
+
+    let (panicking_reg, panicking) = spawn(true);
+    assert_eq!(panicking.recv_timeout(TIMEOUT), Ok("service"));
+    assert_eq!(panicking.recv_timeout(TIMEOUT), Ok("abandoned"));
+    // Dropped: the sender is gone and a wake no longer reaches it.
+    panicking_reg.wake();
+    assert_eq!(
+        panicking.recv_timeout(TIMEOUT),
+        Err(mpsc::RecvTimeoutError::Disconnected)
+    );
+
+    healthy_reg.wake();
+    assert_eq!(healthy.recv_timeout(TIMEOUT), Ok("service"));
+}
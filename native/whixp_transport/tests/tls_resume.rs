@@ -0,0 +1,251 @@
+//! TLS session resumption: a second handshake with the same client configs resumes the
+//! session from the first, seen on both ends; with the cache disabled it never does.
+//! `whixp_transport_tls_info` reports the version, suite, ALPN, SNI, resumption and peer
+//! chain of direct TLS and StartTLS connections.
+
+mod common;
+
+use std::io::Read;
+use std::net::TcpStream;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use common::{issued, starttls_server_with, tls_server, tls_server_with, Pki, TlsServer, READY};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{HandshakeKind, ServerConfig};
+use whixp_transport::config::{SessionCache, TlsConfig, TransportKind};
+use whixp_transport::tls::{self, OnBadCert, ServerNames, TlsContext};
+use whixp_transport::*;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One handshake; returns (client saw a resumption, server's handshake kind).
+fn handshake(server: &TlsServer, tls: &TlsConfig, ctx: &TlsContext) -> (bool, HandshakeKind) {
+    let names = ServerNames {
+        sni: "localhost".to_string(),
+        verify: "localhost".to_string(),
+    };
+    let tcp = TcpStream::connect(("127.0.0.1", server.port)).expect("connect");
+    let mut stream = tls::connect_direct(tcp, &names, tls, ctx, &[], TIMEOUT).expect("handshake");
+    // TLS 1.3 tickets follow the handshake; reading takes them in.
+    let mut ready = [0u8; 2];
+    stream.read_exact(&mut ready).expect("read from server");
+    assert_eq!(ready, READY);
+    let resumed = stream.info().expect("handshake done").resumed;
+    let kind = server
+        .handshakes
+        .recv_timeout(TIMEOUT)
+        .expect("server handshake")
+        .expect("server handshake succeeded");
+    (resumed, kind)
+}
+
+fn setup(cache: SessionCache) -> (TlsServer, TlsConfig, TlsContext) {
+    let pki = issued(&["localhost"]);
+    let server = tls_server(&pki);
+    let tls = TlsConfig {
+        extra_roots: pki.anchor_pem.as_bytes().to_vec(),
+        session_cache: cache,
+        ..Default::default()
+    };
+    let ctx = TlsContext {
+        on_bad_cert: OnBadCert::Reject,
+        configs: tls::client_configs(cache),
+        key_log: None,
+    };
+    (server, tls, ctx)
+}
+
+#[test]
+fn second_connection_resumes() {
+    let (server, tls, ctx) = setup(SessionCache::PerHandle);
+    assert_eq!(handshake(&server, &tls, &ctx), (false, HandshakeKind::Full));
+    assert_eq!(
+        handshake(&server, &tls, &ctx),
+        (true, HandshakeKind::Resumed)
+    );
+}
+
+#[test]
+fn disabled_cache_never_resumes() {
+    let (server, tls, ctx) = setup(SessionCache::Disabled);
+    assert_eq!(handshake(&server, &tls, &ctx), (false, HandshakeKind::Full));
+    assert_eq!(handshake(&server, &tls, &ctx), (false, HandshakeKind::Full));
+}
+
+/// Records the SNI of each ClientHello and answers with one certificate.
+#[derive(Debug)]
+struct RecordSni {
+    key: Arc<CertifiedKey>,
+    names: mpsc::Sender<Option<String>>,
+}
+
+impl ResolvesServerCert for RecordSni {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let _ = self.names.send(hello.server_name().map(str::to_string));
+        Some(Arc::clone(&self.key))
+    }
+}
+
+/// Server config for `pki` offering ALPN `xmpp-client`; the receiver gets each SNI.
+fn recording_config(pki: &Pki) -> (Arc<ServerConfig>, mpsc::Receiver<Option<String>>) {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let key = CertifiedKey::from_der(pki.chain.clone(), pki.key(), &provider).expect("key");
+    let (names, snis) = mpsc::channel();
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .expect("protocol versions")
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(RecordSni {
+            key: Arc::new(key),
+            names,
+        }));
+    config.alpn_protocols = vec![b"xmpp-client".to_vec()];
+    (Arc::new(config), snis)
+}
+
+const SNI: &str = "sni.example";
+const PROCEED: &str = "<proceed xmlns='urn:ietf:params:xml:ns:xmpp-tls'/>";
+
+/// Handle for `localhost:port`, trusting `pki`'s CA and sending `SNI`.
+unsafe fn create(port: u16, kind: TransportKind, pki: &Pki) -> *mut Handle {
+    let host = "localhost";
+    let mut config: CTransportConfig = std::mem::zeroed();
+    config.host_ptr = host.as_ptr() as *const _;
+    config.host_len = host.len() as u32;
+    config.port = port;
+    config.kind = kind as i32;
+    config.connect_timeout_ms = 5000;
+    config.tls_server_name_ptr = SNI.as_ptr() as *const _;
+    config.tls_server_name_len = SNI.len() as u32;
+    config.trust_anchors_ptr = pki.anchor_pem.as_ptr();
+    config.trust_anchors_len = pki.anchor_pem.len() as u32;
+    let handle = whixp_transport_create(&config);
+    assert!(!handle.is_null(), "create");
+    handle
+}
+
+/// Poll until a stanza equal to `want` arrives.
+unsafe fn wait_for_stanza(handle: *mut Handle, want: &str) {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        match whixp_transport_poll(handle) {
+            0 => {
+                assert!(Instant::now() < deadline, "no {}", want);
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            2 => {
+                let (mut ptr, mut len) = (std::ptr::null(), 0u32);
+                whixp_transport_get_polled_stanza(handle, &mut ptr, &mut len);
+                let stanza = std::slice::from_raw_parts(ptr, len as usize);
+                if stanza == want.as_bytes() {
+                    whixp_transport_poll_clear(handle);
+                    return;
+                }
+            }
+            3 => panic!("error event"),
+            _ => {}
+        }
+        whixp_transport_poll_clear(handle);
+    }
+}
+
+unsafe fn tls_info(handle: *mut Handle) -> serde_json::Value {
+    let (mut ptr, mut len) = (std::ptr::null(), 0u32);
+    assert_eq!(
+        whixp_transport_tls_info(handle, &mut ptr, &mut len),
+        0,
+        "tls_info"
+    );
+    serde_json::from_slice(std::slice::from_raw_parts(ptr, len as usize)).expect("JSON")
+}
+
+/// Version, suite, ALPN, SNI and the peer chain in `info`; returns its resumed flag.
+fn check_info(info: &serde_json::Value, alpn: Option<&str>, pki: &Pki) -> bool {
+    assert_eq!(info["version"], "TLSv1.3");
+    let id = info["cipher_suite_id"].as_u64().expect("suite id") as u16;
+    let suite = rustls::crypto::ring::default_provider()
+        .cipher_suites
+        .into_iter()
+        .find(|s| u16::from(s.suite()) == id)
+        .expect("known suite");
+    assert_eq!(info["cipher_suite"], format!("{:?}", suite.suite()));
+    assert_eq!(info["alpn"].as_str(), alpn);
+    assert_eq!(info["sni"], SNI);
+
+    let chain = info["peer_certificates"].as_array().expect("chain");
+    assert_eq!(chain.len(), pki.chain.len());
+    for (summary, cert) in chain.iter().zip(&pki.chain) {
+        let der = base64::engine::general_purpose::STANDARD.encode(cert.as_ref());
+        assert_eq!(summary["der"], der);
+        let sha256: String = ring::digest::digest(&ring::digest::SHA256, cert.as_ref())
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(summary["sha256"], sha256);
+    }
+    info["resumed"].as_bool().expect("resumed")
+}
+
+#[test]
+fn tls_info_direct_tls() {
+    let pki = issued(&["localhost"]);
+    let (config, snis) = recording_config(&pki);
+    let server = tls_server_with(config);
+    unsafe {
+        let handle = create(server.port, TransportKind::DirectTls, &pki);
+        for resumed in [false, true] {
+            assert_eq!(whixp_transport_connect(handle), 0, "connect");
+            assert_eq!(
+                snis.recv_timeout(TIMEOUT).expect("hello").as_deref(),
+                Some(SNI)
+            );
+            let info = tls_info(handle);
+            assert_eq!(check_info(&info, Some("xmpp-client"), &pki), resumed);
+            // The server echoes; the echo comes after the session tickets.
+            let stanza = "<presence/>";
+            assert_eq!(
+                whixp_transport_send(handle, stanza.as_ptr(), stanza.len() as u32),
+                0
+            );
+            wait_for_stanza(handle, stanza);
+        }
+        whixp_transport_destroy(handle);
+    }
+}
+
+#[test]
+fn tls_info_starttls() {
+    let pki = issued(&["localhost"]);
+    let (config, snis) = recording_config(&pki);
+    let server = starttls_server_with(config, PROCEED.as_bytes().to_vec());
+    unsafe {
+        let handle = create(server.port, TransportKind::TcpStartTls, &pki);
+        assert_eq!(whixp_transport_connect(handle), 0, "connect");
+        let (mut ptr, mut len) = (std::ptr::null(), 0u32);
+        assert_eq!(
+            whixp_transport_tls_info(handle, &mut ptr, &mut len),
+            -1,
+            "tls_info in plaintext"
+        );
+        let starttls = "<starttls xmlns='urn:ietf:params:xml:ns:xmpp-tls'/>";
+        assert_eq!(
+            whixp_transport_send(handle, starttls.as_ptr(), starttls.len() as u32),
+            0
+        );
+        wait_for_stanza(handle, PROCEED);
+        assert_eq!(whixp_transport_starttls(handle), 0, "starttls");
+        assert_eq!(
+            snis.recv_timeout(TIMEOUT).expect("hello").as_deref(),
+            Some(SNI)
+        );
+        // No ALPN on StartTLS.
+        assert!(!check_info(&tls_info(handle), None, &pki));
+        whixp_transport_destroy(handle);
+    }
+}
@@ -0,0 +1,129 @@
+//! Handshake timeouts on every TLS path (direct TLS, StartTLS, WSS): a server that never
+//! answers, and one that trickles a byte at a time so every single read succeeds, must both
+//! fail the handshake once the handshake timeout has passed in total. Time spent waiting on
+//! a trust prompt does not count.
+
+mod common;
+
+use std::io::Read;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{self_signed, silent_server, tls_server, trickle_server, READY};
+use whixp_transport::config::{TlsConfig, TransportConfig, TransportKind};
+use whixp_transport::connection::{self, Connection};
+use whixp_transport::handshake::{HandshakeError, HandshakeErrorCode};
+use whixp_transport::retry::RetryPolicy;
+use whixp_transport::tls::{self, OnBadCert, ServerNames, TlsContext, TrustPrompt};
+
+const HANDSHAKE_TIMEOUT_MS: u32 = 600;
+/// Well under the handshake timeout, so a per-read timeout alone never fires.
+const TRICKLE: Duration = Duration::from_millis(100);
+/// Timeout plus slack for a loaded machine; far below what trickling would take.
+const BOUND: Duration = Duration::from_secs(3);
+
+fn connection(port: u16, kind: TransportKind) -> Connection {
+    let config = TransportConfig {
+        host: "127.0.0.1".to_string(),
+        port,
+        kind,
+        handshake_timeout_ms: HANDSHAKE_TIMEOUT_MS,
+        ..Default::default()
+    };
+    Connection::new(config, RetryPolicy::default())
+}
+
+/// Run the handshake of `kind` against `port`; StartTLS connects in plaintext first.
+fn handshake(port: u16, kind: TransportKind) -> (Result<String, HandshakeError>, Duration) {
+    let mut conn = connection(port, kind);
+    let (event_tx, _event_rx) = connection::event_channel(Default::default());
+    let start = Instant::now();
+    let result = match kind {
+        TransportKind::TcpStartTls => {
+            conn.connect_sync(event_tx).expect("plain TCP connect");
+            let start = Instant::now();
+            let result = conn.starttls().map(|()| String::new());
+            conn.shutdown();
+            return (result, start.elapsed());
+        }
+        _ => conn.connect_sync(event_tx),
+    };
+    (result, start.elapsed())
+}
+
+fn assert_times_out(port: u16, kind: TransportKind) {
+    let (result, took) = handshake(port, kind);
+    // Connects report the handshake error among the failed candidates; StartTLS directly.
+    let err = result.expect_err("timeout");
+    assert_eq!(HandshakeErrorCode::from(&err), HandshakeErrorCode::Timeout);
+    let timeout = format!("timeout after {}ms", HANDSHAKE_TIMEOUT_MS);
+    assert!(err.to_string().ends_with(&timeout), "{:?}: {}", kind, err);
+    assert!(
+        took < BOUND,
+        "{:?}: handshake failed after {:?}",
+        kind,
+        took
+    );
+}
+
+#[test]
+fn direct_tls_silent_server() {
+    assert_times_out(silent_server(), TransportKind::DirectTls);
+}
+
+#[test]
+fn direct_tls_trickling_server() {
+    assert_times_out(trickle_server(TRICKLE), TransportKind::DirectTls);
+}
+
+#[test]
+fn starttls_silent_server() {
+    assert_times_out(silent_server(), TransportKind::TcpStartTls);
+}
+
+#[test]
+fn starttls_trickling_server() {
+    assert_times_out(trickle_server(TRICKLE), TransportKind::TcpStartTls);
+}
+
+#[test]
+fn wss_silent_server() {
+    assert_times_out(silent_server(), TransportKind::WebSocketTls);
+}
+
+#[test]
+fn wss_trickling_server() {
+    assert_times_out(trickle_server(TRICKLE), TransportKind::WebSocketTls);
+}
+
+#[test]
+fn trust_prompt_answered_after_the_timeout() {
+    let server = tls_server(&self_signed(&["localhost"]));
+    let prompt = Arc::new(TrustPrompt::default());
+    let answer = Arc::clone(&prompt);
+    prompt.set_notifier(move |_| {
+        let answer = Arc::clone(&answer);
+        thread::spawn(move || {
+            thread::sleep(2 * Duration::from_millis(HANDSHAKE_TIMEOUT_MS as u64));
+            assert!(answer.decide(true), "no prompt waiting");
+        });
+    });
+    let ctx = TlsContext {
+        on_bad_cert: OnBadCert::Ask(prompt, BOUND),
+        configs: None,
+        key_log: None,
+    };
+    let names = ServerNames {
+        sni: "localhost".to_string(),
+        verify: "localhost".to_string(),
+    };
+    let tcp = TcpStream::connect(("127.0.0.1", server.port)).expect("connect");
+    let timeout = Duration::from_millis(HANDSHAKE_TIMEOUT_MS as u64);
+    let mut stream = tls::connect_direct(tcp, &names, &TlsConfig::default(), &ctx, &[], timeout)
+        .expect("handshake after the prompt was accepted");
+    let mut ready = [0u8; 2];
+    stream.read_exact(&mut ready).expect("read from server");
+    assert_eq!(ready, READY);
+}
@@ -0,0 +1,144 @@
+//! Server certificate verification modes against a loopback rustls server with locally
+//! generated certificates: custom trust anchors, SPKI pinning (with and without chain
+//! validation), a verify name other than the SNI, and the OS trust store.
+
+mod common;
+
+use std::io::Read;
+use std::net::TcpStream;
+use std::time::Duration;
+
+use common::{issued, self_signed, tls_server, Pki, READY};
+use whixp_transport::config::{PinMode, TlsConfig};
+use whixp_transport::handshake::HandshakeError;
+use whixp_transport::tls::{self, OnBadCert, ServerNames, TlsContext};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn names(sni: &str, verify: &str) -> ServerNames {
+    ServerNames {
+        sni: sni.to_string(),
+        verify: verify.to_string(),
+    }
+}
+
+/// Direct TLS to a server presenting `pki`; reads `READY` on success.
+fn connect(pki: &Pki, names: &ServerNames, tls: &TlsConfig) -> Result<(), HandshakeError> {
+    let server = tls_server(pki);
+    let tcp = TcpStream::connect(("127.0.0.1", server.port)).expect("connect");
+    let ctx = TlsContext {
+        on_bad_cert: OnBadCert::Reject,
+        configs: None,
+        key_log: None,
+    };
+    let mut stream = tls::connect_direct(tcp, names, tls, &ctx, &[], TIMEOUT)?;
+    let mut ready = [0u8; 2];
+    stream.read_exact(&mut ready).expect("read from server");
+    assert_eq!(ready, READY);
+    Ok(())
+}
+
+fn anchored(pem: &str) -> TlsConfig {
+    TlsConfig {
+        extra_roots: pem.as_bytes().to_vec(),
+        ..Default::default()
+    }
+}
+
+fn pinned(pki: &Pki, mode: PinMode) -> TlsConfig {
+    TlsConfig {
+        spki_pins: vec![tls::spki_sha256(pki.leaf()).expect("leaf SPKI")],
+        pin_mode: mode,
+        ..Default::default()
+    }
+}
+
+fn assert_rejected(result: Result<(), HandshakeError>) {
+    match result {
+        Err(HandshakeError::BadCertificate(_)) => {}
+        other => panic!("expected a certificate rejection, got {:?}", other),
+    }
+}
+
+#[test]
+fn custom_anchor_accepts() {
+    let pki = issued(&["localhost"]);
+    let names = names("localhost", "localhost");
+    connect(&pki, &names, &anchored(&pki.anchor_pem)).expect("trusted by its CA");
+}
+
+#[test]
+fn unknown_ca_rejects() {
+    let pki = issued(&["localhost"]);
+    let names = names("localhost", "localhost");
+    // Bundled roots only.
+    assert_rejected(connect(&pki, &names, &TlsConfig::default()));
+    // A different CA.
+    let other = issued(&["localhost"]);
+    assert_rejected(connect(&pki, &names, &anchored(&other.anchor_pem)));
+}
+
+#[test]
+fn wrong_name_rejects() {
+    let pki = issued(&["other.example"]);
+    let names = names("localhost", "localhost");
+    assert_rejected(connect(&pki, &names, &anchored(&pki.anchor_pem)));
+}
+
+#[test]
+fn verify_name_differs_from_sni() {
+    // Certificate for the source domain, SNI for a name it does not cover.
+    let pki = issued(&["localhost"]);
+    let tls = anchored(&pki.anchor_pem);
+    connect(&pki, &names("xmpp.example.net", "localhost"), &tls).expect("checked as localhost");
+    // The verify name still has to match.
+    assert_rejected(connect(&pki, &names("localhost", "example.net"), &tls));
+}
+
+#[test]
+fn spki_pin_matches() {
+    let names = names("localhost", "localhost");
+    // Pin only: a self-signed leaf no anchor covers.
+    let pki = self_signed(&["localhost"]);
+    connect(&pki, &names, &pinned(&pki, PinMode::PinOnly)).expect("pinned leaf");
+    // Pin and chain: anchored and pinned.
+    let pki = issued(&["localhost"]);
+    let tls = TlsConfig {
+        extra_roots: pki.anchor_pem.as_bytes().to_vec(),
+        ..pinned(&pki, PinMode::PinAndChain)
+    };
+    connect(&pki, &names, &tls).expect("anchored and pinned");
+}
+
+#[test]
+fn spki_pin_mismatch_rejects() {
+    let names = names("localhost", "localhost");
+    let pki = self_signed(&["localhost"]);
+    let other = self_signed(&["localhost"]);
+    assert_rejected(connect(&pki, &names, &pinned(&other, PinMode::PinOnly)));
+    // The chain validates but no certificate in it matches the pin.
+    let pki = issued(&["localhost"]);
+    let tls = TlsConfig {
+        extra_roots: pki.anchor_pem.as_bytes().to_vec(),
+        ..pinned(&other, PinMode::PinAndChain)
+    };
+    assert_rejected(connect(&pki, &names, &tls));
+    // Pinned, but the chain does not validate.
+    assert_rejected(connect(&pki, &names, &pinned(&pki, PinMode::PinAndChain)));
+}
+
+#[cfg(feature = "system-roots")]
+#[test]
+fn os_store_rejects_self_signed() {
+    let pki = self_signed(&["localhost"]);
+    let tls = TlsConfig {
+        trust_store: whixp_transport::config::TrustStore::System,
+        ..Default::default()
+    };
+    match connect(&pki, &names("localhost", "localhost"), &tls) {
+        Err(HandshakeError::BadCertificate(_)) => {}
+        // A host without an OS trust store fails before the handshake.
+        Err(HandshakeError::Tls(msg)) if msg.contains("system trust store") => {}
+        other => panic!("expected a rejection, got {:?}", other),
+    }
+}
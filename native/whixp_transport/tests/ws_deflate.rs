@@ -0,0 +1,269 @@
+//! permessage-deflate round trips through tungstenite over an in-memory socket, with the
+//! test playing the server: context takeover on and off in both directions, compressed
+//! messages fragmented across frames, and control frames between the fragments.
+
+use std::io::{self, Read, Write};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+use whixp_transport::ws_deflate::{DeflateParams, DeflateStream};
+
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+const UPGRADE: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\n\r\n";
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+const TEXT: u8 = 0x1;
+const CONTINUATION: u8 = 0x0;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+/// In-memory socket: reads what the server queued, a few bytes at a time so frames arrive
+/// split, and keeps what the client wrote.
+struct Pipe {
+    incoming: Vec<u8>,
+    pos: usize,
+    written: Vec<u8>,
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min(7).min(self.incoming.len() - self.pos);
+        buf[..n].copy_from_slice(&self.incoming[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.written.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Client over `server_frames`, past the upgrade response and with `params` enabled.
+fn client(params: DeflateParams, server_frames: &[Vec<u8>]) -> WebSocket<DeflateStream<Pipe>> {
+    let mut incoming = UPGRADE.to_vec();
+    incoming.extend(server_frames.concat());
+    let mut stream = DeflateStream::new(Pipe {
+        incoming,
+        pos: 0,
+        written: Vec::new(),
+    });
+    let mut head = Vec::new();
+    let mut buf = [0u8; 64];
+    while !head.ends_with(b"\r\n\r\n") {
+        let n = stream.read(&mut buf).expect("upgrade response");
+        assert!(n > 0, "upgrade response cut short");
+        head.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(head, UPGRADE, "read past the upgrade response");
+    stream.enable(params);
+    WebSocket::from_raw_socket(stream, Role::Client, None)
+}
+
+/// Unmasked server frame.
+fn frame(first: u8, payload: &[u8]) -> Vec<u8> {
+    let mut out = vec![first];
+    if payload.len() < 126 {
+        out.push(payload.len() as u8);
+    } else {
+        out.push(126);
+        out.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    }
+    out.extend_from_slice(payload);
+    out
+}
+
+/// One message deflated with a sync flush, without the tail.
+fn deflate(compress: &mut Compress, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 64);
+    compress
+        .compress_vec(data, &mut out, FlushCompress::Sync)
+        .expect("deflate");
+    assert!(out.ends_with(&TAIL));
+    out.truncate(out.len() - TAIL.len());
+    out
+}
+
+fn inflate(decompress: &mut Decompress, data: &[u8]) -> Vec<u8> {
+    let mut input = data.to_vec();
+    input.extend_from_slice(&TAIL);
+    let mut out = Vec::with_capacity(64 * 1024);
+    decompress
+        .decompress_vec(&input, &mut out, FlushDecompress::Sync)
+        .expect("inflate");
+    out
+}
+
+/// Frames the client wrote: (first header byte, unmasked payload).
+fn written_frames(mut b: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    let mut frames = Vec::new();
+    while !b.is_empty() {
+        assert!(b[1] & 0x80 != 0, "client frame not masked");
+        let (len, mut at) = match b[1] & 0x7f {
+            126 => (u16::from_be_bytes([b[2], b[3]]) as usize, 4),
+            127 => panic!("unexpectedly large frame"),
+            n => (n as usize, 2),
+        };
+        let mask = [b[at], b[at + 1], b[at + 2], b[at + 3]];
+        at += 4;
+        let payload = b[at..at + len]
+            .iter()
+            .enumerate()
+            .map(|(i, c)| c ^ mask[i % 4])
+            .collect();
+        frames.push((b[0], payload));
+        b = &b[at + len..];
+    }
+    frames
+}
+
+fn read_text(ws: &mut WebSocket<DeflateStream<Pipe>>) -> String {
+    match ws.read().expect("read") {
+        Message::Text(text) => text.as_str().to_string(),
+        other => panic!("expected text, got {:?}", other),
+    }
+}
+
+fn params(no_context_takeover: bool) -> DeflateParams {
+    DeflateParams {
+        server_no_context_takeover: no_context_takeover,
+        client_no_context_takeover: no_context_takeover,
+        compress_outgoing: true,
+    }
+}
+
+const STANZA: &str = "<message to='juliet@example.com' from='romeo@example.net/orchard' \
+    type='chat'><body>Art thou not Romeo, and a Montague?</body></message>";
+
+#[test]
+fn context_takeover() {
+    // Server side: the second message refers back into the first.
+    let mut server = Compress::new(Compression::default(), false);
+    let first = deflate(&mut server, STANZA.as_bytes());
+    let second = deflate(&mut server, STANZA.as_bytes());
+    assert!(second.len() < first.len() / 2);
+    let mut ws = client(
+        params(false),
+        &[
+            frame(FIN | RSV1 | TEXT, &first),
+            frame(FIN | RSV1 | TEXT, &second),
+        ],
+    );
+    assert_eq!(read_text(&mut ws), STANZA);
+    assert_eq!(read_text(&mut ws), STANZA);
+
+    // Client side: one inflater for the whole connection.
+    ws.send(Message::text(STANZA)).expect("send");
+    ws.send(Message::text(STANZA)).expect("send");
+    let sent = written_frames(&ws.get_ref().get_ref().written);
+    assert_eq!(sent.len(), 2);
+    let mut inflater = Decompress::new(false);
+    for (first_byte, payload) in &sent {
+        assert_eq!(*first_byte, FIN | RSV1 | TEXT);
+        assert_eq!(inflate(&mut inflater, payload), STANZA.as_bytes());
+    }
+    assert!(sent[1].1.len() < sent[0].1.len() / 2, "context not kept");
+}
+
+#[test]
+fn no_context_takeover() {
+    // Server side: each message is deflated on its own.
+    let message = |text: &str| {
+        let mut server = Compress::new(Compression::default(), false);
+        frame(FIN | RSV1 | TEXT, &deflate(&mut server, text.as_bytes()))
+    };
+    let mut ws = client(params(true), &[message(STANZA), message("<presence/>")]);
+    assert_eq!(read_text(&mut ws), STANZA);
+    assert_eq!(read_text(&mut ws), "<presence/>");
+
+    // Client side: every message inflates with a fresh inflater.
+    ws.send(Message::text(STANZA)).expect("send");
+    ws.send(Message::text(STANZA)).expect("send");
+    let sent = written_frames(&ws.get_ref().get_ref().written);
+    assert_eq!(sent.len(), 2);
+    for (first_byte, payload) in &sent {
+        assert_eq!(*first_byte, FIN | RSV1 | TEXT);
+        let mut inflater = Decompress::new(false);
+        assert_eq!(inflate(&mut inflater, payload), STANZA.as_bytes());
+    }
+    assert_eq!(sent[0].1, sent[1].1);
+}
+
+#[test]
+fn fragmented_message_with_control_frames() {
+    let mut server = Compress::new(Compression::default(), false);
+    let long = STANZA.repeat(20);
+    let compressed = deflate(&mut server, long.as_bytes());
+    let third = compressed.len() / 3;
+    let (a, rest) = compressed.split_at(third);
+    let (b, c) = rest.split_at(third);
+    let after = deflate(&mut server, b"<presence/>");
+    let mut ws = client(
+        params(false),
+        &[
+            // RSV1 only on the first fragment (RFC 7692 6.1).
+            frame(RSV1 | TEXT, a),
+            frame(FIN | PING, b"p1"),
+            frame(CONTINUATION, b),
+            frame(FIN | PONG, b""),
+            frame(FIN | PING, b"p2"),
+            frame(FIN | CONTINUATION, c),
+            // The next message still inflates with the context.
+            frame(FIN | RSV1 | TEXT, &after),
+        ],
+    );
+
+    let mut pings = Vec::new();
+    let text = loop {
+        match ws.read().expect("read") {
+            Message::Ping(p) => pings.push(p.to_vec()),
+            Message::Pong(_) => {}
+            Message::Text(t) => break t.as_str().to_string(),
+            other => panic!("unexpected {:?}", other),
+        }
+    };
+    assert_eq!(text, long);
+    assert_eq!(pings, vec![b"p1".to_vec(), b"p2".to_vec()]);
+    assert_eq!(read_text(&mut ws), "<presence/>");
+
+    // Pongs go out as they are, never compressed.
+    ws.flush().expect("flush");
+    let pongs: Vec<(u8, Vec<u8>)> = written_frames(&ws.get_ref().get_ref().written);
+    assert!(!pongs.is_empty());
+    for (first_byte, payload) in &pongs {
+        assert_eq!(*first_byte, FIN | PONG);
+        assert!(payload == b"p1" || payload == b"p2");
+    }
+}
+
+#[test]
+fn outgoing_fragments_pass_through() {
+    let mut ws = client(params(false), &[]);
+    let stream = ws.get_mut();
+    let mask = [1, 2, 3, 4];
+    let masked = |first: u8, data: &[u8]| {
+        let mut out = vec![first, 0x80 | data.len() as u8];
+        out.extend_from_slice(&mask);
+        out.extend(data.iter().enumerate().map(|(i, c)| c ^ mask[i % 4]));
+        out
+    };
+    stream.write_all(&masked(TEXT, b"<presence")).unwrap();
+    stream
+        .write_all(&masked(FIN | CONTINUATION, b"/>"))
+        .unwrap();
+    stream.flush().unwrap();
+    assert_eq!(
+        written_frames(&stream.get_ref().written),
+        vec![
+            (TEXT, b"<presence".to_vec()),
+            (FIN | CONTINUATION, b"/>".to_vec())
+        ]
+    );
+}
@@ -0,0 +1,193 @@
+//! RFC 7395 WebSocket framing through the FFI against a loopback tungstenite server: the
+//! `xmpp` subprotocol is requested and must be accepted, `<open/>`, `<close/>` and
+//! see-other-host arrive as poll codes 6, 7 and 8, and a write holding several elements
+//! goes out as one message per element.
+
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tungstenite::handshake::server::{Request, Response};
+use tungstenite::http::HeaderValue;
+use tungstenite::Message;
+use whixp_transport::handshake::HandshakeErrorCode;
+use whixp_transport::*;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const FRAMING: &str = "urn:ietf:params:xml:ns:xmpp-framing";
+
+/// What the server saw: the subprotocols the client asked for, then each message it got.
+struct Server {
+    port: u16,
+    protocols: mpsc::Receiver<Option<String>>,
+    messages: mpsc::Receiver<String>,
+}
+
+/// Accepts one WebSocket, answering with `protocol` (None = no subprotocol), sends `script`
+/// as one text message each and then reports every text message from the client.
+fn ws_server(protocol: Option<&'static str>, script: Vec<String>) -> Server {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let port = listener.local_addr().expect("local addr").port();
+    let (protocol_tx, protocols) = mpsc::channel();
+    let (message_tx, messages) = mpsc::channel();
+    thread::spawn(move || {
+        let (tcp, _) = listener.accept().expect("accept");
+        // The error type is tungstenite's.
+        #[allow(clippy::result_large_err)]
+        let callback = |request: &Request, mut response: Response| {
+            let asked = request
+                .headers()
+                .get("Sec-WebSocket-Protocol")
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string);
+            let _ = protocol_tx.send(asked);
+            if let Some(protocol) = protocol {
+                let value = HeaderValue::from_static(protocol);
+                response
+                    .headers_mut()
+                    .insert("Sec-WebSocket-Protocol", value);
+            }
+            Ok(response)
+        };
+        let Ok(mut ws) = tungstenite::accept_hdr(tcp, callback) else {
+            return;
+        };
+        for text in script {
+            if ws.send(Message::text(text)).is_err() {
+                return;
+            }
+        }
+        while let Ok(msg) = ws.read() {
+            if let Message::Text(text) = msg {
+                let _ = message_tx.send(text.to_string());
+            }
+        }
+    });
+    Server {
+        port,
+        protocols,
+        messages,
+    }
+}
+
+unsafe fn create(port: u16) -> *mut Handle {
+    let host = "127.0.0.1";
+    let mut config: CTransportConfig = std::mem::zeroed();
+    config.host_ptr = host.as_ptr() as *const _;
+    config.host_len = host.len() as u32;
+    config.port = port;
+    config.kind = 3;
+    config.connect_timeout_ms = 5000;
+    config.ws_framing = 1;
+    let handle = whixp_transport_create(&config);
+    assert!(!handle.is_null(), "create");
+    handle
+}
+
+/// Next event other than a state change: (poll code, payload).
+unsafe fn next_event(handle: *mut Handle) -> (i32, String) {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let code = whixp_transport_poll(handle);
+        match code {
+            0 => {
+                assert!(Instant::now() < deadline, "no event");
+                thread::sleep(Duration::from_millis(10));
+            }
+            1 => whixp_transport_poll_clear(handle),
+            _ => {
+                let (mut ptr, mut len) = (std::ptr::null(), 0u32);
+                whixp_transport_get_polled_stanza(handle, &mut ptr, &mut len);
+                let payload = if ptr.is_null() {
+                    String::new()
+                } else {
+                    let bytes = std::slice::from_raw_parts(ptr, len as usize);
+                    String::from_utf8_lossy(bytes).into_owned()
+                };
+                whixp_transport_poll_clear(handle);
+                return (code, payload);
+            }
+        }
+    }
+}
+
+unsafe fn last_error(handle: *mut Handle) -> String {
+    let (mut ptr, mut len) = (std::ptr::null(), 0u32);
+    whixp_transport_get_last_error(handle, &mut ptr, &mut len);
+    if ptr.is_null() {
+        return String::new();
+    }
+    String::from_utf8_lossy(std::slice::from_raw_parts(ptr, len as usize)).into_owned()
+}
+
+#[test]
+fn framing_elements_map_to_poll_codes() {
+    let open = format!(
+        "<open xmlns='{}' from='localhost' id='1' version='1.0'/>",
+        FRAMING
+    );
+    let features = "<stream:features xmlns:stream='http://etherx.jabber.org/streams'/>";
+    let redirect = format!(
+        "<close xmlns='{}' see-other-host='wss://other.example/xmpp'/>",
+        FRAMING
+    );
+    let close = format!("<close xmlns='{}'/>", FRAMING);
+    let script = vec![open.clone(), features.to_string(), redirect, close.clone()];
+    let server = ws_server(Some("xmpp"), script);
+    unsafe {
+        let handle = create(server.port);
+        assert_eq!(whixp_transport_connect(handle), 0, "connect");
+        let asked = server.protocols.recv_timeout(TIMEOUT).expect("upgrade");
+        assert_eq!(asked.as_deref(), Some("xmpp"));
+
+        assert_eq!(next_event(handle), (6, open));
+        assert_eq!(next_event(handle), (2, features.to_string()));
+        assert_eq!(
+            next_event(handle),
+            (8, "wss://other.example/xmpp".to_string())
+        );
+        assert_eq!(next_event(handle), (7, close));
+        whixp_transport_destroy(handle);
+    }
+}
+
+#[test]
+fn one_message_per_element() {
+    let server = ws_server(Some("xmpp"), Vec::new());
+    let elements = [
+        format!("<open xmlns='{}' to='localhost' version='1.0'/>", FRAMING),
+        "<iq type='get' id='1'><query xmlns='jabber:iq:version'/></iq>".to_string(),
+        "<presence/>".to_string(),
+    ];
+    let data = elements.concat();
+    unsafe {
+        let handle = create(server.port);
+        assert_eq!(whixp_transport_connect(handle), 0, "connect");
+        assert_eq!(
+            whixp_transport_send(handle, data.as_ptr(), data.len() as u32),
+            0,
+            "send"
+        );
+        for element in &elements {
+            let message = server.messages.recv_timeout(TIMEOUT).expect("message");
+            assert_eq!(&message, element);
+        }
+        whixp_transport_destroy(handle);
+    }
+}
+
+#[test]
+fn server_refusing_the_subprotocol_fails_the_upgrade() {
+    let server = ws_server(None, Vec::new());
+    unsafe {
+        let handle = create(server.port);
+        let code = whixp_transport_connect(handle);
+        let asked = server.protocols.recv_timeout(TIMEOUT).expect("upgrade");
+        assert_eq!(asked.as_deref(), Some("xmpp"));
+        assert_eq!(code, HandshakeErrorCode::Stream as i32, "connect");
+        let error = last_error(handle);
+        assert!(error.contains("xmpp WebSocket subprotocol"), "{}", error);
+        whixp_transport_destroy(handle);
+    }
+}
@@ -1,3 +1,6 @@
+import 'dart:async';
+import 'dart:io';
+
 import 'package:test/test.dart';
 
 import 'package:whixp/src/enums.dart';
@@ -397,6 +400,66 @@ void main() {
             equals(5000),
           );
         });
+
+        test('pushes native events unless turned off', () {
+          expect(
+            Transport('example.com').connection.configuration.pushEvents,
+            isTrue,
+          );
+          expect(
+            Transport('example.com', pushEvents: false)
+                .connection
+                .configuration
+                .pushEvents,
+            isFalse,
+          );
+        });
+      });
+
+      group('Native Events', () {
+        // Connects over plain TCP to a local server, then has the server
+        // close the socket: the native disconnect must still reach Dart.
+        Future<void> closeFromServer({required bool pushEvents}) async {
+          final server =
+              await ServerSocket.bind(InternetAddress.loopbackIPv4, 0);
+          final accepted = server.first;
+          final transport = Transport(
+            '127.0.0.1',
+            port: server.port,
+            disableStartTLS: true,
+            connectionTimeout: 5000,
+            pushEvents: pushEvents,
+          );
+          final connected = Completer<void>();
+          final disconnected = Completer<void>();
+          transport.addEventHandler<TransportState>('state', (state) {
+            if (state == TransportState.connected && !connected.isCompleted) {
+              connected.complete();
+            }
+            if (state == TransportState.disconnected &&
+                !disconnected.isCompleted) {
+              disconnected.complete();
+            }
+          });
+
+          transport.connect();
+
+          final socket = await accepted.timeout(const Duration(seconds: 10));
+          await connected.future.timeout(const Duration(seconds: 10));
+          expect(transport.connection.pushesEvents, pushEvents);
+          socket.destroy();
+          await disconnected.future.timeout(const Duration(seconds: 10));
+          transport.connection.abort();
+          await server.close();
+        }
+
+        test('delivers events in push mode by default', () async {
+          await closeFromServer(pushEvents: true);
+        });
+
+        test('delivers events by polling when push is off', () async {
+          await closeFromServer(pushEvents: false);
+        });
       });
     },
     skip: isNativeTransportAvailable